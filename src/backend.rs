@@ -0,0 +1,132 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Object-safe interface over Redis connections
+use async_trait::async_trait;
+use redis::{from_redis_value, FromRedisValue, RedisResult, Value};
+
+use crate::{Redis, RedisConnection};
+
+/// Something that can run Redis commands, usable as `&dyn RedisBackend` so
+/// code can hold a connection without being generic over it, and tests can
+/// swap in a fake, like the in-memory `MockRedis` of the `test-helpers`
+/// feature.
+///
+/// Only the untyped core lives here to keep the trait object-safe; the typed
+/// helpers are in [RedisBackendExt], implemented for every backend.
+///
+/// Backends are `Send + Sync` and their futures `Send`, so a [BoxedRedis]
+/// can be shared with tasks on any runtime thread.
+#[async_trait]
+pub trait RedisBackend: Send + Sync {
+    /// Run `cmd`, returning the raw reply
+    async fn query(&self, cmd: &mut redis::Cmd) -> RedisResult<Value>;
+
+    /// Run `pipe`, returning the raw replies
+    async fn query_pipe(&self, pipe: &redis::Pipeline) -> RedisResult<Value>;
+
+    /// Whether the backend talks to a Redis Cluster
+    fn is_cluster(&self) -> bool;
+}
+
+/// A boxed [RedisBackend], see [Redis::boxed]
+pub type BoxedRedis = Box<dyn RedisBackend>;
+
+/// Typed helpers on top of [RedisBackend]
+#[async_trait]
+pub trait RedisBackendExt: RedisBackend {
+    /// Run `cmd` and convert the reply to `T`
+    async fn exec<T: FromRedisValue>(&self, cmd: &mut redis::Cmd) -> RedisResult<T> {
+        from_redis_value(&self.query(cmd).await?)
+    }
+
+    /// Run `pipe` and convert the replies to `T`
+    async fn exec_pipe<T: FromRedisValue>(&self, pipe: &redis::Pipeline) -> RedisResult<T> {
+        from_redis_value(&self.query_pipe(pipe).await?)
+    }
+}
+
+impl<B: RedisBackend + ?Sized> RedisBackendExt for B {}
+
+#[async_trait]
+impl RedisBackend for RedisConnection {
+    async fn query(&self, cmd: &mut redis::Cmd) -> RedisResult<Value> {
+        RedisConnection::exec(self, cmd).await
+    }
+
+    async fn query_pipe(&self, pipe: &redis::Pipeline) -> RedisResult<Value> {
+        RedisConnection::exec_grouped(self, pipe).await
+    }
+
+    fn is_cluster(&self) -> bool {
+        RedisConnection::is_cluster(self)
+    }
+}
+
+impl Redis {
+    /// The shared connection as a [BoxedRedis]
+    pub fn boxed(&self) -> BoxedRedis {
+        Box::new(self.get_client())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    struct App {
+        redis: BoxedRedis,
+    }
+
+    impl App {
+        async fn visit(&self, page: &str) -> RedisResult<u64> {
+            self.redis.exec(redis::cmd("INCR").arg(page)).await
+        }
+    }
+
+    /// Answers every command with the same reply
+    struct Fake(Value);
+
+    #[async_trait]
+    impl RedisBackend for Fake {
+        async fn query(&self, _cmd: &mut redis::Cmd) -> RedisResult<Value> {
+            Ok(self.0.clone())
+        }
+
+        async fn query_pipe(&self, _pipe: &redis::Pipeline) -> RedisResult<Value> {
+            Ok(Value::Bulk(vec![self.0.clone()]))
+        }
+
+        fn is_cluster(&self) -> bool {
+            false
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fake_backend_can_be_swapped_in() {
+        let app = App {
+            redis: Box::new(Fake(Value::Int(7))),
+        };
+        assert_eq!(app.visit("home").await.unwrap(), 7);
+        let replies: Vec<u64> = app.redis.exec_pipe(&redis::pipe()).await.unwrap();
+        assert_eq!(replies, vec![7]);
+    }
+
+    #[actix_rt::test]
+    async fn boxed_redis_runs_commands() {
+        const KEY: &str = "boxed_redis_runs_commands";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let app = App { redis: r.boxed() };
+        let _: () = app.redis.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert_eq!(app.visit(KEY).await.unwrap(), 1);
+        assert_eq!(app.visit(KEY).await.unwrap(), 2);
+        assert!(!app.redis.is_cluster());
+    }
+}
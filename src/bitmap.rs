@@ -0,0 +1,112 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Bitmap helpers
+use redis::RedisResult;
+
+use crate::RedisConnection;
+
+/// Unit of the range passed to [RedisConnection::bitpos]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitUnit {
+    /// Offsets count bytes
+    Byte,
+    /// Offsets count bits (Redis 7+)
+    Bit,
+}
+
+impl RedisConnection {
+    /// Offset of the first bit set to `bit` in the string at `key` (BITPOS),
+    /// optionally only looking between the `start` and `end` offsets of
+    /// `range` (inclusive, negative offsets count from the end).
+    ///
+    /// Returns -1 when no such bit exists. Looking for a clear bit without an
+    /// end offset is the exception: the string is considered padded with
+    /// zeros, so the first bit past its end is returned.
+    pub async fn bitpos(
+        &self,
+        key: &str,
+        bit: bool,
+        range: Option<(i64, i64, BitUnit)>,
+    ) -> RedisResult<i64> {
+        let mut cmd = redis::cmd("BITPOS");
+        cmd.arg(key).arg(bit as u8);
+        if let Some((start, end, unit)) = range {
+            cmd.arg(start).arg(end);
+            // BYTE is the default, and spelling it out needs Redis 7
+            if unit == BitUnit::Bit {
+                cmd.arg("BIT");
+            }
+        }
+        self.exec(&mut cmd).await
+    }
+
+    /// Read integers packed in the string at `key` (BITFIELD_RO, Redis 6.2+),
+    /// one per `(type, bit offset)` pair, where types are like `u8` or `i16`.
+    /// Only reads, so it is safe on replicas and read-only connections.
+    pub async fn bitfield_ro(&self, key: &str, fields: &[(&str, u64)]) -> RedisResult<Vec<i64>> {
+        let mut cmd = redis::cmd("BITFIELD_RO");
+        cmd.arg(key);
+        for (ty, offset) in fields {
+            cmd.arg("GET").arg(*ty).arg(*offset);
+        }
+        self.exec(&mut cmd).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn bitpos_finds_set_bit() {
+        const KEY: &str = "bitpos_finds_set_bit";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("SETBIT").arg(KEY).arg(11).arg(1))
+            .await
+            .unwrap();
+
+        assert_eq!(con.bitpos(KEY, true, None).await.unwrap(), 11);
+        assert_eq!(con.bitpos(KEY, false, None).await.unwrap(), 0);
+        let first_byte = Some((0, 0, BitUnit::Byte));
+        assert_eq!(con.bitpos(KEY, true, first_byte).await.unwrap(), -1);
+        let from_bit_12 = Some((12, -1, BitUnit::Bit));
+        assert_eq!(con.bitpos(KEY, true, from_bit_12).await.unwrap(), -1);
+    }
+
+    #[actix_rt::test]
+    async fn bitfield_ro_reads_packed_counters() {
+        const KEY: &str = "bitfield_ro_reads_packed_counters";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: Vec<i64> = con
+            .exec(
+                redis::cmd("BITFIELD")
+                    .arg(KEY)
+                    .arg(&["SET", "u8", "0", "200", "SET", "u8", "8", "7"]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            con.read_only()
+                .bitfield_ro(KEY, &[("u8", 0), ("u8", 8), ("u8", 16)])
+                .await
+                .unwrap(),
+            vec![200, 7, 0]
+        );
+    }
+}
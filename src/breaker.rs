@@ -0,0 +1,136 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Circuit breaker that stops hammering an unreachable server
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use redis::RedisResult;
+
+use crate::GlueError;
+
+/// Settings of the circuit breaker, see [crate::RedisOptions::circuit_breaker]
+#[derive(Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive connection failures that open the circuit
+    pub failure_threshold: u32,
+    /// How long an open circuit fails requests before letting a probe through
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cooldown: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Breaker state, shared by every connection of a [crate::Redis]
+#[derive(Default)]
+pub(crate) struct Breaker {
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    /// Requests fail until then. `Some` while open and while a probe runs.
+    open_until: Option<Instant>,
+    /// A probe was let through and its outcome decides the next state
+    half_open: bool,
+}
+
+impl Breaker {
+    /// Fail fast while the circuit is open. Once the cooldown is over, one
+    /// request is let through as a probe and the rest keep failing until
+    /// it completes, or until another cooldown passes if it never does.
+    pub(crate) fn check(&self, config: &Option<CircuitBreakerConfig>) -> RedisResult<()> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(until) if Instant::now() < until => Err(GlueError::CircuitOpen.into()),
+            Some(_) => {
+                state.half_open = true;
+                state.open_until = Some(Instant::now() + config.cooldown);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Update the breaker with the outcome of a request that passed
+    /// [Self::check]. Only connection errors count as failures: an error
+    /// reply still proves the server is there.
+    pub(crate) fn record<T>(&self, config: &Option<CircuitBreakerConfig>, res: &RedisResult<T>) {
+        let config = match config {
+            Some(config) => config,
+            None => return,
+        };
+        let mut state = self.state.lock().unwrap();
+        match res {
+            Err(e) if e.is_io_error() => {
+                state.failures = state.failures.saturating_add(1);
+                if state.half_open || state.failures >= config.failure_threshold {
+                    state.open_until = Some(Instant::now() + config.cooldown);
+                    state.half_open = false;
+                }
+            }
+            _ => *state = BreakerState::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn breaker_fast_fails_until_cooldown() {
+        let options = RedisOptions {
+            circuit_breaker: Some(CircuitBreakerConfig {
+                failure_threshold: 2,
+                cooldown: Duration::from_millis(300),
+            }),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let killer = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let id: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let _: () = killer
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+
+        for _ in 0..2 {
+            let err = con.exec::<()>(&mut redis::cmd("PING")).await.unwrap_err();
+            assert!(err.is_io_error());
+        }
+        let err = con.exec::<()>(&mut redis::cmd("PING")).await.unwrap_err();
+        assert_eq!(GlueError::from_redis(&err), Some(GlueError::CircuitOpen));
+        // reconnecting is held back too
+        let err = r.ensure_connected().await.unwrap_err();
+        assert_eq!(GlueError::from_redis(&err), Some(GlueError::CircuitOpen));
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        // the probe reconnects, which closes the circuit
+        r.reconnect().await.unwrap();
+        assert!(con.ping().await);
+    }
+}
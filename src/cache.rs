@@ -0,0 +1,308 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Read-through caching
+use std::future::Future;
+use std::time::Duration;
+
+use redis::{FromRedisValue, RedisError, RedisResult, ToRedisArgs};
+
+use crate::{GlueError, Redis, RedisConnection};
+
+/// First delay between cache polls while another caller computes the value
+const POLL_BASE: Duration = Duration::from_millis(10);
+/// Longest delay between cache polls
+const POLL_MAX: Duration = Duration::from_millis(200);
+
+impl RedisConnection {
+    /// Value of `key`, computing and caching it for `ttl` on a miss.
+    ///
+    /// Only one caller computes on a miss: it takes the lock at `<key>:lock`
+    /// (see [Self::try_lock]) for `lock_ttl`, while everyone else polls the
+    /// cache with a short backoff until the value shows up, or until the lock
+    /// expires and one of them takes over. `lock_ttl` should comfortably
+    /// exceed the time `compute` takes.
+    pub async fn get_or_set_locked<T, F, Fut>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        lock_ttl: Duration,
+        compute: F,
+    ) -> RedisResult<T>
+    where
+        T: FromRedisValue + ToRedisArgs,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let lock_key = format!("{}:lock", key);
+        let mut delay = POLL_BASE;
+        let lock = loop {
+            if let Some(val) = self.cached(key).await? {
+                return Ok(val);
+            }
+            if let Some(lock) = self.try_lock(&lock_key, lock_ttl).await? {
+                // the previous holder may have filled the cache between our
+                // GET and taking the lock
+                if let Some(val) = self.cached(key).await? {
+                    lock.release(self).await?;
+                    return Ok(val);
+                }
+                break lock;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(POLL_MAX);
+        };
+        let res = self.fill(key, ttl, compute).await;
+        lock.release(self).await?;
+        res
+    }
+
+    async fn cached<T: FromRedisValue>(&self, key: &str) -> RedisResult<Option<T>> {
+        self.exec(redis::cmd("GET").arg(key)).await
+    }
+
+    async fn fill<T, F, Fut>(&self, key: &str, ttl: Duration, compute: F) -> RedisResult<T>
+    where
+        T: ToRedisArgs,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let val = compute().await?;
+        let _: () = self
+            .exec(
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(val.to_redis_args())
+                    .arg("PX")
+                    .arg((ttl.as_millis() as u64).max(1)),
+            )
+            .await?;
+        Ok(val)
+    }
+}
+
+/// How a [CacheClient] copes with Redis being unreachable. Both are off by
+/// default, so errors propagate as usual.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Reads that fail because Redis is unreachable (connection errors,
+    /// timeouts, an open circuit breaker) return `Ok(None)`, i.e. a cache
+    /// miss, instead. Error replies from the server still fail.
+    pub fail_open: bool,
+    /// Writes that fail because Redis is unreachable return `Ok(())` instead,
+    /// dropping the write. Only for data that can be recomputed.
+    pub drop_failed_writes: bool,
+}
+
+/// Helpers for using Redis as a best-effort cache, see [Redis::cache].
+///
+/// A failure that [CacheOptions] turns into a miss or a dropped write is
+/// logged with the `log` feature. The options only apply to this client's
+/// helpers, never to commands sent through [Self::connection], so writes
+/// that matter can't lose errors by accident.
+#[derive(Clone)]
+pub struct CacheClient {
+    connection: RedisConnection,
+    options: CacheOptions,
+}
+
+impl Redis {
+    /// Get a client for cache reads and writes that can keep going while
+    /// Redis is down, see [CacheOptions]
+    pub fn cache(&self, options: CacheOptions) -> CacheClient {
+        CacheClient {
+            connection: self.get_client(),
+            options,
+        }
+    }
+}
+
+impl CacheClient {
+    /// The underlying connection, which propagates every error
+    pub fn connection(&self) -> &RedisConnection {
+        &self.connection
+    }
+
+    /// Value of `key` (GET), `None` on a miss
+    pub async fn get<T: FromRedisValue>(&self, key: &str) -> RedisResult<Option<T>> {
+        let res = self.connection.exec(redis::cmd("GET").arg(key)).await;
+        self.read(key, res)
+    }
+
+    /// Set `key` to `val` (SET), expiring after `ttl` if given, rounded up to
+    /// a millisecond
+    pub async fn set<V: ToRedisArgs>(
+        &self,
+        key: &str,
+        val: V,
+        ttl: Option<Duration>,
+    ) -> RedisResult<()> {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(val);
+        if let Some(ttl) = ttl {
+            cmd.arg("PX").arg((ttl.as_millis() as u64).max(1));
+        }
+        let res = self.connection.exec(&mut cmd).await;
+        self.write(key, res)
+    }
+
+    /// Value of `key` decoded from JSON, `None` on a miss. A value that isn't
+    /// valid JSON for `T` fails even with [CacheOptions::fail_open].
+    #[cfg(feature = "serde")]
+    pub async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        key: &str,
+    ) -> RedisResult<Option<T>> {
+        let raw: Option<Vec<u8>> = self.get(key).await?;
+        raw.map(|raw| serde_json::from_slice(&raw))
+            .transpose()
+            .map_err(|e| {
+                RedisError::from((
+                    redis::ErrorKind::TypeError,
+                    "cached value isn't valid JSON for the requested type",
+                    format!("{}: {}", key, e),
+                ))
+            })
+    }
+
+    /// Store `val` at `key` as JSON, see [Self::set]
+    #[cfg(feature = "serde")]
+    pub async fn set_json<T: serde::Serialize>(
+        &self,
+        key: &str,
+        val: &T,
+        ttl: Option<Duration>,
+    ) -> RedisResult<()> {
+        let raw = serde_json::to_vec(val).map_err(|e| {
+            RedisError::from((
+                redis::ErrorKind::TypeError,
+                "value couldn't be serialized",
+                e.to_string(),
+            ))
+        })?;
+        self.set(key, raw, ttl).await
+    }
+
+    fn read<T>(&self, key: &str, res: RedisResult<Option<T>>) -> RedisResult<Option<T>> {
+        match res {
+            Err(e) if self.options.fail_open && is_unreachable(&e) => {
+                report_fail_open(key, "treating as a cache miss", &e);
+                Ok(None)
+            }
+            res => res,
+        }
+    }
+
+    fn write(&self, key: &str, res: RedisResult<()>) -> RedisResult<()> {
+        match res {
+            Err(e) if self.options.drop_failed_writes && is_unreachable(&e) => {
+                report_fail_open(key, "dropping the write", &e);
+                Ok(())
+            }
+            res => res,
+        }
+    }
+}
+
+/// Whether `err` means Redis couldn't be reached at all, as opposed to an
+/// error reply
+pub(crate) fn is_unreachable(err: &RedisError) -> bool {
+    err.is_io_error() || GlueError::from_redis(err) == Some(GlueError::CircuitOpen)
+}
+
+#[cfg(feature = "log")]
+fn report_fail_open(key: &str, action: &str, err: &RedisError) {
+    log::warn!("redis unreachable for {}, {}: {}", key, action, err);
+}
+
+#[cfg(not(feature = "log"))]
+fn report_fail_open(_key: &str, _action: &str, _err: &RedisError) {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn get_or_set_locked_computes_once() {
+        const KEY: &str = "get_or_set_locked_computes_once";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("DEL").arg(KEY).arg(format!("{}:lock", KEY)))
+            .await
+            .unwrap();
+
+        let calls = Rc::new(Cell::new(0));
+        let mut callers = Vec::new();
+        for _ in 0..20 {
+            let con = r.dedicated().await.unwrap();
+            let calls = Rc::clone(&calls);
+            callers.push(actix_rt::spawn(async move {
+                con.get_or_set_locked(
+                    KEY,
+                    Duration::from_secs(10),
+                    Duration::from_secs(5),
+                    || async {
+                        calls.set(calls.get() + 1);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        Ok("computed".to_string())
+                    },
+                )
+                .await
+                .unwrap()
+            }));
+        }
+        for caller in callers {
+            assert_eq!(caller.await.unwrap(), "computed");
+        }
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn fail_open_turns_outages_into_misses() {
+        const KEY: &str = "fail_open_turns_outages_into_misses";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let killer = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let best_effort = r.cache(CacheOptions {
+            fail_open: true,
+            drop_failed_writes: true,
+        });
+        let strict = r.cache(CacheOptions::default());
+
+        best_effort.set(KEY, "v", None).await.unwrap();
+        let get: Option<String> = best_effort.get(KEY).await.unwrap();
+        assert_eq!(get.as_deref(), Some("v"));
+
+        // simulate an outage by killing the connection under the clients
+        let id: i64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+        let _: () = killer
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+
+        assert!(best_effort.get::<String>(KEY).await.unwrap().is_none());
+        best_effort.set(KEY, "w", None).await.unwrap();
+        assert!(strict.get::<String>(KEY).await.is_err());
+        assert!(strict.set(KEY, "w", None).await.is_err());
+    }
+}
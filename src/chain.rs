@@ -0,0 +1,189 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Pipelines with a typed reply per command
+use std::marker::PhantomData;
+
+use redis::{FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::RedisConnection;
+
+/// Type-level append: the tuple `Self` with `U` added at the end
+pub trait Append<U> {
+    type Output;
+}
+
+impl<U> Append<U> for () {
+    type Output = (U,);
+}
+
+macro_rules! append_impls {
+    ($($t:ident)+) => {
+        impl<$($t,)+ U> Append<U> for ($($t,)+) {
+            type Output = ($($t,)+ U);
+        }
+    };
+}
+
+append_impls!(A);
+append_impls!(A B);
+append_impls!(A B C);
+append_impls!(A B C D);
+append_impls!(A B C D E);
+append_impls!(A B C D E F);
+append_impls!(A B C D E F G);
+append_impls!(A B C D E F G H);
+append_impls!(A B C D E F G H I);
+append_impls!(A B C D E F G H I J);
+append_impls!(A B C D E F G H I J K);
+
+/// A pipeline whose replies come back as a tuple, one element per command
+/// typed by the method that added it: `Chain::new().set("a", 1).incr("b")
+/// .get::<String>("c")` runs into a `((), i64, Option<String>)`.
+///
+/// Holds up to 12 commands, the most [FromRedisValue] supports in a tuple.
+/// Replies are converted when the chain runs, so the types are only checked
+/// against the server's replies then.
+pub struct Chain<T = ()> {
+    pipe: redis::Pipeline,
+    replies: PhantomData<fn() -> T>,
+}
+
+impl Chain {
+    pub fn new() -> Self {
+        Self {
+            pipe: redis::pipe(),
+            replies: PhantomData,
+        }
+    }
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Chain<T> {
+    /// Add `cmd`, its reply converted to `U`
+    pub fn cmd<U: FromRedisValue>(mut self, cmd: redis::Cmd) -> Chain<T::Output>
+    where
+        T: Append<U>,
+    {
+        self.pipe.add_command(cmd);
+        Chain {
+            pipe: self.pipe,
+            replies: PhantomData,
+        }
+    }
+
+    /// SET `key` to `val`
+    pub fn set<V: ToRedisArgs>(self, key: &str, val: V) -> Chain<T::Output>
+    where
+        T: Append<()>,
+    {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(val);
+        self.cmd(cmd)
+    }
+
+    /// GET `key`, `None` if it doesn't exist
+    pub fn get<U: FromRedisValue>(self, key: &str) -> Chain<T::Output>
+    where
+        T: Append<Option<U>>,
+    {
+        let mut cmd = redis::cmd("GET");
+        cmd.arg(key);
+        self.cmd(cmd)
+    }
+
+    /// INCR `key`, yielding the new value
+    pub fn incr(self, key: &str) -> Chain<T::Output>
+    where
+        T: Append<i64>,
+    {
+        let mut cmd = redis::cmd("INCR");
+        cmd.arg(key);
+        self.cmd(cmd)
+    }
+
+    /// DEL `key`, yielding whether it existed
+    pub fn del(self, key: &str) -> Chain<T::Output>
+    where
+        T: Append<bool>,
+    {
+        let mut cmd = redis::cmd("DEL");
+        cmd.arg(key);
+        self.cmd(cmd)
+    }
+
+    /// Run the commands in a MULTI/EXEC transaction
+    pub fn atomic(mut self) -> Self {
+        self.pipe.atomic();
+        self
+    }
+
+    /// Send the commands as one pipeline and convert the replies
+    pub async fn exec(&self, con: &RedisConnection) -> RedisResult<T>
+    where
+        T: FromRedisValue,
+    {
+        con.exec_pipe(&self.pipe).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn chain_builds_pipeline() {
+        let chain = Chain::new().set("a", 1).incr("b").get::<String>("c");
+        let expected = redis::pipe()
+            .cmd("SET")
+            .arg("a")
+            .arg(1)
+            .cmd("INCR")
+            .arg("b")
+            .cmd("GET")
+            .arg("c")
+            .get_packed_pipeline();
+        assert_eq!(chain.pipe.get_packed_pipeline(), expected);
+    }
+
+    #[actix_rt::test]
+    async fn chain_returns_typed_tuple() {
+        const A: &str = "chain_returns_typed_tuple_a";
+        const B: &str = "chain_returns_typed_tuple_b";
+        const C: &str = "chain_returns_typed_tuple_c";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(&[A, B, C])).await.unwrap();
+
+        let replies: ((), i64, Option<String>) = Chain::new()
+            .set(A, 1)
+            .incr(B)
+            .get::<String>(C)
+            .exec(&con)
+            .await
+            .unwrap();
+        assert_eq!(replies, ((), 1, None));
+
+        let (value, existed, again): (Option<u64>, bool, i64) = Chain::new()
+            .get::<u64>(A)
+            .del(A)
+            .incr(B)
+            .atomic()
+            .exec(&con)
+            .await
+            .unwrap();
+        assert_eq!((value, existed, again), (Some(1), true, 2));
+    }
+}
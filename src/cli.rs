@@ -0,0 +1,197 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Commands given as redis-cli style lines
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult};
+
+use crate::RedisConnection;
+
+impl RedisConnection {
+    /// Run a command written the way redis-cli takes it, like
+    /// `SET greeting "hello world"`, for REPLs and admin tools.
+    ///
+    /// See [split_args] for the quoting rules. Lines that don't split, or
+    /// hold no command at all, fail with a [ErrorKind::ClientError] before
+    /// anything is sent.
+    pub async fn exec_str<T: FromRedisValue>(&self, line: &str) -> RedisResult<T> {
+        let args = split_args(line)?;
+        if args.is_empty() {
+            return Err((ErrorKind::ClientError, "command line holds no command").into());
+        }
+        let mut cmd = redis::Cmd::new();
+        for arg in &args {
+            cmd.arg(&arg[..]);
+        }
+        self.exec(&mut cmd).await
+    }
+}
+
+fn malformed(detail: &str, line: &str) -> RedisError {
+    (
+        ErrorKind::ClientError,
+        "malformed command line",
+        format!("{} in {:?}", detail, line),
+    )
+        .into()
+}
+
+type Bytes<'a> = std::iter::Peekable<std::str::Bytes<'a>>;
+
+/// Split `line` into arguments the way redis-cli does (`sdssplitargs`).
+///
+/// Arguments are separated by whitespace. Within double quotes `\n`, `\r`,
+/// `\t`, `\b`, `\a` and `\xHH` are escapes, and a backslash takes any other
+/// character literally; within single quotes only `\'` is an escape. Quoted
+/// parts join the unquoted text around them, as in `a"b c"`, but a closing
+/// quote must be followed by whitespace or the end of the line. Unbalanced
+/// quotes are an error.
+pub fn split_args(line: &str) -> RedisResult<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut bytes = line.bytes().peekable();
+    loop {
+        while bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        if bytes.peek().is_none() {
+            return Ok(args);
+        }
+        let mut arg = Vec::new();
+        loop {
+            match bytes.next() {
+                None => break,
+                Some(b) if b.is_ascii_whitespace() => break,
+                Some(b'"') => double_quoted(&mut bytes, &mut arg, line)?,
+                Some(b'\'') => single_quoted(&mut bytes, &mut arg, line)?,
+                Some(b) => arg.push(b),
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Read a double-quoted part, opening quote already consumed, into `arg`
+fn double_quoted(bytes: &mut Bytes, arg: &mut Vec<u8>, line: &str) -> RedisResult<()> {
+    let unbalanced = || malformed("unbalanced double quote", line);
+    loop {
+        match bytes.next().ok_or_else(unbalanced)? {
+            b'"' => return ensure_separated(bytes, line),
+            b'\\' => arg.push(match bytes.next().ok_or_else(unbalanced)? {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'b' => 0x08,
+                b'a' => 0x07,
+                b'x' => {
+                    let hex = [bytes.next(), bytes.next()];
+                    let digit = |b: Option<u8>| (b? as char).to_digit(16);
+                    match (digit(hex[0]), digit(hex[1])) {
+                        (Some(hi), Some(lo)) => (hi * 16 + lo) as u8,
+                        _ => return Err(malformed("invalid \\x escape", line)),
+                    }
+                }
+                other => other,
+            }),
+            b => arg.push(b),
+        }
+    }
+}
+
+/// Read a single-quoted part, opening quote already consumed, into `arg`
+fn single_quoted(bytes: &mut Bytes, arg: &mut Vec<u8>, line: &str) -> RedisResult<()> {
+    loop {
+        match bytes.next() {
+            None => return Err(malformed("unbalanced single quote", line)),
+            Some(b'\'') => return ensure_separated(bytes, line),
+            Some(b'\\') if bytes.peek() == Some(&b'\'') => {
+                bytes.next();
+                arg.push(b'\'');
+            }
+            Some(b) => arg.push(b),
+        }
+    }
+}
+
+/// A closing quote must end its argument
+fn ensure_separated(bytes: &mut Bytes, line: &str) -> RedisResult<()> {
+    match bytes.peek() {
+        Some(b) if !b.is_ascii_whitespace() => {
+            Err(malformed("closing quote not followed by a space", line))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn split(line: &str) -> Vec<Vec<u8>> {
+        split_args(line).unwrap()
+    }
+
+    #[test]
+    fn split_args_handles_quotes() {
+        assert_eq!(split("GET foo"), vec![b"GET".to_vec(), b"foo".to_vec()]);
+        assert_eq!(
+            split("  SET\tfoo   \"bar baz\"  "),
+            vec![b"SET".to_vec(), b"foo".to_vec(), b"bar baz".to_vec()]
+        );
+        assert_eq!(
+            split(r#"SET k "say \"hi\"\n\x41\\""#),
+            vec![b"SET".to_vec(), b"k".to_vec(), b"say \"hi\"\nA\\".to_vec()]
+        );
+        assert_eq!(
+            split(r#"SET k 'it\'s "raw" \n'"#),
+            vec![b"SET".to_vec(), b"k".to_vec(), b"it's \"raw\" \\n".to_vec()]
+        );
+        assert_eq!(
+            split(r#"SET k "\xff\x00" """#),
+            vec![b"SET".to_vec(), b"k".to_vec(), vec![0xff, 0x00], Vec::new()]
+        );
+        assert_eq!(split(r#"a"b c""#), vec![b"ab c".to_vec()]);
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn split_args_rejects_malformed_quoting() {
+        for line in [
+            r#"SET k "unterminated"#,
+            "SET k 'unterminated",
+            r#"SET k "ends in escape\"#,
+            r#"SET k "closed"right-away"#,
+            "SET k 'closed'right-away",
+            r#"SET k "\xZZ""#,
+            r#"SET k "\x4""#,
+        ] {
+            let err = split_args(line).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ClientError, "{}", line);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn exec_str_runs_quoted_commands() {
+        const KEY: &str = "exec_str_runs_quoted_commands";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec_str(&format!(r#"SET {} "bar baz""#, KEY))
+            .await
+            .unwrap();
+        let value: String = con.exec_str(&format!("GET '{}'", KEY)).await.unwrap();
+        assert_eq!(value, "bar baz");
+
+        let err = con
+            .exec_str::<()>(&format!(r#"SET {} "unbalanced"#, KEY))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+        assert!(con.exec_str::<()>("").await.is_err());
+        let value: String = con.exec_str(&format!("GET {}", KEY)).await.unwrap();
+        assert_eq!(value, "bar baz");
+    }
+}
@@ -0,0 +1,158 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Per-connection CLIENT settings
+use std::time::Duration;
+
+use redis::RedisResult;
+
+use crate::RedisConnection;
+
+/// Which commands [RedisConnection::client_pause] holds back
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PauseMode {
+    /// Every command from every client
+    All,
+    /// Commands that may write, reads keep being served (Redis 6.2+)
+    Write,
+}
+
+impl RedisConnection {
+    /// Exempt this connection from client eviction under `maxmemory-clients`
+    /// pressure (CLIENT NO-EVICT, Redis 7+), or make it evictable again.
+    ///
+    /// The setting belongs to the server-side connection, so it applies to
+    /// every clone and is lost on reconnect; use it on a
+    /// [dedicated](crate::Redis::dedicated) connection. Single mode only, as
+    /// a cluster connection talks to many nodes.
+    pub async fn client_no_evict(&self, on: bool) -> RedisResult<()> {
+        self.ensure_not_cluster("CLIENT NO-EVICT isn't supported in cluster mode")?;
+        self.exec(&mut client_flag_cmd("NO-EVICT", on)).await
+    }
+
+    /// Stop the commands of this connection from updating the LRU/LFU access
+    /// time of the keys they read (CLIENT NO-TOUCH, Redis 7.2+), so
+    /// monitoring doesn't skew eviction, or restore the default.
+    ///
+    /// Same scope and restrictions as [Self::client_no_evict].
+    pub async fn client_no_touch(&self, on: bool) -> RedisResult<()> {
+        self.ensure_not_cluster("CLIENT NO-TOUCH isn't supported in cluster mode")?;
+        self.exec(&mut client_flag_cmd("NO-TOUCH", on)).await
+    }
+
+    /// Hold back the commands of all clients for `duration`, or until
+    /// [Self::client_unpause] (CLIENT PAUSE). Replication and CLIENT
+    /// commands keep going, so [PauseMode::Write] lets replicas catch up
+    /// while reads are still served, for instance before a failover.
+    ///
+    /// This pauses the whole server, not only this connection. Single mode
+    /// only: in cluster mode each node has to be paused, see
+    /// [crate::Redis::exec_routed].
+    pub async fn client_pause(&self, duration: Duration, mode: PauseMode) -> RedisResult<()> {
+        self.ensure_not_cluster("CLIENT PAUSE isn't supported in cluster mode")?;
+        self.exec(&mut client_pause_cmd(duration, mode)).await
+    }
+
+    /// Resume the clients paused by [Self::client_pause] (CLIENT UNPAUSE,
+    /// Redis 6.2+)
+    pub async fn client_unpause(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("CLIENT UNPAUSE isn't supported in cluster mode")?;
+        self.exec(redis::cmd("CLIENT").arg("UNPAUSE")).await
+    }
+}
+
+fn client_pause_cmd(duration: Duration, mode: PauseMode) -> redis::Cmd {
+    let mut cmd = redis::cmd("CLIENT");
+    cmd.arg("PAUSE")
+        .arg(duration.as_millis() as u64)
+        .arg(match mode {
+            PauseMode::All => "ALL",
+            PauseMode::Write => "WRITE",
+        });
+    cmd
+}
+
+fn client_flag_cmd(flag: &str, on: bool) -> redis::Cmd {
+    let mut cmd = redis::cmd("CLIENT");
+    cmd.arg(flag).arg(if on { "ON" } else { "OFF" });
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn client_flags_assemble_arguments() {
+        let mut expected = redis::cmd("CLIENT");
+        expected.arg(&["NO-EVICT", "ON"]);
+        assert_eq!(
+            client_flag_cmd("NO-EVICT", true).get_packed_command(),
+            expected.get_packed_command()
+        );
+        let mut expected = redis::cmd("CLIENT");
+        expected.arg(&["NO-TOUCH", "OFF"]);
+        assert_eq!(
+            client_flag_cmd("NO-TOUCH", false).get_packed_command(),
+            expected.get_packed_command()
+        );
+    }
+
+    #[test]
+    fn client_pause_assembles_arguments() {
+        let mut expected = redis::cmd("CLIENT");
+        expected.arg(&["PAUSE", "1500", "ALL"]);
+        assert_eq!(
+            client_pause_cmd(Duration::from_millis(1500), PauseMode::All).get_packed_command(),
+            expected.get_packed_command()
+        );
+        let mut expected = redis::cmd("CLIENT");
+        expected.arg(&["PAUSE", "2000", "WRITE"]);
+        assert_eq!(
+            client_pause_cmd(Duration::from_secs(2), PauseMode::Write).get_packed_command(),
+            expected.get_packed_command()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn client_pause_then_unpause() {
+        const KEY: &str = "client_pause_then_unpause";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("SET").arg(KEY).arg(1)).await.unwrap();
+        con.client_pause(Duration::from_secs(5), PauseMode::Write)
+            .await
+            .unwrap();
+        let value: u64 = con.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(value, 1);
+        con.client_unpause().await.unwrap();
+        let value: u64 = con.exec(redis::cmd("INCR").arg(KEY)).await.unwrap();
+        assert_eq!(value, 2);
+    }
+
+    #[actix_rt::test]
+    async fn client_flags_are_accepted() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.dedicated().await.unwrap();
+        con.client_no_evict(true).await.unwrap();
+        con.client_no_touch(true).await.unwrap();
+        let info: String = con.exec(redis::cmd("CLIENT").arg("INFO")).await.unwrap();
+        let flags = info
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("flags="))
+            .unwrap();
+        assert!(flags.contains('e') && flags.contains('T'));
+
+        con.client_no_evict(false).await.unwrap();
+        con.client_no_touch(false).await.unwrap();
+    }
+}
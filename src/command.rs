@@ -0,0 +1,243 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Inspecting commands before they are sent
+use redis::{Arg, Cmd};
+
+/// Upper-cased name of `cmd`, `None` for an empty command
+pub(crate) fn name(cmd: &Cmd) -> Option<String> {
+    match cmd.args_iter().next()? {
+        Arg::Simple(name) => Some(String::from_utf8_lossy(name).to_ascii_uppercase()),
+        Arg::Cursor => None,
+    }
+}
+
+/// Commands whose first argument is a subcommand
+const CONTAINERS: &[&str] = &[
+    "ACL", "CLIENT", "CLUSTER", "COMMAND", "CONFIG", "DEBUG", "FUNCTION", "LATENCY", "MEMORY",
+    "MODULE", "OBJECT", "PUBSUB", "SCRIPT", "SLOWLOG", "XGROUP", "XINFO",
+];
+
+/// Name of `cmd` for metrics and traces. Container commands include their
+/// subcommand, as in `CONFIG|GET`, the way the server's own command table
+/// names them; everything else is just the upper-cased name.
+pub fn command_label(cmd: &Cmd) -> String {
+    let name = match name(cmd) {
+        Some(name) => name,
+        None => return String::new(),
+    };
+    if !CONTAINERS.contains(&name.as_str()) {
+        return name;
+    }
+    match cmd.args_iter().nth(1) {
+        Some(Arg::Simple(sub)) => {
+            format!(
+                "{}|{}",
+                name,
+                String::from_utf8_lossy(sub).to_ascii_uppercase()
+            )
+        }
+        _ => name,
+    }
+}
+
+/// `cmd` rendered for logs and observers. Arguments of commands named in
+/// `sensitive` are replaced by a placeholder, see
+/// [crate::RedisOptions::sensitive_commands].
+pub(crate) fn describe(cmd: &Cmd, sensitive: &[String]) -> String {
+    let name = match name(cmd) {
+        Some(name) => name,
+        None => return String::new(),
+    };
+    if cmd.args_iter().count() > 1 && sensitive.iter().any(|s| s.eq_ignore_ascii_case(&name)) {
+        return format!("{} [redacted]", name);
+    }
+    let mut out = name;
+    for arg in cmd.args_iter().skip(1) {
+        out.push(' ');
+        match arg {
+            Arg::Simple(arg) => out.push_str(&String::from_utf8_lossy(arg)),
+            Arg::Cursor => out.push_str("<cursor>"),
+        }
+    }
+    out
+}
+
+/// Whether sending `cmd` switches the connection to pub/sub mode
+pub(crate) fn enters_pubsub(cmd: &Cmd) -> bool {
+    matches!(
+        name(cmd).as_deref(),
+        Some("SUBSCRIBE") | Some("PSUBSCRIBE") | Some("SSUBSCRIBE")
+    )
+}
+
+/// Whether `cmd` is allowed on a connection in pub/sub mode and takes it out
+/// of that mode
+pub(crate) fn leaves_pubsub(cmd: &Cmd) -> bool {
+    name(cmd).as_deref() == Some("RESET")
+}
+
+/// Commands that never modify the keyspace or server state, as flagged
+/// `readonly` (or harmless, like PING) in the Redis command table
+const READONLY: &[&str] = &[
+    // generic
+    "DBSIZE",
+    "DUMP",
+    "ECHO",
+    "EXISTS",
+    "EXPIRETIME",
+    "KEYS",
+    "LASTSAVE",
+    "OBJECT",
+    "PEXPIRETIME",
+    "PING",
+    "PTTL",
+    "RANDOMKEY",
+    "SCAN",
+    "TIME",
+    "TOUCH",
+    "TTL",
+    "TYPE",
+    // strings and bitmaps
+    "BITCOUNT",
+    "BITFIELD_RO",
+    "BITPOS",
+    "GET",
+    "GETBIT",
+    "GETRANGE",
+    "LCS",
+    "MGET",
+    "STRLEN",
+    "SUBSTR",
+    // hashes
+    "HEXISTS",
+    "HGET",
+    "HGETALL",
+    "HKEYS",
+    "HLEN",
+    "HMGET",
+    "HRANDFIELD",
+    "HSCAN",
+    "HSTRLEN",
+    "HVALS",
+    // lists
+    "LINDEX",
+    "LLEN",
+    "LPOS",
+    "LRANGE",
+    // sets
+    "SCARD",
+    "SDIFF",
+    "SINTER",
+    "SINTERCARD",
+    "SISMEMBER",
+    "SMEMBERS",
+    "SMISMEMBER",
+    "SRANDMEMBER",
+    "SSCAN",
+    "SUNION",
+    // sorted sets
+    "ZCARD",
+    "ZCOUNT",
+    "ZDIFF",
+    "ZINTER",
+    "ZINTERCARD",
+    "ZLEXCOUNT",
+    "ZMSCORE",
+    "ZRANDMEMBER",
+    "ZRANGE",
+    "ZRANGEBYLEX",
+    "ZRANGEBYSCORE",
+    "ZRANK",
+    "ZREVRANGE",
+    "ZREVRANGEBYLEX",
+    "ZREVRANGEBYSCORE",
+    "ZREVRANK",
+    "ZSCAN",
+    "ZSCORE",
+    "ZUNION",
+    // streams
+    "XINFO",
+    "XLEN",
+    "XPENDING",
+    "XRANGE",
+    "XREAD",
+    "XREVRANGE",
+    // geo and hyperloglog
+    "GEODIST",
+    "GEOHASH",
+    "GEOPOS",
+    "GEORADIUSBYMEMBER_RO",
+    "GEORADIUS_RO",
+    "GEOSEARCH",
+    "PFCOUNT",
+    // scripting
+    "EVALSHA_RO",
+    "EVAL_RO",
+    "FCALL_RO",
+    "SORT_RO",
+];
+
+/// Whether `cmd` only reads data. Unknown commands are assumed to write.
+pub fn is_readonly(cmd: &Cmd) -> bool {
+    match name(cmd) {
+        Some(name) => READONLY.contains(&name.as_str()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_label_includes_subcommands() {
+        assert_eq!(
+            command_label(redis::cmd("client").arg("list")),
+            "CLIENT|LIST"
+        );
+        assert_eq!(
+            command_label(redis::cmd("CONFIG").arg(&["get", "maxmemory"])),
+            "CONFIG|GET"
+        );
+        assert_eq!(
+            command_label(redis::cmd("XGROUP").arg(&["CREATE", "s", "g", "$"])),
+            "XGROUP|CREATE"
+        );
+        assert_eq!(command_label(redis::cmd("GET").arg("config")), "GET");
+        assert_eq!(command_label(&redis::cmd("COMMAND")), "COMMAND");
+    }
+
+    #[test]
+    fn describe_redacts_sensitive_commands() {
+        let sensitive = vec!["AUTH".to_string(), "HELLO".to_string()];
+        let auth = redis::cmd("auth").arg("user").arg("hunter2").clone();
+        assert_eq!(describe(&auth, &sensitive), "AUTH [redacted]");
+        let set = redis::cmd("SET").arg("k").arg(1).clone();
+        assert_eq!(describe(&set, &sensitive), "SET k 1");
+        assert_eq!(describe(&set, &[]), "SET k 1");
+    }
+
+    #[test]
+    fn is_readonly_classifies_commands() {
+        for read in &[
+            "GET", "mget", "EXISTS", "ZRANGE", "XRANGE", "HGETALL", "PING",
+        ] {
+            assert!(is_readonly(&redis::cmd(read)), "{} is a read", read);
+        }
+        for write in &[
+            "SET",
+            "DEL",
+            "incr",
+            "XADD",
+            "EVAL",
+            "FLUSHALL",
+            "NOTACOMMAND",
+        ] {
+            assert!(!is_readonly(&redis::cmd(write)), "{} is a write", write);
+        }
+    }
+}
@@ -0,0 +1,360 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Building configs from connection settings instead of hand-written URLs
+use std::time::Duration;
+
+use redis::{ErrorKind, IntoConnectionInfo, RedisResult};
+
+use crate::{ReadPreference, Redis, RedisConfig, RedisOptions};
+
+/// Builder for a [RedisConfig] and the [RedisOptions] to connect with, see
+/// [RedisConfig::builder]
+///
+/// Settings apply to every URL of the config and replace what the URLs say
+/// about them.
+#[derive(Clone)]
+pub struct RedisConfigBuilder {
+    config: RedisConfig,
+    username: Option<String>,
+    password: Option<String>,
+    db: Option<i64>,
+    /// `Some(insecure)` to connect with TLS
+    tls: Option<bool>,
+    connect_timeout: Option<Duration>,
+    command_timeout: Option<Duration>,
+    read_preference: Option<ReadPreference>,
+    options: RedisOptions,
+}
+
+impl RedisConfig {
+    /// Start a [RedisConfigBuilder] from the URLs of this config
+    pub fn builder(self) -> RedisConfigBuilder {
+        RedisConfigBuilder {
+            config: self,
+            username: None,
+            password: None,
+            db: None,
+            tls: None,
+            connect_timeout: None,
+            command_timeout: None,
+            read_preference: None,
+            options: RedisOptions::default(),
+        }
+    }
+}
+
+impl RedisConfigBuilder {
+    /// Authenticate as `username` (Redis 6 ACLs), together with
+    /// [Self::password]
+    pub fn username(&mut self, username: &str) -> &mut Self {
+        self.username = Some(username.to_owned());
+        self
+    }
+
+    /// Authenticate with `password`, as the default user unless
+    /// [Self::username] is set
+    pub fn password(&mut self, password: &str) -> &mut Self {
+        self.password = Some(password.to_owned());
+        self
+    }
+
+    /// SELECT database `db` on every connection. Cluster mode only has
+    /// database 0.
+    pub fn db(&mut self, db: i64) -> &mut Self {
+        self.db = Some(db);
+        self
+    }
+
+    /// Connect with TLS (`rediss://`), verifying the server certificate
+    /// against the system's trusted roots.
+    ///
+    /// Needs the `tls` feature, [Self::build] fails without it. The `redis`
+    /// client it turns on doesn't take a custom CA or client certificates;
+    /// [Self::tls_insecure] is the only other choice.
+    pub fn tls(&mut self) -> &mut Self {
+        self.tls = Some(false);
+        self
+    }
+
+    /// Connect with TLS without verifying the server certificate, for
+    /// self-signed certificates. Anyone in between can read the traffic.
+    pub fn tls_insecure(&mut self) -> &mut Self {
+        self.tls = Some(true);
+        self
+    }
+
+    /// Give up opening a connection after `timeout`, see
+    /// [RedisOptions::connect_timeout]
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail commands whose reply takes longer than `timeout`, see
+    /// [RedisOptions::response_timeout]
+    pub fn command_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Serve [Redis::exec_read] as `preference` says, see
+    /// [RedisOptions::read_preference]
+    pub fn read_preference(&mut self, preference: ReadPreference) -> &mut Self {
+        self.read_preference = Some(preference);
+        self
+    }
+
+    /// Options the timeouts and read preference of this builder are applied to, the defaults
+    /// otherwise
+    pub fn options(&mut self, options: RedisOptions) -> &mut Self {
+        self.options = options;
+        self
+    }
+
+    /// The config and options, failing with
+    /// [ErrorKind::InvalidClientConfig] if a URL is invalid or doesn't go
+    /// with the settings
+    pub fn build(&self) -> RedisResult<(RedisConfig, RedisOptions)> {
+        let apply_all = |urls: &[String]| {
+            urls.iter()
+                .map(|url| self.apply(url))
+                .collect::<RedisResult<Vec<String>>>()
+        };
+        let config = match &self.config {
+            RedisConfig::Single(url) => RedisConfig::Single(self.apply(url)?),
+            RedisConfig::Cluster(nodes) => RedisConfig::Cluster(apply_all(nodes)?),
+            RedisConfig::ClusterSeed(seed) => RedisConfig::ClusterSeed(self.apply(seed)?),
+            RedisConfig::Sentinel {
+                sentinels,
+                master_name,
+            } => RedisConfig::Sentinel {
+                sentinels: apply_all(sentinels)?,
+                master_name: master_name.clone(),
+            },
+        };
+        let cluster = matches!(
+            config,
+            RedisConfig::Cluster(_) | RedisConfig::ClusterSeed(_)
+        );
+        if cluster && self.db.unwrap_or(0) != 0 {
+            return Err((
+                ErrorKind::InvalidClientConfig,
+                "cluster mode only has database 0",
+            )
+                .into());
+        }
+        config.connect()?;
+
+        let mut options = self.options.clone();
+        if let Some(timeout) = self.connect_timeout {
+            options.connect_timeout = Some(timeout);
+        }
+        if let Some(timeout) = self.command_timeout {
+            options.response_timeout = Some(timeout);
+        }
+        if let Some(preference) = self.read_preference {
+            options.read_preference = preference;
+        }
+        Ok((config, options))
+    }
+
+    /// [Self::build] and connect, see [Redis::with_options]
+    pub async fn connect(&self) -> RedisResult<Redis> {
+        let (config, options) = self.build()?;
+        Redis::with_options(config, options).await
+    }
+
+    /// `url` with the settings of this builder
+    fn apply(&self, url: &str) -> RedisResult<String> {
+        let invalid = |desc: &'static str| {
+            redis::RedisError::from((ErrorKind::InvalidClientConfig, desc, url.to_owned()))
+        };
+        let mut parsed = redis::parse_redis_url(url).map_err(|_| invalid("not a Redis URL"))?;
+        if matches!(parsed.scheme(), "unix" | "redis+unix") {
+            if self.tls.is_some() {
+                return Err(invalid("TLS is not available over unix sockets"));
+            }
+            // unix socket URLs carry their settings in the query string
+            let mut pairs: Vec<(String, String)> = parsed
+                .query_pairs()
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect();
+            let settings = [
+                ("user", self.username.clone()),
+                ("pass", self.password.clone()),
+                ("db", self.db.map(|db| db.to_string())),
+            ];
+            for (key, value) in settings {
+                if let Some(value) = value {
+                    pairs.retain(|(k, _)| k != key);
+                    pairs.push((key.to_owned(), value));
+                }
+            }
+            parsed.query_pairs_mut().clear().extend_pairs(pairs);
+        } else {
+            if let Some(insecure) = self.tls {
+                parsed
+                    .set_scheme("rediss")
+                    .map_err(|_| invalid("TLS needs a host to connect to"))?;
+                parsed.set_fragment(if insecure { Some("insecure") } else { None });
+            }
+            if let Some(username) = &self.username {
+                parsed
+                    .set_username(username)
+                    .map_err(|_| invalid("URL has no host to authenticate with"))?;
+            }
+            if let Some(password) = &self.password {
+                parsed
+                    .set_password(Some(password))
+                    .map_err(|_| invalid("URL has no host to authenticate with"))?;
+            }
+            if let Some(db) = self.db {
+                parsed.set_path(&db.to_string());
+            }
+        }
+        let url = parsed.to_string();
+        url.as_str().into_connection_info()?;
+        Ok(url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn single_url(config: RedisConfig) -> String {
+        match config {
+            RedisConfig::Single(url) => url,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn builder_rewrites_urls() {
+        let (config, options) = RedisConfig::Single("redis://:old@127.0.0.1:6380/3".into())
+            .builder()
+            .username("app")
+            .password("p@ss word")
+            .db(2)
+            .command_timeout(Duration::from_secs(2))
+            .connect_timeout(Duration::from_secs(1))
+            .read_preference(ReadPreference::PreferReplica)
+            .build()
+            .unwrap();
+        let info = single_url(config).into_connection_info().unwrap();
+        assert_eq!(
+            *info.addr,
+            redis::ConnectionAddr::Tcp("127.0.0.1".into(), 6380)
+        );
+        assert_eq!(info.username.as_deref(), Some("app"));
+        assert_eq!(info.passwd.as_deref(), Some("p@ss word"));
+        assert_eq!(info.db, 2);
+        assert_eq!(options.response_timeout, Some(Duration::from_secs(2)));
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(1)));
+        assert_eq!(options.read_preference, ReadPreference::PreferReplica);
+
+        let (config, _) = RedisConfig::Single("unix:///tmp/redis.sock?db=1".into())
+            .builder()
+            .password("secret")
+            .db(4)
+            .build()
+            .unwrap();
+        let info = single_url(config).into_connection_info().unwrap();
+        assert_eq!(info.passwd.as_deref(), Some("secret"));
+        assert_eq!(info.db, 4);
+    }
+
+    #[test]
+    fn tls_rewrites_scheme() {
+        let builder = || {
+            let mut builder = RedisConfig::Single("redis://127.0.0.1:6380/1".into()).builder();
+            builder.password("secret");
+            builder
+        };
+        let tls = builder().tls().build();
+        let insecure = builder().tls_insecure().build();
+        if cfg!(feature = "tls") {
+            let url = single_url(tls.unwrap().0);
+            assert_eq!(url, "rediss://:secret@127.0.0.1:6380/1");
+            let info = url.into_connection_info().unwrap();
+            assert_eq!(
+                *info.addr,
+                redis::ConnectionAddr::TcpTls {
+                    host: "127.0.0.1".into(),
+                    port: 6380,
+                    insecure: false
+                }
+            );
+            let url = single_url(insecure.unwrap().0);
+            assert_eq!(url, "rediss://:secret@127.0.0.1:6380/1#insecure");
+        } else {
+            assert_eq!(tls.err().unwrap().kind(), ErrorKind::InvalidClientConfig);
+            assert_eq!(
+                insecure.err().unwrap().kind(),
+                ErrorKind::InvalidClientConfig
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_configs_are_errors() {
+        let err = RedisConfig::Single("127.0.0.1".into())
+            .connect()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidClientConfig);
+
+        let err = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()])
+            .builder()
+            .db(1)
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidClientConfig);
+
+        let err = RedisConfig::Single("unix:///tmp/redis.sock".into())
+            .builder()
+            .tls()
+            .build()
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidClientConfig);
+
+        let err = RedisConfig::Sentinel {
+            sentinels: Vec::new(),
+            master_name: "mymaster".into(),
+        }
+        .connect()
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidClientConfig);
+    }
+
+    #[actix_rt::test]
+    async fn builder_selects_database() {
+        const KEY: &str = "builder_selects_database";
+        let r = RedisConfig::Single("redis://127.0.0.1".into())
+            .builder()
+            .db(1)
+            .command_timeout(Duration::from_secs(5))
+            .connect()
+            .await
+            .unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("SET").arg(&[KEY, "1"]))
+            .await
+            .unwrap();
+        let zero = r.db(0).await.unwrap();
+        let exists: bool = zero.exec(redis::cmd("EXISTS").arg(KEY)).await.unwrap();
+        assert!(!exists);
+        let one = r.db(1).await.unwrap();
+        let exists: bool = one.exec(redis::cmd("EXISTS").arg(KEY)).await.unwrap();
+        assert!(exists);
+    }
+}
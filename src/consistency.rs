@@ -0,0 +1,231 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Read-after-write consistency and durability
+use std::time::Duration;
+
+use redis::{FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::{GlueError, RedisConnection};
+
+/// Guarantee a read gives about a preceding write, see
+/// [RedisConnection::write_then_read]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Consistency {
+    /// The read sees the write: it is served by the primary that took it
+    ReadYourWrites,
+    /// Before reading, WAIT until at least `replicas` replicas have the write,
+    /// so reads served by them see it too. Fails if that doesn't happen
+    /// within `timeout`, rounded up to a millisecond, with
+    /// [GlueError::DurabilityNotMet].
+    Replicated { replicas: usize, timeout: Duration },
+    /// The read may be served by a replica that hasn't caught up yet
+    Eventual,
+}
+
+impl RedisConnection {
+    /// Send `write`, then `read`, with `consistency` deciding what the read
+    /// is guaranteed to see. Returns both replies.
+    ///
+    /// Commands on a connection are served in order by the primary it talks
+    /// to, so [Consistency::ReadYourWrites] and [Consistency::Eventual] only
+    /// differ for reads routed to replicas, like those of
+    /// [crate::Redis::exec_read]; picking one at the call site keeps the
+    /// intent explicit either way.
+    pub async fn write_then_read<W: FromRedisValue, R: FromRedisValue>(
+        &self,
+        write: &mut redis::Cmd,
+        read: &mut redis::Cmd,
+        consistency: Consistency,
+    ) -> RedisResult<(W, R)> {
+        let written = self.exec(write).await?;
+        if let Consistency::Replicated { replicas, timeout } = consistency {
+            let acked: usize = self
+                .exec(
+                    redis::cmd("WAIT")
+                        .arg(replicas)
+                        // WAIT 0 would wait forever
+                        .arg((timeout.as_millis() as u64).max(1)),
+                )
+                .await?;
+            if acked < replicas {
+                return Err(GlueError::DurabilityNotMet {
+                    acked: acked as u32,
+                    required: replicas as u32,
+                }
+                .into());
+            }
+        }
+        let read = self.exec(read).await?;
+        Ok((written, read))
+    }
+
+    /// Set `key` to `val` and wait until at least `replicas` replicas
+    /// acknowledged the write or `timeout` elapses (SET followed by WAIT, in
+    /// one round-trip), so a critical write can't skip the WAIT. A zero
+    /// `timeout` waits forever.
+    ///
+    /// Fails with [GlueError::DurabilityNotMet] if too few replicas
+    /// acknowledged in time. The value is written regardless: WAIT doesn't
+    /// roll back, so on that error the write may still reach the replicas
+    /// later, or be lost if the primary fails before it does.
+    pub async fn set_durable<V: ToRedisArgs>(
+        &self,
+        key: &str,
+        val: V,
+        replicas: u32,
+        timeout: Duration,
+    ) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        pipe.cmd("SET")
+            .arg(key)
+            .arg(val)
+            .ignore()
+            .cmd("WAIT")
+            .arg(replicas)
+            .arg(timeout.as_millis() as u64);
+        let (acked,): (u32,) = self.exec_pipe(&pipe).await?;
+        if acked < replicas {
+            return Err(GlueError::DurabilityNotMet {
+                acked,
+                required: replicas,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Block until the writes sent so far on this connection are fsynced to
+    /// the AOF of the server itself (if `local` is 1) and of at least
+    /// `replicas` replicas (WAITAOF, Redis 7.2+), or `timeout` elapses; a zero
+    /// `timeout` waits forever. Returns how many local and replica AOFs the
+    /// writes reached, which can fall short of what was asked on timeout.
+    ///
+    /// Fails with an error reply if `local` is 1 but the server has AOF
+    /// (`appendonly`) disabled.
+    pub async fn waitaof(
+        &self,
+        local: u32,
+        replicas: u32,
+        timeout: Duration,
+    ) -> RedisResult<(u32, u32)> {
+        self.exec(&mut waitaof_cmd(local, replicas, timeout)).await
+    }
+}
+
+fn waitaof_cmd(local: u32, replicas: u32, timeout: Duration) -> redis::Cmd {
+    let mut cmd = redis::cmd("WAITAOF");
+    cmd.arg(local).arg(replicas).arg(timeout.as_millis() as u64);
+    cmd
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn waitaof_assembles_arguments() {
+        let mut expected = redis::cmd("WAITAOF");
+        expected.arg(&["1", "2", "1500"]);
+        assert_eq!(
+            waitaof_cmd(1, 2, Duration::from_millis(1500)).get_packed_command(),
+            expected.get_packed_command()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn waitaof_reaches_local_aof() {
+        const KEY: &str = "waitaof_reaches_local_aof";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.dedicated().await.unwrap();
+        let (_, appendonly): (String, String) = con
+            .exec(redis::cmd("CONFIG").arg(&["GET", "appendonly"]))
+            .await
+            .unwrap();
+        let _: () = con.exec(redis::cmd("SET").arg(&[KEY, "v"])).await.unwrap();
+        if appendonly != "yes" {
+            assert!(con.waitaof(1, 0, Duration::from_secs(1)).await.is_err());
+            return;
+        }
+        let (local, _) = con.waitaof(1, 0, Duration::from_secs(1)).await.unwrap();
+        assert!(local >= 1);
+    }
+
+    #[actix_rt::test]
+    async fn read_your_writes_sees_write() {
+        const KEY: &str = "read_your_writes_sees_write";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let ((), read): ((), String) = con
+            .write_then_read(
+                redis::cmd("SET").arg(KEY).arg("fresh"),
+                redis::cmd("GET").arg(KEY),
+                Consistency::ReadYourWrites,
+            )
+            .await
+            .unwrap();
+        assert_eq!(read, "fresh");
+
+        // a standalone test server has no replicas to wait for
+        let replicated = Consistency::Replicated {
+            replicas: 1,
+            timeout: Duration::from_millis(50),
+        };
+        let res: RedisResult<((), String)> = con
+            .write_then_read(
+                redis::cmd("SET").arg(KEY).arg("fresh"),
+                redis::cmd("GET").arg(KEY),
+                replicated,
+            )
+            .await;
+        assert_eq!(
+            GlueError::from_redis(&res.err().unwrap()),
+            Some(GlueError::DurabilityNotMet {
+                acked: 0,
+                required: 1
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn set_durable_reports_missing_replicas() {
+        const KEY: &str = "set_durable_reports_missing_replicas";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        con.set_durable(KEY, "a", 0, Duration::from_millis(50))
+            .await
+            .unwrap();
+
+        // a standalone test server has no replicas to acknowledge the write
+        let err = con
+            .set_durable(KEY, "b", 1, Duration::from_millis(50))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::DurabilityNotMet {
+                acked: 0,
+                required: 1
+            })
+        );
+        // but the value was written all the same
+        let value: String = con.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(value, "b");
+    }
+}
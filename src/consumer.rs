@@ -0,0 +1,457 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Consuming a stream as a member of a consumer group
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures::stream::{BoxStream, StreamExt};
+use redis::{RedisError, RedisResult};
+
+use crate::stream::Batch;
+use crate::{Redis, RedisConnection, StreamEntry};
+
+/// Entries fetched per XREADGROUP or XAUTOCLAIM
+const BATCH: usize = 16;
+
+/// Reads a stream as one consumer of a consumer group, entry by entry, see
+/// [Redis::stream_consumer].
+///
+/// Starts by redelivering the entries already pending for the consumer, the
+/// ones it read before a crash or a lost connection but didn't acknowledge,
+/// then, with [Self::with_reclaim], claims the entries other consumers left
+/// pending for too long (XAUTOCLAIM), and then moves on to new entries (the
+/// `>` ID). Entries are fetched in batches and pending from the moment they
+/// are fetched until [Self::ack].
+///
+/// Owns a dedicated connection, so that blocking reads don't hold up the
+/// shared one. When that connection is lost, it is replaced on the next call
+/// and the pending entries are redelivered again. Works in cluster mode as
+/// well, every command is about the one stream.
+pub struct StreamConsumer {
+    redis: Redis,
+    connection: RedisConnection,
+    key: String,
+    group: String,
+    consumer: String,
+    phase: Phase,
+    block: Duration,
+    reclaim: Option<Duration>,
+    fetched: VecDeque<StreamEntry>,
+}
+
+/// Which entries [StreamConsumer::next] fetches next
+enum Phase {
+    /// Those pending for this consumer, after this ID
+    Pending(String),
+    /// Those idle for long enough in the group, from this XAUTOCLAIM cursor
+    Reclaim(String),
+    /// Those never delivered
+    New,
+}
+
+impl Redis {
+    /// Consume the stream at `key` as `consumer` of group `group`, see
+    /// [StreamConsumer]. The group is created if it doesn't exist, together
+    /// with the stream, delivering every entry of the stream.
+    pub async fn stream_consumer(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+    ) -> RedisResult<StreamConsumer> {
+        let connection = self.dedicated().await?;
+        connection.xgroup_create(key, group, "0", true).await?;
+        Ok(StreamConsumer {
+            redis: self.clone(),
+            connection,
+            key: key.to_owned(),
+            group: group.to_owned(),
+            consumer: consumer.to_owned(),
+            phase: Phase::Pending("0".into()),
+            block: Duration::from_secs(5),
+            reclaim: None,
+            fetched: VecDeque::new(),
+        })
+    }
+}
+
+/// An entry yielded by [StreamConsumer::into_stream], to be acknowledged
+/// once processed
+pub struct StreamMessage {
+    pub entry: StreamEntry,
+    connection: RedisConnection,
+    key: String,
+    group: String,
+}
+
+impl StreamMessage {
+    /// Acknowledge the entry (XACK) through the shared connection, so that
+    /// it isn't delivered again
+    pub async fn ack(&self) -> RedisResult<()> {
+        self.connection
+            .xack(&self.key, &self.group, &[&self.entry.id])
+            .await?;
+        Ok(())
+    }
+}
+
+impl StreamConsumer {
+    /// How long [Self::next] waits for new entries, 5 seconds by default.
+    /// Keep it below [crate::RedisOptions::response_timeout], which applies
+    /// to blocking reads too.
+    pub fn with_block(mut self, block: Duration) -> Self {
+        self.block = block;
+        self
+    }
+
+    /// Once the entries pending for this consumer are redelivered, claim the
+    /// entries of the group that have been pending for other consumers for
+    /// at least `min_idle`, left behind by consumers that crashed or went
+    /// away. Needs Redis 6.2+.
+    pub fn with_reclaim(mut self, min_idle: Duration) -> Self {
+        self.reclaim = Some(min_idle);
+        self
+    }
+
+    /// The next entry for this consumer, `None` if none came in for the
+    /// block timeout
+    pub async fn next(&mut self) -> RedisResult<Option<StreamEntry>> {
+        loop {
+            if let Some(entry) = self.fetched.pop_front() {
+                return Ok(Some(entry));
+            }
+            let timed_out = match self.fetch().await {
+                Err(err) if is_connection_lost(&err) => {
+                    self.reconnect().await?;
+                    self.fetch().await?
+                }
+                timed_out => timed_out?,
+            };
+            if timed_out {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Entries for this consumer as they come in, acknowledged through the
+    /// [StreamMessage]s. Block timeouts are skipped; the first error ends the
+    /// stream after it is yielded.
+    pub fn into_stream(self) -> BoxStream<'static, RedisResult<StreamMessage>> {
+        futures::stream::unfold(Some(self), |consumer| async move {
+            let mut consumer = consumer?;
+            loop {
+                match consumer.next().await {
+                    Ok(Some(entry)) => {
+                        let message = StreamMessage {
+                            entry,
+                            connection: consumer.redis.get_client(),
+                            key: consumer.key.clone(),
+                            group: consumer.group.clone(),
+                        };
+                        return Some((Ok(message), Some(consumer)));
+                    }
+                    Ok(None) => continue,
+                    Err(err) => return Some((Err(err), None)),
+                }
+            }
+        })
+        .boxed()
+    }
+
+    /// Fetch the next batch of the current phase into [Self::fetched],
+    /// moving on to the next phase once it is exhausted. True if nothing new
+    /// came in for the block timeout.
+    async fn fetch(&mut self) -> RedisResult<bool> {
+        match &self.phase {
+            Phase::Pending(after) => {
+                let batch = self.read(after, None).await?;
+                self.ack_deleted(&batch).await?;
+                // read on after deleted entries too, a batch may hold nothing
+                // else
+                self.phase = match (batch.last_id, self.reclaim) {
+                    (Some(last), _) => Phase::Pending(last),
+                    // every pending entry was redelivered
+                    (None, Some(_)) => Phase::Reclaim("0-0".into()),
+                    (None, None) => Phase::New,
+                };
+                self.fetched.extend(batch.entries);
+            }
+            Phase::Reclaim(start) => {
+                let min_idle = self.reclaim.unwrap_or_default();
+                let (cursor, batch) = self
+                    .connection
+                    .xautoclaim_batch(
+                        &self.key,
+                        &self.group,
+                        &self.consumer,
+                        min_idle,
+                        start,
+                        Some(BATCH),
+                    )
+                    .await?;
+                self.phase = if cursor == "0-0" {
+                    Phase::New
+                } else {
+                    Phase::Reclaim(cursor)
+                };
+                self.ack_deleted(&batch).await?;
+                self.fetched.extend(batch.entries);
+            }
+            Phase::New => {
+                let entries = self.read(">", Some(self.block)).await?.entries;
+                if entries.is_empty() {
+                    return Ok(true);
+                }
+                self.fetched.extend(entries);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Acknowledge the entry `id`, so that it isn't delivered again (XACK)
+    pub async fn ack(&mut self, id: &str) -> RedisResult<()> {
+        let acked = self.connection.xack(&self.key, &self.group, &[id]).await;
+        match acked {
+            Err(err) if is_connection_lost(&err) => {
+                self.reconnect().await?;
+                self.connection.xack(&self.key, &self.group, &[id]).await?;
+            }
+            acked => {
+                acked?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledge the pending entries of `batch` that were deleted from the
+    /// stream, there is nothing left of them to process
+    async fn ack_deleted(&self, batch: &Batch) -> RedisResult<()> {
+        if !batch.deleted.is_empty() {
+            let deleted: Vec<&str> = batch.deleted.iter().map(String::as_str).collect();
+            self.connection
+                .xack(&self.key, &self.group, &deleted)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn read(&self, id: &str, block: Option<Duration>) -> RedisResult<Batch> {
+        self.connection
+            .xreadgroup_batch(
+                &self.key,
+                &self.group,
+                &self.consumer,
+                id,
+                Some(BATCH),
+                block,
+            )
+            .await
+    }
+
+    /// Open a new connection and redeliver the pending entries from the start,
+    /// some of the entries read before may never have arrived
+    async fn reconnect(&mut self) -> RedisResult<()> {
+        self.connection = self.redis.dedicated().await?;
+        self.phase = Phase::Pending("0".into());
+        self.fetched.clear();
+        Ok(())
+    }
+}
+
+fn is_connection_lost(err: &RedisError) -> bool {
+    err.is_io_error() || err.is_connection_dropped()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    async fn fresh_group(con: &RedisConnection, key: &str) {
+        let _: () = con.exec(redis::cmd("DEL").arg(key)).await.unwrap();
+        let _: () = con
+            .exec(
+                redis::cmd("XGROUP")
+                    .arg("CREATE")
+                    .arg(key)
+                    .arg("workers")
+                    .arg("$")
+                    .arg("MKSTREAM"),
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn produce(con: &RedisConnection, key: &str, job: u64) -> String {
+        con.exec(redis::cmd("XADD").arg(key).arg("*").arg("job").arg(job))
+            .await
+            .unwrap()
+    }
+
+    async fn reclaim_left_behind_entries(r: Redis) {
+        const KEY: &str = "{consumer}reclaim_left_behind_entries";
+
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let mut crashed = r
+            .stream_consumer(KEY, "workers", "a")
+            .await
+            .unwrap()
+            .with_block(Duration::from_millis(100));
+        let first = con.xadd(KEY, &[("job", 1)]).await.unwrap();
+        assert_eq!(crashed.next().await.unwrap().unwrap().id, first);
+        drop(crashed);
+        let second = con.xadd(KEY, &[("job", 2)]).await.unwrap();
+
+        let pending = con.xpending(KEY, "workers", 10, Some("a")).await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, first);
+        assert_eq!(pending[0].deliveries, 1);
+
+        let mut messages = r
+            .stream_consumer(KEY, "workers", "b")
+            .await
+            .unwrap()
+            .with_block(Duration::from_millis(100))
+            .with_reclaim(Duration::ZERO)
+            .into_stream();
+        let reclaimed = messages.next().await.unwrap().unwrap();
+        assert_eq!(reclaimed.entry.id, first);
+        reclaimed.ack().await.unwrap();
+        let fresh = messages.next().await.unwrap().unwrap();
+        assert_eq!(fresh.entry.id, second);
+        assert_eq!(fresh.entry.get("job"), Some(&b"2"[..]));
+        fresh.ack().await.unwrap();
+        assert!(con
+            .xpending(KEY, "workers", 10, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn consumer_reclaims_entries_of_others() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        reclaim_left_behind_entries(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn consumer_reclaims_entries_of_others_in_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        reclaim_left_behind_entries(r).await;
+    }
+
+    #[actix_rt::test]
+    async fn consumer_reads_and_acks() {
+        const KEY: &str = "consumer_reads_and_acks";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        fresh_group(&con, KEY).await;
+        let mut ids = Vec::new();
+        for job in 0..3 {
+            ids.push(produce(&con, KEY, job).await);
+        }
+
+        let mut consumer = r
+            .stream_consumer(KEY, "workers", "a")
+            .await
+            .unwrap()
+            .with_block(Duration::from_millis(100));
+        for (job, id) in ids.iter().enumerate() {
+            let entry = consumer.next().await.unwrap().unwrap();
+            assert_eq!(&entry.id, id);
+            assert_eq!(entry.get("job"), Some(job.to_string().as_bytes()));
+            consumer.ack(&entry.id).await.unwrap();
+        }
+        assert!(consumer.next().await.unwrap().is_none());
+
+        let pending: (u64, redis::Value, redis::Value, redis::Value) = con
+            .exec(redis::cmd("XPENDING").arg(KEY).arg("workers"))
+            .await
+            .unwrap();
+        assert_eq!(pending.0, 0);
+    }
+
+    #[actix_rt::test]
+    async fn consumer_redelivers_unacked_entries() {
+        const KEY: &str = "consumer_redelivers_unacked_entries";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        fresh_group(&con, KEY).await;
+        let first = produce(&con, KEY, 1).await;
+        let second = produce(&con, KEY, 2).await;
+
+        let mut crashed = r.stream_consumer(KEY, "workers", "a").await.unwrap();
+        assert_eq!(crashed.next().await.unwrap().unwrap().id, first);
+        drop(crashed);
+
+        let mut consumer = r
+            .stream_consumer(KEY, "workers", "a")
+            .await
+            .unwrap()
+            .with_block(Duration::from_millis(100));
+        let redelivered = consumer.next().await.unwrap().unwrap();
+        assert_eq!(redelivered.id, first);
+        consumer.ack(&redelivered.id).await.unwrap();
+        assert_eq!(consumer.next().await.unwrap().unwrap().id, second);
+        consumer.ack(&second).await.unwrap();
+        assert!(consumer.next().await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn consumer_skips_deleted_pending_entries() {
+        const KEY: &str = "consumer_skips_deleted_pending_entries";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        fresh_group(&con, KEY).await;
+        // more than a batch, all read and then deleted
+        let mut ids = Vec::new();
+        for job in 0..BATCH as u64 + 4 {
+            ids.push(produce(&con, KEY, job).await);
+        }
+        let read = con
+            .xreadgroup(KEY, "workers", "a", ">", None, None)
+            .await
+            .unwrap();
+        assert_eq!(read.len(), ids.len());
+        let deleted: u64 = con
+            .exec(redis::cmd("XDEL").arg(KEY).arg(&ids[..BATCH]))
+            .await
+            .unwrap();
+        assert_eq!(deleted, BATCH as u64);
+
+        let mut consumer = r
+            .stream_consumer(KEY, "workers", "a")
+            .await
+            .unwrap()
+            .with_block(Duration::from_millis(100));
+        for id in &ids[BATCH..] {
+            let entry = consumer.next().await.unwrap().unwrap();
+            assert_eq!(&entry.id, id);
+            consumer.ack(id).await.unwrap();
+        }
+        assert!(consumer.next().await.unwrap().is_none());
+        assert!(con
+            .xpending(KEY, "workers", 100, None)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Errors raised by redis-glue itself rather than by the Redis server
+use std::fmt;
+
+use redis::{ErrorKind, RedisError};
+
+/// Errors detected by this crate before or instead of talking to Redis.
+///
+/// Every method still returns [redis::RedisResult]: a [GlueError] travels
+/// inside a [RedisError] of kind [ErrorKind::ClientError] (or
+/// [ErrorKind::ClusterDown] for [GlueError::ClusterDown]) and can be
+/// recovered with [GlueError::from_redis].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GlueError {
+    /// The connection was put into pub/sub mode (SUBSCRIBE and friends), so
+    /// it can't serve regular commands anymore
+    ConnectionInPubSubMode,
+    /// A command that may write was sent through a connection obtained from
+    /// [crate::RedisConnection::read_only]
+    WriteOnReadOnlyConnection {
+        /// Name of the rejected command
+        command: String,
+    },
+    /// Requests fail fast after repeated connection failures, see
+    /// [crate::RedisOptions::circuit_breaker]
+    CircuitOpen,
+    /// A command expected a different type of value than the one at `key`,
+    /// see [crate::RedisConnection::get_checked]
+    WrongType {
+        key: String,
+        /// As reported by TYPE, like `hash` or `list`
+        actual_type: String,
+    },
+    /// A command that may write was passed to [crate::Redis::exec_read_retry],
+    /// which only retries commands that are safe to send twice
+    NonIdempotent {
+        /// Name of the rejected command
+        command: String,
+    },
+    /// The cluster replied CLUSTERDOWN: it can't serve the slot right now,
+    /// typically while a failover or resharding is under way.
+    ///
+    /// [crate::Redis::exec_retry] retries it with twice the delays of its
+    /// strategy, as the cluster takes a while to recover. A CLUSTERDOWN that
+    /// persists means slots are left unassigned or a primary is gone for
+    /// good without a replica to take over, which retries won't fix.
+    ClusterDown,
+    /// Fewer replicas than required acknowledged a write before WAIT timed
+    /// out, see [crate::RedisConnection::set_durable]. The write happened
+    /// anyway: WAIT doesn't roll anything back.
+    DurabilityNotMet {
+        /// Replicas that acknowledged the write
+        acked: u32,
+        /// Replicas that were asked for
+        required: u32,
+    },
+    /// No script was registered under `name`, see
+    /// [crate::ScriptManager::invoke]
+    UnknownScript { name: String },
+}
+
+impl GlueError {
+    fn description(&self) -> &'static str {
+        match self {
+            Self::ConnectionInPubSubMode => "connection is in pub/sub mode",
+            Self::WriteOnReadOnlyConnection { .. } => "write command on a read-only connection",
+            Self::CircuitOpen => "circuit breaker is open after repeated connection failures",
+            Self::WrongType { .. } => "key holds the wrong kind of value",
+            Self::NonIdempotent { .. } => "only read-only commands can be retried safely",
+            Self::ClusterDown => "cluster is down",
+            Self::DurabilityNotMet { .. } => "write didn't reach enough replicas in time",
+            Self::UnknownScript { .. } => "no script registered under this name",
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        match self {
+            Self::ConnectionInPubSubMode | Self::CircuitOpen | Self::ClusterDown => None,
+            Self::WriteOnReadOnlyConnection { command } | Self::NonIdempotent { command } => {
+                Some(command.clone())
+            }
+            Self::UnknownScript { name } => Some(name.clone()),
+            Self::WrongType { key, actual_type } => Some(format!("{} is a {}", key, actual_type)),
+            Self::DurabilityNotMet { acked, required } => {
+                Some(format!("{} of {} replicas", acked, required))
+            }
+        }
+    }
+
+    /// Recover the [GlueError] carried by `err`, if any. Server errors this
+    /// crate classifies, like CLUSTERDOWN replies, count too.
+    pub fn from_redis(err: &RedisError) -> Option<Self> {
+        if err.kind() == ErrorKind::ClusterDown {
+            return Some(Self::ClusterDown);
+        }
+        if err.kind() != ErrorKind::ClientError {
+            return None;
+        }
+        #[allow(deprecated)]
+        let description = std::error::Error::description(err);
+        let detail = || err.detail().unwrap_or_default().to_owned();
+        let candidates = vec![
+            Self::ConnectionInPubSubMode,
+            Self::WriteOnReadOnlyConnection { command: detail() },
+            Self::CircuitOpen,
+            Self::NonIdempotent { command: detail() },
+            Self::UnknownScript { name: detail() },
+        ];
+        let wrong_type = detail()
+            .rsplit_once(" is a ")
+            .map(|(key, ty)| Self::WrongType {
+                key: key.to_owned(),
+                actual_type: ty.to_owned(),
+            });
+        let durability = detail()
+            .strip_suffix(" replicas")
+            .and_then(|counts| counts.split_once(" of "))
+            .and_then(|(acked, required)| {
+                Some(Self::DurabilityNotMet {
+                    acked: acked.parse().ok()?,
+                    required: required.parse().ok()?,
+                })
+            });
+        candidates
+            .into_iter()
+            .chain(wrong_type)
+            .chain(durability)
+            .find(|candidate| candidate.description() == description)
+    }
+}
+
+impl fmt::Display for GlueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.description())?;
+        if let Some(detail) = self.detail() {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GlueError {}
+
+impl From<GlueError> for RedisError {
+    fn from(err: GlueError) -> RedisError {
+        if err == GlueError::ClusterDown {
+            return (ErrorKind::ClusterDown, err.description()).into();
+        }
+        match err.detail() {
+            Some(detail) => (ErrorKind::ClientError, err.description(), detail).into(),
+            None => (ErrorKind::ClientError, err.description()).into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glue_error_round_trips() {
+        let err: RedisError = GlueError::ConnectionInPubSubMode.into();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::ConnectionInPubSubMode)
+        );
+
+        let err: RedisError = GlueError::WriteOnReadOnlyConnection {
+            command: "SET".into(),
+        }
+        .into();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::WriteOnReadOnlyConnection {
+                command: "SET".into()
+            })
+        );
+
+        let err: RedisError = GlueError::CircuitOpen.into();
+        assert_eq!(GlueError::from_redis(&err), Some(GlueError::CircuitOpen));
+
+        let wrong_type = GlueError::WrongType {
+            key: "user is a key".into(),
+            actual_type: "hash".into(),
+        };
+        let err: RedisError = wrong_type.clone().into();
+        assert_eq!(GlueError::from_redis(&err), Some(wrong_type));
+
+        let err: RedisError = GlueError::NonIdempotent {
+            command: "INCR".into(),
+        }
+        .into();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::NonIdempotent {
+                command: "INCR".into()
+            })
+        );
+
+        let not_met = GlueError::DurabilityNotMet {
+            acked: 1,
+            required: 2,
+        };
+        let err: RedisError = not_met.clone().into();
+        assert_eq!(GlueError::from_redis(&err), Some(not_met));
+
+        let unknown = GlueError::UnknownScript {
+            name: "rate_limit".into(),
+        };
+        let err: RedisError = unknown.clone().into();
+        assert_eq!(GlueError::from_redis(&err), Some(unknown));
+
+        let err: RedisError = GlueError::ClusterDown.into();
+        assert_eq!(GlueError::from_redis(&err), Some(GlueError::ClusterDown));
+        // as parsed from a `-CLUSTERDOWN The cluster is down` reply
+        let reply: RedisError = (
+            ErrorKind::ClusterDown,
+            "An error was signalled by the server",
+            "The cluster is down".to_string(),
+        )
+            .into();
+        assert_eq!(GlueError::from_redis(&reply), Some(GlueError::ClusterDown));
+
+        let other: RedisError = (ErrorKind::ClientError, "something else").into();
+        assert_eq!(GlueError::from_redis(&other), None);
+    }
+}
@@ -0,0 +1,127 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Hash helpers
+use redis::{FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::set::random_sample;
+use crate::RedisConnection;
+
+impl RedisConnection {
+    /// Add `by` to the integer in `field` of the hash at `key` (HINCRBY),
+    /// returning the new value. Missing fields and keys start at 0.
+    pub async fn hincr_by(&self, key: &str, field: &str, by: i64) -> RedisResult<i64> {
+        self.exec(redis::cmd("HINCRBY").arg(key).arg(field).arg(by))
+            .await
+    }
+
+    /// Add `by` to the float in `field` of the hash at `key` (HINCRBYFLOAT),
+    /// returning the new value. Missing fields and keys start at 0.
+    pub async fn hincr_by_float(&self, key: &str, field: &str, by: f64) -> RedisResult<f64> {
+        self.exec(redis::cmd("HINCRBYFLOAT").arg(key).arg(field).arg(by))
+            .await
+    }
+
+    /// Set `field` of the hash at `key` to `val` unless it already exists
+    /// (HSETNX). Returns whether the field was created.
+    pub async fn hsetnx(&self, key: &str, field: &str, val: impl ToRedisArgs) -> RedisResult<bool> {
+        self.exec(redis::cmd("HSETNX").arg(key).arg(field).arg(val))
+            .await
+    }
+
+    /// Remove `fields` from the hash at `key` (HDEL), returning how many
+    /// existed
+    pub async fn hdel(&self, key: &str, fields: &[&str]) -> RedisResult<u64> {
+        self.exec(redis::cmd("HDEL").arg(key).arg(fields)).await
+    }
+
+    /// Random field names of the hash at `key` (HRANDFIELD, Redis 6.2+).
+    ///
+    /// A positive `count` returns up to that many distinct fields, a negative
+    /// one returns exactly `-count` fields that may repeat. Without a count a
+    /// single field is returned, if any.
+    pub async fn hrandfield<T: FromRedisValue>(
+        &self,
+        key: &str,
+        count: Option<i64>,
+    ) -> RedisResult<Vec<T>> {
+        random_sample(self, "HRANDFIELD", key, count).await
+    }
+
+    /// Like [Self::hrandfield], with the values of the fields (WITHVALUES)
+    pub async fn hrandfield_with_values<K: FromRedisValue, V: FromRedisValue>(
+        &self,
+        key: &str,
+        count: i64,
+    ) -> RedisResult<Vec<(K, V)>> {
+        self.exec(
+            redis::cmd("HRANDFIELD")
+                .arg(key)
+                .arg(count)
+                .arg("WITHVALUES"),
+        )
+        .await
+    }
+
+    /// Whether the hash at `key` has `field` (HEXISTS)
+    pub async fn hexists(&self, key: &str, field: &str) -> RedisResult<bool> {
+        self.exec(redis::cmd("HEXISTS").arg(key).arg(field)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn hash_numeric_helpers_work() {
+        const KEY: &str = "hash_numeric_helpers_work";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        assert_eq!(con.hincr_by(KEY, "hits", 1).await.unwrap(), 1);
+        assert_eq!(con.hincr_by(KEY, "hits", 5).await.unwrap(), 6);
+        assert_eq!(con.hincr_by(KEY, "hits", -2).await.unwrap(), 4);
+        assert_eq!(con.hincr_by_float(KEY, "avg", 1.5).await.unwrap(), 1.5);
+        assert_eq!(con.hincr_by_float(KEY, "avg", 0.25).await.unwrap(), 1.75);
+
+        assert!(con.hsetnx(KEY, "owner", "a").await.unwrap());
+        assert!(!con.hsetnx(KEY, "owner", "b").await.unwrap());
+        assert!(con.hexists(KEY, "owner").await.unwrap());
+
+        assert_eq!(con.hdel(KEY, &["owner", "missing"]).await.unwrap(), 1);
+        assert!(!con.hexists(KEY, "owner").await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn hrandfield_works() {
+        const KEY: &str = "hrandfield_works";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("HSET").arg(KEY).arg(&["a", "1", "b", "2"]))
+            .await
+            .unwrap();
+
+        let mut fields: Vec<String> = con.hrandfield(KEY, Some(5)).await.unwrap();
+        fields.sort();
+        assert_eq!(fields, vec!["a", "b"]);
+        let repeated: Vec<String> = con.hrandfield(KEY, Some(-5)).await.unwrap();
+        assert_eq!(repeated.len(), 5);
+
+        let mut pairs: Vec<(String, u8)> = con.hrandfield_with_values(KEY, 2).await.unwrap();
+        pairs.sort();
+        assert_eq!(pairs, vec![("a".into(), 1), ("b".into(), 2)]);
+    }
+}
@@ -0,0 +1,296 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Mapping structs to hashes with serde
+use serde::de::value::{Error as DeError, MapDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::Serialize;
+
+use redis::{ErrorKind, RedisError, RedisResult};
+
+use crate::RedisConnection;
+
+impl RedisConnection {
+    /// Deserialize the hash at `key` (HGETALL) into `T`, `None` if the key
+    /// doesn't exist.
+    ///
+    /// Field values are parsed according to the type of the matching struct
+    /// field: numbers and booleans from their text, nested structures and
+    /// sequences from JSON, as written by [Self::hset_struct]. A value that
+    /// doesn't parse fails with an error naming the problem.
+    pub async fn hget_struct<T: DeserializeOwned>(&self, key: &str) -> RedisResult<Option<T>> {
+        let fields: Vec<(String, String)> = self.exec(redis::cmd("HGETALL").arg(key)).await?;
+        if fields.is_empty() {
+            return Ok(None);
+        }
+        let map = MapDeserializer::new(
+            fields
+                .iter()
+                .map(|(field, val)| (field.as_str(), FieldValue(val.as_str()))),
+        );
+        T::deserialize(map).map(Some).map_err(|e| {
+            RedisError::from((
+                ErrorKind::TypeError,
+                "hash doesn't match the requested struct",
+                format!("{}: {}", key, e),
+            ))
+        })
+    }
+
+    /// Store the fields of `val` in the hash at `key` (HSET), one hash field
+    /// per struct field.
+    ///
+    /// Strings are stored as is, numbers and booleans as text, and nested
+    /// structures and sequences as JSON. `None` fields are removed from the
+    /// hash (HDEL), in the same MULTI/EXEC transaction.
+    pub async fn hset_struct<T: Serialize>(&self, key: &str, val: &T) -> RedisResult<()> {
+        let fields = match serde_json::to_value(val) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            Ok(_) => {
+                return Err((
+                    ErrorKind::TypeError,
+                    "only structs and maps can be stored as a hash",
+                )
+                    .into())
+            }
+            Err(e) => {
+                return Err((
+                    ErrorKind::TypeError,
+                    "value couldn't be serialized",
+                    e.to_string(),
+                )
+                    .into())
+            }
+        };
+        let mut set = Vec::new();
+        let mut unset = Vec::new();
+        for (field, val) in fields {
+            match val {
+                serde_json::Value::Null => unset.push(field),
+                serde_json::Value::String(val) => set.push((field, val)),
+                val => set.push((field, val.to_string())),
+            }
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        if !set.is_empty() {
+            pipe.cmd("HSET").arg(key).arg(set).ignore();
+        }
+        if !unset.is_empty() {
+            pipe.cmd("HDEL").arg(key).arg(unset).ignore();
+        }
+        self.exec_pipe(&pipe).await
+    }
+}
+
+/// A hash field value, deserialized following the type the visitor expects
+struct FieldValue<'a>(&'a str);
+
+impl<'a> FieldValue<'a> {
+    fn parse<T: std::str::FromStr>(&self, what: &str) -> Result<T, DeError> {
+        self.0
+            .parse()
+            .map_err(|_| de::Error::custom(format!("expected {}, got {:?}", what, self.0)))
+    }
+
+    fn json(&self) -> serde_json::Deserializer<serde_json::de::StrRead<'a>> {
+        serde_json::Deserializer::from_str(self.0)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for FieldValue<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+macro_rules! parse_as {
+    ($($method:ident => $visit:ident: $ty:ty,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                visitor.$visit(self.parse::<$ty>(stringify!($ty))?)
+            }
+        )*
+    };
+}
+
+macro_rules! from_json {
+    ($($method:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+                de::Deserializer::$method(&mut self.json(), visitor).map_err(de::Error::custom)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for FieldValue<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    parse_as! {
+        deserialize_bool => visit_bool: bool,
+        deserialize_i8 => visit_i8: i8,
+        deserialize_i16 => visit_i16: i16,
+        deserialize_i32 => visit_i32: i32,
+        deserialize_i64 => visit_i64: i64,
+        deserialize_u8 => visit_u8: u8,
+        deserialize_u16 => visit_u16: u16,
+        deserialize_u32 => visit_u32: u32,
+        deserialize_u64 => visit_u64: u64,
+        deserialize_f32 => visit_f32: f32,
+        deserialize_f64 => visit_f64: f64,
+        deserialize_char => visit_char: char,
+    }
+
+    from_json! {
+        deserialize_seq,
+        deserialize_map,
+        deserialize_unit,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        // fields set to None are removed, so a present field is always Some
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        de::Deserializer::deserialize_tuple(&mut self.json(), len, visitor)
+            .map_err(de::Error::custom)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        de::Deserializer::deserialize_struct(&mut self.json(), name, fields, visitor)
+            .map_err(de::Error::custom)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        // unit variants are stored as their bare name, others as JSON
+        if self.0.starts_with('{') {
+            de::Deserializer::deserialize_enum(&mut self.json(), name, variants, visitor)
+                .map_err(de::Error::custom)
+        } else {
+            visitor.visit_enum(self.0.into_deserializer())
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct tuple_struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use crate::*;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    enum Plan {
+        Free,
+        Paid,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        city: String,
+        zip: u32,
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u8,
+        score: f64,
+        active: bool,
+        plan: Plan,
+        nickname: Option<String>,
+        tags: Vec<String>,
+        address: Address,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Strict {
+        #[allow(dead_code)]
+        age: u8,
+    }
+
+    #[actix_rt::test]
+    async fn hash_struct_round_trips() {
+        const KEY: &str = "hash_struct_round_trips";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert!(con.hget_struct::<User>(KEY).await.unwrap().is_none());
+
+        let mut user = User {
+            name: "1337".into(),
+            age: 42,
+            score: 0.5,
+            active: true,
+            plan: Plan::Paid,
+            nickname: Some("leet".into()),
+            tags: vec!["a".into(), "b".into()],
+            address: Address {
+                city: "Chennai".into(),
+                zip: 600001,
+            },
+        };
+        con.hset_struct(KEY, &user).await.unwrap();
+        let age: String = con
+            .exec(redis::cmd("HGET").arg(KEY).arg("age"))
+            .await
+            .unwrap();
+        assert_eq!(age, "42");
+        assert_eq!(
+            con.hget_struct::<User>(KEY).await.unwrap(),
+            Some(user.clone())
+        );
+
+        user.nickname = None;
+        user.plan = Plan::Free;
+        con.hset_struct(KEY, &user).await.unwrap();
+        assert_eq!(con.hget_struct::<User>(KEY).await.unwrap(), Some(user));
+        assert!(!con.hexists(KEY, "nickname").await.unwrap());
+
+        let _: () = con
+            .exec(redis::cmd("HSET").arg(KEY).arg(&["age", "old"]))
+            .await
+            .unwrap();
+        let err = con.hget_struct::<Strict>(KEY).await.unwrap_err();
+        assert!(err.to_string().contains("expected u8"));
+    }
+}
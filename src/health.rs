@@ -0,0 +1,214 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Connection health tracking and background liveness checks
+use std::time::{Duration, Instant};
+
+use redis::RedisResult;
+use tokio::sync::watch;
+
+use crate::retry::is_retryable;
+use crate::{Redis, RedisConnection, Routing};
+
+/// Last-known health of a connection, see [RedisConnection::connection_state]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The latest command got a reply from the server, even an error reply
+    Healthy,
+    /// The latest command failed with a connection error or a transient
+    /// server error (loading, cluster down, ...)
+    Degraded,
+    /// No command went through since the connection was (re)established
+    Unknown,
+}
+
+impl RedisConnection {
+    /// Health of the connection according to the outcome of the latest
+    /// command, shared by every clone. Costs no I/O, unlike [Self::ping].
+    pub fn connection_state(&self) -> ConnectionState {
+        match (self.state.last_success(), self.state.last_error()) {
+            (None, None) => ConnectionState::Unknown,
+            (Some(_), None) => ConnectionState::Healthy,
+            (None, Some(_)) => ConnectionState::Degraded,
+            (Some(success), Some(error)) if success > error => ConnectionState::Healthy,
+            (Some(_), Some(_)) => ConnectionState::Degraded,
+        }
+    }
+
+    /// When a command last got a reply, `None` if none did yet
+    pub fn last_success(&self) -> Option<Instant> {
+        self.state.last_success()
+    }
+
+    /// When a command last failed as described in
+    /// [ConnectionState::Degraded], `None` if none did yet
+    pub fn last_error(&self) -> Option<Instant> {
+        self.state.last_error()
+    }
+
+    /// Update the health bookkeeping with the outcome of a command
+    pub(crate) fn record<T>(&self, res: &RedisResult<T>) {
+        match res {
+            Err(e) if is_retryable(e) => self.state.set_last_error(Some(Instant::now())),
+            _ => self.state.set_last_success(Some(Instant::now())),
+        }
+    }
+}
+
+/// Background liveness checks of a [Redis], see [Redis::with_health_check].
+/// The checks stop when this is dropped.
+pub struct HealthMonitor {
+    state: watch::Receiver<ConnectionState>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl HealthMonitor {
+    /// Outcome of the latest check: [ConnectionState::Unknown] until the
+    /// first one completes
+    pub fn state(&self) -> ConnectionState {
+        *self.state.borrow()
+    }
+
+    /// Receiver notified whenever the outcome of a check differs from the
+    /// previous one, to flip a readiness probe for example
+    pub fn changes(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+}
+
+impl Drop for HealthMonitor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Redis {
+    /// Check the deployment every `interval` from a task spawned on the
+    /// current Tokio runtime: PING the shared connection and replace it if it
+    /// is dead, as [Self::ensure_connected] does, and in cluster mode PING
+    /// every primary as well.
+    ///
+    /// A check is [ConnectionState::Healthy] if every PING (or the
+    /// reconnection) succeeded and [ConnectionState::Degraded] otherwise,
+    /// including when it takes longer than `interval`. Checks don't overlap:
+    /// a slow one delays the next.
+    pub fn with_health_check(&self, interval: Duration) -> HealthMonitor {
+        let (tx, rx) = watch::channel(ConnectionState::Unknown);
+        let redis = self.clone();
+        let task = tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                let healthy = tokio::time::timeout(interval, redis.check_health())
+                    .await
+                    .unwrap_or(false);
+                let state = if healthy {
+                    ConnectionState::Healthy
+                } else {
+                    ConnectionState::Degraded
+                };
+                tx.send_if_modified(|current| std::mem::replace(current, state) != state);
+            }
+        });
+        HealthMonitor { state: rx, task }
+    }
+
+    async fn check_health(&self) -> bool {
+        if self.ensure_connected().await.is_err() {
+            return false;
+        }
+        match self.node_info {
+            Some(_) => self
+                .exec_routed::<String>(&mut redis::cmd("PING"), Routing::AllMasters)
+                .await
+                .is_ok(),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn health_check_reports_unreachable_server() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let r =
+            Redis::new_lazy(RedisConfig::Single(format!("redis://127.0.0.1:{}", port))).unwrap();
+        let monitor = r.with_health_check(Duration::from_millis(20));
+        assert_eq!(monitor.state(), ConnectionState::Unknown);
+        let mut changes = monitor.changes();
+        changes.changed().await.unwrap();
+        assert_eq!(*changes.borrow(), ConnectionState::Degraded);
+    }
+
+    #[actix_rt::test]
+    async fn health_check_replaces_dead_connections() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let monitor = r.with_health_check(Duration::from_millis(20));
+        let mut changes = monitor.changes();
+        changes.changed().await.unwrap();
+        assert_eq!(monitor.state(), ConnectionState::Healthy);
+
+        let id: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let _: () = r
+            .dedicated()
+            .await
+            .unwrap()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let replaced: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_ne!(replaced, id);
+        assert_eq!(monitor.state(), ConnectionState::Healthy);
+    }
+
+    #[actix_rt::test]
+    async fn connection_state_follows_outcomes() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let killer = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        assert_eq!(con.connection_state(), ConnectionState::Unknown);
+
+        let id: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_eq!(con.connection_state(), ConnectionState::Healthy);
+        // error replies still mean the connection works
+        assert!(con
+            .exec::<()>(&mut redis::cmd("NOTACOMMAND"))
+            .await
+            .is_err());
+        assert_eq!(con.connection_state(), ConnectionState::Healthy);
+
+        let _: () = killer
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+        assert!(!con.ping().await);
+        assert_eq!(con.connection_state(), ConnectionState::Degraded);
+        assert!(con.last_error() > con.last_success());
+
+        r.ensure_connected().await.unwrap();
+        assert!(con.ping().await);
+        assert_eq!(con.connection_state(), ConnectionState::Healthy);
+    }
+}
@@ -0,0 +1,907 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Generic key helpers
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{StreamExt, TryChunksError, TryStreamExt};
+use rand::Rng;
+use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisResult, ToRedisArgs, Value};
+
+use crate::{slot_for, GlueError, Redis, RedisConnection, ScanOptions};
+
+/// Renames KEYS[1] to KEYS[2] if it exists, in one step so that no write to
+/// KEYS[1] can land between the check and the rename
+const ROTATE: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('RENAME', KEYS[1], KEYS[2])
+    return 1
+end
+return 0
+"#;
+
+/// Condition under which [RedisConnection::expire_opts] sets an expiry (Redis 7+)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpireCond {
+    /// Always set the expiry
+    None,
+    /// Only set the expiry when the key has none
+    Nx,
+    /// Only set the expiry when the key already has one
+    Xx,
+    /// Only set the expiry when it is later than the current one.
+    ///
+    /// A key without an expiry is treated as living forever, so `Gt` never
+    /// adds an expiry to a persistent key.
+    Gt,
+    /// Only set the expiry when it is sooner than the current one.
+    ///
+    /// A key without an expiry is treated as living forever, so `Lt` always
+    /// adds an expiry to a persistent key.
+    Lt,
+}
+
+impl ExpireCond {
+    fn as_arg(&self) -> Option<&'static str> {
+        match self {
+            Self::None => None,
+            Self::Nx => Some("NX"),
+            Self::Xx => Some("XX"),
+            Self::Gt => Some("GT"),
+            Self::Lt => Some("LT"),
+        }
+    }
+}
+
+/// What [RedisConnection::getex] does to the TTL of the key it reads
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GetExTtl {
+    /// Remove the expiry
+    Persist,
+    /// Leave the expiry as it is
+    Keep,
+    /// Expire after the given duration
+    Ex(Duration),
+    /// Expire at the given time. Times before the epoch expire immediately.
+    ExAt(SystemTime),
+}
+
+/// Absolute expiry of a key, see [RedisConnection::expire_time]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpireTime {
+    /// The key expires at this time
+    At(SystemTime),
+    /// The key exists but doesn't expire
+    Persistent,
+    /// The key doesn't exist
+    Missing,
+}
+
+impl ExpireTime {
+    /// Map an (P)EXPIRETIME reply, where -1 and -2 flag persistent and
+    /// missing keys, with `unit` converting it to a duration since the epoch
+    fn from_reply(reply: i64, unit: fn(u64) -> Duration) -> Self {
+        match reply {
+            -2 => Self::Missing,
+            -1 => Self::Persistent,
+            at => Self::At(UNIX_EPOCH + unit(at as u64)),
+        }
+    }
+
+    /// The expiry time, `None` for persistent and missing keys
+    pub fn at(&self) -> Option<SystemTime> {
+        match self {
+            Self::At(at) => Some(*at),
+            _ => None,
+        }
+    }
+}
+
+/// Acknowledgement that [RedisConnection::keys] blocks the server while it
+/// walks the whole keyspace. Prefer SCAN outside of debugging sessions.
+#[derive(Clone, Copy, Debug)]
+pub struct KeysAck;
+
+/// The keys of a cluster KEYS reply, merged from one `[addr, keys]` pair per
+/// node
+fn merge_node_keys(reply: &Value) -> RedisResult<Vec<String>> {
+    let nodes: Vec<Value> = from_redis_value(reply)?;
+    let mut keys = Vec::new();
+    for node in &nodes {
+        let (_addr, node_keys): (String, Vec<String>) = from_redis_value(node)?;
+        keys.extend(node_keys);
+    }
+    keys.sort_unstable();
+    keys.dedup();
+    Ok(keys)
+}
+
+impl RedisConnection {
+    /// Set a TTL on `key` if `cond` holds. Uses PEXPIRE, so sub-second TTLs
+    /// are preserved; sub-millisecond ones are rounded up to a millisecond.
+    ///
+    /// Returns whether the expiry was set; `false` if the key doesn't exist
+    /// or the condition wasn't met.
+    pub async fn expire_opts(
+        &self,
+        key: &str,
+        ttl: Duration,
+        cond: ExpireCond,
+    ) -> RedisResult<bool> {
+        let mut cmd = redis::cmd("PEXPIRE");
+        cmd.arg(key).arg((ttl.as_millis() as u64).max(1));
+        if let Some(cond) = cond.as_arg() {
+            cmd.arg(cond);
+        }
+        self.exec(&mut cmd).await
+    }
+
+    /// Get the value of `key` along with its remaining TTL (`None` when it
+    /// doesn't expire), or `None` if the key doesn't exist.
+    ///
+    /// GET and PTTL are pipelined, so this costs a single round-trip.
+    pub async fn get_with_ttl<T: FromRedisValue>(
+        &self,
+        key: &str,
+    ) -> RedisResult<Option<(T, Option<Duration>)>> {
+        let mut pipe = redis::pipe();
+        pipe.cmd("GET").arg(key).cmd("PTTL").arg(key);
+        let (val, pttl): (Option<T>, i64) = self.exec_pipe(&pipe).await?;
+        // PTTL is -1 for keys without an expiry and -2 for missing keys
+        let ttl = if pttl >= 0 {
+            Some(Duration::from_millis(pttl as u64))
+        } else {
+            None
+        };
+        Ok(val.map(|val| (val, ttl)))
+    }
+
+    /// Value of `key` (GET), `None` if it doesn't exist.
+    ///
+    /// When the key holds something other than a string, fails with
+    /// [GlueError::WrongType] naming the actual type, at the cost of a TYPE
+    /// round-trip on that error path only.
+    pub async fn get_checked<T: FromRedisValue>(&self, key: &str) -> RedisResult<Option<T>> {
+        match self.exec(redis::cmd("GET").arg(key)).await {
+            Err(e) if e.code() == Some("WRONGTYPE") => {
+                let actual_type: String = self.exec(redis::cmd("TYPE").arg(key)).await?;
+                Err(GlueError::WrongType {
+                    key: key.to_owned(),
+                    actual_type,
+                }
+                .into())
+            }
+            res => res,
+        }
+    }
+
+    /// Set `key` to `val` (SET PX) with `ttl` moved up or down by a random
+    /// fraction of up to `jitter`, so that keys written together with the
+    /// same TTL don't all expire at once and stampede whatever recomputes
+    /// them. A `jitter` of 0.1 on 60 seconds gives a TTL anywhere between 54
+    /// and 66 seconds, uniformly.
+    ///
+    /// `jitter` must be in `0.0..1.0`.
+    pub async fn set_ex_jittered<V: ToRedisArgs>(
+        &self,
+        key: &str,
+        val: V,
+        ttl: Duration,
+        jitter: f64,
+    ) -> RedisResult<()> {
+        if !(0.0..1.0).contains(&jitter) {
+            return Err((
+                ErrorKind::ClientError,
+                "TTL jitter must be at least 0 and below 1",
+                jitter.to_string(),
+            )
+                .into());
+        }
+        let ttl = jittered(ttl, jitter, &mut rand::thread_rng());
+        self.exec(
+            redis::cmd("SET")
+                .arg(key)
+                .arg(val)
+                .arg("PX")
+                .arg((ttl.as_millis() as u64).max(1)),
+        )
+        .await
+    }
+
+    /// Get the value of `key` and update its TTL in the same atomic command
+    /// (GETEX, Redis 6.2+), `None` if the key doesn't exist.
+    ///
+    /// Expiries are sent in milliseconds (PX/PXAT), so sub-second precision
+    /// is preserved; TTLs under a millisecond are rounded up to one.
+    pub async fn getex<T: FromRedisValue>(
+        &self,
+        key: &str,
+        ttl: GetExTtl,
+    ) -> RedisResult<Option<T>> {
+        let mut cmd = redis::cmd("GETEX");
+        cmd.arg(key);
+        match ttl {
+            GetExTtl::Persist => {
+                cmd.arg("PERSIST");
+            }
+            GetExTtl::Keep => {}
+            GetExTtl::Ex(ttl) => {
+                cmd.arg("PX").arg((ttl.as_millis() as u64).max(1));
+            }
+            GetExTtl::ExAt(at) => {
+                let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+                cmd.arg("PXAT").arg(at.as_millis() as u64);
+            }
+        }
+        self.exec(&mut cmd).await
+    }
+
+    /// When `key` expires, to the second (EXPIRETIME, Redis 7+)
+    pub async fn expire_time(&self, key: &str) -> RedisResult<ExpireTime> {
+        let reply = self.exec(redis::cmd("EXPIRETIME").arg(key)).await?;
+        Ok(ExpireTime::from_reply(reply, Duration::from_secs))
+    }
+
+    /// When `key` expires, to the millisecond (PEXPIRETIME, Redis 7+)
+    pub async fn pexpire_time(&self, key: &str) -> RedisResult<ExpireTime> {
+        let reply = self.exec(redis::cmd("PEXPIRETIME").arg(key)).await?;
+        Ok(ExpireTime::from_reply(reply, Duration::from_millis))
+    }
+
+    /// A random key from the current database (RANDOMKEY), `None` if it is
+    /// empty. Fails with [ErrorKind::ClientError] in cluster mode, where
+    /// RANDOMKEY would only look at one node picked at random and answer
+    /// `None` whenever that one is empty.
+    pub async fn randomkey(&self) -> RedisResult<Option<String>> {
+        self.ensure_not_cluster("RANDOMKEY isn't supported in cluster mode")?;
+        self.exec(&mut redis::cmd("RANDOMKEY")).await
+    }
+
+    /// All keys matching `pattern` (KEYS).
+    ///
+    /// KEYS is O(N) over the whole keyspace and blocks the server until it
+    /// is done, hence [KeysAck]. Use SCAN for anything but small or debug
+    /// databases. In cluster mode KEYS runs on every node and the keys of
+    /// all of them are returned, sorted and without duplicates.
+    pub async fn keys(&self, pattern: &str, _ack: KeysAck) -> RedisResult<Vec<String>> {
+        if !self.is_cluster() {
+            return self.exec(redis::cmd("KEYS").arg(pattern)).await;
+        }
+        let reply: Value = self.exec(redis::cmd("KEYS").arg(pattern)).await?;
+        merge_node_keys(&reply)
+    }
+
+    /// Serialized value of `key` (DUMP) along with its remaining TTL (`None`
+    /// when it doesn't expire), or `None` if the key doesn't exist. Both are
+    /// read in one MULTI/EXEC, so the TTL matches the payload.
+    pub async fn dump(&self, key: &str) -> RedisResult<Option<(Vec<u8>, Option<Duration>)>> {
+        let mut pipe = redis::pipe();
+        pipe.atomic().cmd("DUMP").arg(key).cmd("PTTL").arg(key);
+        let (payload, pttl): (Option<Vec<u8>>, i64) = self.exec_pipe(&pipe).await?;
+        // PTTL is -1 for keys without an expiry; 0 means "expiring right now",
+        // which must not turn into RESTORE's 0 for "never expires"
+        let ttl = match pttl {
+            -1 => None,
+            pttl => Some(Duration::from_millis(pttl.max(1) as u64)),
+        };
+        Ok(payload.map(|payload| (payload, ttl)))
+    }
+
+    /// Create `key` from a [Self::dump] payload (RESTORE), expiring after
+    /// `ttl` if given. Fails with a BUSYKEY error reply if `key` exists,
+    /// unless `replace` is set.
+    pub async fn restore(
+        &self,
+        key: &str,
+        payload: &[u8],
+        ttl: Option<Duration>,
+        replace: bool,
+    ) -> RedisResult<()> {
+        let ttl = ttl.map_or(0, |ttl| (ttl.as_millis() as u64).max(1));
+        let mut cmd = redis::cmd("RESTORE");
+        cmd.arg(key).arg(ttl).arg(payload);
+        if replace {
+            cmd.arg("REPLACE");
+        }
+        self.exec(&mut cmd).await
+    }
+
+    /// Move the value at `key` to `archive`, overwriting it, so that writes to
+    /// `key` start over on an empty value. Returns `false`, leaving `archive`
+    /// alone, if `key` doesn't exist.
+    ///
+    /// Runs as a Lua script, so no write to `key` is lost between the check
+    /// and the rename. In cluster mode both keys must hash to the same slot.
+    pub async fn rotate(&self, key: &str, archive: &str) -> RedisResult<bool> {
+        self.ensure_same_slot(&[key, archive])?;
+        self.exec(redis::cmd("EVAL").arg(ROTATE).arg(2).arg(key).arg(archive))
+            .await
+    }
+
+    async fn unlink(&self, keys: &[String]) -> RedisResult<u64> {
+        self.exec(redis::cmd("UNLINK").arg(keys)).await
+    }
+
+    /// Logarithmic access frequency counter of `key` (OBJECT FREQ), `None`
+    /// if the key doesn't exist.
+    ///
+    /// Only tracked under an LFU `maxmemory-policy` (`allkeys-lfu` or
+    /// `volatile-lfu`); under any other policy the server's error explaining
+    /// that is returned.
+    pub async fn object_freq(&self, key: &str) -> RedisResult<Option<u64>> {
+        self.exec(redis::cmd("OBJECT").arg("FREQ").arg(key)).await
+    }
+
+    /// Internal representation of `key`'s value (OBJECT ENCODING), like
+    /// `listpack`, `hashtable` or `embstr`, `None` if the key doesn't exist
+    pub async fn object_encoding(&self, key: &str) -> RedisResult<Option<String>> {
+        self.exec(redis::cmd("OBJECT").arg("ENCODING").arg(key))
+            .await
+    }
+
+    /// Turn the server's active expiry cycle on or off (DEBUG
+    /// SET-ACTIVE-EXPIRE), so that with it off expired keys linger, still
+    /// counted by DBSIZE, until something accesses them. For testing code
+    /// that depends on lazy expiry.
+    ///
+    /// The setting is server-wide: turn it back on when done. Since Redis 7
+    /// DEBUG is only accepted with `enable-debug-command` set. Single mode
+    /// only.
+    #[cfg(feature = "test-helpers")]
+    pub async fn debug_set_active_expire(&self, enabled: bool) -> RedisResult<()> {
+        self.ensure_not_cluster("DEBUG isn't supported in cluster mode")?;
+        self.exec(
+            redis::cmd("DEBUG")
+                .arg("SET-ACTIVE-EXPIRE")
+                .arg(u8::from(enabled)),
+        )
+        .await
+    }
+}
+
+/// `ttl` scaled by a factor picked uniformly in `1 - jitter..=1 + jitter`
+fn jittered(ttl: Duration, jitter: f64, rng: &mut impl Rng) -> Duration {
+    if jitter == 0.0 {
+        return ttl;
+    }
+    ttl.mul_f64(rng.gen_range(1.0 - jitter..=1.0 + jitter))
+}
+
+impl Redis {
+    /// Delete every key matching `pattern`, returning how many were deleted.
+    ///
+    /// Walks the keyspace with [Self::cluster_scan], `batch` keys at a time
+    /// (also the COUNT hint), and frees each batch with UNLINK before moving
+    /// on, so neither the server nor this process has to hold the full match
+    /// list. Keys created during the walk may survive. In cluster mode every
+    /// primary is walked and each UNLINK only carries keys of one slot. The
+    /// first node that fails ends the walk with its error.
+    pub async fn delete_matching(&self, pattern: &str, batch: usize) -> RedisResult<u64> {
+        let batch = batch.max(1);
+        let mut keys = self
+            .cluster_scan(ScanOptions {
+                pattern: Some(pattern.into()),
+                count: Some(batch),
+                ..ScanOptions::default()
+            })
+            .try_chunks(batch);
+        let con = self.get_client();
+        let mut deleted = 0;
+        while let Some(keys) = keys.next().await {
+            let keys = keys.map_err(|TryChunksError(_, err)| err)?;
+            if con.is_cluster() {
+                let mut by_slot: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+                for key in keys {
+                    by_slot.entry(slot_for(&key)).or_default().push(key);
+                }
+                for keys in by_slot.values() {
+                    deleted += con.unlink(keys).await?;
+                }
+            } else {
+                deleted += con.unlink(&keys).await?;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Copy `key` to the deployment behind `dest` with DUMP and RESTORE,
+    /// keeping its remaining TTL. Returns `false` if `key` doesn't exist here.
+    ///
+    /// The key is left in place on this side. If it already exists in `dest`
+    /// the copy fails with a BUSYKEY error reply unless `replace` is set. The
+    /// servers must run compatible versions: RESTORE rejects payloads from a
+    /// newer RDB format.
+    pub async fn migrate_key(&self, dest: &Redis, key: &str, replace: bool) -> RedisResult<bool> {
+        let (payload, ttl) = match self.connection.dump(key).await? {
+            Some(dumped) => dumped,
+            None => return Ok(false),
+        };
+        dest.connection.restore(key, &payload, ttl, replace).await?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::{jittered, merge_node_keys};
+    use crate::*;
+    use std::time::Duration;
+
+    #[test]
+    fn jittered_stays_in_range() {
+        let mut rng = rand::thread_rng();
+        let ttl = Duration::from_secs(60);
+        for _ in 0..1000 {
+            let ttl = jittered(ttl, 0.1, &mut rng);
+            assert!(ttl >= Duration::from_secs(54) && ttl <= Duration::from_secs(66));
+        }
+        assert_eq!(jittered(ttl, 0.0, &mut rng), ttl);
+    }
+
+    #[actix_rt::test]
+    async fn set_ex_jittered_spreads_ttls() {
+        const KEY: &str = "set_ex_jittered_spreads_ttls";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let mut ttls = Vec::new();
+        for _ in 0..20 {
+            con.set_ex_jittered(KEY, "v", Duration::from_secs(60), 0.1)
+                .await
+                .unwrap();
+            let ttl: u64 = con.exec(redis::cmd("PTTL").arg(KEY)).await.unwrap();
+            assert!((53_000..=66_000).contains(&ttl), "{} out of range", ttl);
+            ttls.push(ttl);
+        }
+        ttls.dedup();
+        assert!(ttls.len() > 1);
+
+        assert!(con
+            .set_ex_jittered(KEY, "v", Duration::from_secs(60), 1.5)
+            .await
+            .is_err());
+    }
+
+    async fn ttl(con: &RedisConnection, key: &str) -> i64 {
+        con.exec(redis::cmd("TTL").arg(key)).await.unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn rotate_starts_over() {
+        const KEY: &str = "{rotate_starts_over}log";
+        const ARCHIVE: &str = "{rotate_starts_over}log.1";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[KEY, ARCHIVE]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(ARCHIVE).arg("older"))
+            .await
+            .unwrap();
+        assert!(!con.rotate(KEY, ARCHIVE).await.unwrap());
+
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(KEY).arg(&["a", "b"]))
+            .await
+            .unwrap();
+        assert!(con.rotate(KEY, ARCHIVE).await.unwrap());
+        let archived: Vec<String> = con
+            .exec(redis::cmd("LRANGE").arg(ARCHIVE).arg(0).arg(-1))
+            .await
+            .unwrap();
+        assert_eq!(archived, vec!["a", "b"]);
+        let len: u64 = con.exec(redis::cmd("LLEN").arg(KEY)).await.unwrap();
+        assert_eq!(len, 0);
+        assert!(!con.rotate(KEY, ARCHIVE).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn migrate_key_keeps_ttl() {
+        const KEY: &str = "migrate_key_keeps_ttl";
+        let src = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let dest = Redis::new(RedisConfig::Single("redis://127.0.0.1/14".into()))
+            .await
+            .unwrap();
+        let _: () = dest
+            .get_client()
+            .exec(redis::cmd("DEL").arg(KEY))
+            .await
+            .unwrap();
+        let con = src.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert!(!src.migrate_key(&dest, KEY, false).await.unwrap());
+
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(KEY).arg(&["a", "b"]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("EXPIRE").arg(KEY).arg(100))
+            .await
+            .unwrap();
+        assert!(src.migrate_key(&dest, KEY, false).await.unwrap());
+
+        let copied: Vec<String> = dest
+            .get_client()
+            .exec(redis::cmd("LRANGE").arg(KEY).arg(0).arg(-1))
+            .await
+            .unwrap();
+        assert_eq!(copied, vec!["a", "b"]);
+        let copied_ttl = ttl(&dest.get_client(), KEY).await;
+        assert!((95..=100).contains(&copied_ttl));
+        assert!(ttl(&con, KEY).await > 0);
+
+        // the key now exists on both sides
+        assert!(src.migrate_key(&dest, KEY, false).await.is_err());
+        let _: () = con.exec(redis::cmd("PERSIST").arg(KEY)).await.unwrap();
+        assert!(src.migrate_key(&dest, KEY, true).await.unwrap());
+        assert_eq!(ttl(&dest.get_client(), KEY).await, -1);
+    }
+
+    #[actix_rt::test]
+    async fn expire_gt_refuses_to_shorten() {
+        const KEY: &str = "expire_gt_refuses_to_shorten";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&[KEY, "1", "EX", "100"]))
+            .await
+            .unwrap();
+
+        let shorter = Duration::from_secs(10);
+        assert!(!con.expire_opts(KEY, shorter, ExpireCond::Gt).await.unwrap());
+        assert!(ttl(&con, KEY).await > 10);
+
+        let longer = Duration::from_secs(200);
+        assert!(con.expire_opts(KEY, longer, ExpireCond::Gt).await.unwrap());
+        assert!(ttl(&con, KEY).await > 100);
+    }
+
+    #[actix_rt::test]
+    async fn expire_lt_refuses_to_extend() {
+        const KEY: &str = "expire_lt_refuses_to_extend";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&[KEY, "1", "EX", "100"]))
+            .await
+            .unwrap();
+
+        let longer = Duration::from_secs(200);
+        assert!(!con.expire_opts(KEY, longer, ExpireCond::Lt).await.unwrap());
+        assert!(ttl(&con, KEY).await <= 100);
+
+        let shorter = Duration::from_secs(10);
+        assert!(con.expire_opts(KEY, shorter, ExpireCond::Lt).await.unwrap());
+        assert!(ttl(&con, KEY).await <= 10);
+    }
+
+    #[actix_rt::test]
+    async fn get_with_ttl_works() {
+        const KEY: &str = "get_with_ttl_works";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&[KEY, "val", "EX", "100"]))
+            .await
+            .unwrap();
+
+        let (val, ttl): (String, _) = con.get_with_ttl(KEY).await.unwrap().unwrap();
+        assert_eq!(val, "val");
+        let ttl = ttl.unwrap();
+        assert!(ttl <= Duration::from_secs(100) && ttl > Duration::from_secs(95));
+
+        let _: () = con.exec(redis::cmd("PERSIST").arg(KEY)).await.unwrap();
+        let (_, ttl): (String, _) = con.get_with_ttl(KEY).await.unwrap().unwrap();
+        assert!(ttl.is_none());
+
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert!(con.get_with_ttl::<String>(KEY).await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn get_checked_names_actual_type() {
+        const KEY: &str = "get_checked_names_actual_type";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert!(con.get_checked::<String>(KEY).await.unwrap().is_none());
+
+        let _: () = con
+            .exec(redis::cmd("HSET").arg(KEY).arg(&["f", "v"]))
+            .await
+            .unwrap();
+        let err = con.get_checked::<String>(KEY).await.unwrap_err();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::WrongType {
+                key: KEY.into(),
+                actual_type: "hash".into()
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn getex_ex_extends_ttl() {
+        const KEY: &str = "getex_ex_extends_ttl";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&[KEY, "val", "EX", "10"]))
+            .await
+            .unwrap();
+
+        let ex = GetExTtl::Ex(Duration::from_secs(100));
+        let val: Option<String> = con.getex(KEY, ex).await.unwrap();
+        assert_eq!(val.as_deref(), Some("val"));
+        assert!(ttl(&con, KEY).await > 10);
+
+        let val: Option<String> = con.getex(KEY, GetExTtl::Keep).await.unwrap();
+        assert_eq!(val.as_deref(), Some("val"));
+        assert!(ttl(&con, KEY).await > 10);
+
+        let at = std::time::SystemTime::now() + Duration::from_secs(300);
+        let _: Option<String> = con.getex(KEY, GetExTtl::ExAt(at)).await.unwrap();
+        assert!(ttl(&con, KEY).await > 200);
+
+        let missing: Option<String> = con.getex("getex_missing", ex).await.unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn getex_persist_removes_ttl() {
+        const KEY: &str = "getex_persist_removes_ttl";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&[KEY, "val", "EX", "100"]))
+            .await
+            .unwrap();
+
+        let val: Option<String> = con.getex(KEY, GetExTtl::Persist).await.unwrap();
+        assert_eq!(val.as_deref(), Some("val"));
+        assert_eq!(ttl(&con, KEY).await, -1);
+    }
+
+    #[actix_rt::test]
+    async fn expire_time_works() {
+        const KEY: &str = "expire_time_works";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert_eq!(con.expire_time(KEY).await.unwrap(), ExpireTime::Missing);
+        assert_eq!(con.pexpire_time(KEY).await.unwrap(), ExpireTime::Missing);
+
+        let _: () = con.exec(redis::cmd("SET").arg(&[KEY, "1"])).await.unwrap();
+        assert_eq!(con.expire_time(KEY).await.unwrap(), ExpireTime::Persistent);
+        assert_eq!(con.pexpire_time(KEY).await.unwrap().at(), None);
+
+        let at = std::time::UNIX_EPOCH + Duration::from_millis(4_102_444_800_123);
+        let _: () = con
+            .exec(redis::cmd("PEXPIREAT").arg(KEY).arg(4_102_444_800_123u64))
+            .await
+            .unwrap();
+        assert_eq!(con.pexpire_time(KEY).await.unwrap(), ExpireTime::At(at));
+        assert_eq!(
+            con.expire_time(KEY).await.unwrap(),
+            ExpireTime::At(std::time::UNIX_EPOCH + Duration::from_secs(4_102_444_800))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn randomkey_and_keys_work() {
+        const KEY: &str = "randomkey_and_keys_work";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // a database of its own, so that the key is the only one around
+        let con = r.dedicated().await.unwrap();
+        let _: () = con.exec(redis::cmd("SELECT").arg(9)).await.unwrap();
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        assert_eq!(con.randomkey().await.unwrap(), None);
+
+        let _: () = con.exec(redis::cmd("SET").arg(&[KEY, "1"])).await.unwrap();
+        assert_eq!(con.randomkey().await.unwrap(), Some(KEY.into()));
+        assert_eq!(
+            con.keys("randomkey_*", KeysAck).await.unwrap(),
+            vec![KEY.to_string()]
+        );
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+    }
+
+    #[test]
+    fn cluster_keys_are_merged() {
+        use redis::Value;
+
+        let node = |addr: &str, keys: &[&str]| {
+            Value::Bulk(vec![
+                Value::Data(addr.into()),
+                Value::Bulk(
+                    keys.iter()
+                        .map(|key| Value::Data(key.as_bytes().into()))
+                        .collect(),
+                ),
+            ])
+        };
+        let reply = Value::Bulk(vec![
+            node("127.0.0.1:7001", &["b", "c"]),
+            node("127.0.0.1:7000", &["a"]),
+            node("127.0.0.1:7002", &[]),
+            // a replica, holding its primary's keys
+            node("127.0.0.1:7003", &["a"]),
+        ]);
+        assert_eq!(merge_node_keys(&reply).unwrap(), vec!["a", "b", "c"]);
+        assert!(merge_node_keys(&Value::Status("OK".into())).is_err());
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_keys_cover_every_node() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let con = r.get_client();
+        let mut expected = Vec::new();
+        for i in 0..20 {
+            let key = format!("cluster_keys_cover_every_node:{:02}", i);
+            let _: () = con.exec(redis::cmd("SET").arg(&key).arg(i)).await.unwrap();
+            expected.push(key);
+        }
+        let keys = con
+            .keys("cluster_keys_cover_every_node:*", KeysAck)
+            .await
+            .unwrap();
+        assert_eq!(keys, expected);
+        let err = con.randomkey().await.unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+    }
+
+    #[actix_rt::test]
+    async fn delete_matching_spares_other_keys() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let mut pipe = redis::pipe();
+        for i in 0..500 {
+            pipe.cmd("SET")
+                .arg(format!("delete_matching:hit:{}", i))
+                .arg(i)
+                .ignore();
+            pipe.cmd("SET")
+                .arg(format!("delete_matching:miss:{}", i))
+                .arg(i)
+                .ignore();
+        }
+        let _: () = con.exec_pipe(&pipe).await.unwrap();
+
+        assert_eq!(
+            r.delete_matching("delete_matching:hit:*", 100)
+                .await
+                .unwrap(),
+            500
+        );
+        let hits = con.keys("delete_matching:hit:*", KeysAck).await.unwrap();
+        assert!(hits.is_empty());
+        assert_eq!(
+            r.delete_matching("delete_matching:miss:*", 1000)
+                .await
+                .unwrap(),
+            500
+        );
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_delete_matching_walks_every_primary() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let con = r.get_client();
+        // spread over the slot space, so every primary holds some
+        for i in 0..100 {
+            let key = format!("cluster_delete_matching:{}", i);
+            let _: () = con.exec(redis::cmd("SET").arg(&key).arg(i)).await.unwrap();
+        }
+        assert_eq!(
+            r.delete_matching("cluster_delete_matching:*", 10)
+                .await
+                .unwrap(),
+            100
+        );
+        let left: Vec<String> = r
+            .cluster_scan(ScanOptions {
+                pattern: Some("cluster_delete_matching:*".into()),
+                ..ScanOptions::default()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        assert!(left.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn object_freq_increases_with_access() {
+        const KEY: &str = "object_freq_increases_with_access";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let (_, policy): (String, String) = con
+            .exec(redis::cmd("CONFIG").arg(&["GET", "maxmemory-policy"]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("CONFIG").arg(&["SET", "maxmemory-policy", "allkeys-lfu"]))
+            .await
+            .unwrap();
+
+        let _: () = con.exec(redis::cmd("SET").arg(&[KEY, "1"])).await.unwrap();
+        let before = con.object_freq(KEY).await.unwrap().unwrap();
+        for _ in 0..100 {
+            let _: String = con.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        }
+        let after = con.object_freq(KEY).await.unwrap().unwrap();
+        assert!(con
+            .object_freq("object_freq_missing")
+            .await
+            .unwrap()
+            .is_none());
+
+        let _: () = con
+            .exec(redis::cmd("CONFIG").arg(&["SET", "maxmemory-policy", &policy]))
+            .await
+            .unwrap();
+        assert!(after > before);
+    }
+
+    #[cfg(feature = "test-helpers")]
+    #[actix_rt::test]
+    async fn expired_keys_linger_without_active_expire() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.db(8).await.unwrap();
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        con.debug_set_active_expire(false).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("SET").arg("lingering").arg(1).arg("PX").arg(10))
+            .await
+            .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(300)).await;
+        let size: u64 = con.exec(&mut redis::cmd("DBSIZE")).await.unwrap();
+        con.debug_set_active_expire(true).await.unwrap();
+        assert_eq!(size, 1);
+
+        let value: Option<u64> = con.exec(redis::cmd("GET").arg("lingering")).await.unwrap();
+        assert_eq!(value, None);
+        let size: u64 = con.exec(&mut redis::cmd("DBSIZE")).await.unwrap();
+        assert_eq!(size, 0);
+    }
+}
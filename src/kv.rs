@@ -0,0 +1,266 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Typed string keys, with values stored as JSON
+use std::time::Duration;
+
+use redis::RedisResult;
+#[cfg(feature = "serde")]
+use redis::{ErrorKind, RedisError};
+#[cfg(feature = "serde")]
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::RedisConnection;
+
+impl RedisConnection {
+    /// Value of `key` (GET) decoded from JSON, `None` if the key doesn't
+    /// exist. A value that isn't valid JSON for `T` fails with
+    /// [ErrorKind::TypeError].
+    #[cfg(feature = "serde")]
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> RedisResult<Option<T>> {
+        let raw: Option<Vec<u8>> = self.exec(redis::cmd("GET").arg(key)).await?;
+        raw.map(|raw| decode(key, &raw)).transpose()
+    }
+
+    /// Store `val` at `key` as JSON (SET), removing any expiry the key had
+    #[cfg(feature = "serde")]
+    pub async fn set<T: Serialize>(&self, key: &str, val: &T) -> RedisResult<()> {
+        self.exec(redis::cmd("SET").arg(key).arg(encode(val)?))
+            .await
+    }
+
+    /// Store `val` at `key` as JSON and make it expire after `ttl` (SET PX),
+    /// rounded up to a millisecond
+    #[cfg(feature = "serde")]
+    pub async fn set_ex<T: Serialize>(&self, key: &str, val: &T, ttl: Duration) -> RedisResult<()> {
+        self.exec(
+            redis::cmd("SET")
+                .arg(key)
+                .arg(encode(val)?)
+                .arg("PX")
+                .arg((ttl.as_millis() as u64).max(1)),
+        )
+        .await
+    }
+
+    /// Values of `keys` (MGET) decoded from JSON, in the order of `keys`,
+    /// `None` for keys that don't exist.
+    ///
+    /// Keys may span hash slots: in cluster mode one MGET is sent per slot,
+    /// concurrently, as with [Self::exec_multikey].
+    #[cfg(feature = "serde")]
+    pub async fn mget<T: DeserializeOwned>(&self, keys: &[&str]) -> RedisResult<Vec<Option<T>>> {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let groups = self.key_groups(keys);
+        let replies = futures::future::join_all(groups.iter().map(|indices| async move {
+            let mut cmd = redis::cmd("MGET");
+            for i in indices {
+                cmd.arg(keys[*i]);
+            }
+            self.exec::<Vec<Option<Vec<u8>>>>(&mut cmd).await
+        }))
+        .await;
+
+        let mut values: Vec<Option<T>> = keys.iter().map(|_| None).collect();
+        for (indices, raw) in groups.iter().zip(replies) {
+            for (i, raw) in indices.iter().zip(raw?) {
+                values[*i] = raw.map(|raw| decode(keys[*i], &raw)).transpose()?;
+            }
+        }
+        Ok(values)
+    }
+
+    /// Store every `(key, value)` of `items` as JSON (MSET).
+    ///
+    /// Keys may span hash slots: in cluster mode one MSET is sent per slot,
+    /// concurrently, and only the keys of a slot are set atomically. A
+    /// failing slot fails the call even if other slots were set.
+    #[cfg(feature = "serde")]
+    pub async fn mset<T: Serialize>(&self, items: &[(&str, T)]) -> RedisResult<()> {
+        if items.is_empty() {
+            return Ok(());
+        }
+        let keys: Vec<&str> = items.iter().map(|(key, _)| *key).collect();
+        let mut cmds = Vec::new();
+        for indices in self.key_groups(&keys) {
+            let mut cmd = redis::cmd("MSET");
+            for i in indices {
+                cmd.arg(items[i].0).arg(encode(&items[i].1)?);
+            }
+            cmds.push(cmd);
+        }
+        let replies = futures::future::join_all(
+            cmds.into_iter()
+                .map(|mut cmd| async move { self.exec::<()>(&mut cmd).await }),
+        )
+        .await;
+        replies.into_iter().collect()
+    }
+
+    /// Delete `key` (DEL), returning whether it existed. See
+    /// [Self::del_many] for several keys.
+    pub async fn del(&self, key: &str) -> RedisResult<bool> {
+        self.exec(redis::cmd("DEL").arg(key)).await
+    }
+
+    /// Whether `key` exists (EXISTS). See [Self::exists_many] for several
+    /// keys.
+    pub async fn exists(&self, key: &str) -> RedisResult<bool> {
+        self.exec(redis::cmd("EXISTS").arg(key)).await
+    }
+
+    /// Add `by` to the integer at `key` (INCRBY), starting from 0 if the key
+    /// doesn't exist, and return the new value
+    pub async fn incr(&self, key: &str, by: i64) -> RedisResult<i64> {
+        self.exec(redis::cmd("INCRBY").arg(key).arg(by)).await
+    }
+
+    /// Make `key` expire after `ttl` (PEXPIRE), rounded up to a millisecond,
+    /// returning whether it existed. See [Self::expire_many] for several
+    /// keys.
+    pub async fn expire(&self, key: &str, ttl: Duration) -> RedisResult<bool> {
+        self.exec(
+            redis::cmd("PEXPIRE")
+                .arg(key)
+                .arg((ttl.as_millis() as u64).max(1)),
+        )
+        .await
+    }
+}
+
+#[cfg(feature = "serde")]
+fn encode<T: Serialize>(val: &T) -> RedisResult<Vec<u8>> {
+    serde_json::to_vec(val).map_err(|e| {
+        RedisError::from((
+            ErrorKind::TypeError,
+            "value couldn't be serialized",
+            e.to_string(),
+        ))
+    })
+}
+
+#[cfg(feature = "serde")]
+fn decode<T: DeserializeOwned>(key: &str, raw: &[u8]) -> RedisResult<T> {
+    serde_json::from_slice(raw).map_err(|e| {
+        RedisError::from((
+            ErrorKind::TypeError,
+            "value isn't valid JSON for the requested type",
+            format!("{}: {}", key, e),
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::*;
+
+    #[cfg(feature = "serde")]
+    async fn typed_values_round_trip(r: Redis) {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct User {
+            name: String,
+            visits: u32,
+        }
+
+        let con = r.get_client();
+        let keys: Vec<String> = (0..6)
+            .map(|i| format!("typed_values_round_trip_{}", i))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        con.del_many(&keys).await.unwrap();
+
+        let user = User {
+            name: "ana".into(),
+            visits: 3,
+        };
+        con.set(keys[0], &user).await.unwrap();
+        assert_eq!(con.get::<User>(keys[0]).await.unwrap(), Some(user));
+        assert_eq!(con.get::<User>(keys[1]).await.unwrap(), None);
+
+        let items: Vec<(&str, Vec<u32>)> = keys[1..4]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (*key, vec![i as u32; i]))
+            .collect();
+        con.mset(&items).await.unwrap();
+        let mut lookup = keys[1..5].to_vec();
+        lookup.reverse();
+        assert_eq!(
+            con.mget::<Vec<u32>>(&lookup).await.unwrap(),
+            vec![None, Some(vec![2, 2]), Some(vec![1]), Some(vec![])]
+        );
+
+        let err = con.get::<Vec<u32>>(keys[0]).await.unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::TypeError);
+
+        con.set_ex(keys[5], &"soon gone", Duration::from_secs(60))
+            .await
+            .unwrap();
+        let ttl: i64 = con.exec(redis::cmd("TTL").arg(keys[5])).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        // PX 0 would be rejected
+        con.set_ex(keys[5], &"gone", Duration::from_micros(10))
+            .await
+            .unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[actix_rt::test]
+    async fn typed_values_round_trip_single() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        typed_values_round_trip(r).await;
+    }
+
+    #[cfg(feature = "serde")]
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn typed_values_round_trip_across_slots() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        typed_values_round_trip(r).await;
+    }
+
+    #[actix_rt::test]
+    async fn counters_and_expiry() {
+        const KEY: &str = "counters_and_expiry";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        con.del(KEY).await.unwrap();
+        assert!(!con.exists(KEY).await.unwrap());
+        assert!(!con.expire(KEY, Duration::from_secs(60)).await.unwrap());
+
+        assert_eq!(con.incr(KEY, 2).await.unwrap(), 2);
+        assert_eq!(con.incr(KEY, -5).await.unwrap(), -3);
+        assert!(con.exists(KEY).await.unwrap());
+        assert!(con.expire(KEY, Duration::from_secs(60)).await.unwrap());
+        assert!(con.del(KEY).await.unwrap());
+        assert!(!con.del(KEY).await.unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn sub_millisecond_expiry_is_rounded_up() {
+        const KEY: &str = "sub_millisecond_expiry_is_rounded_up";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        assert_eq!(con.incr(KEY, 1).await.unwrap(), 1);
+        // PEXPIRE 1 rather than PEXPIRE 0, which deletes the key
+        assert!(con.expire(KEY, Duration::from_micros(10)).await.unwrap());
+        let ttl: i64 = con.exec(redis::cmd("PTTL").arg(KEY)).await.unwrap();
+        assert!(ttl <= 1);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!con.exists(KEY).await.unwrap());
+    }
+}
@@ -5,8 +5,11 @@
  */
 
 //! Redis Client/Connection manager that can handle both single and clustered Redis Instances
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use redis::cluster::ClusterClient;
 use redis::Client;
@@ -14,8 +17,103 @@ use redis::FromRedisValue;
 use redis::RedisResult;
 use redis::{aio::Connection, cluster::ClusterConnection};
 
+use breaker::Breaker;
+
 pub use redis;
 
+mod backend;
+mod bitmap;
+mod breaker;
+mod cache;
+mod chain;
+mod cli;
+mod client;
+mod command;
+mod config;
+mod consistency;
+mod consumer;
+mod error;
+mod hash;
+#[cfg(feature = "serde")]
+mod hash_serde;
+mod health;
+mod keys;
+mod kv;
+mod list;
+mod lock;
+#[cfg(feature = "deadpool")]
+mod manager;
+mod memo;
+mod memory;
+mod metrics;
+#[cfg(feature = "test-helpers")]
+mod mock;
+mod monitor;
+mod multikey;
+mod observe;
+mod pipeline;
+mod pool;
+mod prefix;
+mod pubsub;
+mod replica;
+mod retry;
+mod routing;
+mod scheduler;
+mod script;
+mod sentinel;
+mod server;
+mod set;
+mod slot;
+mod sort;
+mod sorted_set;
+mod stream;
+mod tracking;
+mod value;
+
+pub use backend::{BoxedRedis, RedisBackend, RedisBackendExt};
+pub use bitmap::BitUnit;
+pub use breaker::CircuitBreakerConfig;
+pub use cache::{CacheClient, CacheOptions};
+pub use chain::{Append, Chain};
+pub use cli::split_args;
+pub use client::PauseMode;
+pub use command::{command_label, is_readonly};
+pub use config::RedisConfigBuilder;
+pub use consistency::Consistency;
+pub use consumer::{StreamConsumer, StreamMessage};
+pub use error::GlueError;
+pub use health::{ConnectionState, HealthMonitor};
+pub use keys::{ExpireCond, ExpireTime, GetExTtl, KeysAck};
+pub use list::End;
+pub use lock::{Lock, LockGuard};
+#[cfg(feature = "deadpool")]
+pub use manager::{ManagedConnection, RedisManager};
+pub use memo::{CachedRedis, MemoOptions};
+pub use memory::{EncodingFinding, SizeStats};
+pub use metrics::MetricsRecorder;
+#[cfg(feature = "test-helpers")]
+pub use mock::MockRedis;
+pub use monitor::MonitorLine;
+pub use observe::Observer;
+pub use pool::{Fairness, PoolOptions, PooledConnection, RedisPool};
+pub use prefix::PrefixedRedis;
+pub use pubsub::{Overflow, PubSubEvent, PubSubMessage, RedisPubSub, SubscribeOptions};
+pub use replica::ReadPreference;
+pub use retry::{DecorrelatedJitter, ExponentialBackoff, FixedBackoff, RetryStrategy};
+pub use routing::{Routing, ScanOptions};
+pub use script::ScriptManager;
+pub use sentinel::SentinelClient;
+pub use server::{
+    detect_mode, Capabilities, FailoverOpts, HelloInfo, LatencyStats, ModuleInfo, ReplicaLag,
+    ServerMode,
+};
+pub use slot::slot_for;
+pub use sort::Sort;
+pub use sorted_set::{ScoreEnd, ZAdd};
+pub use stream::{PendingEntry, StreamEntry, XTrimStrategy};
+pub use tracking::InvalidatedKey;
+pub use value::RedisValue;
+
 /// Client configuration
 #[derive(Clone)]
 pub enum RedisConfig {
@@ -23,55 +121,474 @@ pub enum RedisConfig {
     Single(String),
     /// List of URL of Redis nodes in cluster mode
     Cluster(Vec<String>),
+    /// URL of a single Redis node in cluster mode. The rest of the cluster is
+    /// discovered from it.
+    ClusterSeed(String),
+    /// Sentinel URLs and the name of the master they watch. The master is
+    /// looked up through the sentinels whenever a connection is opened, see
+    /// [SentinelClient]. Credentials and database are taken from the first
+    /// sentinel URL and used for the master only.
+    Sentinel {
+        sentinels: Vec<String>,
+        master_name: String,
+    },
 }
 
 impl RedisConfig {
-    /// Create Redis connection
-    pub fn connect(&self) -> RedisClient {
-        match self {
-            Self::Single(url) => {
-                let client = Client::open(url.as_str()).unwrap();
-                RedisClient::Single(client)
+    /// Create Redis client, failing with [redis::ErrorKind::InvalidClientConfig]
+    /// on invalid URLs. Nothing is connected yet.
+    pub fn connect(&self) -> RedisResult<RedisClient> {
+        let client = match self {
+            Self::Single(url) => RedisClient::Single(Client::open(url.as_str())?),
+            Self::Cluster(nodes) => RedisClient::Cluster(ClusterClient::open(nodes.to_owned())?),
+            Self::ClusterSeed(seed) => {
+                RedisClient::Cluster(ClusterClient::open(vec![seed.as_str()])?)
             }
-            Self::Cluster(nodes) => {
-                let cluster_client = ClusterClient::open(nodes.to_owned()).unwrap();
-                RedisClient::Cluster(cluster_client)
+            Self::Sentinel {
+                sentinels,
+                master_name,
+            } => RedisClient::Sentinel(SentinelClient::new(sentinels, master_name)?),
+        };
+        Ok(client)
+    }
+
+    /// Make sure a [Self::ClusterSeed] points at a node that has cluster mode
+    /// enabled, so that a misconfigured seed fails loudly instead of during
+    /// slot discovery
+    async fn check_seed(&self) -> RedisResult<()> {
+        if let Self::ClusterSeed(seed) = self {
+            let mut con = Client::open(seed.as_str())?.get_async_connection().await?;
+            let info: redis::InfoDict = redis::cmd("INFO")
+                .arg("cluster")
+                .query_async(&mut con)
+                .await?;
+            if info.get::<u8>("cluster_enabled") != Some(1) {
+                return Err((
+                    redis::ErrorKind::InvalidClientConfig,
+                    "cluster seed node doesn't have cluster mode enabled",
+                    seed.to_owned(),
+                )
+                    .into());
             }
         }
+        Ok(())
     }
+
+    /// [Self::Single] config for the first of `urls` that answers a PING
+    /// within `per_try_timeout`, trying them in order, for deployments where
+    /// the active endpoint varies.
+    ///
+    /// Every candidate gets a throwaway connection; the timeout covers
+    /// connecting and the PING. If none answers, the error lists why each
+    /// candidate failed.
+    pub async fn first_reachable(
+        urls: Vec<String>,
+        per_try_timeout: Duration,
+    ) -> RedisResult<RedisConfig> {
+        let mut failures = Vec::new();
+        for url in urls {
+            let ping = async {
+                let mut con = Client::open(url.as_str())?.get_async_connection().await?;
+                redis::cmd("PING").query_async::<_, String>(&mut con).await
+            };
+            match tokio::time::timeout(per_try_timeout, ping).await {
+                Ok(Ok(_)) => return Ok(Self::Single(url)),
+                Ok(Err(e)) => failures.push(format!("{}: {}", url, e)),
+                Err(_) => failures.push(format!("{}: timed out", url)),
+            }
+        }
+        if failures.is_empty() {
+            return Err((
+                redis::ErrorKind::InvalidClientConfig,
+                "no candidate URLs given",
+            )
+                .into());
+        }
+        Err((
+            redis::ErrorKind::IoError,
+            "none of the candidate URLs is reachable",
+            failures.join("; "),
+        )
+            .into())
+    }
+}
+
+/// Redis connection - manages both single and clustered deployments.
+///
+/// `Send + Sync`: clones can move into tasks spawned on any runtime thread.
+/// Commands sent through clones of one connection queue up behind each
+/// other, use [Redis::dedicated] for connections of their own.
+#[derive(Clone)]
+pub struct RedisConnection {
+    handle: Handle,
+    /// Opens fresh connections, see [RedisOptions::auto_reconnect] and
+    /// [Self::transaction]
+    client: RedisClient,
+    state: Arc<SharedState>,
+    /// Reject commands that may write, see [Self::read_only]
+    read_only: bool,
+    options: Arc<RedisOptions>,
+    breaker: Arc<Breaker>,
 }
 
-/// Redis connection - manages both single and clustered deployments
+/// The underlying connection, shared by every clone of a [RedisConnection]
 #[derive(Clone)]
-pub enum RedisConnection {
-    Single(Rc<RefCell<Connection>>),
-    Cluster(Rc<RefCell<ClusterConnection>>),
+enum Handle {
+    Single(Arc<tokio::sync::Mutex<Connection>>),
+    /// Commands run on the blocking thread pool, see [blocking]
+    Cluster(SharedCluster),
+    /// Connected by the first command, see [Redis::new_lazy]
+    Lazy(Arc<LazyHandle>),
+}
+
+struct LazyHandle {
+    client: RedisClient,
+    /// Never holds another [Handle::Lazy]
+    handle: tokio::sync::OnceCell<Handle>,
+}
+
+impl Handle {
+    /// The handle commands go through, `None` for a lazy handle that isn't
+    /// connected yet
+    fn resolved(&self) -> Option<&Handle> {
+        match self {
+            Self::Lazy(lazy) => lazy.handle.get(),
+            handle => Some(handle),
+        }
+    }
+}
+
+/// Bookkeeping shared by every clone of a [RedisConnection]
+#[derive(Default)]
+struct SharedState {
+    /// Set once a SUBSCRIBE-family command went through: the server only
+    /// sends pub/sub messages on this connection from then on
+    pubsub: AtomicBool,
+    /// Set when a command was abandoned after [RedisOptions::response_timeout]:
+    /// its reply may still arrive and would be read as the next one's
+    desynced: AtomicBool,
+    /// See [RedisConnection::connection_state]
+    last_success: Mutex<Option<Instant>>,
+    last_error: Mutex<Option<Instant>>,
+    /// See [RedisConnection::last_command]
+    last_command: Mutex<Option<String>>,
+    /// Slot ranges of the cluster, to record which node a command went to,
+    /// see [RedisConnection::traced]. Looked up again after a reconnection.
+    #[cfg(feature = "tracing")]
+    topology: Mutex<Option<Arc<Vec<routing::SlotRange>>>>,
+}
+
+impl SharedState {
+    fn is_pubsub(&self) -> bool {
+        self.pubsub.load(Ordering::Relaxed)
+    }
+
+    fn set_pubsub(&self, pubsub: bool) {
+        self.pubsub.store(pubsub, Ordering::Relaxed)
+    }
+
+    fn is_desynced(&self) -> bool {
+        self.desynced.load(Ordering::Relaxed)
+    }
+
+    fn set_desynced(&self, desynced: bool) {
+        self.desynced.store(desynced, Ordering::Relaxed)
+    }
+
+    fn last_success(&self) -> Option<Instant> {
+        *self.last_success.lock().unwrap()
+    }
+
+    fn set_last_success(&self, at: Option<Instant>) {
+        *self.last_success.lock().unwrap() = at;
+    }
+
+    fn last_error(&self) -> Option<Instant> {
+        *self.last_error.lock().unwrap()
+    }
+
+    fn set_last_error(&self, at: Option<Instant>) {
+        *self.last_error.lock().unwrap() = at;
+    }
+}
+
+/// Whether `reply` to PING says every node is up: `PONG`, or in cluster mode,
+/// where PING goes to every node, a non-empty `[addr, PONG]` pair per node
+pub(crate) fn is_pong(reply: &redis::Value) -> bool {
+    use redis::Value;
+
+    match reply {
+        Value::Status(status) => status == "PONG",
+        Value::Bulk(nodes) => {
+            !nodes.is_empty()
+                && nodes.iter().all(|node| match node {
+                    Value::Bulk(pair) => {
+                        matches!(pair.as_slice(), [_, Value::Status(status)] if status == "PONG")
+                    }
+                    _ => false,
+                })
+        }
+        _ => false,
+    }
 }
 
 impl RedisConnection {
+    fn new(
+        handle: Handle,
+        client: RedisClient,
+        options: Arc<RedisOptions>,
+        breaker: Arc<Breaker>,
+    ) -> Self {
+        Self {
+            handle,
+            client,
+            state: Arc::new(SharedState::default()),
+            read_only: false,
+            options,
+            breaker,
+        }
+    }
+
+    /// A connection through `client` opened by its first command, see
+    /// [Redis::new_lazy]
+    fn lazy(client: RedisClient, options: Arc<RedisOptions>) -> Self {
+        let handle = Handle::Lazy(Arc::new(LazyHandle {
+            client: client.clone(),
+            handle: tokio::sync::OnceCell::new(),
+        }));
+        Self::new(handle, client, options, Arc::default())
+    }
+
     #[inline]
-    /// Get client. Uses interior mutability, so lookout for panics
+    /// Get client. Clones share the underlying connection.
     pub fn get_client(&self) -> Self {
-        match self {
-            Self::Single(con) => Self::Single(Rc::clone(&con)),
-            Self::Cluster(con) => Self::Cluster(Rc::clone(&con)),
+        self.clone()
+    }
+
+    /// Get a clone that only accepts read-only commands (see [is_readonly])
+    /// and fails with [GlueError::WriteOnReadOnlyConnection] otherwise. Safe to
+    /// hand to code that must not modify data.
+    ///
+    /// The guard only applies to the returned clone, other clones of this
+    /// connection can still write.
+    pub fn read_only(&self) -> Self {
+        Self {
+            read_only: true,
+            ..self.clone()
         }
     }
+
+    /// Whether this connection talks to a Redis Cluster
+    pub fn is_cluster(&self) -> bool {
+        match &self.handle {
+            Handle::Lazy(lazy) => matches!(lazy.client, RedisClient::Cluster(_)),
+            handle => matches!(handle, Handle::Cluster(_)),
+        }
+    }
+
+    /// The handle to send commands through, connecting a lazy one on first
+    /// use. Concurrent first commands share a single connection attempt; a
+    /// failed attempt is retried by the next command.
+    async fn connected(&self) -> RedisResult<&Handle> {
+        let lazy = match &self.handle {
+            Handle::Lazy(lazy) => lazy,
+            handle => return Ok(handle),
+        };
+        lazy.handle
+            .get_or_try_init(|| async {
+                let handle = lazy.client.connect_handle(&self.options).await;
+                self.breaker.record(&self.options.circuit_breaker, &handle);
+                if handle.is_err() {
+                    self.state.set_last_error(Some(Instant::now()));
+                }
+                handle
+            })
+            .await
+    }
+
     #[inline]
     /// execute a redis command against a [Self]
+    ///
+    /// Fails with [GlueError::ConnectionInPubSubMode] once any clone of this
+    /// connection was used to SUBSCRIBE, instead of returning whatever
+    /// pub/sub message happens to arrive next, until [Self::reset].
+    ///
+    /// Connection errors are retried on a fresh connection with
+    /// [RedisOptions::auto_reconnect].
     pub async fn exec<T: FromRedisValue>(&self, cmd: &mut redis::Cmd) -> redis::RedisResult<T> {
-        match self {
-            RedisConnection::Single(con) => cmd.query_async(&mut *con.borrow_mut()).await,
-            RedisConnection::Cluster(con) => cmd.query(&mut *con.borrow_mut()),
+        let cmd = &*cmd;
+        self.reconnecting(|| self.exec_once(cmd)).await
+    }
+
+    async fn exec_once<T: FromRedisValue>(&self, cmd: &redis::Cmd) -> RedisResult<T> {
+        self.guard(cmd)?;
+        self.breaker.check(&self.options.circuit_breaker)?;
+        let handle = self.connected().await?;
+        let label = self.label(|| command_label(cmd));
+        let start = Instant::now();
+        let request = async {
+            match handle {
+                Handle::Single(con) => self.bounded(cmd.query_async(&mut *con.lock().await)).await,
+                Handle::Cluster(con) => {
+                    let cmd = cmd.clone();
+                    blocking(con, move |con| cmd.query(con))
+                        .await
+                        .and_then(|reply| T::from_redis_value(&reply))
+                }
+                Handle::Lazy(_) => unreachable!("connected() resolves lazy handles"),
+            }
+        };
+        let res = self
+            .traced(label.as_deref(), slot::routing_key(cmd), request)
+            .await;
+        let elapsed = start.elapsed();
+        self.observe(cmd, elapsed);
+        self.record_command(label.as_deref(), elapsed, &res);
+        self.record(&res);
+        self.breaker.record(&self.options.circuit_breaker, &res);
+        res
+    }
+
+    /// execute a pipeline against a [Self]. In cluster mode the whole pipeline
+    /// is routed by its first command, so all commands must target one slot.
+    pub(crate) async fn exec_pipe<T: FromRedisValue>(
+        &self,
+        pipe: &redis::Pipeline,
+    ) -> redis::RedisResult<T> {
+        self.reconnecting(|| self.exec_pipe_once(pipe)).await
+    }
+
+    async fn exec_pipe_once<T: FromRedisValue>(&self, pipe: &redis::Pipeline) -> RedisResult<T> {
+        for cmd in pipe.cmd_iter() {
+            self.guard(cmd)?;
+        }
+        self.breaker.check(&self.options.circuit_breaker)?;
+        let handle = self.connected().await?;
+        let label = self.label(|| "pipeline".to_owned());
+        let start = Instant::now();
+        let request = async {
+            match handle {
+                Handle::Single(con) => self.bounded(pipe.query_async(&mut *con.lock().await)).await,
+                Handle::Cluster(con) => {
+                    let pipe = pipe.clone();
+                    blocking(con, move |con| pipe.query(con))
+                        .await
+                        .and_then(|reply| T::from_redis_value(&reply))
+                }
+                Handle::Lazy(_) => unreachable!("connected() resolves lazy handles"),
+            }
+        };
+        let key = pipe.cmd_iter().next().and_then(slot::routing_key);
+        let res = self.traced(label.as_deref(), key, request).await;
+        let elapsed = start.elapsed();
+        self.observe_pipe(pipe, elapsed);
+        self.record_command(label.as_deref(), elapsed, &res);
+        self.record(&res);
+        self.breaker.record(&self.options.circuit_breaker, &res);
+        res
+    }
+
+    /// Apply [RedisOptions::response_timeout] to a request on a single-node
+    /// connection. Cluster connections enforce it through socket read
+    /// timeouts instead.
+    async fn bounded<T>(&self, request: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+        let limit = match self.options.response_timeout {
+            Some(limit) => limit,
+            None => return request.await,
+        };
+        match tokio::time::timeout(limit, request).await {
+            Ok(res) => res,
+            Err(_) => {
+                self.state.set_desynced(true);
+                Err(io::Error::new(io::ErrorKind::TimedOut, "response timed out").into())
+            }
+        }
+    }
+
+    /// Checks that have to pass before `cmd` can be sent
+    fn guard(&self, cmd: &redis::Cmd) -> RedisResult<()> {
+        if self.state.is_desynced() {
+            let msg = "connection abandoned after a response timeout";
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, msg).into());
+        }
+        if self.state.is_pubsub() && !command::leaves_pubsub(cmd) {
+            return Err(GlueError::ConnectionInPubSubMode.into());
+        }
+        if self.read_only && !is_readonly(cmd) {
+            let command = command::name(cmd).unwrap_or_default();
+            return Err(GlueError::WriteOnReadOnlyConnection { command }.into());
+        }
+        if command::enters_pubsub(cmd) {
+            self.state.set_pubsub(true);
+        }
+        Ok(())
+    }
+
+    /// Return the connection to its default state (RESET, Redis 6.2+)
+    /// without reconnecting: this leaves pub/sub and MONITOR mode, discards
+    /// a pending MULTI, turns CLIENT TRACKING off, selects database 0 and
+    /// deauthenticates. Clones of this connection can send commands again
+    /// afterwards.
+    ///
+    /// If a pub/sub message arrives ahead of the RESET reply, the reply can't
+    /// be told apart from later ones anymore and the connection stays
+    /// unusable until [Redis::ensure_connected] replaces it.
+    pub async fn reset(&self) -> RedisResult<()> {
+        let reply: redis::Value = self.exec(&mut redis::cmd("RESET")).await?;
+        self.state.set_pubsub(false);
+        match reply {
+            redis::Value::Status(status) if status == "RESET" => Ok(()),
+            _ => {
+                self.state.set_desynced(true);
+                let msg = "pub/sub message received instead of the RESET reply";
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, msg).into())
+            }
         }
     }
 
     pub async fn ping(&self) -> bool {
-        if let Ok(redis::Value::Status(v)) = self.exec(&mut redis::cmd("PING")).await {
-            v == "PONG"
-        } else {
-            false
+        matches!(self.exec(&mut redis::cmd("PING")).await, Ok(v) if is_pong(&v))
+    }
+
+    /// Replace the underlying connection with a fresh one from the
+    /// [RedisClient] it was opened with. Every clone observes the new one.
+    pub(crate) async fn reconnect(&self) -> RedisResult<()> {
+        let res = self.swap_fresh().await;
+        self.record_reconnect(&res);
+        res
+    }
+
+    async fn swap_fresh(&self) -> RedisResult<()> {
+        let fresh = self
+            .client
+            .open(Arc::clone(&self.options), Arc::clone(&self.breaker))
+            .await?;
+        match (self.handle.resolved(), &fresh.handle) {
+            // waits for the command in flight, if any, to complete
+            (Some(Handle::Single(old)), Handle::Single(new)) => {
+                std::mem::swap(&mut *old.lock().await, &mut *new.lock().await)
+            }
+            (Some(Handle::Cluster(old)), Handle::Cluster(new)) => {
+                std::mem::swap(&mut *old.lock().await, &mut *new.lock().await)
+            }
+            (None, _) => {
+                if let Handle::Lazy(lazy) = &self.handle {
+                    // a concurrent first command may have connected already,
+                    // its connection is just as fresh
+                    let _ = lazy.handle.set(fresh.handle.clone());
+                }
+            }
+            _ => unreachable!("client and connection deployment modes match"),
+        }
+        self.state.set_pubsub(false);
+        self.state.set_desynced(false);
+        self.state.set_last_success(None);
+        self.state.set_last_error(None);
+        #[cfg(feature = "tracing")]
+        {
+            *self.state.topology.lock().unwrap() = None;
         }
+        Ok(())
     }
 }
 
@@ -80,54 +597,440 @@ impl RedisConnection {
 pub enum RedisClient {
     Single(Client),
     Cluster(ClusterClient),
+    Sentinel(SentinelClient),
+}
+
+impl RedisClient {
+    /// Open a new connection
+    pub async fn get_connection(&self) -> RedisResult<RedisConnection> {
+        self.open(Arc::new(RedisOptions::default()), Arc::default())
+            .await
+    }
+
+    /// Open a connection unless `breaker` is open, see
+    /// [RedisOptions::circuit_breaker]
+    async fn open(
+        &self,
+        options: Arc<RedisOptions>,
+        breaker: Arc<Breaker>,
+    ) -> RedisResult<RedisConnection> {
+        breaker.check(&options.circuit_breaker)?;
+        let handle = self.connect_handle(&options).await;
+        breaker.record(&options.circuit_breaker, &handle);
+        Ok(RedisConnection::new(
+            handle?,
+            self.clone(),
+            options,
+            breaker,
+        ))
+    }
+
+    /// Open the connection of a [Handle], within
+    /// [RedisOptions::connect_timeout]
+    async fn connect_handle(&self, options: &RedisOptions) -> RedisResult<Handle> {
+        let timeout = match options.connect_timeout {
+            Some(timeout) => timeout,
+            None => return self.connect_untimed(options).await,
+        };
+        match tokio::time::timeout(timeout, self.connect_untimed(options)).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out").into()),
+        }
+    }
+
+    async fn connect_untimed(&self, options: &RedisOptions) -> RedisResult<Handle> {
+        let handle = match self {
+            Self::Single(c) => Handle::Single(Arc::new(tokio::sync::Mutex::new(
+                connect_single(c, options).await?,
+            ))),
+            Self::Cluster(c) => {
+                let con = connect_cluster(c).await?;
+                con.set_read_timeout(options.response_timeout)?;
+                Handle::Cluster(Arc::new(tokio::sync::Mutex::new(con)))
+            }
+            Self::Sentinel(sentinel) if sentinel.is_replica() => Handle::Single(Arc::new(
+                tokio::sync::Mutex::new(connect_single(&sentinel.replica().await?, options).await?),
+            )),
+            Self::Sentinel(sentinel) => {
+                let mut con = connect_single(&sentinel.master().await?, options).await?;
+                sentinel::ensure_master(&mut con).await?;
+                Handle::Single(Arc::new(tokio::sync::Mutex::new(con)))
+            }
+        };
+        Ok(handle)
+    }
+}
+
+/// Open a connection to a single server with the options that apply to it
+async fn connect_single(client: &Client, options: &RedisOptions) -> RedisResult<Connection> {
+    let mut con = connect_tcp(client, options).await?;
+    if let Some((name, version)) = &options.lib_info {
+        set_lib_info(&mut con, name, version).await?;
+    }
+    Ok(con)
+}
+
+/// The blocking cluster connection, shared like [Handle::Cluster]
+pub(crate) type SharedCluster = Arc<tokio::sync::Mutex<ClusterConnection>>;
+
+/// Run `request` against a cluster connection on the blocking thread pool,
+/// so that waiting for the nodes doesn't stall the other tasks of the
+/// runtime. The connection stays locked until `request` returns, even if
+/// the caller stops waiting.
+pub(crate) async fn blocking<F>(con: &SharedCluster, request: F) -> RedisResult<redis::Value>
+where
+    F: FnOnce(&mut ClusterConnection) -> RedisResult<redis::Value> + Send + 'static,
+{
+    let mut con = Arc::clone(con).lock_owned().await;
+    join_blocking(tokio::task::spawn_blocking(move || request(&mut con))).await
+}
+
+/// Open a cluster connection, discovering the slots, on the blocking thread
+/// pool like [blocking]
+pub(crate) async fn connect_cluster(client: &ClusterClient) -> RedisResult<ClusterConnection> {
+    let client = client.clone();
+    join_blocking(tokio::task::spawn_blocking(move || client.get_connection())).await
+}
+
+/// The outcome of a blocking task, propagating its panic if it had one
+async fn join_blocking<T>(task: tokio::task::JoinHandle<RedisResult<T>>) -> RedisResult<T> {
+    match task.await {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(io::Error::other("runtime is shutting down").into()),
+    }
+}
+
+/// Open a connection through `client`, applying the TCP options of
+/// `options` if the client connects over plain TCP
+async fn connect_tcp(client: &Client, options: &RedisOptions) -> RedisResult<Connection> {
+    let info = client.get_connection_info();
+    let (host, port) = match &*info.addr {
+        redis::ConnectionAddr::Tcp(host, port)
+            if options.tcp_nodelay || options.tcp_keepalive.is_some() =>
+        {
+            (host, *port)
+        }
+        _ => return client.get_async_connection().await,
+    };
+    let stream = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+    stream.set_nodelay(options.tcp_nodelay)?;
+    if let Some(idle) = options.tcp_keepalive {
+        let keepalive = socket2::TcpKeepalive::new().with_time(idle);
+        socket2::SockRef::from(&stream).set_tcp_keepalive(&keepalive)?;
+    }
+    let stream: std::pin::Pin<Box<dyn redis::aio::AsyncStream + Send + Sync>> = Box::pin(stream);
+    Connection::new(info, stream).await
+}
+
+/// Report `name` and `version` as the client library of `con` (CLIENT
+/// SETINFO), unless the server is too old to know about it
+async fn set_lib_info(con: &mut Connection, name: &str, version: &str) -> RedisResult<()> {
+    let res: RedisResult<()> = redis::pipe()
+        .cmd("CLIENT")
+        .arg(&["SETINFO", "LIB-NAME", name])
+        .ignore()
+        .cmd("CLIENT")
+        .arg(&["SETINFO", "LIB-VER", version])
+        .ignore()
+        .query_async(con)
+        .await;
+    match res {
+        Err(e) if e.kind() == redis::ErrorKind::ResponseError && is_unknown_subcommand(&e) => {
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn is_unknown_subcommand(err: &redis::RedisError) -> bool {
+    err.detail()
+        .is_some_and(|detail| detail.to_ascii_lowercase().contains("unknown subcommand"))
+}
+
+/// Tunables for [Redis]. The defaults favour latency over safety checks.
+#[derive(Clone, Debug)]
+pub struct RedisOptions {
+    /// PING the connection in [Redis::acquire] and replace it with a fresh one
+    /// if it is dead. Costs a round-trip per checkout.
+    pub validate_on_acquire: bool,
+    /// Log a warning for every command that takes longer than this. Needs the
+    /// `log` feature.
+    pub slow_command_threshold: Option<Duration>,
+    /// Called after every command, see [Observer]
+    pub observer: Option<Observer>,
+    /// Fail every command whose reply takes longer than this with a timeout
+    /// error ([redis::RedisError::is_timeout]).
+    ///
+    /// The reply to a single-node command that timed out may still arrive,
+    /// so the connection refuses further commands until it is replaced:
+    /// [Redis::ensure_connected], [Redis::acquire] (with
+    /// [Self::validate_on_acquire]) and [Redis::exec_retry] all do that.
+    pub response_timeout: Option<Duration>,
+    /// Fail fast with [GlueError::CircuitOpen] after repeated connection
+    /// failures instead of piling up more, see [CircuitBreakerConfig]. The
+    /// breaker is shared by every connection of a [Redis], dedicated ones
+    /// included, and also holds back reconnection attempts.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Commands whose arguments are replaced by `[redacted]` wherever this
+    /// crate renders a command: slow-command logs and [Observer]s. Matched
+    /// case-insensitively; defaults to AUTH and HELLO, which carry passwords.
+    pub sensitive_commands: Vec<String>,
+    /// `(name, version)` every connection reports as its client library
+    /// with CLIENT SETINFO (Redis 7.2+), shown by CLIENT LIST and CLIENT
+    /// INFO. Defaults to `redis-glue` and the crate version; set the name
+    /// and version of the application instead to tell its connections
+    /// apart, or `None` to skip the round-trip. Older servers are left
+    /// alone. Single mode only: in cluster mode the connections to each node
+    /// are opened behind the scenes.
+    pub lib_info: Option<(String, String)>,
+    /// Set TCP_NODELAY, sending commands right away instead of letting the
+    /// OS batch small writes (Nagle's algorithm)
+    pub tcp_nodelay: bool,
+    /// Turn OS-level TCP keepalive on, with the first probe after the
+    /// connection was idle this long, so a peer that vanished without
+    /// closing the connection is noticed even while no command is waiting.
+    /// The interval and count of later probes are the OS defaults. Applied
+    /// as TCP_KEEPIDLE on Linux and most BSDs, TCP_KEEPALIVE on macOS and
+    /// SIO_KEEPALIVE_VALS on Windows; OpenBSD only turns keepalive on and
+    /// uses its system-wide idle time.
+    ///
+    /// Both TCP options apply to the connections behind [RedisConnection]
+    /// to `redis://` URLs in single mode. Unix sockets don't have them, and
+    /// cluster connections, like pub/sub, MONITOR and tracking listeners,
+    /// are opened with the OS defaults.
+    pub tcp_keepalive: Option<Duration>,
+    /// Keep the rendered form of the last command sent, redacted like for
+    /// [Observer]s, for [RedisConnection::last_command]. Costs rendering
+    /// every command.
+    pub record_last_command: bool,
+    /// Reopen the connection and resend the command when it fails with a
+    /// connection error, after the delay `backoff` gives for every attempt,
+    /// so a server restart goes unnoticed by callers. Other errors are
+    /// returned right away.
+    ///
+    /// Applies to every command and pipeline, reconnecting the connection
+    /// clones share. A command whose reply was lost may already have been
+    /// applied and will be applied twice, so only enable this if that is
+    /// acceptable for non-idempotent commands like INCR. Pub/sub
+    /// subscriptions and SELECTed databases don't survive the reconnection.
+    ///
+    /// With [RedisConfig::Sentinel] this makes failovers transparent: the
+    /// new connection goes to the master the sentinels report, and writes
+    /// rejected by a demoted master (READONLY) are resent there too.
+    pub auto_reconnect: Option<ExponentialBackoff>,
+    /// Fail opening a connection that takes longer than this, reconnects
+    /// included, with a timeout error. Covers looking up the master in
+    /// sentinel mode and discovering the slots in cluster mode, though a
+    /// cluster connection attempt that timed out keeps going on the
+    /// blocking thread pool until the nodes answer.
+    pub connect_timeout: Option<Duration>,
+    /// Receives command latencies and errors and reconnection events, see
+    /// [MetricsRecorder]
+    pub metrics: Option<Arc<dyn MetricsRecorder>>,
+    /// Where [Redis::exec_read] sends read-only commands, the master by
+    /// default
+    pub read_preference: ReadPreference,
+    /// Sizing of the pool [Redis::checkout] takes connections from
+    pub pool: PoolOptions,
+}
+
+impl Default for RedisOptions {
+    fn default() -> Self {
+        Self {
+            validate_on_acquire: false,
+            slow_command_threshold: None,
+            observer: None,
+            response_timeout: None,
+            circuit_breaker: None,
+            sensitive_commands: vec!["AUTH".into(), "HELLO".into()],
+            lib_info: Some(("redis-glue".into(), env!("CARGO_PKG_VERSION").into())),
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            record_last_command: false,
+            auto_reconnect: None,
+            connect_timeout: None,
+            metrics: None,
+            read_preference: ReadPreference::Master,
+            pool: PoolOptions::default(),
+        }
+    }
 }
 
 /// A Redis Client Object that encapsulates [RedisClient] and [RedisConnection].
-/// Use this when you need a Redis Client
+/// Use this when you need a Redis Client. `Send + Sync`, like
+/// [RedisConnection].
 #[derive(Clone)]
 pub struct Redis {
-    _client: RedisClient,
+    client: RedisClient,
     connection: RedisConnection,
+    options: Arc<RedisOptions>,
+    /// Template for direct connections to cluster nodes, `None` in single mode
+    node_info: Option<redis::ConnectionInfo>,
+    /// Lazy connection to the replicas for [Self::exec_read], `None` unless
+    /// [RedisOptions::read_preference] asks for replicas of a cluster or
+    /// sentinel deployment
+    replica: Option<replica::Replicas>,
+    /// See [Self::checkout]. Only `None` for the handle a [RedisPool] opens
+    /// its own connections with.
+    pool: Option<RedisPool>,
 }
 
 impl Redis {
     /// create new [Redis]. Will try to connect to Redis instance specified in [RedisConfig]
     pub async fn new(redis: RedisConfig) -> RedisResult<Self> {
-        let (_client, connection) = Self::connect(redis).await?;
+        Self::with_options(redis, RedisOptions::default()).await
+    }
+
+    /// create new [Redis] with non-default [RedisOptions]
+    pub async fn with_options(redis: RedisConfig, options: RedisOptions) -> RedisResult<Self> {
+        let client = redis.connect()?;
+        redis.check_seed().await?;
+        let node_info = redis.node_info()?;
+        let options = Arc::new(options);
+        let replica = redis.replica_connection(&options)?;
+        let connection = client.open(Arc::clone(&options), Arc::default()).await?;
         let master = Self {
-            _client,
+            client,
             connection,
+            options,
+            node_info,
+            replica,
+            pool: None,
         };
-        Ok(master)
+        Ok(master.with_pool())
+    }
+
+    /// Create a [Redis] without connecting: the connection is opened by the
+    /// first command instead, so a service can start while Redis is still
+    /// unavailable. Commands fail with the connection error until Redis is
+    /// reachable.
+    ///
+    /// Unlike [Self::new], a [RedisConfig::ClusterSeed] isn't checked for
+    /// cluster mode up front. Invalid URLs are still reported right away.
+    pub fn new_lazy(redis: RedisConfig) -> RedisResult<Self> {
+        Self::lazy_with_options(redis, RedisOptions::default())
+    }
+
+    /// [Self::new_lazy] with non-default [RedisOptions]. The circuit breaker
+    /// also guards the deferred connection attempts.
+    pub fn lazy_with_options(redis: RedisConfig, options: RedisOptions) -> RedisResult<Self> {
+        let client = redis.connect()?;
+        let node_info = redis.node_info()?;
+        let options = Arc::new(options);
+        let replica = redis.replica_connection(&options)?;
+        let connection = RedisConnection::lazy(client.clone(), Arc::clone(&options));
+        Ok(Self {
+            client,
+            connection,
+            options,
+            node_info,
+            replica,
+            pool: None,
+        }
+        .with_pool())
     }
 
     /// Get client to do interact with Redis server.
     ///
-    /// Uses Interior mutability so look out for panics
+    /// Every clone shares one connection, see [RedisConnection]; use
+    /// [Self::checkout] for a connection of its own.
     pub fn get_client(&self) -> RedisConnection {
         self.connection.get_client()
     }
 
-    async fn connect(redis: RedisConfig) -> RedisResult<(RedisClient, RedisConnection)> {
-        let redis = redis.connect();
-        let client = match &redis {
-            RedisClient::Single(c) => {
-                let con = c.get_async_connection().await?;
-                RedisConnection::Single(Rc::new(RefCell::new(con)))
-            }
-            RedisClient::Cluster(c) => {
-                let con = c.get_connection()?;
-                RedisConnection::Cluster(Rc::new(RefCell::new(con)))
-            }
-        };
-        Ok((redis, client))
+    /// Open a brand-new connection that isn't shared with [Self::get_client].
+    ///
+    /// Use this to avoid queueing behind other tasks on the shared
+    /// connection. The connection is closed when the last clone is dropped.
+    pub async fn dedicated(&self) -> RedisResult<RedisConnection> {
+        self.client
+            .open(
+                Arc::clone(&self.options),
+                Arc::clone(&self.connection.breaker),
+            )
+            .await
+    }
+
+    /// Open a dedicated connection (see [Self::dedicated]) that has SELECTed
+    /// database `index`.
+    ///
+    /// SELECT changes state of the whole connection, so doing it on the
+    /// shared one would switch the database under every other task. Single
+    /// mode only: Redis Cluster only has database 0.
+    pub async fn db(&self, index: i64) -> RedisResult<RedisConnection> {
+        if let RedisClient::Cluster(_) = self.client {
+            return Err((
+                redis::ErrorKind::ClientError,
+                "Redis Cluster only supports database 0",
+            )
+                .into());
+        }
+        let con = self.dedicated().await?;
+        let _: () = con.exec(redis::cmd("SELECT").arg(index)).await?;
+        Ok(con)
+    }
+
+    /// Get client to interact with Redis server, validating it first if
+    /// [RedisOptions::validate_on_acquire] is set. A dead connection, or in
+    /// cluster mode one to a node that doesn't answer PING, is
+    /// transparently replaced.
+    pub async fn acquire(&self) -> RedisResult<RedisConnection> {
+        if self.options.validate_on_acquire && !self.connection.ping().await {
+            self.reconnect().await?;
+        }
+        Ok(self.get_client())
+    }
+
+    /// Make sure the shared connection is alive, reconnecting if a PING
+    /// fails, or in cluster mode if any node doesn't answer it. Errors only
+    /// if the reconnection fails too.
+    ///
+    /// Meant as a pre-flight check before a burst of commands; unlike
+    /// [Self::exec_retry] it doesn't resend anything.
+    pub async fn ensure_connected(&self) -> RedisResult<()> {
+        if self.connection.ping().await {
+            Ok(())
+        } else {
+            self.reconnect().await
+        }
+    }
+
+    /// Replace the shared connection with a fresh one from [RedisClient].
+    /// Every clone of the connection observes the new one.
+    pub(crate) async fn reconnect(&self) -> RedisResult<()> {
+        self.connection.reconnect().await
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
     use super::*;
 
+    #[test]
+    fn cluster_pings_are_pongs() {
+        use redis::Value;
+
+        let node = |addr: &str, reply: Value| Value::Bulk(vec![Value::Data(addr.into()), reply]);
+        let pong = || Value::Status("PONG".into());
+        assert!(is_pong(&pong()));
+        assert!(is_pong(&Value::Bulk(vec![
+            node("127.0.0.1:7000", pong()),
+            node("127.0.0.1:7001", pong()),
+        ])));
+        assert!(!is_pong(&Value::Bulk(vec![
+            node("127.0.0.1:7000", pong()),
+            node("127.0.0.1:7001", Value::Status("LOADING".into())),
+        ])));
+        assert!(!is_pong(&Value::Bulk(vec![])));
+        assert!(!is_pong(&Value::Bulk(vec![pong()])));
+        assert!(!is_pong(&Value::Nil));
+    }
+
     #[actix_rt::test]
     async fn ping_works() {
         let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
@@ -156,4 +1059,559 @@ mod tests {
 
         assert_eq!(&get, VAR.1);
     }
+
+    #[actix_rt::test]
+    async fn acquire_replaces_dead_connection() {
+        let options = RedisOptions {
+            validate_on_acquire: true,
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let killer = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+
+        let id: i64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+        let _: () = killer
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+
+        let con = r.acquire().await.unwrap();
+        assert!(con.ping().await);
+        let new_id: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_ne!(id, new_id);
+    }
+
+    #[actix_rt::test]
+    async fn dedicated_connections_work_concurrently() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let shared_id: i64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+
+        let tasks: Vec<_> = (0..4)
+            .map(|i| {
+                let r = r.clone();
+                actix_rt::spawn(async move {
+                    let con = r.dedicated().await.unwrap();
+                    let key = format!("dedicated_connections_work_concurrently_{}", i);
+                    let _: () = con.exec(redis::cmd("SET").arg(&key).arg(i)).await.unwrap();
+                    let get: usize = con.exec(redis::cmd("GET").arg(&key)).await.unwrap();
+                    assert_eq!(get, i);
+                    let id: i64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+                    id
+                })
+            })
+            .collect();
+
+        let mut ids = Vec::new();
+        for task in tasks {
+            ids.push(task.await.unwrap());
+        }
+        ids.push(shared_id);
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 5);
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    #[test]
+    fn connection_is_send_and_sync() {
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        let con = r.get_client();
+        assert_send_sync::<Redis>();
+        assert_send_sync::<RedisConnection>();
+        assert_send(&r.dedicated());
+        assert_send(&r.reconnect());
+        let mut ping = redis::cmd("PING");
+        assert_send(&con.exec::<String>(&mut ping));
+        assert_send(&con.exec_pipe::<Vec<String>>(&redis::pipe()));
+    }
+
+    #[test]
+    fn public_handles_are_send_and_sync() {
+        assert_send_sync::<RedisClient>();
+        assert_send_sync::<RedisConfigBuilder>();
+        assert_send_sync::<SentinelClient>();
+        assert_send_sync::<BoxedRedis>();
+        assert_send_sync::<RedisPool>();
+        assert_send_sync::<PooledConnection>();
+        assert_send_sync::<CachedRedis>();
+        assert_send_sync::<CacheClient>();
+        assert_send_sync::<PrefixedRedis>();
+        assert_send_sync::<RedisPubSub>();
+        assert_send_sync::<ScriptManager>();
+        assert_send_sync::<HealthMonitor>();
+        assert_send_sync::<Lock>();
+        assert_send_sync::<LockGuard>();
+        assert_send_sync::<StreamConsumer>();
+        assert_send_sync::<StreamMessage>();
+        #[cfg(feature = "deadpool")]
+        assert_send_sync::<RedisManager>();
+        #[cfg(feature = "test-helpers")]
+        assert_send_sync::<MockRedis>();
+
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        assert_send(&r.subscribe(&["channel"], &[]));
+        assert_send(&r.ssubscribe(&["channel"]));
+        assert_send(&r.monitor());
+        assert_send(&r.cluster_scan(ScanOptions::default()));
+        let pool = r.pool(PoolOptions::default());
+        assert_send(&pool.get());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn background_tasks_run_without_local_set() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let r =
+            Redis::new_lazy(RedisConfig::Single(format!("redis://127.0.0.1:{}", port))).unwrap();
+        let pool = r.pool(PoolOptions {
+            max_idle: Some(Duration::from_millis(10)),
+            ..PoolOptions::default()
+        });
+        let pool = tokio::spawn(async move {
+            // starts the reaper, then fails to connect
+            assert!(pool.get().await.is_err());
+            pool
+        })
+        .await
+        .unwrap();
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn clones_work_across_threads() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let tasks: Vec<_> = (0..16)
+            .map(|i| {
+                let con = r.get_client();
+                tokio::spawn(async move {
+                    let key = format!("clones_work_across_threads_{}", i);
+                    let _: () = con.exec(redis::cmd("SET").arg(&key).arg(i)).await.unwrap();
+                    con.exec::<usize>(redis::cmd("GET").arg(&key))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert_eq!(task.await.unwrap(), i);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn read_only_guard_rejects_writes() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client().read_only();
+
+        let err = con
+            .exec::<()>(redis::cmd("SET").arg(&["read_only_guard_rejects_writes", "1"]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::WriteOnReadOnlyConnection {
+                command: "SET".into()
+            })
+        );
+        let _: Option<String> = con
+            .exec(redis::cmd("GET").arg("read_only_guard_rejects_writes"))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn pubsub_mode_guards_clones() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let subscriber = r.get_client();
+        let other = r.get_client();
+
+        let _: redis::Value = subscriber
+            .exec(redis::cmd("SUBSCRIBE").arg("pubsub_mode_guards_clones"))
+            .await
+            .unwrap();
+
+        let err = other
+            .exec::<Option<String>>(redis::cmd("GET").arg("pubsub_mode_guards_clones"))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::ConnectionInPubSubMode)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn reset_leaves_pubsub_mode() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.dedicated().await.unwrap();
+        let _: redis::Value = con
+            .exec(redis::cmd("SUBSCRIBE").arg("reset_leaves_pubsub_mode"))
+            .await
+            .unwrap();
+        assert!(con.state.is_pubsub());
+        assert!(con
+            .exec::<redis::Value>(&mut redis::cmd("PING"))
+            .await
+            .is_err());
+
+        con.reset().await.unwrap();
+        assert!(!con.state.is_pubsub());
+        assert!(con.ping().await);
+        let _: Option<String> = con
+            .exec(redis::cmd("GET").arg("reset_leaves_pubsub_mode"))
+            .await
+            .unwrap();
+    }
+
+    /// Forward every connection accepted on `listener` to the local Redis,
+    /// counting them
+    fn proxy_to_redis(listener: TcpListener) -> Arc<AtomicUsize> {
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        std::thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let server = TcpStream::connect("127.0.0.1:6379").unwrap();
+                let (mut client_r, mut server_w) =
+                    (client.try_clone().unwrap(), server.try_clone().unwrap());
+                let (mut server_r, mut client_w) = (server, client);
+                std::thread::spawn(move || std::io::copy(&mut client_r, &mut server_w));
+                std::thread::spawn(move || std::io::copy(&mut server_r, &mut client_w));
+            }
+        });
+        accepted
+    }
+
+    #[test]
+    fn lazy_rejects_invalid_configs() {
+        let err = Redis::new_lazy(RedisConfig::Single("127.0.0.1".into()))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+        let err = Redis::new_lazy(RedisConfig::Sentinel {
+            sentinels: Vec::new(),
+            master_name: "mymaster".into(),
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+    }
+
+    #[actix_rt::test]
+    async fn lazy_connects_on_first_command() {
+        // find a free port, then keep it closed: Redis is "down"
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let r =
+            Redis::new_lazy(RedisConfig::Single(format!("redis://127.0.0.1:{}", port))).unwrap();
+        let con = r.get_client();
+        assert!(!con.is_cluster());
+        let err = con
+            .exec::<String>(&mut redis::cmd("PING"))
+            .await
+            .unwrap_err();
+        assert!(err.is_io_error());
+        assert_eq!(con.connection_state(), ConnectionState::Degraded);
+
+        // Redis comes "up"
+        let accepted = proxy_to_redis(TcpListener::bind(("127.0.0.1", port)).unwrap());
+        let clones: Vec<RedisConnection> = (0..5).map(|_| r.get_client()).collect();
+        let attempts = clones.iter().map(|con| con.connected());
+        for handle in futures::future::join_all(attempts).await {
+            assert!(handle.is_ok());
+        }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert!(con.ping().await);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn ensure_connected_reconnects() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let killer = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        r.ensure_connected().await.unwrap();
+
+        let id: i64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+        let _: () = killer
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg(&["KILL", "ID"]).arg(id))
+            .await
+            .unwrap();
+
+        r.ensure_connected().await.unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("SET").arg(&["ensure_connected_reconnects", "1"]))
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn cluster_seed_rejects_standalone() {
+        let err = Redis::new(RedisConfig::ClusterSeed("redis://127.0.0.1".into()))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+    }
+
+    /// Counts reconnections, to tell whether a connection was kept
+    #[derive(Default)]
+    struct Reconnects(AtomicUsize);
+
+    impl MetricsRecorder for Reconnects {
+        fn record_reconnect(&self, _: Option<&redis::RedisError>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_ensure_connected_keeps_live_connection() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let reconnects = Arc::new(Reconnects::default());
+        let options = RedisOptions {
+            metrics: Some(Arc::clone(&reconnects) as Arc<dyn MetricsRecorder>),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::ClusterSeed(seed), options)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            r.ensure_connected().await.unwrap();
+        }
+        assert_eq!(reconnects.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_acquire_keeps_live_connection() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let reconnects = Arc::new(Reconnects::default());
+        let options = RedisOptions {
+            validate_on_acquire: true,
+            metrics: Some(Arc::clone(&reconnects) as Arc<dyn MetricsRecorder>),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::ClusterSeed(seed), options)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            assert!(r.acquire().await.unwrap().ping().await);
+        }
+        assert_eq!(reconnects.0.load(Ordering::SeqCst), 0);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_seed_discovers_nodes() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let con = r.get_client();
+        // 16 keys spread over the slot space, so some are owned by nodes other
+        // than the seed
+        for i in 0..16 {
+            let key = format!("cluster_seed_discovers_nodes_{}", i);
+            let _: () = con.exec(redis::cmd("SET").arg(&key).arg(i)).await.unwrap();
+            let get: usize = con.exec(redis::cmd("GET").arg(&key)).await.unwrap();
+            assert_eq!(get, i);
+        }
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_commands_dont_stall_the_runtime() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let waiting = r.dedicated().await.unwrap();
+        let blocked = actix_rt::spawn(async move {
+            waiting
+                .exec::<Option<(String, String)>>(
+                    redis::cmd("BLPOP")
+                        .arg("cluster_commands_dont_stall_the_runtime")
+                        .arg(1),
+                )
+                .await
+                .unwrap()
+        });
+        // let the BLPOP go out first
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        assert!(r.get_client().ping().await);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(blocked.await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn response_timeout_bounds_every_command() {
+        let options = RedisOptions {
+            response_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let con = r.get_client();
+        // DEBUG SLEEP is disabled by default since Redis 7
+        let script = "local s = redis.call('TIME')[1]; while redis.call('TIME')[1] - s < 1 do end";
+        let err = con
+            .exec::<()>(redis::cmd("EVAL").arg(script).arg(0))
+            .await
+            .unwrap_err();
+        assert!(err.is_timeout());
+        assert!(!con.ping().await);
+
+        // give the script time to finish, the reconnect's PING would time out
+        // behind it otherwise
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        r.ensure_connected().await.unwrap();
+        assert!(con.ping().await);
+    }
+
+    #[actix_rt::test]
+    async fn db_handles_are_isolated() {
+        const KEY: &str = "db_handles_are_isolated";
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let zero = r.db(0).await.unwrap();
+        let one = r.db(1).await.unwrap();
+        let _: () = zero.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = one.exec(redis::cmd("SET").arg(&[KEY, "1"])).await.unwrap();
+
+        let get: Option<String> = zero.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert!(get.is_none());
+        let get: Option<String> = one.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(get.as_deref(), Some("1"));
+        let _: () = one.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn connections_report_lib_info() {
+        let client_info = |r: Redis| async move {
+            let info: String = r
+                .get_client()
+                .exec(redis::cmd("CLIENT").arg("INFO"))
+                .await
+                .unwrap();
+            info
+        };
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        if r.server_capabilities().await.unwrap().version < (7, 2, 0) {
+            // SETINFO is skipped, connecting is all there is to check
+            return;
+        }
+        let info = client_info(r).await;
+        assert!(info.contains(&format!(
+            " lib-name=redis-glue lib-ver={} ",
+            env!("CARGO_PKG_VERSION")
+        )));
+
+        let options = RedisOptions {
+            lib_info: Some(("billing".into(), "2.4.1".into())),
+            ..RedisOptions::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let info = client_info(r).await;
+        assert!(info.contains(" lib-name=billing lib-ver=2.4.1"));
+    }
+
+    #[actix_rt::test]
+    async fn first_reachable_skips_dead_candidates() {
+        let config = RedisConfig::first_reachable(
+            vec!["redis://127.0.0.1:1".into(), "redis://127.0.0.1".into()],
+            Duration::from_secs(1),
+        )
+        .await
+        .unwrap();
+        match config {
+            RedisConfig::Single(url) => assert_eq!(url, "redis://127.0.0.1"),
+            _ => panic!("expected a single-node config"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn first_reachable_reports_every_failure() {
+        let err = RedisConfig::first_reachable(
+            vec!["redis://127.0.0.1:1".into(), "not a url".into()],
+            Duration::from_secs(1),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), redis::ErrorKind::IoError);
+        let detail = err.detail().unwrap();
+        assert!(detail.contains("redis://127.0.0.1:1: "));
+        assert!(detail.contains("not a url: "));
+
+        let err = RedisConfig::first_reachable(Vec::new(), Duration::from_secs(1))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), redis::ErrorKind::InvalidClientConfig);
+    }
+
+    #[actix_rt::test]
+    async fn tcp_options_are_applied() {
+        let options = RedisOptions {
+            tcp_nodelay: true,
+            tcp_keepalive: Some(Duration::from_secs(30)),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1/3".into()), options)
+            .await
+            .unwrap();
+        let con = r.dedicated().await.unwrap();
+        assert!(con.ping().await);
+        // the URL's database is still selected on the hand-built socket
+        let info: String = con.exec(redis::cmd("CLIENT").arg("INFO")).await.unwrap();
+        assert!(info.contains(" db=3 "));
+    }
 }
@@ -0,0 +1,366 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! List helpers
+use std::time::Duration;
+
+use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisResult, ToRedisArgs, Value};
+
+use crate::{Redis, RedisConnection};
+
+/// End of a list to operate on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum End {
+    /// Head of the list
+    Left,
+    /// Tail of the list
+    Right,
+}
+
+impl End {
+    fn as_arg(&self) -> &'static str {
+        match self {
+            Self::Left => "LEFT",
+            Self::Right => "RIGHT",
+        }
+    }
+}
+
+impl Redis {
+    /// Pop up to `count` elements from the first non-empty list in `keys`
+    /// (LMPOP, Redis 7+).
+    ///
+    /// Returns the key that was popped from along with the elements, or `None`
+    /// when all lists are empty. In cluster mode, all keys must hash to the same
+    /// slot, and the command goes to its primary over a short-lived
+    /// connection, see [Redis::exec_routed].
+    pub async fn lmpop<T: FromRedisValue>(
+        &self,
+        keys: &[&str],
+        from: End,
+        count: usize,
+    ) -> RedisResult<Option<(String, Vec<T>)>> {
+        let mut cmd = redis::cmd("LMPOP");
+        cmd.arg(keys.len())
+            .arg(keys)
+            .arg(from.as_arg())
+            .arg("COUNT")
+            .arg(count);
+        let reply: Value = self.exec_numkeys(&mut cmd, keys).await?;
+        parse_mpop(&reply, |v| from_redis_value(v))
+    }
+
+    /// Like [Redis::lmpop], but waits up to `timeout` for an element when all
+    /// lists are empty (BLMPOP, Redis 7+), `None` meaning the wait timed out.
+    ///
+    /// The wait runs on a connection of its own, opened for the call: a
+    /// [dedicated](Redis::dedicated) one in single mode, one to the keys'
+    /// primary in cluster mode. `timeout` must be shorter than
+    /// [crate::RedisOptions::response_timeout], if set, or the reply would be
+    /// given up on: that fails upfront.
+    pub async fn blmpop<T: FromRedisValue>(
+        &self,
+        timeout: Duration,
+        keys: &[&str],
+        from: End,
+        count: usize,
+    ) -> RedisResult<Option<(String, Vec<T>)>> {
+        self.connection.ensure_block_fits(timeout)?;
+        let mut cmd = redis::cmd("BLMPOP");
+        cmd.arg(timeout.as_secs_f64())
+            .arg(keys.len())
+            .arg(keys)
+            .arg(from.as_arg())
+            .arg("COUNT")
+            .arg(count);
+        let reply: Value = self.exec_numkeys_blocking(&mut cmd, keys).await?;
+        parse_mpop(&reply, |v| from_redis_value(v))
+    }
+}
+
+impl RedisConnection {
+    /// Fail if a blocking command waiting `timeout` would outlive
+    /// [crate::RedisOptions::response_timeout]. A zero `timeout` waits
+    /// forever.
+    pub(crate) fn ensure_block_fits(&self, timeout: Duration) -> RedisResult<()> {
+        match self.options.response_timeout {
+            Some(limit) if timeout.is_zero() || timeout >= limit => Err((
+                ErrorKind::ClientError,
+                "block timeout must be shorter than the response timeout",
+                format!("{:?} >= {:?}", timeout, limit),
+            )
+                .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Positions of `element` in the list at `key` (LPOS).
+    ///
+    /// `rank` picks which match to start from: `Some(2)` skips the first
+    /// match, and a negative rank searches from the tail, so `Some(-1)` finds
+    /// the last match first. Positions are always counted from the head.
+    ///
+    /// With `count: None` at most one position is returned, `Some(0)` returns
+    /// all matches.
+    pub async fn lpos(
+        &self,
+        key: &str,
+        element: impl ToRedisArgs,
+        rank: Option<i64>,
+        count: Option<usize>,
+    ) -> RedisResult<Vec<usize>> {
+        let mut cmd = redis::cmd("LPOS");
+        cmd.arg(key).arg(element);
+        if let Some(rank) = rank {
+            cmd.arg("RANK").arg(rank);
+        }
+        match count {
+            Some(count) => self.exec(cmd.arg("COUNT").arg(count)).await,
+            None => {
+                let pos: Option<usize> = self.exec(&mut cmd).await?;
+                Ok(pos.into_iter().collect())
+            }
+        }
+    }
+
+    /// Insert `element` before (or after, with `before: false`) the first
+    /// occurrence of `pivot` in the list at `key` (LINSERT).
+    ///
+    /// Returns the new length of the list, `-1` if `pivot` wasn't found (the
+    /// list is left untouched) and `0` if the key doesn't exist.
+    pub async fn linsert(
+        &self,
+        key: &str,
+        before: bool,
+        pivot: impl ToRedisArgs,
+        element: impl ToRedisArgs,
+    ) -> RedisResult<i64> {
+        let position = if before { "BEFORE" } else { "AFTER" };
+        self.exec(
+            redis::cmd("LINSERT")
+                .arg(key)
+                .arg(position)
+                .arg(pivot)
+                .arg(element),
+        )
+        .await
+    }
+
+    /// Overwrite the element at `index` in the list at `key` (LSET). Negative
+    /// indexes count from the tail. Fails if `index` is out of range or the
+    /// key doesn't exist.
+    pub async fn lset(&self, key: &str, index: i64, element: impl ToRedisArgs) -> RedisResult<()> {
+        self.exec(redis::cmd("LSET").arg(key).arg(index).arg(element))
+            .await
+    }
+
+    /// Keep only the elements from `start` to `stop` (inclusive, negative
+    /// indexes count from the tail) of the list at `key` (LTRIM). Out of range
+    /// indexes are clamped, and an empty range deletes the key.
+    pub async fn ltrim(&self, key: &str, start: i64, stop: i64) -> RedisResult<()> {
+        self.exec(redis::cmd("LTRIM").arg(key).arg(start).arg(stop))
+            .await
+    }
+}
+
+/// Parse the `[key, [element, ...]]` reply shared by the *MPOP family, with
+/// `element` decoding each item of the inner array
+pub(crate) fn parse_mpop<T, F>(reply: &Value, element: F) -> RedisResult<Option<(String, Vec<T>)>>
+where
+    F: Fn(&Value) -> RedisResult<T>,
+{
+    match reply {
+        Value::Nil => Ok(None),
+        Value::Bulk(items) => match items.as_slice() {
+            [key, Value::Bulk(elements)] => {
+                let key: String = from_redis_value(key)?;
+                let elements = elements.iter().map(element).collect::<RedisResult<_>>()?;
+                Ok(Some((key, elements)))
+            }
+            _ => Err((
+                redis::ErrorKind::TypeError,
+                "Response was of incompatible type",
+                format!("expected [key, [elements]], got {:?}", reply),
+            )
+                .into()),
+        },
+        _ => Err((
+            redis::ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected [key, [elements]] or nil, got {:?}", reply),
+        )
+            .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn lmpop_works() {
+        const EMPTY: &str = "lmpop_works_empty";
+        const FULL: &str = "lmpop_works_full";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[EMPTY, FULL]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(FULL).arg(&[1, 2, 3]))
+            .await
+            .unwrap();
+
+        let popped: Option<(String, Vec<usize>)> =
+            r.lmpop(&[EMPTY, FULL], End::Left, 2).await.unwrap();
+        assert_eq!(popped, Some((FULL.into(), vec![1, 2])));
+
+        let _: () = con.exec(redis::cmd("DEL").arg(FULL)).await.unwrap();
+        let popped: Option<(String, Vec<usize>)> =
+            r.lmpop(&[EMPTY, FULL], End::Left, 2).await.unwrap();
+        assert_eq!(popped, None);
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_lmpop_goes_to_the_keys_primary() {
+        const KEY: &str = "{cluster_lmpop_goes_to_the_keys_primary}";
+
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("RPUSH").arg(KEY).arg(&[1, 2]))
+            .await
+            .unwrap();
+        let popped: Option<(String, Vec<usize>)> = r.lmpop(&[KEY], End::Left, 2).await.unwrap();
+        assert_eq!(popped, Some((KEY.into(), vec![1, 2])));
+        let err = r
+            .lmpop::<usize>(&[KEY, "elsewhere"], End::Left, 1)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+    }
+
+    #[actix_rt::test]
+    async fn blmpop_waits_for_any_queue() {
+        const IDLE: &str = "{blmpop_waits_for_any_queue}idle";
+        const BUSY: &str = "{blmpop_waits_for_any_queue}busy";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[IDLE, BUSY]))
+            .await
+            .unwrap();
+
+        let producer = actix_rt::spawn(async move {
+            actix_rt::time::sleep(Duration::from_millis(100)).await;
+            let _: () = con
+                .exec(redis::cmd("RPUSH").arg(BUSY).arg("job"))
+                .await
+                .unwrap();
+        });
+        let popped: Option<(String, Vec<String>)> = r
+            .blmpop(Duration::from_secs(5), &[IDLE, BUSY], End::Left, 10)
+            .await
+            .unwrap();
+        producer.await.unwrap();
+        assert_eq!(popped, Some((BUSY.into(), vec!["job".into()])));
+
+        let popped: Option<(String, Vec<String>)> = r
+            .blmpop(Duration::from_millis(50), &[IDLE, BUSY], End::Left, 10)
+            .await
+            .unwrap();
+        assert_eq!(popped, None);
+    }
+
+    #[actix_rt::test]
+    async fn blmpop_must_fit_response_timeout() {
+        let options = RedisOptions {
+            response_timeout: Some(Duration::from_secs(1)),
+            ..RedisOptions::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        for timeout in [Duration::from_secs(2), Duration::ZERO] {
+            let res = r
+                .blmpop::<String>(timeout, &["blmpop_must_fit_response_timeout"], End::Left, 1)
+                .await;
+            assert_eq!(res.unwrap_err().kind(), redis::ErrorKind::ClientError);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn lpos_works() {
+        const KEY: &str = "lpos_works";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(KEY).arg(&["a", "b", "a", "c", "a"]))
+            .await
+            .unwrap();
+
+        assert_eq!(con.lpos(KEY, "a", None, None).await.unwrap(), vec![0]);
+        assert_eq!(con.lpos(KEY, "a", Some(2), None).await.unwrap(), vec![2]);
+        assert_eq!(con.lpos(KEY, "a", Some(-1), None).await.unwrap(), vec![4]);
+        assert_eq!(
+            con.lpos(KEY, "a", None, Some(0)).await.unwrap(),
+            vec![0, 2, 4]
+        );
+        assert_eq!(
+            con.lpos(KEY, "a", Some(-1), Some(2)).await.unwrap(),
+            vec![4, 2]
+        );
+        assert!(con.lpos(KEY, "z", None, None).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn linsert_lset_ltrim_work() {
+        const KEY: &str = "linsert_lset_ltrim_work";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert_eq!(con.linsert(KEY, true, "a", "b").await.unwrap(), 0);
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(KEY).arg(&["a", "c"]))
+            .await
+            .unwrap();
+
+        assert_eq!(con.linsert(KEY, true, "c", "b").await.unwrap(), 3);
+        assert_eq!(con.linsert(KEY, false, "c", "d").await.unwrap(), 4);
+        assert_eq!(con.linsert(KEY, true, "z", "y").await.unwrap(), -1);
+
+        con.lset(KEY, -1, "e").await.unwrap();
+        assert!(con.lset(KEY, 10, "f").await.is_err());
+        let list: Vec<String> = con
+            .exec(redis::cmd("LRANGE").arg(KEY).arg(0).arg(-1))
+            .await
+            .unwrap();
+        assert_eq!(list, vec!["a", "b", "c", "e"]);
+
+        con.ltrim(KEY, 1, 2).await.unwrap();
+        let list: Vec<String> = con
+            .exec(redis::cmd("LRANGE").arg(KEY).arg(0).arg(-1))
+            .await
+            .unwrap();
+        assert_eq!(list, vec!["b", "c"]);
+    }
+}
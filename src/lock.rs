@@ -0,0 +1,184 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Single-instance distributed locks
+use std::time::Duration;
+
+use rand::Rng;
+use redis::RedisResult;
+
+use crate::RedisConnection;
+
+/// Deletes the lock key only if it still holds our token, so an expired lock
+/// that was taken over by someone else is left alone
+const RELEASE: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// A held lock that is released when dropped, see
+/// [RedisConnection::lock_scoped]
+pub struct LockGuard {
+    lock: Option<Lock>,
+    connection: RedisConnection,
+}
+
+impl LockGuard {
+    /// Key backing the lock
+    pub fn key(&self) -> &str {
+        self.lock.as_ref().map(Lock::key).unwrap_or_default()
+    }
+
+    /// Release the lock now and wait for it, see [Lock::release]
+    pub async fn release(mut self) -> RedisResult<bool> {
+        match self.lock.take() {
+            Some(lock) => lock.release(&self.connection).await,
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(lock) = self.lock.take() {
+            let connection = self.connection.clone();
+            tokio::spawn(async move {
+                let _ = lock.release(&connection).await;
+            });
+        }
+    }
+}
+
+/// A held lock, see [RedisConnection::try_lock]
+#[derive(Clone, Debug)]
+pub struct Lock {
+    key: String,
+    token: String,
+}
+
+impl Lock {
+    /// Key backing the lock
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Release the lock. Returns `false` if it had already expired.
+    pub async fn release(self, con: &RedisConnection) -> RedisResult<bool> {
+        con.exec(
+            redis::cmd("EVAL")
+                .arg(RELEASE)
+                .arg(1)
+                .arg(&self.key)
+                .arg(&self.token),
+        )
+        .await
+    }
+}
+
+impl RedisConnection {
+    /// Try to take the lock at `key` for `ttl` (SET NX PX with a random
+    /// token), `None` if someone else holds it. `ttl` is rounded up to a
+    /// millisecond.
+    ///
+    /// The lock expires on its own after `ttl`, so work done while holding it
+    /// must finish well within that. This is not Redlock: a failover can hand
+    /// the same lock out twice.
+    pub async fn try_lock(&self, key: &str, ttl: Duration) -> RedisResult<Option<Lock>> {
+        let token = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let acquired: Option<String> = self
+            .exec(
+                redis::cmd("SET")
+                    .arg(key)
+                    .arg(&token)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg((ttl.as_millis() as u64).max(1)),
+            )
+            .await?;
+        Ok(acquired.map(|_| Lock {
+            key: key.to_owned(),
+            token,
+        }))
+    }
+
+    /// Like [Self::try_lock], but the lock is released when the returned
+    /// guard is dropped, early returns included.
+    ///
+    /// Drop can't wait, so the release runs in a task spawned on the current
+    /// tokio runtime and is best-effort:
+    /// it may fail or run late, and the TTL remains what eventually frees
+    /// the lock. Use [LockGuard::release] to release it for sure.
+    pub async fn lock_scoped(&self, key: &str, ttl: Duration) -> RedisResult<Option<LockGuard>> {
+        Ok(self.try_lock(key, ttl).await?.map(|lock| LockGuard {
+            lock: Some(lock),
+            connection: self.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn lock_excludes_other_holders() {
+        const KEY: &str = "lock_excludes_other_holders";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        let ttl = Duration::from_secs(5);
+        let lock = con.try_lock(KEY, ttl).await.unwrap().unwrap();
+        assert!(con.try_lock(KEY, ttl).await.unwrap().is_none());
+        assert!(lock.clone().release(&con).await.unwrap());
+        assert!(!lock.release(&con).await.unwrap());
+        assert!(con.try_lock(KEY, ttl).await.unwrap().is_some());
+    }
+
+    #[actix_rt::test]
+    async fn sub_millisecond_lock_ttls_are_rounded_up() {
+        const KEY: &str = "sub_millisecond_lock_ttls_are_rounded_up";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        // PX 0 would be rejected
+        let lock = con.try_lock(KEY, Duration::from_micros(10)).await.unwrap();
+        assert!(lock.is_some());
+    }
+
+    #[actix_rt::test]
+    async fn scoped_lock_released_on_drop() {
+        const KEY: &str = "scoped_lock_released_on_drop";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        let ttl = Duration::from_secs(30);
+        let guard = con.lock_scoped(KEY, ttl).await.unwrap().unwrap();
+        assert_eq!(guard.key(), KEY);
+        assert!(con.lock_scoped(KEY, ttl).await.unwrap().is_none());
+        drop(guard);
+
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        let exists: bool = con.exec(redis::cmd("EXISTS").arg(KEY)).await.unwrap();
+        assert!(!exists);
+        let guard = con.lock_scoped(KEY, ttl).await.unwrap().unwrap();
+        assert!(guard.release().await.unwrap());
+    }
+}
@@ -0,0 +1,200 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Connections managed by a deadpool pool
+use deadpool::managed::{Manager, Metrics, RecycleError, RecycleResult};
+use std::sync::Arc;
+
+use redis::aio::Connection;
+use redis::{FromRedisValue, RedisError, RedisResult, Value};
+
+use crate::{blocking, is_pong, Handle, RedisClient, RedisOptions, SharedCluster};
+
+/// [Manager] for a `deadpool` pool of connections to the deployment of a
+/// [RedisClient].
+///
+/// The pool holds [ManagedConnection]s: plain connections without the
+/// guards and circuit breaker of a [crate::Redis]. Of its [RedisOptions],
+/// only the ones that apply to opening a connection are used:
+/// [RedisOptions::connect_timeout], [RedisOptions::lib_info], the TCP
+/// options and, in cluster mode, [RedisOptions::response_timeout].
+///
+/// Every connection is checked with a PING when it goes back into service,
+/// and thrown away if that fails or doesn't get a PONG back (from every
+/// node in cluster mode), for instance because it was left in pub/sub mode.
+/// That costs a round-trip per checkout in exchange for never handing out a
+/// dead connection.
+pub struct RedisManager {
+    client: RedisClient,
+    options: RedisOptions,
+}
+
+impl RedisManager {
+    /// Manager opening connections with the default [RedisOptions]
+    pub fn new(client: RedisClient) -> Self {
+        Self::with_options(client, RedisOptions::default())
+    }
+
+    /// Manager opening connections with `options`, see [RedisManager]
+    pub fn with_options(client: RedisClient, options: RedisOptions) -> Self {
+        Self { client, options }
+    }
+}
+
+/// A connection opened by a [RedisManager]
+pub struct ManagedConnection(Raw);
+
+enum Raw {
+    Single(Connection),
+    /// Commands run on the blocking thread pool, as for
+    /// [crate::RedisConnection]
+    Cluster(SharedCluster),
+}
+
+impl ManagedConnection {
+    /// Run `cmd` and convert the reply to `T`
+    pub async fn exec<T: FromRedisValue>(&mut self, cmd: &redis::Cmd) -> RedisResult<T> {
+        match &mut self.0 {
+            Raw::Single(con) => cmd.query_async(con).await,
+            Raw::Cluster(con) => {
+                let cmd = cmd.clone();
+                blocking(con, move |con| cmd.query(con))
+                    .await
+                    .and_then(|reply| T::from_redis_value(&reply))
+            }
+        }
+    }
+
+    /// Run `pipe` and convert the replies to `T`
+    pub async fn exec_pipe<T: FromRedisValue>(&mut self, pipe: &redis::Pipeline) -> RedisResult<T> {
+        match &mut self.0 {
+            Raw::Single(con) => pipe.query_async(con).await,
+            Raw::Cluster(con) => {
+                let pipe = pipe.clone();
+                blocking(con, move |con| pipe.query(con))
+                    .await
+                    .and_then(|reply| T::from_redis_value(&reply))
+            }
+        }
+    }
+}
+
+impl Manager for RedisManager {
+    type Type = ManagedConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> RedisResult<ManagedConnection> {
+        let raw = match self.client.connect_handle(&self.options).await? {
+            // nothing else holds a freshly opened handle
+            Handle::Single(con) => match Arc::try_unwrap(con) {
+                Ok(con) => Raw::Single(con.into_inner()),
+                Err(_) => unreachable!("fresh connection is shared"),
+            },
+            Handle::Cluster(con) => Raw::Cluster(con),
+            Handle::Lazy(_) => unreachable!("connections are opened right away"),
+        };
+        Ok(ManagedConnection(raw))
+    }
+
+    async fn recycle(
+        &self,
+        con: &mut ManagedConnection,
+        _metrics: &Metrics,
+    ) -> RecycleResult<RedisError> {
+        match con.exec::<Value>(&redis::cmd("PING")).await? {
+            reply if is_pong(&reply) => Ok(()),
+            reply => Err(RecycleError::message(format!(
+                "expected PONG, got {:?}",
+                reply
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use deadpool::managed::Pool;
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn deadpool_reuses_connections() {
+        const KEY: &str = "deadpool_reuses_connections";
+
+        let manager = RedisManager::new(
+            RedisConfig::Single("redis://127.0.0.1".into())
+                .connect()
+                .unwrap(),
+        );
+        let pool: Pool<RedisManager> = Pool::builder(manager).max_size(1).build().unwrap();
+
+        let mut con = pool.get().await.unwrap();
+        let _: () = con.exec(redis::cmd("SET").arg(KEY).arg(1)).await.unwrap();
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        drop(con);
+
+        let mut con = pool.get().await.unwrap();
+        assert_eq!(
+            con.exec::<u64>(redis::cmd("GET").arg(KEY)).await.unwrap(),
+            1
+        );
+        let reused: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_eq!(reused, id);
+        assert_eq!(pool.status().size, 1);
+    }
+
+    #[actix_rt::test]
+    async fn create_applies_connect_options() {
+        let options = RedisOptions {
+            lib_info: Some(("create_applies_connect_options".into(), "1.0".into())),
+            ..Default::default()
+        };
+        let manager = RedisManager::with_options(
+            RedisConfig::Single("redis://127.0.0.1".into())
+                .connect()
+                .unwrap(),
+            options,
+        );
+        let mut con = manager.create().await.unwrap();
+        let info: String = con.exec(redis::cmd("CLIENT").arg("INFO")).await.unwrap();
+        assert!(info.contains("lib-name=create_applies_connect_options"));
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_connections_are_recycled() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let manager = RedisManager::new(RedisConfig::ClusterSeed(seed).connect().unwrap());
+        let mut con = manager.create().await.unwrap();
+        manager
+            .recycle(&mut con, &Metrics::default())
+            .await
+            .unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn deadpool_discards_connections_in_pubsub_mode() {
+        let manager = RedisManager::new(
+            RedisConfig::Single("redis://127.0.0.1".into())
+                .connect()
+                .unwrap(),
+        );
+        let pool: Pool<RedisManager> = Pool::builder(manager).max_size(1).build().unwrap();
+
+        let mut con = pool.get().await.unwrap();
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let _: Value = con
+            .exec(redis::cmd("SUBSCRIBE").arg("deadpool_discards_connections"))
+            .await
+            .unwrap();
+        drop(con);
+
+        let mut con = pool.get().await.unwrap();
+        let fresh: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_ne!(fresh, id);
+    }
+}
@@ -0,0 +1,357 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! In-process memoization of read command results
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use redis::{from_redis_value, Arg, FromRedisValue, RedisResult, Value};
+
+use crate::{command, is_readonly, Redis, RedisConnection};
+
+/// What a [CachedRedis] keeps and for how long
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoOptions {
+    /// Most results kept; the least recently used one is dropped to make
+    /// room for a new one
+    pub capacity: usize,
+    /// How long a result is served locally after it was fetched
+    pub ttl: Duration,
+    /// Names of the commands to cache, like `GET` or `HGETALL`; empty for
+    /// every read-only command. Commands whose reply changes without any
+    /// write, like RANDOMKEY, TIME or SCAN, are never cached.
+    pub commands: Vec<String>,
+    /// Cache only commands whose key starts with one of these; empty for
+    /// every key
+    pub key_prefixes: Vec<String>,
+}
+
+impl Default for MemoOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl: Duration::from_secs(1),
+            commands: Vec::new(),
+            key_prefixes: Vec::new(),
+        }
+    }
+}
+
+/// Read-only commands that may answer differently every time, never cached
+const UNCACHEABLE: &[&str] = &[
+    "HRANDFIELD",
+    "HSCAN",
+    "LASTSAVE",
+    "PING",
+    "RANDOMKEY",
+    "SCAN",
+    "SRANDMEMBER",
+    "SSCAN",
+    "TIME",
+    "XREAD",
+    "ZRANDMEMBER",
+    "ZSCAN",
+];
+
+/// Upper-cased command name followed by the arguments, so `get k` and
+/// `GET k` share an entry
+type MemoKey = Vec<Vec<u8>>;
+
+struct Entry {
+    value: Value,
+    expires: Instant,
+    /// Position in [Memo::order]
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Memo {
+    entries: HashMap<MemoKey, Entry>,
+    /// Keys of [Self::entries] from the least recently used
+    order: BTreeMap<u64, MemoKey>,
+    tick: u64,
+}
+
+impl Memo {
+    fn get(&mut self, key: &MemoKey, now: Instant) -> Option<Value> {
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires <= now {
+            let tick = entry.tick;
+            self.entries.remove(key);
+            self.order.remove(&tick);
+            return None;
+        }
+        self.tick += 1;
+        let key = self.order.remove(&entry.tick).expect("entries are ordered");
+        entry.tick = self.tick;
+        let value = entry.value.clone();
+        self.order.insert(self.tick, key);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: MemoKey, value: Value, expires: Instant, capacity: usize) {
+        if let Some(old) = self.entries.remove(&key) {
+            self.order.remove(&old.tick);
+        }
+        while self.entries.len() >= capacity {
+            match self.order.pop_first() {
+                Some((_, lru)) => self.entries.remove(&lru),
+                None => break,
+            };
+        }
+        if capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires,
+                tick: self.tick,
+            },
+        );
+    }
+
+    /// Drop every entry for a command on `key`
+    fn invalidate(&mut self, key: &[u8]) {
+        let entries = &mut self.entries;
+        self.order.retain(|_, memo_key| {
+            let stale = memo_key.get(1).map(Vec::as_slice) == Some(key);
+            if stale {
+                entries.remove(memo_key);
+            }
+            !stale
+        });
+    }
+}
+
+/// A connection that serves repeated read-only commands from an in-memory
+/// LRU for a short TTL, see [Redis::cached].
+///
+/// Results are keyed on the whole command, name and arguments. Only replies
+/// are cached, never errors. Clones share the cache. For commands sent
+/// through this wrapper, anything that may write drops the cached results
+/// for its first argument, taken as its key; writes made elsewhere are only
+/// noticed once the TTL elapses or after [Self::invalidate].
+///
+/// `Send + Sync`, like [RedisConnection].
+#[derive(Clone)]
+pub struct CachedRedis {
+    connection: RedisConnection,
+    options: Arc<MemoOptions>,
+    memo: Arc<Mutex<Memo>>,
+}
+
+impl Redis {
+    /// Get a connection that memoizes read-only command results locally,
+    /// see [CachedRedis]
+    pub fn cached(&self, options: MemoOptions) -> CachedRedis {
+        CachedRedis {
+            connection: self.get_client(),
+            options: Arc::new(options),
+            memo: Arc::new(Mutex::new(Memo::default())),
+        }
+    }
+}
+
+impl CachedRedis {
+    /// The underlying connection, which never caches
+    pub fn connection(&self) -> &RedisConnection {
+        &self.connection
+    }
+
+    /// Run `cmd`, or return its cached result if the same command ran less
+    /// than [MemoOptions::ttl] ago
+    pub async fn exec<T: FromRedisValue>(&self, cmd: &mut redis::Cmd) -> RedisResult<T> {
+        let key = match self.memo_key(cmd) {
+            Some(key) => key,
+            None => {
+                let res = self.connection.exec(cmd).await;
+                if !is_readonly(cmd) {
+                    if let Some(Arg::Simple(key)) = cmd.args_iter().nth(1) {
+                        self.memo.lock().unwrap().invalidate(key);
+                    }
+                }
+                return res;
+            }
+        };
+        let cached = self.memo.lock().unwrap().get(&key, Instant::now());
+        if let Some(value) = cached {
+            return from_redis_value(&value);
+        }
+        let value: Value = self.connection.exec(cmd).await?;
+        let expires = Instant::now() + self.options.ttl;
+        self.memo
+            .lock()
+            .unwrap()
+            .insert(key, value.clone(), expires, self.options.capacity);
+        from_redis_value(&value)
+    }
+
+    /// Drop every cached result of a command on `key`
+    pub fn invalidate(&self, key: &str) {
+        self.memo.lock().unwrap().invalidate(key.as_bytes());
+    }
+
+    /// Drop all cached results
+    pub fn clear(&self) {
+        *self.memo.lock().unwrap() = Memo::default();
+    }
+
+    /// Cache key of `cmd`, `None` if its result mustn't be cached
+    fn memo_key(&self, cmd: &redis::Cmd) -> Option<MemoKey> {
+        if !is_readonly(cmd) {
+            return None;
+        }
+        let name = command::name(cmd)?;
+        if UNCACHEABLE.contains(&name.as_str()) {
+            return None;
+        }
+        let options = &self.options;
+        if !options.commands.is_empty()
+            && !options
+                .commands
+                .iter()
+                .any(|c| c.eq_ignore_ascii_case(&name))
+        {
+            return None;
+        }
+        let mut key = vec![name.into_bytes()];
+        for arg in cmd.args_iter().skip(1) {
+            match arg {
+                Arg::Simple(arg) => key.push(arg.to_vec()),
+                Arg::Cursor => return None,
+            }
+        }
+        if !options.key_prefixes.is_empty() {
+            let arg = key.get(1)?;
+            if !options
+                .key_prefixes
+                .iter()
+                .any(|prefix| arg.starts_with(prefix.as_bytes()))
+            {
+                return None;
+            }
+        }
+        Some(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::*;
+
+    fn key(args: &[&str]) -> MemoKey {
+        args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn memo_evicts_least_recently_used() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(10);
+        let mut memo = Memo::default();
+        memo.insert(key(&["GET", "a"]), Value::Int(1), later, 2);
+        memo.insert(key(&["GET", "b"]), Value::Int(2), later, 2);
+        assert_eq!(memo.get(&key(&["GET", "a"]), now), Some(Value::Int(1)));
+        memo.insert(key(&["GET", "c"]), Value::Int(3), later, 2);
+        assert!(memo.get(&key(&["GET", "b"]), now).is_none());
+        assert_eq!(memo.get(&key(&["GET", "a"]), now), Some(Value::Int(1)));
+        assert_eq!(memo.get(&key(&["GET", "c"]), now), Some(Value::Int(3)));
+
+        assert!(memo.get(&key(&["GET", "a"]), later).is_none());
+        assert_eq!(memo.entries.len(), 1);
+        assert_eq!(memo.order.len(), 1);
+
+        memo.insert(key(&["HGET", "c", "f"]), Value::Int(4), later, 2);
+        memo.invalidate(b"c");
+        assert!(memo.entries.is_empty() && memo.order.is_empty());
+    }
+
+    #[test]
+    fn random_and_time_commands_are_not_memoized() {
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        let everything = r.cached(MemoOptions::default());
+        assert!(everything.memo_key(redis::cmd("GET").arg("k")).is_some());
+        assert!(everything.memo_key(&redis::cmd("RANDOMKEY")).is_none());
+        assert!(everything.memo_key(&redis::cmd("TIME")).is_none());
+        assert!(everything
+            .memo_key(redis::cmd("SRANDMEMBER").arg("k"))
+            .is_none());
+        // not even when asked for
+        let randomkey = r.cached(MemoOptions {
+            commands: vec!["randomkey".into()],
+            ..Default::default()
+        });
+        assert!(randomkey.memo_key(&redis::cmd("RANDOMKEY")).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn cached_redis_serves_repeated_reads_locally() {
+        const KEY: &str = "cached_redis_serves_repeated_reads_locally";
+
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&sent);
+        let options = RedisOptions {
+            observer: Some(Observer::new(move |_, _| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let cached = r.cached(MemoOptions {
+            ttl: Duration::from_millis(300),
+            commands: vec!["GET".into()],
+            ..Default::default()
+        });
+        let _: () = cached
+            .exec(redis::cmd("SET").arg(&[KEY, "1"]))
+            .await
+            .unwrap();
+        sent.store(0, Ordering::SeqCst);
+
+        let get = || async { cached.exec::<String>(redis::cmd("GET").arg(KEY)).await };
+        assert_eq!(get().await.unwrap(), "1");
+        assert_eq!(get().await.unwrap(), "1");
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+
+        // writes made elsewhere go unnoticed until invalidated or expired
+        let _: () = cached
+            .connection()
+            .exec(redis::cmd("SET").arg(&[KEY, "2"]))
+            .await
+            .unwrap();
+        assert_eq!(get().await.unwrap(), "1");
+        cached.invalidate(KEY);
+        assert_eq!(get().await.unwrap(), "2");
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+
+        // writes through the wrapper drop the key's results
+        let _: () = cached
+            .exec(redis::cmd("SET").arg(&[KEY, "3"]))
+            .await
+            .unwrap();
+        assert_eq!(get().await.unwrap(), "3");
+        assert_eq!(sent.load(Ordering::SeqCst), 5);
+
+        actix_rt::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(get().await.unwrap(), "3");
+        assert_eq!(sent.load(Ordering::SeqCst), 6);
+
+        // commands outside the configured set always go to the server
+        let _: u64 = cached.exec(redis::cmd("STRLEN").arg(KEY)).await.unwrap();
+        let _: u64 = cached.exec(redis::cmd("STRLEN").arg(KEY)).await.unwrap();
+        assert_eq!(sent.load(Ordering::SeqCst), 8);
+    }
+}
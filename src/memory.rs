@@ -0,0 +1,381 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Memory usage estimates
+use std::collections::HashMap;
+
+use redis::{RedisResult, Value};
+
+use crate::RedisConnection;
+
+/// Value sizes observed by [RedisConnection::sample_value_sizes], in bytes
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SizeStats {
+    /// Number of keys that were measured
+    pub sampled: usize,
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    /// Sum over the sampled keys, not the whole database
+    pub total: u64,
+}
+
+impl SizeStats {
+    fn add(&mut self, size: u64) {
+        if self.sampled == 0 || size < self.min {
+            self.min = size;
+        }
+        self.max = self.max.max(size);
+        self.total += size;
+        self.sampled += 1;
+        self.mean = self.total as f64 / self.sampled as f64;
+    }
+}
+
+/// A key stored in an encoding that doesn't suit its size, see
+/// [RedisConnection::encoding_report]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodingFinding {
+    pub key: String,
+    /// As reported by TYPE, like `hash`
+    pub key_type: String,
+    /// As reported by OBJECT ENCODING, like `listpack`
+    pub encoding: String,
+    /// Number of fields, members or elements
+    pub len: u64,
+    /// What to look into
+    pub recommendation: String,
+}
+
+/// Compact encodings holding more entries than this are worth a look: every
+/// access to them is a linear scan
+const LARGE_COMPACT: u64 = 512;
+
+/// Hash table encodings holding at most this many entries are worth a look:
+/// a compact encoding would take a fraction of the memory
+const SMALL_TABLE: u64 = 16;
+
+/// Length command for the collections [recommend] knows about
+fn len_cmd(key_type: &str) -> Option<&'static str> {
+    match key_type {
+        "hash" => Some("HLEN"),
+        "set" => Some("SCARD"),
+        "zset" => Some("ZCARD"),
+        _ => None,
+    }
+}
+
+/// What to do about a `key_type` key of `len` entries stored as `encoding`,
+/// `None` if the encoding fits
+fn recommend(key_type: &str, encoding: &str, len: u64) -> Option<String> {
+    match encoding {
+        "listpack" | "ziplist" | "intset" if len > LARGE_COMPACT => {
+            let setting = match (key_type, encoding) {
+                ("set", "intset") => "set-max-intset-entries".to_owned(),
+                _ => format!("{}-max-{}-entries", key_type, encoding),
+            };
+            Some(format!(
+                "{} entries in a {} make every access a linear scan, lower {}",
+                len, encoding, setting
+            ))
+        }
+        "hashtable" | "skiplist" if len <= SMALL_TABLE => Some(format!(
+            "only {} entries but stored as a {}, which Redis never converts back \
+             after the key shrinks or once it held a long value; rewriting the \
+             key stores it compactly",
+            len, encoding
+        )),
+        _ => None,
+    }
+}
+
+impl RedisConnection {
+    /// Bytes used by `key` and its value, including overhead (MEMORY USAGE),
+    /// `None` if the key doesn't exist.
+    ///
+    /// Nested values of aggregate types are sampled by the server itself, see
+    /// the SAMPLES option of MEMORY USAGE.
+    pub async fn memory_usage(&self, key: &str) -> RedisResult<Option<u64>> {
+        self.exec(redis::cmd("MEMORY").arg("USAGE").arg(key)).await
+    }
+
+    /// Estimate value sizes from `sample` random keys (RANDOMKEY followed by
+    /// MEMORY USAGE), costing two round-trips per key.
+    ///
+    /// This is a rough, sample-based overview, not an exact account: keys are
+    /// picked with replacement, so the same key can be measured more than
+    /// once, and keys deleted in between are skipped. An empty database
+    /// yields empty stats. In cluster mode only a single node is sampled.
+    pub async fn sample_value_sizes(&self, sample: usize) -> RedisResult<SizeStats> {
+        let mut stats = SizeStats::default();
+        for _ in 0..sample {
+            // RANDOMKEY itself rather than [Self::randomkey], which refuses
+            // cluster mode
+            let key: Option<String> = self.exec(&mut redis::cmd("RANDOMKEY")).await?;
+            let key = match key {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(size) = self.memory_usage(&key).await? {
+                stats.add(size);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Estimate memory use per key prefix: the part of a key up to the first
+    /// `separator`, like `session` for `session:42`. Keys without the
+    /// separator are counted under the empty prefix.
+    ///
+    /// SCANs up to `sample` keys and adds up their MEMORY USAGE, one pipeline
+    /// per SCAN batch. This is an approximation meant for capacity planning:
+    /// only the sampled keys are counted, so compare buckets with each other
+    /// rather than with the server's total, and MEMORY USAGE itself samples
+    /// large aggregates. Fails with [redis::ErrorKind::ClientError] in
+    /// cluster mode, where SCAN can't be routed.
+    pub async fn memory_by_prefix(
+        &self,
+        separator: char,
+        sample: usize,
+    ) -> RedisResult<HashMap<String, u64>> {
+        self.ensure_not_cluster("memory_by_prefix SCANs, which isn't routable in cluster mode")?;
+        let mut by_prefix = HashMap::new();
+        let mut cursor = 0u64;
+        let mut seen = 0;
+        while seen < sample {
+            let (next, mut keys): (u64, Vec<String>) = self
+                .exec(redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(100))
+                .await?;
+            keys.truncate(sample - seen);
+            seen += keys.len();
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("MEMORY").arg("USAGE").arg(key);
+                }
+                let sizes: Vec<Option<u64>> = self.exec_pipe(&pipe).await?;
+                for (key, size) in keys.iter().zip(sizes) {
+                    let prefix = key.split_once(separator).map_or("", |(prefix, _)| prefix);
+                    *by_prefix.entry(prefix.to_owned()).or_default() += size.unwrap_or(0);
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(by_prefix)
+    }
+
+    /// Hashes, sets and sorted sets whose encoding doesn't suit their size:
+    /// large ones still in a compact encoding (`listpack`, `ziplist` or
+    /// `intset`), where every access is a linear scan, and small ones in a
+    /// `hashtable` or `skiplist`, which the server doesn't convert back when
+    /// they shrink.
+    ///
+    /// SCANs up to `sample` keys and checks them with TYPE, OBJECT ENCODING
+    /// and their length, pipelined per SCAN batch. Like
+    /// [Self::memory_by_prefix] this is sample-based, keys that aren't
+    /// sampled aren't reported, and fails with [redis::ErrorKind::ClientError]
+    /// in cluster mode.
+    pub async fn encoding_report(&self, sample: usize) -> RedisResult<Vec<EncodingFinding>> {
+        self.ensure_not_cluster("encoding_report SCANs, which isn't routable in cluster mode")?;
+        let mut findings = Vec::new();
+        let mut cursor = 0u64;
+        let mut seen = 0;
+        while seen < sample {
+            let (next, mut keys): (u64, Vec<String>) = self
+                .exec(redis::cmd("SCAN").arg(cursor).arg("COUNT").arg(100))
+                .await?;
+            keys.truncate(sample - seen);
+            seen += keys.len();
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("TYPE").arg(key);
+                    pipe.cmd("OBJECT").arg("ENCODING").arg(key);
+                }
+                let replies: Vec<Value> = self.exec_pipe(&pipe).await?;
+                let mut candidates = Vec::new();
+                for (key, pair) in keys.into_iter().zip(replies.chunks(2)) {
+                    let key_type: String = redis::from_redis_value(&pair[0])?;
+                    // deleted since SCAN
+                    let encoding: Option<String> = redis::from_redis_value(&pair[1])?;
+                    if let (Some(len_cmd), Some(encoding)) = (len_cmd(&key_type), encoding) {
+                        candidates.push((key, key_type, encoding, len_cmd));
+                    }
+                }
+                if !candidates.is_empty() {
+                    let mut pipe = redis::pipe();
+                    for (key, _, _, len_cmd) in &candidates {
+                        pipe.cmd(len_cmd).arg(key);
+                    }
+                    let lens: Vec<u64> = self.exec_pipe(&pipe).await?;
+                    for ((key, key_type, encoding, _), len) in candidates.into_iter().zip(lens) {
+                        if let Some(recommendation) = recommend(&key_type, &encoding, len) {
+                            findings.push(EncodingFinding {
+                                key,
+                                key_type,
+                                encoding,
+                                len,
+                                recommendation,
+                            });
+                        }
+                    }
+                }
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        Ok(findings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn recommend_flags_mismatched_encodings() {
+        assert!(recommend("hash", "listpack", 128).is_none());
+        assert!(recommend("hash", "hashtable", 10_000).is_none());
+        let large = recommend("hash", "listpack", 2_000).unwrap();
+        assert!(large.contains("hash-max-listpack-entries"));
+        let large = recommend("set", "intset", 2_000).unwrap();
+        assert!(large.contains("set-max-intset-entries"));
+        let large = recommend("zset", "ziplist", 2_000).unwrap();
+        assert!(large.contains("zset-max-ziplist-entries"));
+        assert!(recommend("set", "hashtable", 3)
+            .unwrap()
+            .contains("rewriting"));
+        assert!(recommend("zset", "skiplist", 16).is_some());
+        assert!(recommend("zset", "skiplist", 17).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn sample_value_sizes_works() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // a database of its own, so only the seeded keys are sampled
+        let con = r.dedicated().await.unwrap();
+        let _: () = con.exec(redis::cmd("SELECT").arg(10)).await.unwrap();
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        assert_eq!(
+            con.sample_value_sizes(10).await.unwrap(),
+            SizeStats::default()
+        );
+
+        for (i, len) in [100, 1_000, 10_000].iter().enumerate() {
+            let _: () = con
+                .exec(
+                    redis::cmd("SET")
+                        .arg(format!("sample_value_sizes_{}", i))
+                        .arg("x".repeat(*len)),
+                )
+                .await
+                .unwrap();
+        }
+        let stats = con.sample_value_sizes(50).await.unwrap();
+        assert_eq!(stats.sampled, 50);
+        assert!(stats.min >= 100 && stats.max >= 10_000);
+        assert!(stats.mean >= stats.min as f64 && stats.mean <= stats.max as f64);
+        assert!(stats.mean > 100.0 && stats.mean < 12_000.0);
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn sampling_scans_refuse_cluster_mode() {
+        let cluster = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()]);
+        let r = Redis::new_lazy(cluster).unwrap();
+        let con = r.get_client();
+        let err = con.memory_by_prefix(':', 10).await.unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+        let err = con.encoding_report(10).await.unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+    }
+
+    #[actix_rt::test]
+    async fn memory_by_prefix_ranks_buckets() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // a database of its own, so only the seeded keys are scanned
+        let con = r.dedicated().await.unwrap();
+        let _: () = con.exec(redis::cmd("SELECT").arg(15)).await.unwrap();
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+
+        let mut pipe = redis::pipe();
+        for i in 0..20 {
+            pipe.cmd("SET")
+                .arg(format!("session:{}", i))
+                .arg("x".repeat(2_000))
+                .ignore();
+            pipe.cmd("SET")
+                .arg(format!("cache:{}", i))
+                .arg("x".repeat(10))
+                .ignore();
+        }
+        pipe.cmd("SET").arg("plain").arg("x").ignore();
+        let _: () = con.exec_pipe(&pipe).await.unwrap();
+
+        let by_prefix = con.memory_by_prefix(':', 1_000).await.unwrap();
+        assert_eq!(by_prefix.len(), 3);
+        assert!(by_prefix["session"] > by_prefix["cache"]);
+        assert!(by_prefix[""] > 0);
+
+        let sampled = con.memory_by_prefix(':', 5).await.unwrap();
+        assert!(sampled.values().sum::<u64>() < by_prefix.values().sum::<u64>());
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+    }
+
+    #[actix_rt::test]
+    async fn encoding_report_flags_shrunk_set() {
+        const SHRUNK: &str = "encoding_report_flags_shrunk_set";
+        const SMALL: &str = "encoding_report_flags_shrunk_set_small";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // a database of its own, so only the seeded keys are scanned
+        let con = r.dedicated().await.unwrap();
+        let _: () = con.exec(redis::cmd("SELECT").arg(7)).await.unwrap();
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+
+        // grown past every compact encoding limit, then shrunk to 3 members:
+        // the set stays a hashtable
+        let members: Vec<String> = (0..1_000).map(|i| format!("member-{}", i)).collect();
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(SHRUNK).arg(&members[..]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("SREM").arg(SHRUNK).arg(&members[3..]))
+            .await
+            .unwrap();
+        // an intset on every version, unlike small sets of strings
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(SMALL).arg(&[1, 2, 3]))
+            .await
+            .unwrap();
+        assert_eq!(
+            con.object_encoding(SHRUNK).await.unwrap().as_deref(),
+            Some("hashtable")
+        );
+
+        let findings = con.encoding_report(100).await.unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].key, SHRUNK);
+        assert_eq!(findings[0].key_type, "set");
+        assert_eq!(findings[0].encoding, "hashtable");
+        assert_eq!(findings[0].len, 3);
+        assert!(con.object_encoding("missing").await.unwrap().is_none());
+        let _: () = con.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+    }
+}
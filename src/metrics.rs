@@ -0,0 +1,313 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Command metrics and tracing spans
+use std::fmt;
+use std::future::Future;
+#[cfg(feature = "tracing")]
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::{RedisError, RedisResult};
+
+#[cfg(feature = "tracing")]
+use crate::routing::parse_cluster_slots;
+#[cfg(feature = "tracing")]
+use crate::slot::slot_of;
+use crate::RedisConnection;
+#[cfg(feature = "tracing")]
+use crate::{blocking, Handle, RedisClient};
+
+/// Receives measurements of a [crate::Redis] and its connections, to feed
+/// Prometheus or any other metrics backend. Set through
+/// [crate::RedisOptions::metrics].
+///
+/// Methods are called inline on the task that sent the command, keep them
+/// cheap. Every method does nothing by default.
+pub trait MetricsRecorder: Send + Sync {
+    /// A command completed after `elapsed`, with `error` if it failed.
+    ///
+    /// `command` is the [crate::command_label] of the command, or
+    /// `pipeline` for pipelines and transactions. Commands rejected before
+    /// anything is sent, by an open circuit breaker for example, aren't
+    /// recorded.
+    fn record_command(&self, command: &str, elapsed: Duration, error: Option<&RedisError>) {
+        let _ = (command, elapsed, error);
+    }
+
+    /// A connection was replaced with a fresh one, with `error` if opening
+    /// the fresh one failed and the old one was kept
+    fn record_reconnect(&self, error: Option<&RedisError>) {
+        let _ = error;
+    }
+}
+
+impl fmt::Debug for dyn MetricsRecorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsRecorder")
+    }
+}
+
+impl RedisConnection {
+    /// `label()` if a span or [MetricsRecorder] needs it, to avoid rendering
+    /// it for nothing
+    pub(crate) fn label(&self, label: impl FnOnce() -> String) -> Option<String> {
+        if spans_enabled() || self.options.metrics.is_some() {
+            Some(label())
+        } else {
+            None
+        }
+    }
+
+    /// Run `request` in a `redis` span (with the `tracing` feature) that
+    /// carries the command `label` and, once the reply is in, the node it
+    /// went to, routed by `key` in cluster mode
+    #[cfg(feature = "tracing")]
+    pub(crate) async fn traced<T>(
+        &self,
+        label: Option<&str>,
+        key: Option<&[u8]>,
+        request: impl Future<Output = T>,
+    ) -> T {
+        use tracing::Instrument;
+
+        let span = tracing::debug_span!(
+            "redis",
+            command = label.unwrap_or_default(),
+            node = tracing::field::Empty,
+        );
+        if span.is_disabled() {
+            return request.await;
+        }
+        let res = request.instrument(span.clone()).await;
+        if let Some(node) = self.node(key).await {
+            span.record("node", node.as_str());
+        }
+        res
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) async fn traced<T>(
+        &self,
+        _label: Option<&str>,
+        _key: Option<&[u8]>,
+        request: impl Future<Output = T>,
+    ) -> T {
+        request.await
+    }
+
+    /// Report a completed command to the [MetricsRecorder], if any
+    pub(crate) fn record_command<T>(
+        &self,
+        label: Option<&str>,
+        elapsed: Duration,
+        res: &RedisResult<T>,
+    ) {
+        if let (Some(metrics), Some(label)) = (&self.options.metrics, label) {
+            metrics.record_command(label, elapsed, res.as_ref().err());
+        }
+    }
+
+    /// Report a reconnection to the [MetricsRecorder], if any
+    pub(crate) fn record_reconnect(&self, res: &RedisResult<()>) {
+        if let Some(metrics) = &self.options.metrics {
+            metrics.record_reconnect(res.as_ref().err());
+        }
+    }
+
+    /// Address of the node a command routed by `key` went to: the server in
+    /// single mode, the one the sentinels reported in sentinel mode, and in
+    /// cluster mode the primary serving the key's slot as of the last
+    /// topology lookup (CLUSTER SLOTS, once per connection). `None` for
+    /// cluster commands without a key, which go to any node.
+    #[cfg(feature = "tracing")]
+    async fn node(&self, key: Option<&[u8]>) -> Option<String> {
+        let slot = match (&self.client, key) {
+            (RedisClient::Single(client), _) => {
+                return Some(client.get_connection_info().addr.to_string())
+            }
+            (RedisClient::Sentinel(sentinel), _) => return sentinel.last_server(),
+            (RedisClient::Cluster(_), key) => slot_of(key?),
+        };
+        let cached = self.state.topology.lock().unwrap().clone();
+        let topology = match cached {
+            Some(topology) => topology,
+            None => {
+                let con = match self.handle.resolved()? {
+                    Handle::Cluster(con) => con,
+                    _ => return None,
+                };
+                let reply = blocking(con, |con| redis::cmd("CLUSTER").arg("SLOTS").query(con))
+                    .await
+                    .ok()?;
+                let topology = Arc::new(parse_cluster_slots(&reply).ok()?);
+                *self.state.topology.lock().unwrap() = Some(Arc::clone(&topology));
+                topology
+            }
+        };
+        topology
+            .iter()
+            .find(|range| range.start <= slot && slot <= range.end)
+            .map(|range| format!("{}:{}", range.master.0, range.master.1))
+    }
+}
+
+/// Whether `redis` spans are recorded by the current subscriber
+#[cfg(feature = "tracing")]
+fn spans_enabled() -> bool {
+    tracing::span_enabled!(tracing::Level::DEBUG)
+}
+
+#[cfg(not(feature = "tracing"))]
+fn spans_enabled() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::*;
+
+    #[derive(Default)]
+    struct Recorded {
+        commands: Mutex<Vec<(String, bool)>>,
+        reconnects: Mutex<Vec<bool>>,
+    }
+
+    impl MetricsRecorder for Recorded {
+        fn record_command(&self, command: &str, _: Duration, error: Option<&redis::RedisError>) {
+            self.commands
+                .lock()
+                .unwrap()
+                .push((command.to_owned(), error.is_none()));
+        }
+
+        fn record_reconnect(&self, error: Option<&redis::RedisError>) {
+            self.reconnects.lock().unwrap().push(error.is_none());
+        }
+    }
+
+    #[actix_rt::test]
+    async fn failed_reconnects_are_recorded() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let recorded = Arc::new(Recorded::default());
+        let options = RedisOptions {
+            metrics: Some(Arc::clone(&recorded) as Arc<dyn MetricsRecorder>),
+            ..Default::default()
+        };
+        let r = Redis::lazy_with_options(
+            RedisConfig::Single(format!("redis://127.0.0.1:{}", port)),
+            options,
+        )
+        .unwrap();
+        assert!(r.ensure_connected().await.is_err());
+        assert_eq!(*recorded.reconnects.lock().unwrap(), vec![false]);
+        // never connected, nothing was sent
+        assert!(recorded.commands.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn labels_are_skipped_without_spans_or_metrics() {
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        // no subscriber is installed
+        assert_eq!(r.get_client().label(|| unreachable!()), None);
+    }
+
+    /// Subscriber keeping the `node` field recorded on `redis` spans
+    #[cfg(feature = "tracing")]
+    #[derive(Default)]
+    struct Nodes(Mutex<Vec<String>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Nodes {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            struct Node<'a>(&'a Nodes);
+
+            impl tracing::field::Visit for Node<'_> {
+                fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                    if field.name() == "node" {
+                        self.0 .0.lock().unwrap().push(value.to_owned());
+                    }
+                }
+
+                fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+            }
+
+            values.record(&mut Node(self));
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, _: &tracing::Event<'_>) {}
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[actix_rt::test]
+    async fn spans_record_the_node() {
+        let nodes = Arc::new(Nodes::default());
+        let _default = tracing::dispatcher::set_default(&tracing::Dispatch::from(
+            Arc::clone(&nodes) as Arc<dyn tracing::Subscriber + Send + Sync>,
+        ));
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let _: () = r.get_client().exec(&mut redis::cmd("PING")).await.unwrap();
+        assert_eq!(*nodes.0.lock().unwrap(), vec!["127.0.0.1:6379".to_owned()]);
+    }
+
+    #[actix_rt::test]
+    async fn commands_are_recorded() {
+        let recorded = Arc::new(Recorded::default());
+        let options = RedisOptions {
+            metrics: Some(Arc::clone(&recorded) as Arc<dyn MetricsRecorder>),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&["commands_are_recorded", "1"]))
+            .await
+            .unwrap();
+        assert!(con
+            .exec::<()>(redis::cmd("CONFIG").arg("NOPE"))
+            .await
+            .is_err());
+        let _: () = con
+            .exec_pipeline(redis::pipe().cmd("PING").ignore())
+            .await
+            .unwrap();
+        r.reconnect().await.unwrap();
+
+        assert_eq!(
+            *recorded.commands.lock().unwrap(),
+            vec![
+                ("SET".to_owned(), true),
+                ("CONFIG|NOPE".to_owned(), false),
+                ("pipeline".to_owned(), true),
+            ]
+        );
+        assert_eq!(*recorded.reconnects.lock().unwrap(), vec![true]);
+    }
+}
@@ -0,0 +1,563 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! In-memory stand-in for Redis, for unit tests
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use redis::{Arg, ErrorKind, RedisError, RedisResult, Value};
+
+use crate::pipeline::{is_atomic, Replay};
+use crate::RedisBackend;
+
+/// A [RedisBackend] keeping string keys in memory, so code written against
+/// [crate::BoxedRedis] can be unit-tested without a server. Clones share
+/// the keys.
+///
+/// Understands PING, GET, SET (with EX, PX, NX and XX), MGET, MSET, DEL,
+/// EXISTS, EXPIRE, PEXPIRE, PERSIST, TTL, PTTL, INCR, INCRBY, DECR and
+/// DECRBY, replying like the server does, errors included. Other commands
+/// fail with [ErrorKind::ClientError]. Pipelines run every command in order,
+/// atomic ones without other commands in between, and fail with the first
+/// error once all of them ran, as they do against a server. A transaction
+/// with a command the server would reject before EXEC (wrong number of
+/// arguments, or unknown to the mock) is discarded as a whole instead,
+/// failing with [ErrorKind::ExecAbortError].
+#[derive(Clone, Debug, Default)]
+pub struct MockRedis {
+    keys: Arc<Mutex<HashMap<Vec<u8>, Entry>>>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl MockRedis {
+    /// A mock without any keys
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remove every key, like FLUSHDB
+    pub fn clear(&self) {
+        self.keys.lock().unwrap().clear();
+    }
+}
+
+#[async_trait]
+impl RedisBackend for MockRedis {
+    async fn query(&self, cmd: &mut redis::Cmd) -> RedisResult<Value> {
+        apply(&mut self.keys.lock().unwrap(), &args(cmd))
+    }
+
+    async fn query_pipe(&self, pipe: &redis::Pipeline) -> RedisResult<Value> {
+        let replies: Vec<RedisResult<Value>> = {
+            let mut keys = self.keys.lock().unwrap();
+            if is_atomic(pipe) {
+                // applied to a copy, kept only if EXEC would have run
+                let mut staged = keys.clone();
+                let replies: Vec<_> = pipe
+                    .cmd_iter()
+                    .map(|cmd| apply(&mut staged, &args(cmd)))
+                    .collect();
+                if replies
+                    .iter()
+                    .any(|reply| matches!(reply, Err(e) if rejected_when_queued(e)))
+                {
+                    return Err(RedisError::from((
+                        ErrorKind::ExecAbortError,
+                        "An error was signalled by the server",
+                        "Transaction discarded because of previous errors.".to_owned(),
+                    )));
+                }
+                *keys = staged;
+                replies
+            } else {
+                pipe.cmd_iter()
+                    .map(|cmd| apply(&mut keys, &args(cmd)))
+                    .collect()
+            }
+        };
+        let replies = replies.into_iter().collect::<RedisResult<Vec<Value>>>()?;
+        let replies = if is_atomic(pipe) {
+            vec![Value::Bulk(replies)]
+        } else {
+            replies
+        };
+        pipe.query(&mut Replay::new(replies))
+    }
+
+    fn is_cluster(&self) -> bool {
+        false
+    }
+}
+
+fn args(cmd: &redis::Cmd) -> Vec<&[u8]> {
+    cmd.args_iter()
+        .map(|arg| match arg {
+            Arg::Simple(arg) => arg,
+            Arg::Cursor => b"0",
+        })
+        .collect()
+}
+
+/// Whether the server rejects `err`'s command as soon as it is queued in a
+/// transaction, rather than when EXEC runs it
+fn rejected_when_queued(err: &RedisError) -> bool {
+    err.kind() == ErrorKind::ClientError
+        || err
+            .detail()
+            .is_some_and(|detail| detail.starts_with("wrong number of arguments"))
+}
+
+/// An error reply, as the server would send it
+fn server_error(message: &str) -> RedisError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "An error was signalled by the server",
+        message.to_owned(),
+    ))
+}
+
+/// When a key set to expire in `ttl` by `command` expires, refused like the
+/// server does if that is out of range
+fn expiry(ttl: Duration, command: &str) -> RedisResult<Instant> {
+    Instant::now()
+        .checked_add(ttl)
+        .ok_or_else(|| server_error(&format!("invalid expire time in '{}' command", command)))
+}
+
+fn int(arg: &[u8]) -> RedisResult<i64> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or_else(|| server_error("value is not an integer or out of range"))
+}
+
+/// `key` unless it expired, in which case it is removed
+fn live<'a>(keys: &'a mut HashMap<Vec<u8>, Entry>, key: &[u8]) -> Option<&'a mut Entry> {
+    let expired = matches!(
+        keys.get(key),
+        Some(Entry { expires_at: Some(at), .. }) if *at <= Instant::now()
+    );
+    if expired {
+        keys.remove(key);
+    }
+    keys.get_mut(key)
+}
+
+fn apply(keys: &mut HashMap<Vec<u8>, Entry>, args: &[&[u8]]) -> RedisResult<Value> {
+    let name = match args.first() {
+        Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+        None => return Err(server_error("empty command")),
+    };
+    let args = &args[1..];
+    let arity = |ok: bool| {
+        if ok {
+            Ok(())
+        } else {
+            Err(server_error(&format!(
+                "wrong number of arguments for '{}' command",
+                name.to_ascii_lowercase()
+            )))
+        }
+    };
+
+    match name.as_str() {
+        "PING" => {
+            arity(args.len() <= 1)?;
+            Ok(match args.first() {
+                Some(msg) => Value::Data(msg.to_vec()),
+                None => Value::Status("PONG".into()),
+            })
+        }
+        "GET" => {
+            arity(args.len() == 1)?;
+            Ok(match live(keys, args[0]) {
+                Some(entry) => Value::Data(entry.value.clone()),
+                None => Value::Nil,
+            })
+        }
+        "MGET" => {
+            arity(!args.is_empty())?;
+            let values = args
+                .iter()
+                .map(|key| match live(keys, key) {
+                    Some(entry) => Value::Data(entry.value.clone()),
+                    None => Value::Nil,
+                })
+                .collect();
+            Ok(Value::Bulk(values))
+        }
+        "SET" => {
+            arity(args.len() >= 2)?;
+            let (mut expires_at, mut nx, mut xx) = (None, false, false);
+            let mut opts = args[2..].iter();
+            while let Some(opt) = opts.next() {
+                match &*opt.to_ascii_uppercase() {
+                    b"NX" => nx = true,
+                    b"XX" => xx = true,
+                    unit @ (b"EX" | b"PX") => {
+                        let n = int(opts.next().ok_or_else(|| server_error("syntax error"))?)?;
+                        if n <= 0 {
+                            return Err(server_error("invalid expire time in 'set' command"));
+                        }
+                        let ttl = if unit == b"EX" {
+                            Duration::from_secs(n as u64)
+                        } else {
+                            Duration::from_millis(n as u64)
+                        };
+                        expires_at = Some(expiry(ttl, "set")?);
+                    }
+                    _ => return Err(server_error("syntax error")),
+                }
+            }
+            if nx && xx {
+                return Err(server_error("syntax error"));
+            }
+            let exists = live(keys, args[0]).is_some();
+            if (nx && exists) || (xx && !exists) {
+                return Ok(Value::Nil);
+            }
+            let entry = Entry {
+                value: args[1].to_vec(),
+                expires_at,
+            };
+            keys.insert(args[0].to_vec(), entry);
+            Ok(Value::Okay)
+        }
+        "MSET" => {
+            arity(!args.is_empty() && args.len().is_multiple_of(2))?;
+            for pair in args.chunks(2) {
+                let entry = Entry {
+                    value: pair[1].to_vec(),
+                    expires_at: None,
+                };
+                keys.insert(pair[0].to_vec(), entry);
+            }
+            Ok(Value::Okay)
+        }
+        "DEL" => {
+            arity(!args.is_empty())?;
+            let deleted = args
+                .iter()
+                .filter(|key| live(keys, key).is_some() && keys.remove(**key).is_some())
+                .count();
+            Ok(Value::Int(deleted as i64))
+        }
+        "EXISTS" => {
+            arity(!args.is_empty())?;
+            let found = args.iter().filter(|key| live(keys, key).is_some()).count();
+            Ok(Value::Int(found as i64))
+        }
+        "EXPIRE" | "PEXPIRE" => {
+            arity(args.len() == 2)?;
+            let n = int(args[1])?;
+            let expires_at = match n {
+                // already expired
+                ..=0 => None,
+                n if name == "EXPIRE" => Some(expiry(Duration::from_secs(n as u64), "expire")?),
+                n => Some(expiry(Duration::from_millis(n as u64), "pexpire")?),
+            };
+            if live(keys, args[0]).is_none() {
+                return Ok(Value::Int(0));
+            }
+            match expires_at {
+                None => {
+                    keys.remove(args[0]);
+                }
+                Some(at) => {
+                    if let Some(entry) = keys.get_mut(args[0]) {
+                        entry.expires_at = Some(at);
+                    }
+                }
+            }
+            Ok(Value::Int(1))
+        }
+        "PERSIST" => {
+            arity(args.len() == 1)?;
+            let persisted = match live(keys, args[0]) {
+                Some(entry) => entry.expires_at.take().is_some(),
+                None => false,
+            };
+            Ok(Value::Int(persisted as i64))
+        }
+        "TTL" | "PTTL" => {
+            arity(args.len() == 1)?;
+            let ttl = match live(keys, args[0]) {
+                None => -2,
+                Some(Entry {
+                    expires_at: None, ..
+                }) => -1,
+                Some(Entry {
+                    expires_at: Some(at),
+                    ..
+                }) => {
+                    let left = at.saturating_duration_since(Instant::now()).as_millis() as i64;
+                    if name == "TTL" {
+                        (left + 500) / 1000
+                    } else {
+                        left
+                    }
+                }
+            };
+            Ok(Value::Int(ttl))
+        }
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let by = match name.as_str() {
+                "INCR" | "DECR" => {
+                    arity(args.len() == 1)?;
+                    1
+                }
+                _ => {
+                    arity(args.len() == 2)?;
+                    int(args[1])?
+                }
+            };
+            let by = if name.starts_with("DECR") {
+                by.checked_neg()
+                    .ok_or_else(|| server_error("decrement would overflow"))?
+            } else {
+                by
+            };
+            let current = match live(keys, args[0]) {
+                Some(entry) => int(&entry.value)?,
+                None => 0,
+            };
+            let value = current
+                .checked_add(by)
+                .ok_or_else(|| server_error("increment or decrement would overflow"))?;
+            match keys.get_mut(args[0]) {
+                Some(entry) => entry.value = value.to_string().into_bytes(),
+                None => {
+                    let entry = Entry {
+                        value: value.to_string().into_bytes(),
+                        expires_at: None,
+                    };
+                    keys.insert(args[0].to_vec(), entry);
+                }
+            }
+            Ok(Value::Int(value))
+        }
+        _ => Err(RedisError::from((
+            ErrorKind::ClientError,
+            "command not supported by MockRedis",
+            name,
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn mock_handles_string_commands() {
+        let redis: BoxedRedis = Box::new(MockRedis::new());
+        let _: () = redis
+            .exec(redis::cmd("SET").arg(&["name", "ana"]))
+            .await
+            .unwrap();
+        let name: Option<String> = redis.exec(redis::cmd("GET").arg("name")).await.unwrap();
+        assert_eq!(name.as_deref(), Some("ana"));
+        let set: Option<()> = redis
+            .exec(redis::cmd("SET").arg(&["name", "bob", "NX"]))
+            .await
+            .unwrap();
+        assert!(set.is_none());
+
+        let hits: i64 = redis.exec(redis::cmd("INCR").arg("hits")).await.unwrap();
+        assert_eq!(hits, 1);
+        let hits: i64 = redis
+            .exec(redis::cmd("DECRBY").arg("hits").arg(3))
+            .await
+            .unwrap();
+        assert_eq!(hits, -2);
+        let err = redis
+            .exec::<i64>(redis::cmd("INCR").arg("name"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ResponseError);
+
+        let found: u64 = redis
+            .exec(redis::cmd("EXISTS").arg(&["name", "hits", "missing"]))
+            .await
+            .unwrap();
+        assert_eq!(found, 2);
+        let deleted: u64 = redis
+            .exec(redis::cmd("DEL").arg(&["name", "missing"]))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        let err = redis
+            .exec::<()>(&mut redis::cmd("FLUSHALL"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ClientError);
+    }
+
+    #[actix_rt::test]
+    async fn mock_refuses_out_of_range_expire_times() {
+        let redis = MockRedis::new();
+        for ttl in ["9223372036854775807", "0", "-1"] {
+            let err = redis
+                .exec::<()>(redis::cmd("SET").arg(&["key", "1", "EX", ttl]))
+                .await
+                .unwrap_err();
+            assert_eq!(err.detail(), Some("invalid expire time in 'set' command"));
+        }
+        let _: () = redis
+            .exec(redis::cmd("SET").arg(&["key", "1"]))
+            .await
+            .unwrap();
+        let err = redis
+            .exec::<()>(redis::cmd("EXPIRE").arg("key").arg(i64::MAX))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.detail(),
+            Some("invalid expire time in 'expire' command")
+        );
+        let ttl: i64 = redis.exec(redis::cmd("TTL").arg("key")).await.unwrap();
+        assert_eq!(ttl, -1);
+    }
+
+    #[actix_rt::test]
+    async fn mock_expires_keys() {
+        let redis = MockRedis::new();
+        let _: () = redis
+            .exec(redis::cmd("SET").arg(&["session", "1", "PX", "20"]))
+            .await
+            .unwrap();
+        let _: () = redis
+            .exec(redis::cmd("SET").arg(&["user", "1"]))
+            .await
+            .unwrap();
+        let ttl: i64 = redis.exec(redis::cmd("TTL").arg("user")).await.unwrap();
+        assert_eq!(ttl, -1);
+        let set: bool = redis
+            .exec(redis::cmd("EXPIRE").arg("user").arg(60))
+            .await
+            .unwrap();
+        assert!(set);
+        let ttl: i64 = redis.exec(redis::cmd("TTL").arg("user")).await.unwrap();
+        assert_eq!(ttl, 60);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let session: Option<String> = redis.exec(redis::cmd("GET").arg("session")).await.unwrap();
+        assert!(session.is_none());
+        let ttl: i64 = redis.exec(redis::cmd("PTTL").arg("session")).await.unwrap();
+        assert_eq!(ttl, -2);
+
+        redis.clear();
+        let found: bool = redis.exec(redis::cmd("EXISTS").arg("user")).await.unwrap();
+        assert!(!found);
+    }
+
+    #[actix_rt::test]
+    async fn mock_runs_pipelines() {
+        let redis = MockRedis::new();
+        let (a, b): (i64, Vec<Option<String>>) = redis
+            .exec_pipe(
+                redis::pipe()
+                    .cmd("MSET")
+                    .arg(&["a", "1", "b", "2"])
+                    .ignore()
+                    .cmd("INCRBY")
+                    .arg("a")
+                    .arg(9)
+                    .cmd("MGET")
+                    .arg(&["a", "b", "c"]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(a, 10);
+        assert_eq!(b, vec![Some("10".into()), Some("2".into()), None]);
+
+        let (b,): (i64,) = redis
+            .exec_pipe(
+                redis::pipe()
+                    .atomic()
+                    .cmd("INCR")
+                    .arg("b")
+                    .cmd("DEL")
+                    .arg("a")
+                    .ignore(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(b, 3);
+    }
+
+    #[actix_rt::test]
+    async fn mock_pipelines_run_past_errors() {
+        let redis = MockRedis::new();
+        let get = |key: &'static str| {
+            let redis = redis.clone();
+            async move {
+                redis
+                    .exec::<Option<String>>(redis::cmd("GET").arg(key))
+                    .await
+                    .unwrap()
+            }
+        };
+        let _: () = redis
+            .exec(redis::cmd("SET").arg(&["word", "abc"]))
+            .await
+            .unwrap();
+
+        // the first error is returned, once the later commands ran
+        let err = redis
+            .exec_pipe::<()>(
+                redis::pipe()
+                    .cmd("INCR")
+                    .arg("word")
+                    .cmd("SET")
+                    .arg(&["after", "1"])
+                    .ignore(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ResponseError);
+        assert_eq!(get("after").await.as_deref(), Some("1"));
+
+        // errors when EXEC runs don't undo the other commands
+        let err = redis
+            .exec_pipe::<()>(
+                redis::pipe()
+                    .atomic()
+                    .cmd("SET")
+                    .arg(&["in_exec", "1"])
+                    .cmd("INCR")
+                    .arg("word"),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ResponseError);
+        assert_eq!(get("in_exec").await.as_deref(), Some("1"));
+
+        // errors when queued discard the whole transaction
+        let err = redis
+            .exec_pipe::<()>(
+                redis::pipe()
+                    .atomic()
+                    .cmd("SET")
+                    .arg(&["discarded", "1"])
+                    .cmd("GET"),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::ExecAbortError);
+        assert_eq!(get("discarded").await, None);
+    }
+}
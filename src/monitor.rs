@@ -0,0 +1,182 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Tailing the commands a server processes
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::stream::{BoxStream, StreamExt};
+use redis::{ErrorKind, RedisError, RedisResult};
+
+use crate::Redis;
+
+/// A command reported by MONITOR, see [Redis::monitor]
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorLine {
+    /// When the server processed the command
+    pub timestamp: SystemTime,
+    /// Database the command ran against
+    pub db: i64,
+    /// Address of the client that sent the command, like `127.0.0.1:60866`,
+    /// or `lua` for commands run by scripts
+    pub client: String,
+    /// Command name followed by its arguments, unescaped
+    pub args: Vec<Vec<u8>>,
+}
+
+impl Redis {
+    /// Stream every command the server processes (MONITOR), for debugging.
+    ///
+    /// MONITOR is expensive: the server formats and sends every command to
+    /// each monitoring client, which can cut its throughput by half or more.
+    /// Keep it to short sessions and never leave it running in production.
+    ///
+    /// Uses a connection of its own, which can't serve commands anymore and
+    /// is closed when the stream is dropped. Lines that can't be parsed are
+    /// yielded as errors. Not available in cluster mode.
+    pub async fn monitor(&self) -> RedisResult<BoxStream<'static, RedisResult<MonitorLine>>> {
+        let client = match self.client.single().await? {
+            Some(client) => client,
+            None => {
+                return Err((
+                    ErrorKind::ClientError,
+                    "MONITOR is not supported in cluster mode",
+                )
+                    .into())
+            }
+        };
+        let mut monitor = client.get_async_connection().await?.into_monitor();
+        monitor.monitor().await?;
+        Ok(monitor
+            .into_on_message::<String>()
+            .map(|line| parse_monitor_line(&line))
+            .boxed())
+    }
+}
+
+fn invalid_line(line: &str) -> RedisError {
+    (
+        ErrorKind::TypeError,
+        "Response was of incompatible type",
+        format!("expected a MONITOR line, got {:?}", line),
+    )
+        .into()
+}
+
+/// Parse a MONITOR line: `<unix time> [<db> <client>] "<arg>" "<arg>" ...`,
+/// where arguments are quoted and escaped like `sdscatrepr` does
+fn parse_monitor_line(line: &str) -> RedisResult<MonitorLine> {
+    let invalid = || invalid_line(line);
+    let (timestamp, rest) = line.split_once(' ').ok_or_else(invalid)?;
+    let timestamp: f64 = timestamp.parse().map_err(|_| invalid())?;
+    let rest = rest.strip_prefix('[').ok_or_else(invalid)?;
+    let (source, rest) = rest.split_once("] ").ok_or_else(invalid)?;
+    let (db, client) = source.split_once(' ').ok_or_else(invalid)?;
+    Ok(MonitorLine {
+        timestamp: UNIX_EPOCH + Duration::from_secs_f64(timestamp),
+        db: db.parse().map_err(|_| invalid())?,
+        client: client.to_owned(),
+        args: parse_quoted_args(rest).ok_or_else(invalid)?,
+    })
+}
+
+/// Split space-separated, double-quoted and escaped arguments
+fn parse_quoted_args(s: &str) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut bytes = s.bytes();
+    loop {
+        match bytes.next() {
+            None => return Some(args),
+            Some(b' ') => continue,
+            Some(b'"') => {}
+            Some(_) => return None,
+        }
+        let mut arg = Vec::new();
+        loop {
+            match bytes.next()? {
+                b'"' => break,
+                b'\\' => arg.push(match bytes.next()? {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'x' => {
+                        let hex = [bytes.next()?, bytes.next()?];
+                        u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+                    }
+                    other => other,
+                }),
+                b => arg.push(b),
+            }
+        }
+        args.push(arg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn parse_monitor_lines() {
+        let line = parse_monitor_line(
+            r#"1339518083.107412 [0 127.0.0.1:60866] "set" "key" "say \"hi\"\\n\x00\n""#,
+        )
+        .unwrap();
+        assert_eq!(
+            line.timestamp,
+            UNIX_EPOCH + Duration::from_secs_f64(1339518083.107412)
+        );
+        assert_eq!(line.db, 0);
+        assert_eq!(line.client, "127.0.0.1:60866");
+        assert_eq!(
+            line.args,
+            vec![
+                b"set".to_vec(),
+                b"key".to_vec(),
+                b"say \"hi\"\\n\x00\n".to_vec()
+            ]
+        );
+
+        let line = parse_monitor_line(r#"1339518083.107412 [3 lua] "get" """#).unwrap();
+        assert_eq!(line.db, 3);
+        assert_eq!(line.client, "lua");
+        assert_eq!(line.args, vec![b"get".to_vec(), Vec::new()]);
+
+        let line = parse_monitor_line(r#"1339518083.1 [0 unix:/tmp/redis.sock] "ping""#).unwrap();
+        assert_eq!(line.client, "unix:/tmp/redis.sock");
+
+        assert!(parse_monitor_line("OK").is_err());
+        assert!(parse_monitor_line(r#"1.0 [0 lua] "unterminated"#).is_err());
+        assert!(parse_monitor_line(r#"1.0 [0 lua] unquoted"#).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn monitor_sees_commands() {
+        const KEY: &str = "monitor_sees_commands";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let mut lines = r.monitor().await.unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("SET").arg(&[KEY, "a \"quoted\" value"]))
+            .await
+            .unwrap();
+        loop {
+            let line = lines.next().await.unwrap().unwrap();
+            if line.args.get(1).map(Vec::as_slice) == Some(KEY.as_bytes()) {
+                assert_eq!(line.args[0].to_ascii_uppercase(), b"SET");
+                assert_eq!(line.args[2], b"a \"quoted\" value");
+                break;
+            }
+        }
+    }
+}
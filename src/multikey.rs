@@ -0,0 +1,330 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Multi-key operations split by hash slot
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use redis::{from_redis_value, FromRedisValue, RedisResult, Value};
+
+use crate::{slot_for, RedisConnection};
+
+impl RedisConnection {
+    /// Indices into `keys` that can go out together: one group per slot in
+    /// cluster mode, a single one otherwise
+    pub(crate) fn key_groups(&self, keys: &[&str]) -> Vec<Vec<usize>> {
+        if self.is_cluster() {
+            group_by_slot(keys)
+        } else {
+            vec![(0..keys.len()).collect()]
+        }
+    }
+
+    /// Run `command(key)` for every key in `keys` and return the replies in
+    /// the order of `keys`.
+    ///
+    /// Unlike the multi-key helpers that reject keys spanning hash slots, this
+    /// works with any keys: in cluster mode they are grouped by slot and every
+    /// group is sent as one pipeline, with the pipelines running
+    /// concurrently. In single mode all commands go out as a single pipeline.
+    /// The commands aren't atomic as a whole, even within a slot.
+    pub async fn exec_multikey<T, F>(&self, keys: &[&str], command: F) -> RedisResult<Vec<T>>
+    where
+        T: FromRedisValue,
+        F: Fn(&str) -> redis::Cmd,
+    {
+        if keys.is_empty() {
+            return Ok(Vec::new());
+        }
+        let groups = self.key_groups(keys);
+        let pipes: Vec<redis::Pipeline> = groups
+            .iter()
+            .map(|indices| {
+                let mut pipe = redis::pipe();
+                for i in indices {
+                    pipe.add_command(command(keys[*i]));
+                }
+                pipe
+            })
+            .collect();
+        let replies =
+            futures::future::join_all(pipes.iter().map(|pipe| self.exec_pipe::<Vec<Value>>(pipe)))
+                .await;
+
+        let mut ordered: Vec<Option<Value>> = vec![None; keys.len()];
+        for (indices, replies) in groups.iter().zip(replies) {
+            for (i, reply) in indices.iter().zip(replies?) {
+                ordered[*i] = Some(reply);
+            }
+        }
+        ordered
+            .into_iter()
+            .map(|reply| from_redis_value(&reply.unwrap_or(Value::Nil)))
+            .collect()
+    }
+
+    /// Values of `keys` (MGET) by key, leaving out the keys that don't exist.
+    ///
+    /// Keys may span hash slots: in cluster mode one MGET is sent per slot,
+    /// concurrently, as with [Self::exec_multikey]. Values that don't
+    /// convert to `T` fail the whole call.
+    pub async fn get_map<T: FromRedisValue>(
+        &self,
+        keys: &[&str],
+    ) -> RedisResult<HashMap<String, T>> {
+        if keys.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let groups = self.key_groups(keys);
+        let cmds: Vec<redis::Cmd> = groups
+            .iter()
+            .map(|indices| {
+                let mut cmd = redis::cmd("MGET");
+                for i in indices {
+                    cmd.arg(keys[*i]);
+                }
+                cmd
+            })
+            .collect();
+        let replies = futures::future::join_all(
+            cmds.into_iter()
+                .map(|mut cmd| async move { self.exec::<Vec<Value>>(&mut cmd).await }),
+        )
+        .await;
+
+        let mut map = HashMap::new();
+        for (indices, values) in groups.iter().zip(replies) {
+            for (i, value) in indices.iter().zip(values?) {
+                if value != Value::Nil {
+                    map.insert(keys[*i].to_owned(), from_redis_value(&value)?);
+                }
+            }
+        }
+        Ok(map)
+    }
+
+    /// Delete `keys` (DEL), returning how many existed. Keys may span hash
+    /// slots, see [Self::exec_multikey].
+    pub async fn del_many(&self, keys: &[&str]) -> RedisResult<u64> {
+        let deleted: Vec<u64> = self
+            .exec_multikey(keys, |key| {
+                let mut cmd = redis::cmd("DEL");
+                cmd.arg(key);
+                cmd
+            })
+            .await?;
+        Ok(deleted.into_iter().sum())
+    }
+
+    /// Make every key in `keys` expire after `ttl` (PEXPIRE), rounded up to a
+    /// millisecond, returning for each key, in the order of `keys`, whether
+    /// it existed and got the expiry. Keys may span hash slots, see [Self::exec_multikey].
+    pub async fn expire_many(&self, keys: &[&str], ttl: Duration) -> RedisResult<Vec<bool>> {
+        let ttl = (ttl.as_millis() as u64).max(1);
+        self.exec_multikey(keys, |key| {
+            let mut cmd = redis::cmd("PEXPIRE");
+            cmd.arg(key).arg(ttl);
+            cmd
+        })
+        .await
+    }
+
+    /// Number of `keys` that exist (EXISTS), counting repeated keys as many
+    /// times as they are given. Keys may span hash slots, see
+    /// [Self::exec_multikey].
+    pub async fn exists_many(&self, keys: &[&str]) -> RedisResult<u64> {
+        let existing: Vec<u64> = self
+            .exec_multikey(keys, |key| {
+                let mut cmd = redis::cmd("EXISTS");
+                cmd.arg(key);
+                cmd
+            })
+            .await?;
+        Ok(existing.into_iter().sum())
+    }
+
+    /// The keys of `keys` that don't exist, in the order of `keys`, to know
+    /// what to recompute. Keys may span hash slots, see
+    /// [Self::exec_multikey].
+    pub async fn missing_keys(&self, keys: &[&str]) -> RedisResult<Vec<String>> {
+        let existing: Vec<bool> = self
+            .exec_multikey(keys, |key| {
+                let mut cmd = redis::cmd("EXISTS");
+                cmd.arg(key);
+                cmd
+            })
+            .await?;
+        Ok(keys
+            .iter()
+            .zip(existing)
+            .filter(|(_, exists)| !exists)
+            .map(|(key, _)| (*key).to_owned())
+            .collect())
+    }
+}
+
+/// Indices into `keys`, grouped by the hash slot of the key they point to
+fn group_by_slot(keys: &[&str]) -> Vec<Vec<usize>> {
+    let mut by_slot: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+    for (i, key) in keys.iter().enumerate() {
+        by_slot.entry(slot_for(key)).or_default().push(i);
+    }
+    by_slot.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn group_by_slot_keeps_indices() {
+        let keys = ["{a}1", "{b}1", "{a}2", "{c}1", "{b}2"];
+        let mut groups = group_by_slot(&keys);
+        groups.sort();
+        assert_eq!(groups, vec![vec![0, 2], vec![1, 4], vec![3]]);
+    }
+
+    async fn multikey_preserves_order(r: Redis) {
+        let con = r.get_client();
+        let keys: Vec<String> = (0..20)
+            .map(|i| format!("multikey_preserves_order_{}", i))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        con.del_many(&keys).await.unwrap();
+        let _: Vec<()> = con
+            .exec_multikey(&keys, |key| {
+                let mut cmd = redis::cmd("SET");
+                cmd.arg(key).arg(format!("value of {}", key));
+                cmd
+            })
+            .await
+            .unwrap();
+
+        let mut lookup = keys.clone();
+        lookup.reverse();
+        lookup.insert(3, "multikey_preserves_order_missing");
+        let values: Vec<Option<String>> = con
+            .exec_multikey(&lookup, |key| {
+                let mut cmd = redis::cmd("GET");
+                cmd.arg(key);
+                cmd
+            })
+            .await
+            .unwrap();
+        for (key, value) in lookup.iter().zip(&values) {
+            if key.ends_with("missing") {
+                assert!(value.is_none());
+            } else {
+                assert_eq!(value.as_deref(), Some(format!("value of {}", key).as_str()));
+            }
+        }
+
+        assert_eq!(con.exists_many(&lookup).await.unwrap(), 20);
+        assert_eq!(con.del_many(&lookup).await.unwrap(), 20);
+        assert_eq!(con.exists_many(&keys).await.unwrap(), 0);
+    }
+
+    async fn get_map_skips_missing(r: Redis) {
+        let con = r.get_client();
+        let keys: Vec<String> = (0..10)
+            .map(|i| format!("get_map_skips_missing_{}", i))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        con.del_many(&keys).await.unwrap();
+        for (i, key) in keys.iter().enumerate().filter(|(i, _)| i % 3 == 0) {
+            let _: () = con.exec(redis::cmd("SET").arg(*key).arg(i)).await.unwrap();
+        }
+
+        let map: HashMap<String, u64> = con.get_map(&keys).await.unwrap();
+        let mut expected = HashMap::new();
+        for i in [0, 3, 6, 9] {
+            expected.insert(keys[i].to_owned(), i as u64);
+        }
+        assert_eq!(map, expected);
+        assert!(con.get_map::<u64>(&[]).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn missing_keys_keeps_input_order() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let keys: Vec<String> = (0..6)
+            .map(|i| format!("missing_keys_keeps_input_order_{}", i))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        con.del_many(&keys).await.unwrap();
+        for key in [keys[0], keys[3], keys[4]] {
+            let _: () = con.exec(redis::cmd("SET").arg(key).arg(1)).await.unwrap();
+        }
+
+        let mut lookup = keys.clone();
+        lookup.reverse();
+        assert_eq!(
+            con.missing_keys(&lookup).await.unwrap(),
+            vec![keys[5], keys[2], keys[1]]
+        );
+        assert!(con.missing_keys(&[]).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn expire_many_reports_existing_keys() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let keys: Vec<String> = (0..5)
+            .map(|i| format!("expire_many_reports_existing_keys_{}", i))
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        con.del_many(&keys).await.unwrap();
+        for key in [keys[1], keys[2], keys[4]] {
+            let _: () = con.exec(redis::cmd("SET").arg(key).arg(1)).await.unwrap();
+        }
+
+        let expired = con
+            .expire_many(&keys, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(expired, vec![false, true, true, false, true]);
+        let ttl: i64 = con.exec(redis::cmd("TTL").arg(keys[4])).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+    }
+
+    #[actix_rt::test]
+    async fn get_map_omits_missing_keys() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        get_map_skips_missing(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn get_map_omits_missing_keys_across_slots() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        get_map_skips_missing(r).await;
+    }
+
+    #[actix_rt::test]
+    async fn exec_multikey_preserves_order() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        multikey_preserves_order(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn exec_multikey_preserves_order_across_slots() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        multikey_preserves_order(r).await;
+    }
+}
@@ -0,0 +1,238 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Hooks that run after every command
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use redis::{Cmd, Pipeline};
+
+use crate::{command, RedisConnection};
+
+/// Callback run after every command with its rendered form and the time it
+/// took, whatever its outcome. Set through [crate::RedisOptions::observer].
+///
+/// Arguments of [crate::RedisOptions::sensitive_commands] are redacted before
+/// the callback sees them. Pipelines are reported once, with their commands
+/// separated by `; `.
+#[derive(Clone)]
+pub struct Observer(Arc<ObserverFn>);
+
+type ObserverFn = dyn Fn(&str, Duration) + Send + Sync;
+
+impl Observer {
+    /// Wrap `f` as an observer
+    pub fn new(f: impl Fn(&str, Duration) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+impl RedisConnection {
+    /// The last command or pipeline sent through this connection or a clone
+    /// of it, rendered as for [Observer]s with sensitive arguments redacted,
+    /// to tell what was sent when a command fails deep in an application.
+    /// Commands are recorded once they complete, whatever their outcome, so
+    /// after an error this names the command that failed.
+    ///
+    /// Only kept with [crate::RedisOptions::record_last_command], `None`
+    /// otherwise or before the first command.
+    pub fn last_command(&self) -> Option<String> {
+        self.state.last_command.lock().unwrap().clone()
+    }
+
+    /// Whether commands have to be rendered at all
+    fn is_watched(&self, elapsed: Duration) -> bool {
+        self.is_slow(elapsed) || self.options.observer.is_some() || self.options.record_last_command
+    }
+
+    /// Called with the time `cmd` took, whatever its outcome
+    pub(crate) fn observe(&self, cmd: &Cmd, elapsed: Duration) {
+        if self.is_watched(elapsed) {
+            let desc = command::describe(cmd, &self.options.sensitive_commands);
+            self.report(&desc, elapsed);
+        }
+    }
+
+    /// Called with the time `pipe` took, whatever its outcome
+    pub(crate) fn observe_pipe(&self, pipe: &Pipeline, elapsed: Duration) {
+        if self.is_watched(elapsed) {
+            let descs: Vec<_> = pipe
+                .cmd_iter()
+                .map(|cmd| command::describe(cmd, &self.options.sensitive_commands))
+                .collect();
+            self.report(&descs.join("; "), elapsed);
+        }
+    }
+
+    fn report(&self, desc: &str, elapsed: Duration) {
+        if self.is_slow(elapsed) {
+            report_slow(desc, elapsed);
+        }
+        if let Some(observer) = &self.options.observer {
+            (observer.0)(desc, elapsed);
+        }
+        if self.options.record_last_command {
+            *self.state.last_command.lock().unwrap() = Some(desc.to_owned());
+        }
+    }
+
+    fn is_slow(&self, elapsed: Duration) -> bool {
+        matches!(self.options.slow_command_threshold, Some(threshold) if elapsed > threshold)
+    }
+}
+
+#[cfg(feature = "log")]
+fn report_slow(desc: &str, elapsed: Duration) {
+    log::warn!("slow redis command: {} took {:?}", desc, elapsed);
+}
+
+#[cfg(not(feature = "log"))]
+fn report_slow(_desc: &str, _elapsed: Duration) {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn observer_never_sees_passwords() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        let options = RedisOptions {
+            observer: Some(Observer::new(move |cmd, _| {
+                sink.lock().unwrap().push(cmd.to_owned())
+            })),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let err = con
+            .exec::<()>(redis::cmd("AUTH").arg("nobody").arg("hunter2"))
+            .await;
+        assert!(err.is_err());
+        let _: () = con
+            .exec(
+                redis::cmd("SET")
+                    .arg("observer_never_sees_passwords")
+                    .arg(1),
+            )
+            .await
+            .unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec!["AUTH [redacted]", "SET observer_never_sees_passwords 1"]
+        );
+        assert!(seen.iter().all(|cmd| !cmd.contains("hunter2")));
+    }
+
+    #[actix_rt::test]
+    async fn last_command_is_recorded_redacted() {
+        const KEY: &str = "last_command_is_recorded_redacted";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("SET").arg(&[KEY, "1"]))
+            .await
+            .unwrap();
+        assert!(r.get_client().last_command().is_none());
+
+        let options = RedisOptions {
+            record_last_command: true,
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let con = r.get_client();
+        assert!(con.last_command().is_none());
+        let _: () = con.exec(redis::cmd("SET").arg(&[KEY, "2"])).await.unwrap();
+        assert_eq!(
+            r.get_client().last_command().as_deref(),
+            Some("SET last_command_is_recorded_redacted 2")
+        );
+
+        let err = con
+            .exec::<()>(redis::cmd("AUTH").arg("nobody").arg("hunter2"))
+            .await;
+        assert!(err.is_err());
+        assert_eq!(con.last_command().as_deref(), Some("AUTH [redacted]"));
+
+        let mut pipe = redis::pipe();
+        pipe.cmd("GET").arg(KEY).cmd("TTL").arg(KEY);
+        let _: (String, i64) = con.exec_pipe(&pipe).await.unwrap();
+        assert_eq!(
+            con.last_command().as_deref(),
+            Some("GET last_command_is_recorded_redacted; TTL last_command_is_recorded_redacted")
+        );
+    }
+}
+
+#[cfg(all(test, feature = "log"))]
+mod log_tests {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use crate::*;
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    static CAPTURE: Capture = Capture(Mutex::new(Vec::new()));
+
+    #[actix_rt::test]
+    async fn slow_commands_are_logged() {
+        log::set_logger(&CAPTURE).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let options = RedisOptions {
+            slow_command_threshold: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        assert!(r.get_client().ping().await);
+        assert!(CAPTURE.0.lock().unwrap().is_empty());
+
+        // DEBUG SLEEP is disabled by default since Redis 7, so busy-wait in
+        // a script instead
+        let script = "local s = redis.call('TIME')[1]; while redis.call('TIME')[1] - s < 1 do end";
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("EVAL").arg(script).arg(0))
+            .await
+            .unwrap();
+        let logged = CAPTURE.0.lock().unwrap();
+        assert_eq!(logged.len(), 1);
+        assert!(logged[0].starts_with("slow redis command: EVAL took"));
+    }
+}
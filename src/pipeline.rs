@@ -0,0 +1,425 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Pipelines and transactions that work the same in both deployment modes
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use redis::{ConnectionLike, ErrorKind, FromRedisValue, Pipeline, RedisResult, Value};
+
+use crate::slot::{routing_key, slot_of};
+use crate::{RedisConnection, RedisOptions};
+
+impl RedisConnection {
+    /// Run `pipe` and convert the replies of the commands that weren't
+    /// ignored to `T`. An atomic pipeline is run as with
+    /// [Self::exec_transaction].
+    ///
+    /// In cluster mode the commands are grouped by the hash slot of their
+    /// key and every group is sent as one pipeline, with the pipelines
+    /// running concurrently as in [Self::exec_multikey]; replies still come
+    /// back in the order of `pipe`. Commands without a key go out with the
+    /// first group. Groups aren't atomic with respect to each other, and a
+    /// failing group fails the call even if other groups were applied.
+    pub async fn exec_pipeline<T: FromRedisValue>(&self, pipe: &mut Pipeline) -> RedisResult<T> {
+        self.exec_grouped(pipe).await
+    }
+
+    /// Run `pipe` as a MULTI/EXEC transaction and convert the replies of the
+    /// commands that weren't ignored to `T`. `pipe` is made atomic first.
+    ///
+    /// In cluster mode every key of the transaction must hash to the same
+    /// slot, or it fails with [ErrorKind::CrossSlot] before anything is sent.
+    pub async fn exec_transaction<T: FromRedisValue>(&self, pipe: &mut Pipeline) -> RedisResult<T> {
+        pipe.atomic();
+        self.exec_grouped(pipe).await
+    }
+
+    /// Run an optimistic transaction: WATCH `keys`, let `build` read through
+    /// the connection it is given and return the commands to queue, then run
+    /// them with MULTI/EXEC and convert the replies of the commands that
+    /// weren't ignored to `T`. If a watched key changed in the meantime, EXEC
+    /// aborts and it starts over with a new call to `build`, for as long as
+    /// conflicts keep happening. Errors from `build` are returned as is.
+    ///
+    /// WATCH is connection state that clones of this connection would
+    /// interfere with, so the transaction runs on a connection of its own.
+    /// That connection isn't reconnected by
+    /// [crate::RedisOptions::auto_reconnect], which would drop the watches.
+    ///
+    /// In cluster mode the watched keys and the keys of the queued commands
+    /// must all hash to the same slot, or it fails with
+    /// [ErrorKind::CrossSlot].
+    pub async fn transaction<T, F, Fut>(&self, keys: &[&str], mut build: F) -> RedisResult<T>
+    where
+        T: FromRedisValue,
+        F: FnMut(RedisConnection) -> Fut,
+        Fut: Future<Output = RedisResult<Pipeline>>,
+    {
+        let route = match keys.first() {
+            Some(key) => key.as_bytes(),
+            None => {
+                return Err((ErrorKind::ClientError, "a transaction needs keys to WATCH").into())
+            }
+        };
+        if self.is_cluster()
+            && keys
+                .iter()
+                .any(|key| slot_of(key.as_bytes()) != slot_of(route))
+        {
+            return Err((
+                ErrorKind::CrossSlot,
+                "watched keys don't hash to the same slot",
+            )
+                .into());
+        }
+        let options = RedisOptions {
+            auto_reconnect: None,
+            ..(*self.options).clone()
+        };
+        let con = self
+            .client
+            .open(Arc::new(options), Arc::clone(&self.breaker))
+            .await?;
+        loop {
+            let _: () = con.exec(redis::cmd("WATCH").arg(keys)).await?;
+            let mut pipe = build(con.clone()).await?;
+            pipe.atomic();
+            let replies: Option<T> = if con.is_cluster() {
+                pipe.query(&mut Replay::new(vec![
+                    con.exec_cluster_transaction(&pipe, Some(route)).await?,
+                ]))?
+            } else {
+                con.exec_pipe(&pipe).await?
+            };
+            if let Some(replies) = replies {
+                return Ok(replies);
+            }
+        }
+    }
+
+    /// [Self::exec_pipeline] for a shared pipeline
+    pub(crate) async fn exec_grouped<T: FromRedisValue>(&self, pipe: &Pipeline) -> RedisResult<T> {
+        if !self.is_cluster() || pipe.cmd_iter().next().is_none() {
+            return self.exec_pipe(pipe).await;
+        }
+        let replies = if is_atomic(pipe) {
+            vec![self.exec_cluster_transaction(pipe, None).await?]
+        } else {
+            self.exec_slot_groups(pipe).await?
+        };
+        pipe.query(&mut Replay::new(replies))
+    }
+
+    /// The replies to every command of `pipe`, sent in one pipeline per slot
+    async fn exec_slot_groups(&self, pipe: &Pipeline) -> RedisResult<Vec<Value>> {
+        let groups = group_by_slot(pipe);
+        let pipes: Vec<Pipeline> = groups
+            .iter()
+            .map(|indices| {
+                let mut group = redis::pipe();
+                for i in indices {
+                    group.add_command(pipe.cmd_iter().nth(*i).unwrap().clone());
+                }
+                group
+            })
+            .collect();
+        let replies = futures::future::join_all(
+            pipes
+                .iter()
+                .map(|group| self.exec_pipe::<Vec<Value>>(group)),
+        )
+        .await;
+
+        let mut ordered = vec![Value::Nil; pipe.cmd_iter().count()];
+        for (indices, replies) in groups.iter().zip(replies) {
+            for (i, reply) in indices.iter().zip(replies?) {
+                ordered[*i] = reply;
+            }
+        }
+        Ok(ordered)
+    }
+
+    /// The EXEC reply of the atomic `pipe`, or Nil if a WATCHed key changed.
+    ///
+    /// The cluster connection routes a pipeline by its first command, which
+    /// MULTI doesn't give it a key to do, so MULTI, the commands and EXEC are
+    /// sent after an EXISTS on the transaction's key instead. A node that
+    /// doesn't own the slot answers the EXISTS with MOVED, aborts the
+    /// transaction, and the whole pipeline is resent to the right node.
+    ///
+    /// `route` is the key to route by instead of the first key of `pipe`,
+    /// which must hash to the same slot.
+    async fn exec_cluster_transaction(
+        &self,
+        pipe: &Pipeline,
+        route: Option<&[u8]>,
+    ) -> RedisResult<Value> {
+        let mut slots = route
+            .map(slot_of)
+            .into_iter()
+            .chain(pipe.cmd_iter().filter_map(cmd_slot));
+        if let Some(first) = slots.next() {
+            if slots.any(|slot| slot != first) {
+                return Err((
+                    ErrorKind::CrossSlot,
+                    "keys in transaction don't hash to the same slot",
+                )
+                    .into());
+            }
+        }
+        let key = route.or_else(|| pipe.cmd_iter().find_map(routing_key));
+        let mut routed = redis::pipe();
+        if let Some(key) = key {
+            routed.cmd("EXISTS").arg(key);
+        }
+        routed.cmd("MULTI");
+        for cmd in pipe.cmd_iter() {
+            routed.add_command(cmd.clone());
+        }
+        routed.cmd("EXEC");
+        let mut replies: Vec<Value> = self.exec_pipe(&routed).await?;
+        Ok(replies.pop().unwrap_or(Value::Nil))
+    }
+}
+
+/// Hash slot of the key `cmd` is routed by, `None` if it has none
+fn cmd_slot(cmd: &redis::Cmd) -> Option<u16> {
+    routing_key(cmd).map(slot_of)
+}
+
+/// Indices of the commands of `pipe` grouped by the slot of their key, in
+/// slot order. Commands without a key are appended to the first group, so
+/// every group starts with a command the cluster connection can route.
+fn group_by_slot(pipe: &Pipeline) -> Vec<Vec<usize>> {
+    let mut by_slot: BTreeMap<u16, Vec<usize>> = BTreeMap::new();
+    let mut keyless = Vec::new();
+    for (i, cmd) in pipe.cmd_iter().enumerate() {
+        match cmd_slot(cmd) {
+            Some(slot) => by_slot.entry(slot).or_default().push(i),
+            None => keyless.push(i),
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = by_slot.into_values().collect();
+    match groups.first_mut() {
+        Some(first) => first.append(&mut keyless),
+        None => groups.push(keyless),
+    }
+    groups
+}
+
+/// Whether `pipe` was made atomic, which [Pipeline] doesn't expose: it
+/// only shows in how many replies it asks its connection for
+pub(crate) fn is_atomic(pipe: &Pipeline) -> bool {
+    let mut probe = Replay::new(Vec::new());
+    let _ = pipe.query::<Value>(&mut probe);
+    probe.atomic
+}
+
+/// Connection that hands out replies read beforehand, so that
+/// [Pipeline::query] drops those of ignored commands and unwraps EXEC the
+/// way it does for a real connection
+pub(crate) struct Replay {
+    replies: Vec<Value>,
+    /// Set if the pipeline skipped replies, as only transactions do
+    atomic: bool,
+}
+
+impl Replay {
+    pub(crate) fn new(replies: Vec<Value>) -> Self {
+        Self {
+            replies,
+            atomic: false,
+        }
+    }
+}
+
+impl ConnectionLike for Replay {
+    fn req_packed_command(&mut self, _cmd: &[u8]) -> RedisResult<Value> {
+        unreachable!("only pipelines are replayed")
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        _cmd: &[u8],
+        offset: usize,
+        _count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        self.atomic = offset > 0;
+        Ok(std::mem::take(&mut self.replies))
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+
+    fn check_connection(&mut self) -> bool {
+        true
+    }
+
+    fn is_open(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn pipelines_group_by_slot() {
+        let mut pipe = redis::pipe();
+        pipe.cmd("SET")
+            .arg("{a}1")
+            .arg(1)
+            .cmd("PING")
+            .cmd("GET")
+            .arg("{b}1")
+            .cmd("GET")
+            .arg("{a}2")
+            .cmd("EVAL")
+            .arg("return 1")
+            .arg(0);
+        assert!(slot_for("b") < slot_for("a"));
+        assert_eq!(group_by_slot(&pipe), vec![vec![2, 1, 4], vec![0, 3]]);
+
+        assert!(!is_atomic(&pipe));
+        pipe.atomic();
+        assert!(is_atomic(&pipe));
+    }
+
+    #[test]
+    fn replay_applies_ignore_and_exec() {
+        let mut pipe = redis::pipe();
+        pipe.cmd("SET").arg("a").arg(1).ignore().cmd("GET").arg("a");
+        let replies = vec![Value::Okay, Value::Data(b"1".to_vec())];
+        let (got,): (u64,) = pipe.query(&mut Replay::new(replies.clone())).unwrap();
+        assert_eq!(got, 1);
+
+        pipe.atomic();
+        let (got,): (u64,) = pipe
+            .query(&mut Replay::new(vec![Value::Bulk(replies)]))
+            .unwrap();
+        assert_eq!(got, 1);
+    }
+
+    async fn pipeline_and_transaction(r: Redis) {
+        let con = r.get_client();
+        let keys: Vec<String> = (0..8)
+            .map(|i| format!("exec_pipeline_keeps_order_{}", i))
+            .collect();
+        let mut pipe = redis::pipe();
+        for (i, key) in keys.iter().enumerate() {
+            pipe.cmd("SET").arg(key).arg(i).ignore();
+        }
+        for key in keys.iter().rev() {
+            pipe.cmd("GET").arg(key);
+        }
+        let values: Vec<u64> = con.exec_pipeline(&mut pipe).await.unwrap();
+        assert_eq!(values, (0..8).rev().collect::<Vec<u64>>());
+
+        let mut tx = redis::pipe();
+        tx.cmd("DEL")
+            .arg("{exec_transaction}a")
+            .ignore()
+            .cmd("INCR")
+            .arg("{exec_transaction}a")
+            .cmd("INCRBY")
+            .arg("{exec_transaction}a")
+            .arg(2);
+        let (first, second): (u64, u64) = con.exec_transaction(&mut tx).await.unwrap();
+        assert_eq!((first, second), (1, 3));
+    }
+
+    async fn transaction_retries_conflicts(r: Redis) {
+        const KEY: &str = "{transaction}counter";
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("SET").arg(KEY).arg(1)).await.unwrap();
+
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (value,): (i64,) = con
+            .transaction(&[KEY, "{transaction}other"], |tx| {
+                let attempts = Arc::clone(&attempts);
+                let other = con.clone();
+                async move {
+                    let current: i64 = tx.exec(redis::cmd("GET").arg(KEY)).await?;
+                    if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                        // a concurrent write aborts the first EXEC
+                        let _: () = other.exec(redis::cmd("INCR").arg(KEY)).await?;
+                    }
+                    let mut pipe = redis::pipe();
+                    pipe.cmd("SET")
+                        .arg(KEY)
+                        .arg(current * 10)
+                        .ignore()
+                        .cmd("GET")
+                        .arg(KEY);
+                    Ok(pipe)
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(value, 20);
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn transaction_checks_watched_keys() {
+        let r = Redis::new_lazy(RedisConfig::ClusterSeed("redis://127.0.0.1:7000".into())).unwrap();
+        let con = r.get_client();
+        let build = |_| async { Ok(redis::pipe()) };
+        let err = con
+            .transaction::<(), _, _>(&["{a}", "{b}"], build)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::CrossSlot);
+        let err = con.transaction::<(), _, _>(&[], build).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+    }
+
+    #[actix_rt::test]
+    async fn transaction_retries_watch_conflicts() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        transaction_retries_conflicts(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn transaction_retries_watch_conflicts_in_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        transaction_retries_conflicts(r).await;
+    }
+
+    #[actix_rt::test]
+    async fn exec_pipeline_keeps_order() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        pipeline_and_transaction(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn exec_pipeline_keeps_order_across_slots() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        pipeline_and_transaction(r.clone()).await;
+
+        let mut tx = redis::pipe();
+        tx.cmd("SET").arg("{a}").arg(1).cmd("SET").arg("{b}").arg(1);
+        let err = r
+            .get_client()
+            .exec_transaction::<()>(&mut tx)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), redis::ErrorKind::CrossSlot);
+    }
+}
@@ -0,0 +1,535 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Pool of dedicated connections
+use std::collections::VecDeque;
+use std::io;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+
+use futures::channel::oneshot;
+use redis::{ErrorKind, RedisResult};
+
+use crate::{Redis, RedisClient, RedisConnection};
+
+/// Order in which tasks waiting on an exhausted [RedisPool] get connections
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fairness {
+    /// First come, first served. Nobody waits forever under contention.
+    #[default]
+    Fifo,
+    /// Most recent waiter first. Keeps latency low for fresh requests at the
+    /// cost of possibly starving old ones.
+    Lifo,
+}
+
+/// Tunables for [RedisPool]
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// Most connections open at once. In cluster mode every pooled
+    /// connection keeps one connection to each node it was routed to, so
+    /// this also caps the connections per node.
+    pub max_size: usize,
+    pub fairness: Fairness,
+    /// Fail [RedisPool::get] with a timeout error
+    /// ([redis::RedisError::is_timeout]) after waiting this long for a
+    /// connection to be returned to an exhausted pool, instead of waiting
+    /// indefinitely. Opening a connection isn't bounded by it.
+    pub wait_timeout: Option<Duration>,
+    /// Close connections left idle for longer than this, which servers and
+    /// firewalls tend to drop silently, so they get reopened on demand
+    /// instead. Checked-out connections are never closed.
+    ///
+    /// A task spawned on the tokio runtime by the first checkout checks
+    /// every `max_idle / 2`. The task ends once the pool is dropped.
+    pub max_idle: Option<Duration>,
+    /// Send RESET (Redis 6.2+) on a returned connection before checking it
+    /// out again, so state a borrower left behind, like a SELECTed database,
+    /// a client name or a pending MULTI, doesn't leak to the next one. Costs
+    /// one round-trip per reuse.
+    ///
+    /// The credentials and database of the URL are restored after RESET;
+    /// servers without RESET get SELECT and an empty CLIENT SETNAME instead.
+    /// A connection that can't be reset is closed and its error returned.
+    /// Cluster connections are handed out as they are.
+    pub reset_on_return: bool,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 10,
+            fairness: Fairness::default(),
+            wait_timeout: None,
+            max_idle: None,
+            reset_on_return: false,
+        }
+    }
+}
+
+/// Pool of connections opened with [Redis::dedicated], handed out with
+/// [Self::get] and returned when the [PooledConnection] is dropped. Every
+/// [Redis] has one, see [Redis::checkout].
+///
+/// Connections are opened lazily, up to [PoolOptions::max_size]; after that
+/// callers wait for one to be returned, for up to
+/// [PoolOptions::wait_timeout]. Works the same for single and cluster
+/// deployments.
+///
+/// `Send + Sync`: clones share the connections, so one pool can serve every
+/// worker of a multi-threaded runtime.
+#[derive(Clone)]
+pub struct RedisPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    redis: Redis,
+    options: PoolOptions,
+    state: Mutex<PoolState>,
+    /// Starts the task closing idle connections, see [PoolOptions::max_idle]
+    reaper: Once,
+}
+
+#[derive(Default)]
+struct PoolState {
+    idle: Vec<IdleConnection>,
+    /// Connections open, idle or checked out
+    size: usize,
+    waiters: VecDeque<oneshot::Sender<PooledConnection>>,
+}
+
+/// Where [RedisPool::get] takes a connection from
+enum Checkout {
+    Idle(RedisConnection),
+    Open,
+    Wait(oneshot::Receiver<PooledConnection>),
+}
+
+struct IdleConnection {
+    connection: RedisConnection,
+    /// When the connection was returned
+    since: Instant,
+}
+
+/// A connection checked out of a [RedisPool], returned on drop
+pub struct PooledConnection {
+    connection: Option<RedisConnection>,
+    pool: Arc<PoolInner>,
+}
+
+impl Redis {
+    /// Create a pool of dedicated connections to the same deployment, apart
+    /// from the one of [Self::checkout], see [RedisPool]
+    pub fn pool(&self, options: PoolOptions) -> RedisPool {
+        let inner = Arc::new(PoolInner {
+            // the pool of a pool's own handle would never be used
+            redis: Self {
+                pool: None,
+                ..self.clone()
+            },
+            options,
+            state: Mutex::new(PoolState::default()),
+            reaper: Once::new(),
+        });
+        RedisPool { inner }
+    }
+
+    /// Give this [Redis] the pool [Self::checkout] takes connections from,
+    /// sized by [crate::RedisOptions::pool]
+    pub(crate) fn with_pool(mut self) -> Self {
+        self.pool = Some(self.pool(self.options.pool.clone()));
+        self
+    }
+
+    /// Check out a connection of its own, unlike [Self::get_client], from
+    /// the pool every clone of this [Redis] shares (see [RedisPool] and
+    /// [crate::RedisOptions::pool]). It is returned to the pool on drop.
+    ///
+    /// Use this under load, where tasks sharing one connection would queue
+    /// up behind each other: commands on different checked-out connections
+    /// run in parallel, in cluster mode on every node.
+    pub async fn checkout(&self) -> RedisResult<PooledConnection> {
+        self.pool
+            .as_ref()
+            .expect("every Redis handed out has a pool")
+            .get()
+            .await
+    }
+}
+
+impl RedisPool {
+    /// Check out a connection, opening one if the pool isn't full yet and
+    /// waiting for one to be returned otherwise
+    pub async fn get(&self) -> RedisResult<PooledConnection> {
+        self.inner.start_reaper();
+        let checkout = {
+            let mut state = self.inner.state.lock().unwrap();
+            if let Some(idle) = state.idle.pop() {
+                Checkout::Idle(idle.connection)
+            } else if state.size < self.inner.options.max_size {
+                state.size += 1;
+                Checkout::Open
+            } else {
+                let (tx, rx) = oneshot::channel();
+                state.waiters.push_back(tx);
+                Checkout::Wait(rx)
+            }
+        };
+        let reused = match checkout {
+            Checkout::Idle(connection) => self.wrap(connection),
+            Checkout::Wait(rx) => self.wait(rx).await?,
+            Checkout::Open => {
+                return match self.inner.redis.dedicated().await {
+                    Ok(connection) => Ok(self.wrap(connection)),
+                    Err(e) => {
+                        self.inner.state.lock().unwrap().size -= 1;
+                        Err(e)
+                    }
+                }
+            }
+        };
+        if self.inner.options.reset_on_return {
+            if let Err(e) = self.scrub(&reused).await {
+                reused.discard();
+                return Err(e);
+            }
+        }
+        Ok(reused)
+    }
+
+    /// Wait for a returned connection, see [PoolOptions::wait_timeout]. A
+    /// waiter that gives up is skipped by [PoolInner::put].
+    async fn wait(&self, rx: oneshot::Receiver<PooledConnection>) -> RedisResult<PooledConnection> {
+        let returned = match self.inner.options.wait_timeout {
+            Some(limit) => tokio::time::timeout(limit, rx).await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for a pooled connection",
+                )
+            })?,
+            None => rx.await,
+        };
+        returned.map_err(|_| (ErrorKind::ClientError, "connection pool was dropped").into())
+    }
+
+    /// Connections currently open, idle or checked out
+    pub fn size(&self) -> usize {
+        self.inner.state.lock().unwrap().size
+    }
+
+    /// Connections currently idle
+    pub fn idle(&self) -> usize {
+        self.inner.state.lock().unwrap().idle.len()
+    }
+
+    /// Clear the state a previous borrower may have left on `connection`, see
+    /// [PoolOptions::reset_on_return]
+    async fn scrub(&self, connection: &RedisConnection) -> RedisResult<()> {
+        let info = match &self.inner.redis.client {
+            RedisClient::Single(client) => client.get_connection_info(),
+            RedisClient::Sentinel(sentinel) => sentinel.master_info(),
+            RedisClient::Cluster(_) => return Ok(()),
+        };
+        let mut restore = redis::pipe();
+        match connection.reset().await {
+            Ok(()) => {
+                if let Some(passwd) = &info.passwd {
+                    let auth = restore.cmd("AUTH");
+                    if let Some(username) = &info.username {
+                        auth.arg(username);
+                    }
+                    auth.arg(passwd).ignore();
+                }
+            }
+            // RESET is unknown before Redis 6.2
+            Err(e) if e.kind() == ErrorKind::ResponseError => {
+                restore.cmd("CLIENT").arg(&["SETNAME", ""]).ignore();
+            }
+            Err(e) => return Err(e),
+        }
+        restore.cmd("SELECT").arg(info.db).ignore();
+        connection.exec_pipe(&restore).await
+    }
+
+    fn wrap(&self, connection: RedisConnection) -> PooledConnection {
+        PooledConnection {
+            connection: Some(connection),
+            pool: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl PoolInner {
+    /// Hand `connection` to the next waiter, or park it if nobody waits
+    fn put(self: &Arc<Self>, connection: RedisConnection) {
+        let mut pooled = PooledConnection {
+            connection: Some(connection),
+            pool: Arc::clone(self),
+        };
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().unwrap();
+                let waiter = match self.options.fairness {
+                    Fairness::Fifo => state.waiters.pop_front(),
+                    Fairness::Lifo => state.waiters.pop_back(),
+                };
+                match waiter {
+                    Some(waiter) => waiter,
+                    None => {
+                        if let Some(connection) = pooled.connection.take() {
+                            state.idle.push(IdleConnection {
+                                connection,
+                                since: Instant::now(),
+                            });
+                        }
+                        return;
+                    }
+                }
+            };
+            // a waiter that gave up hands the connection back
+            match waiter.send(pooled) {
+                Ok(()) => return,
+                Err(back) => pooled = back,
+            }
+        }
+    }
+}
+
+impl PoolInner {
+    /// Spawn the task closing idle connections, once, if
+    /// [PoolOptions::max_idle] is set. Runs on checkout rather than when
+    /// the pool is created, which may happen outside a runtime.
+    fn start_reaper(self: &Arc<Self>) {
+        let max_idle = match self.options.max_idle {
+            Some(max_idle) => max_idle,
+            None => return,
+        };
+        self.reaper.call_once(|| {
+            let pool = Arc::downgrade(self);
+            tokio::spawn(async move {
+                let period = (max_idle / 2).max(Duration::from_millis(1));
+                loop {
+                    tokio::time::sleep(period).await;
+                    match pool.upgrade() {
+                        Some(pool) => pool.reap(max_idle),
+                        None => return,
+                    }
+                }
+            });
+        });
+    }
+
+    /// Close the idle connections returned more than `max_idle` ago
+    fn reap(&self, max_idle: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let before = state.idle.len();
+        state.idle.retain(|idle| idle.since.elapsed() < max_idle);
+        state.size -= before - state.idle.len();
+    }
+}
+
+impl PooledConnection {
+    /// Close the connection instead of returning it, freeing its slot
+    fn discard(mut self) {
+        if self.connection.take().is_some() {
+            self.pool.state.lock().unwrap().size -= 1;
+        }
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = RedisConnection;
+
+    fn deref(&self) -> &RedisConnection {
+        self.connection
+            .as_ref()
+            .expect("connection is only taken on drop")
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.put(connection);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use crate::*;
+
+    async fn serve_order(fairness: Fairness) -> Vec<usize> {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let pool = r.pool(PoolOptions {
+            max_size: 1,
+            fairness,
+            ..PoolOptions::default()
+        });
+        let held = pool.get().await.unwrap();
+
+        let served = Arc::new(Mutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for i in 0..3 {
+            let pool = pool.clone();
+            let served = Arc::clone(&served);
+            waiters.push(actix_rt::spawn(async move {
+                let con = pool.get().await.unwrap();
+                served.lock().unwrap().push(i);
+                assert!(con.ping().await);
+            }));
+            // let the waiter queue up before the next one arrives
+            actix_rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        drop(held);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.idle(), 1);
+        let served = served.lock().unwrap().clone();
+        served
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn checkout_hands_out_independent_connections() {
+        let options = RedisOptions {
+            pool: PoolOptions {
+                max_size: 4,
+                ..PoolOptions::default()
+            },
+            ..RedisOptions::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let shared: u64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+        let tasks: Vec<_> = (0..32)
+            .map(|_| {
+                let r = r.clone();
+                tokio::spawn(async move {
+                    let con = r.checkout().await.unwrap();
+                    con.exec::<u64>(redis::cmd("CLIENT").arg("ID"))
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut ids = Vec::new();
+        for task in tasks {
+            ids.push(task.await.unwrap());
+        }
+        assert!(!ids.contains(&shared));
+        ids.sort_unstable();
+        ids.dedup();
+        assert!(ids.len() <= 4);
+        assert!(r.checkout().await.unwrap().ping().await);
+    }
+
+    #[actix_rt::test]
+    async fn fifo_serves_waiters_in_arrival_order() {
+        assert_eq!(serve_order(Fairness::Fifo).await, vec![0, 1, 2]);
+    }
+
+    #[actix_rt::test]
+    async fn lifo_serves_latest_waiter_first() {
+        assert_eq!(serve_order(Fairness::Lifo).await, vec![2, 1, 0]);
+    }
+
+    #[actix_rt::test]
+    async fn wait_timeout_bounds_checkout() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let pool = r.pool(PoolOptions {
+            max_size: 1,
+            wait_timeout: Some(Duration::from_millis(50)),
+            ..PoolOptions::default()
+        });
+        let held = pool.get().await.unwrap();
+        let err = pool.get().await.err().unwrap();
+        assert!(err.is_timeout());
+
+        // the waiter that gave up doesn't swallow the returned connection
+        drop(held);
+        assert_eq!((pool.size(), pool.idle()), (1, 1));
+        assert!(pool.get().await.unwrap().ping().await);
+    }
+
+    #[actix_rt::test]
+    async fn idle_connections_are_reaped() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let pool = r.pool(PoolOptions {
+            max_size: 2,
+            max_idle: Some(Duration::from_millis(50)),
+            ..PoolOptions::default()
+        });
+        let held = pool.get().await.unwrap();
+        let returned = pool.get().await.unwrap();
+        let id: u64 = returned.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        drop(returned);
+        assert_eq!((pool.size(), pool.idle()), (2, 1));
+
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!((pool.size(), pool.idle()), (1, 0));
+        assert!(held.ping().await);
+
+        let reopened = pool.get().await.unwrap();
+        let new_id: u64 = reopened.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_ne!(new_id, id);
+        assert_eq!(pool.size(), 2);
+    }
+
+    async fn db_after_return(reset_on_return: bool) -> (String, bool) {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let pool = r.pool(PoolOptions {
+            max_size: 1,
+            reset_on_return,
+            ..PoolOptions::default()
+        });
+        let con = pool.get().await.unwrap();
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let _: () = con.exec(redis::cmd("SELECT").arg(5)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("CLIENT").arg(&["SETNAME", "tenant-a"]))
+            .await
+            .unwrap();
+        drop(con);
+
+        let con = pool.get().await.unwrap();
+        let new_id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_eq!(new_id, id, "the connection is reused, not reopened");
+        let info: String = con.exec(redis::cmd("CLIENT").arg("INFO")).await.unwrap();
+        let db = info
+            .split(' ')
+            .find_map(|field| field.strip_prefix("db="))
+            .unwrap()
+            .to_owned();
+        let name: Option<String> = con.exec(redis::cmd("CLIENT").arg("GETNAME")).await.unwrap();
+        (db, name.is_some())
+    }
+
+    #[actix_rt::test]
+    async fn reset_on_return_clears_borrower_state() {
+        assert_eq!(db_after_return(true).await, ("0".to_string(), false));
+        assert_eq!(db_after_return(false).await, ("5".to_string(), true));
+    }
+}
@@ -0,0 +1,139 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Key namespacing for shared Redis instances
+use std::time::Duration;
+
+use redis::{FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::{ExpireCond, Redis, RedisConnection};
+
+/// A connection that prepends a fixed prefix to the keys passed to its
+/// helpers, see [Redis::with_prefix].
+///
+/// Only key arguments are prefixed: values, scores and options pass through
+/// untouched. Commands sent with [Self::connection] and
+/// [RedisConnection::exec] are never rewritten; use [Self::key] to build
+/// their key arguments.
+///
+/// Prefixing keeps `{hash tags}` intact as long as the prefix has no braces
+/// of its own, so keys sharing a tag still share a cluster slot. A prefix
+/// that contains a tag, like `{tenant}:`, puts every key in the same slot.
+#[derive(Clone)]
+pub struct PrefixedRedis {
+    prefix: String,
+    connection: RedisConnection,
+}
+
+impl Redis {
+    /// Get a client whose helpers operate on keys under `prefix`
+    pub fn with_prefix(&self, prefix: &str) -> PrefixedRedis {
+        PrefixedRedis {
+            prefix: prefix.to_owned(),
+            connection: self.get_client(),
+        }
+    }
+}
+
+impl PrefixedRedis {
+    /// The prefix that is prepended to keys
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// `key` with the prefix prepended
+    pub fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    /// The underlying connection, which doesn't prefix anything
+    pub fn connection(&self) -> &RedisConnection {
+        &self.connection
+    }
+
+    /// See [RedisConnection::get_checked]
+    pub async fn get<T: FromRedisValue>(&self, key: &str) -> RedisResult<Option<T>> {
+        self.connection.get_checked(&self.key(key)).await
+    }
+
+    /// Set `key` to `val` (SET)
+    pub async fn set<V: ToRedisArgs>(&self, key: &str, val: V) -> RedisResult<()> {
+        self.connection
+            .exec(redis::cmd("SET").arg(self.key(key)).arg(val))
+            .await
+    }
+
+    /// Delete `keys` (DEL), returning how many existed. In cluster mode, all
+    /// keys must hash to the same slot.
+    pub async fn del(&self, keys: &[&str]) -> RedisResult<u64> {
+        let keys: Vec<String> = keys.iter().map(|k| self.key(k)).collect();
+        let refs: Vec<&str> = keys.iter().map(String::as_str).collect();
+        self.connection.ensure_same_slot(&refs)?;
+        self.connection.exec(redis::cmd("DEL").arg(keys)).await
+    }
+
+    /// Whether `key` exists (EXISTS)
+    pub async fn exists(&self, key: &str) -> RedisResult<bool> {
+        self.connection
+            .exec(redis::cmd("EXISTS").arg(self.key(key)))
+            .await
+    }
+
+    /// See [RedisConnection::expire_opts]
+    pub async fn expire_opts(
+        &self,
+        key: &str,
+        ttl: Duration,
+        cond: ExpireCond,
+    ) -> RedisResult<bool> {
+        self.connection.expire_opts(&self.key(key), ttl, cond).await
+    }
+
+    /// See [RedisConnection::get_with_ttl]
+    pub async fn get_with_ttl<T: FromRedisValue>(
+        &self,
+        key: &str,
+    ) -> RedisResult<Option<(T, Option<Duration>)>> {
+        self.connection.get_with_ttl(&self.key(key)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn prefix_preserves_hash_tags() {
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        let app = r.with_prefix("app:");
+        assert_eq!(slot_for(&app.key("{user1}.a")), slot_for("user1"));
+        assert_eq!(
+            slot_for(&app.key("{user1}.a")),
+            slot_for(&app.key("{user1}.b"))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn prefix_applies_to_keys_only() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let app = r.with_prefix("app:");
+        app.set("prefix_applies_to_keys_only", "foo").await.unwrap();
+
+        let raw: Option<String> = r
+            .get_client()
+            .exec(redis::cmd("GET").arg("app:prefix_applies_to_keys_only"))
+            .await
+            .unwrap();
+        assert_eq!(raw.as_deref(), Some("foo"));
+        let get: Option<String> = app.get("prefix_applies_to_keys_only").await.unwrap();
+        assert_eq!(get.as_deref(), Some("foo"));
+
+        assert_eq!(app.del(&["prefix_applies_to_keys_only"]).await.unwrap(), 1);
+        assert!(!app.exists("prefix_applies_to_keys_only").await.unwrap());
+    }
+}
@@ -0,0 +1,634 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Pub/sub subscriptions that survive connection loss
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, AbortHandle, Abortable};
+use futures::stream::{self, BoxStream, StreamExt};
+use redis::{from_redis_value, Client, ErrorKind, RedisError, RedisResult, ToRedisArgs, Value};
+use tokio::sync::Notify;
+
+use crate::{slot_for, ExponentialBackoff, Redis, RetryStrategy};
+
+/// Name the subscriber connections go by in CLIENT LIST
+const SUBSCRIBER_NAME: &str = "redis-glue:subscriber";
+
+/// What a subscription yields, see [Redis::subscribe]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PubSubEvent {
+    Message(PubSubMessage),
+    /// The connection was lost and has been re-established, with every
+    /// channel and pattern subscribed again. Messages published in between
+    /// are lost.
+    Reconnected,
+}
+
+/// A message published on a subscribed channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PubSubMessage {
+    pub channel: String,
+    /// The pattern that matched `channel`, `None` for messages on channels
+    /// subscribed to by name
+    pub pattern: Option<String>,
+    pub payload: Vec<u8>,
+}
+
+/// What a subscription does with a message that arrives while its buffer is
+/// full, see [SubscribeOptions]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Discard the oldest buffered message to make room
+    DropOldest,
+    /// Discard the message that just arrived
+    DropNewest,
+    /// Stop reading from the connection until the consumer catches up. The
+    /// messages then pile up on the server, which disconnects the subscriber
+    /// once they exceed its `client-output-buffer-limit` for pubsub.
+    Block,
+}
+
+/// Buffering of a subscription, see [Redis::subscribe_with]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SubscribeOptions {
+    /// Most events buffered between the connection and the consumer, at
+    /// least 1
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for SubscribeOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+/// Events read from the connection and not consumed yet
+struct Buffer {
+    events: Mutex<VecDeque<PubSubEvent>>,
+    options: SubscribeOptions,
+    /// Notified when an event is queued
+    queued: Notify,
+    /// Notified when an event is taken
+    taken: Notify,
+}
+
+impl Buffer {
+    async fn push(&self, event: PubSubEvent) {
+        loop {
+            {
+                let mut events = self.events.lock().unwrap();
+                if events.len() >= self.options.capacity.max(1) {
+                    match self.options.overflow {
+                        Overflow::DropOldest => {
+                            events.pop_front();
+                        }
+                        Overflow::DropNewest => return,
+                        Overflow::Block => {}
+                    }
+                }
+                if events.len() < self.options.capacity.max(1) {
+                    events.push_back(event);
+                    self.queued.notify_one();
+                    return;
+                }
+            }
+            self.taken.notified().await;
+        }
+    }
+
+    async fn pop(&self) -> PubSubEvent {
+        loop {
+            let event = self.events.lock().unwrap().pop_front();
+            if let Some(event) = event {
+                self.taken.notify_one();
+                return event;
+            }
+            self.queued.notified().await;
+        }
+    }
+}
+
+/// Stops the task reading a subscription when its stream is dropped
+struct StopOnDrop(AbortHandle);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct Subscriber {
+    redis: Redis,
+    channels: Vec<String>,
+    patterns: Vec<String>,
+}
+
+impl Subscriber {
+    async fn connect(&self) -> RedisResult<BoxStream<'static, redis::Msg>> {
+        // a cluster delivers every message to every node, so any node would
+        // do: the primary of the first name's slot spreads subscribers out,
+        // and is looked up again on every reconnection
+        let name = self.channels.iter().chain(&self.patterns).next();
+        let slot = name.map_or(0, |name| slot_for(name));
+        let node = self.redis.slot_primary(slot).await?;
+        let mut con = Client::open(node)?.get_async_connection().await?;
+        let _: () = redis::cmd("CLIENT")
+            .arg("SETNAME")
+            .arg(SUBSCRIBER_NAME)
+            .query_async(&mut con)
+            .await?;
+        let mut pubsub = con.into_pubsub();
+        for channel in &self.channels {
+            pubsub.subscribe(channel).await?;
+        }
+        for pattern in &self.patterns {
+            pubsub.psubscribe(pattern).await?;
+        }
+        Ok(pubsub.into_on_message().boxed())
+    }
+
+    /// Connect and subscribe again, backing off for as long as that fails
+    async fn reconnect(&self) -> BoxStream<'static, redis::Msg> {
+        let mut backoff = ExponentialBackoff {
+            base: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            max_retries: u32::MAX,
+        };
+        let mut attempt = 0;
+        loop {
+            match self.connect().await {
+                Ok(messages) => return messages,
+                Err(_) => {
+                    attempt += 1;
+                    let delay = backoff.next_delay(attempt).unwrap_or(backoff.max_delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    /// Feed `buffer` with what arrives on `messages`, forever
+    async fn read(self, mut messages: BoxStream<'static, redis::Msg>, buffer: Arc<Buffer>) {
+        loop {
+            let event = match messages.next().await {
+                Some(msg) => PubSubEvent::Message(PubSubMessage {
+                    channel: msg.get_channel_name().to_owned(),
+                    pattern: msg.from_pattern().then(|| msg.get_pattern().ok()).flatten(),
+                    payload: msg.get_payload_bytes().to_vec(),
+                }),
+                None => {
+                    messages = self.reconnect().await;
+                    PubSubEvent::Reconnected
+                }
+            };
+            buffer.push(event).await;
+        }
+    }
+}
+
+impl Redis {
+    /// Subscribe to `channels` (SUBSCRIBE) and `patterns` (PSUBSCRIBE) on a
+    /// connection of its own, yielding the messages published on them.
+    ///
+    /// When the connection is lost, the stream keeps going: it reconnects
+    /// with a backoff, subscribes to everything again and yields
+    /// [PubSubEvent::Reconnected] before the next messages. Pub/sub doesn't
+    /// queue anything, so messages published while disconnected are lost for
+    /// good; use a stream (XADD/XREAD) where that matters.
+    ///
+    /// The connection is named `redis-glue:subscriber` (CLIENT SETNAME). Only
+    /// the first connection attempt can fail, later ones are retried forever.
+    /// In cluster mode, where published messages reach every node, the
+    /// connection goes to one of the primaries, picked again on every
+    /// reconnection.
+    ///
+    /// Buffers up to 1024 events for a slow consumer before it stops
+    /// reading, see [Self::subscribe_with] to change that.
+    pub async fn subscribe(
+        &self,
+        channels: &[&str],
+        patterns: &[&str],
+    ) -> RedisResult<BoxStream<'static, PubSubEvent>> {
+        self.subscribe_with(channels, patterns, SubscribeOptions::default())
+            .await
+    }
+
+    /// Like [Self::subscribe], buffering events as set by `options`.
+    ///
+    /// The connection is read by a task spawned on the current tokio
+    /// runtime, independently of the consumer, into a buffer of [SubscribeOptions::capacity] events.
+    /// What happens once the buffer is full is up to
+    /// [SubscribeOptions::overflow]; the drop policies lose messages
+    /// silently. The task stops when the stream is dropped.
+    pub async fn subscribe_with(
+        &self,
+        channels: &[&str],
+        patterns: &[&str],
+        options: SubscribeOptions,
+    ) -> RedisResult<BoxStream<'static, PubSubEvent>> {
+        let subscriber = Subscriber {
+            redis: self.clone(),
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        };
+        let messages = subscriber.connect().await?;
+
+        let buffer = Arc::new(Buffer {
+            events: Mutex::new(VecDeque::new()),
+            options,
+            queued: Notify::new(),
+            taken: Notify::new(),
+        });
+        let (stop, registration) = AbortHandle::new_pair();
+        tokio::spawn(Abortable::new(
+            subscriber.read(messages, Arc::clone(&buffer)),
+            registration,
+        ));
+        let events = stream::unfold((buffer, StopOnDrop(stop)), |(buffer, stop)| async move {
+            let event = buffer.pop().await;
+            Some((event, (buffer, stop)))
+        });
+        Ok(events.boxed())
+    }
+}
+
+/// Publishing and subscribing over either deployment mode, see
+/// [Redis::pubsub]
+#[derive(Clone)]
+pub struct RedisPubSub {
+    redis: Redis,
+    options: SubscribeOptions,
+}
+
+impl Redis {
+    /// Pub/sub over this deployment, with subscriptions buffering as set by
+    /// `options`
+    pub fn pubsub(&self, options: SubscribeOptions) -> RedisPubSub {
+        RedisPubSub {
+            redis: self.clone(),
+            options,
+        }
+    }
+}
+
+impl RedisPubSub {
+    /// Publish `payload` on `channel` (PUBLISH) through the shared
+    /// connection, returning how many subscribers received it. In cluster
+    /// mode the message reaches the subscribers of every node, but only
+    /// those connected to the node it was published on are counted.
+    pub async fn publish(&self, channel: &str, payload: impl ToRedisArgs) -> RedisResult<u64> {
+        self.redis
+            .get_client()
+            .exec(redis::cmd("PUBLISH").arg(channel).arg(payload))
+            .await
+    }
+
+    /// Subscribe to `channels` (SUBSCRIBE), see [Redis::subscribe_with] for
+    /// how reconnections and slow consumers are handled
+    pub async fn subscribe(
+        &self,
+        channels: &[&str],
+    ) -> RedisResult<BoxStream<'static, PubSubEvent>> {
+        self.redis.subscribe_with(channels, &[], self.options).await
+    }
+
+    /// Subscribe to the channels matching `patterns` (PSUBSCRIBE), see
+    /// [Redis::subscribe_with]
+    pub async fn psubscribe(
+        &self,
+        patterns: &[&str],
+    ) -> RedisResult<BoxStream<'static, PubSubEvent>> {
+        self.redis.subscribe_with(&[], patterns, self.options).await
+    }
+}
+
+impl Redis {
+    /// Subscribe to the shard channels `channels` (SSUBSCRIBE, Redis 7+),
+    /// yielding the messages published on them with SPUBLISH.
+    ///
+    /// Shard channels live in hash slots like keys, so the subscription is
+    /// made on a connection of its own to the primary serving their slot;
+    /// channels spanning slots are rejected with a cross-slot error, in
+    /// single mode too. Unlike [Self::subscribe] this doesn't reconnect: the
+    /// stream ends when the connection is lost, and yields an error before
+    /// ending when the server drops the subscription because the slot moved
+    /// to another node. Subscribe again in both cases; messages published in
+    /// between are lost.
+    pub async fn ssubscribe(
+        &self,
+        channels: &[&str],
+    ) -> RedisResult<BoxStream<'static, RedisResult<PubSubMessage>>> {
+        let slot = match channels.first() {
+            Some(channel) => slot_for(channel),
+            None => return Err((ErrorKind::ClientError, "no shard channel given").into()),
+        };
+        if channels.iter().any(|channel| slot_for(channel) != slot) {
+            return Err((
+                ErrorKind::CrossSlot,
+                "shard channels don't hash to the same slot",
+                channels.join(", "),
+            )
+                .into());
+        }
+        let node = self.slot_primary(slot).await?;
+        let mut con = Client::open(node)?.get_async_connection().await?;
+        // one channel at a time, so every reply read is the confirmation of
+        // the command just sent and nothing is left behind unread
+        for channel in channels {
+            let _: Value = redis::cmd("SSUBSCRIBE")
+                .arg(*channel)
+                .query_async(&mut con)
+                .await?;
+        }
+        let mut ended = false;
+        let messages = con
+            .into_monitor()
+            .into_on_message::<Value>()
+            .filter_map(|push| future::ready(parse_shard_push(&push)))
+            .take_while(move |item| {
+                let more = !ended;
+                ended = item.is_err();
+                future::ready(more)
+            });
+        Ok(messages.boxed())
+    }
+}
+
+/// Parse what a shard channel subscription pushes: `smessage` arrays become
+/// messages and `sunsubscribe` ones, sent when the slot moves away, errors.
+/// Anything else is left out.
+fn parse_shard_push(push: &Value) -> Option<RedisResult<PubSubMessage>> {
+    let items = match push {
+        Value::Bulk(items) if items.len() == 3 => items,
+        _ => return None,
+    };
+    let kind: String = from_redis_value(&items[0]).ok()?;
+    match kind.as_str() {
+        "smessage" => Some(from_redis_value(&items[1]).and_then(|channel| {
+            Ok(PubSubMessage {
+                channel,
+                pattern: None,
+                payload: from_redis_value(&items[2])?,
+            })
+        })),
+        "sunsubscribe" => Some(Err(RedisError::from((
+            ErrorKind::ClientError,
+            "shard channel subscription was dropped by the server",
+            from_redis_value::<String>(&items[1]).unwrap_or_default(),
+        )))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn subscription_survives_connection_loss() {
+        const CHANNEL: &str = "subscription_survives_connection_loss";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let mut events = r
+            .subscribe(&[CHANNEL], &["subscription_*_loss"])
+            .await
+            .unwrap();
+
+        let _: () = con
+            .exec(redis::cmd("PUBLISH").arg(&[CHANNEL, "before"]))
+            .await
+            .unwrap();
+        let mut received = vec![events.next().await.unwrap(), events.next().await.unwrap()];
+        received.sort_by_key(|event| match event {
+            PubSubEvent::Message(msg) => msg.pattern.clone(),
+            PubSubEvent::Reconnected => None,
+        });
+        assert_eq!(
+            received,
+            vec![
+                PubSubEvent::Message(PubSubMessage {
+                    channel: CHANNEL.into(),
+                    pattern: None,
+                    payload: b"before".to_vec(),
+                }),
+                PubSubEvent::Message(PubSubMessage {
+                    channel: CHANNEL.into(),
+                    pattern: Some("subscription_*_loss".into()),
+                    payload: b"before".to_vec(),
+                }),
+            ]
+        );
+
+        let clients: String = con
+            .exec(redis::cmd("CLIENT").arg(&["LIST", "TYPE", "pubsub"]))
+            .await
+            .unwrap();
+        for client in clients.lines() {
+            if client.contains(&format!("name={} ", SUBSCRIBER_NAME)) {
+                let id = client.split(' ').next().unwrap().trim_start_matches("id=");
+                let _: () = con
+                    .exec(redis::cmd("CLIENT").arg(&["KILL", "ID", id]))
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(events.next().await, Some(PubSubEvent::Reconnected));
+
+        let _: () = con
+            .exec(redis::cmd("PUBLISH").arg(&[CHANNEL, "after"]))
+            .await
+            .unwrap();
+        match events.next().await {
+            Some(PubSubEvent::Message(msg)) => assert_eq!(msg.payload, b"after"),
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    async fn publish_reaches_subscribers(r: Redis) {
+        const CHANNEL: &str = "publish_reaches_subscribers";
+
+        let pubsub = r.pubsub(SubscribeOptions::default());
+        let mut by_name = pubsub.subscribe(&[CHANNEL]).await.unwrap();
+        let mut by_pattern = pubsub.psubscribe(&["publish_reaches_*"]).await.unwrap();
+        assert!(pubsub.publish(CHANNEL, "hi").await.unwrap() >= 1);
+
+        assert_eq!(
+            by_name.next().await,
+            Some(PubSubEvent::Message(PubSubMessage {
+                channel: CHANNEL.into(),
+                pattern: None,
+                payload: b"hi".to_vec(),
+            }))
+        );
+        assert_eq!(
+            by_pattern.next().await,
+            Some(PubSubEvent::Message(PubSubMessage {
+                channel: CHANNEL.into(),
+                pattern: Some("publish_reaches_*".into()),
+                payload: b"hi".to_vec(),
+            }))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn pubsub_publishes_to_subscribers() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        publish_reaches_subscribers(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn pubsub_publishes_to_subscribers_in_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        publish_reaches_subscribers(r).await;
+    }
+
+    /// Payloads a consumer gets when it only starts reading after 5 messages
+    /// were published to a subscription buffering 2
+    async fn received_after_overflow(
+        overflow: Overflow,
+        channel: &str,
+        count: usize,
+    ) -> Vec<Vec<u8>> {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let mut events = r
+            .subscribe_with(
+                &[channel],
+                &[],
+                SubscribeOptions {
+                    capacity: 2,
+                    overflow,
+                },
+            )
+            .await
+            .unwrap();
+        for i in 1..=5 {
+            let _: () = con
+                .exec(redis::cmd("PUBLISH").arg(channel).arg(i))
+                .await
+                .unwrap();
+        }
+        // give the reader time to hit the limit before consuming anything
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+
+        let mut payloads = Vec::new();
+        for _ in 0..count {
+            match events.next().await.unwrap() {
+                PubSubEvent::Message(msg) => payloads.push(msg.payload),
+                PubSubEvent::Reconnected => panic!("unexpected reconnection"),
+            }
+        }
+        payloads
+    }
+
+    #[actix_rt::test]
+    async fn overflow_policy_applies_to_slow_consumers() {
+        let payloads = |items: &[&str]| -> Vec<Vec<u8>> {
+            items.iter().map(|item| item.as_bytes().to_vec()).collect()
+        };
+        assert_eq!(
+            received_after_overflow(Overflow::DropOldest, "overflow_drop_oldest", 2).await,
+            payloads(&["4", "5"])
+        );
+        assert_eq!(
+            received_after_overflow(Overflow::DropNewest, "overflow_drop_newest", 2).await,
+            payloads(&["1", "2"])
+        );
+        assert_eq!(
+            received_after_overflow(Overflow::Block, "overflow_block", 5).await,
+            payloads(&["1", "2", "3", "4", "5"])
+        );
+    }
+
+    #[test]
+    fn parse_shard_push_works() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let push = Value::Bulk(vec![data("smessage"), data("orders"), data("42")]);
+        assert_eq!(
+            parse_shard_push(&push).unwrap().unwrap(),
+            PubSubMessage {
+                channel: "orders".into(),
+                pattern: None,
+                payload: b"42".to_vec(),
+            }
+        );
+        let dropped = Value::Bulk(vec![data("sunsubscribe"), data("orders"), Value::Int(0)]);
+        assert!(parse_shard_push(&dropped).unwrap().is_err());
+        assert!(parse_shard_push(&Value::Status("PONG".into())).is_none());
+        let other = Value::Bulk(vec![data("message"), data("orders"), data("42")]);
+        assert!(parse_shard_push(&other).is_none());
+    }
+
+    async fn shard_message_arrives(r: Redis) {
+        const CHANNEL: &str = "{shard_message_arrives}:a";
+        const OTHER: &str = "{shard_message_arrives}:b";
+
+        let err = r
+            .ssubscribe(&[CHANNEL, "shard_message_arrives"])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::CrossSlot);
+
+        let mut messages = r.ssubscribe(&[CHANNEL, OTHER]).await.unwrap();
+        let con = r.get_client();
+        let _: u64 = con
+            .exec(redis::cmd("SPUBLISH").arg(&[OTHER, "hello"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            messages.next().await.unwrap().unwrap(),
+            PubSubMessage {
+                channel: OTHER.into(),
+                pattern: None,
+                payload: b"hello".to_vec(),
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn ssubscribe_receives_spublish() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // shard channels need Redis 7
+        if !r
+            .server_capabilities()
+            .await
+            .unwrap()
+            .supports_redis7_commands()
+        {
+            return;
+        }
+        shard_message_arrives(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn ssubscribe_receives_spublish_in_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        shard_message_arrives(r).await;
+    }
+}
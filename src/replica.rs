@@ -0,0 +1,337 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Serving reads from replicas
+use std::sync::{Arc, Mutex};
+
+use redis::cluster::ClusterClientBuilder;
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult, Value};
+
+use crate::cache::is_unreachable;
+use crate::routing::{parse_cluster_slots, SlotRange};
+use crate::slot::{routing_key, slot_of};
+use crate::{
+    is_readonly, Redis, RedisClient, RedisConfig, RedisConnection, RedisOptions, SentinelClient,
+};
+
+/// Where [Redis::exec_read] sends read-only commands, see
+/// [RedisOptions::read_preference]. Replicas may trail the master, so reads
+/// sent to them may not see the latest writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// The master, like every other command
+    #[default]
+    Master,
+    /// A replica, or the master if no replica can be reached or it is still
+    /// loading its data or cut off from the master (LOADING, MASTERDOWN)
+    PreferReplica,
+    /// A replica, failing if none can be reached. In cluster mode, reads of
+    /// keys in slots without a replica, and reads without a key, fail
+    /// without being sent, with [redis::ErrorKind::ClientError].
+    ReplicaOnly,
+}
+
+/// The replica side of a [Redis], see [Redis::exec_read]
+#[derive(Clone)]
+pub(crate) struct Replicas {
+    pub(crate) connection: RedisConnection,
+    /// Slot ranges without a replica, for [ReadPreference::ReplicaOnly] in
+    /// cluster mode. Looked up (CLUSTER SLOTS) by the first read and again
+    /// after a read fails, as the topology may have changed.
+    unreplicated: Arc<Mutex<Option<SlotRanges>>>,
+}
+
+/// Inclusive `(start, end)` ranges of hash slots
+type SlotRanges = Vec<(u16, u16)>;
+
+impl Replicas {
+    fn forget_topology(&self) {
+        *self.unreplicated.lock().unwrap() = None;
+    }
+}
+
+impl RedisConfig {
+    /// Lazy read-only connection to the replicas, if `options` asks for
+    /// them and the deployment knows its replicas
+    pub(crate) fn replica_connection(
+        &self,
+        options: &Arc<RedisOptions>,
+    ) -> RedisResult<Option<Replicas>> {
+        if options.read_preference == ReadPreference::Master {
+            return Ok(None);
+        }
+        let client = match self {
+            Self::Single(_) => return Ok(None),
+            // sends READONLY to every node it connects to
+            Self::Cluster(nodes) => RedisClient::Cluster(
+                ClusterClientBuilder::new(nodes.clone())
+                    .readonly(true)
+                    .open()?,
+            ),
+            Self::ClusterSeed(seed) => RedisClient::Cluster(
+                ClusterClientBuilder::new(vec![seed.as_str()])
+                    .readonly(true)
+                    .open()?,
+            ),
+            Self::Sentinel {
+                sentinels,
+                master_name,
+            } => RedisClient::Sentinel(SentinelClient::new(sentinels, master_name)?.for_replicas()),
+        };
+        Ok(Some(Replicas {
+            connection: RedisConnection::lazy(client, Arc::clone(options)).read_only(),
+            unreplicated: Arc::default(),
+        }))
+    }
+}
+
+impl Redis {
+    /// Run `cmd` where [RedisOptions::read_preference] says if it is
+    /// read-only (see [is_readonly]), on the master otherwise.
+    ///
+    /// In sentinel mode reads go to a healthy replica the sentinels report;
+    /// in cluster mode to a replica of the slot's master, sent READONLY
+    /// first. Slots without replicas are read from the master itself with
+    /// [ReadPreference::PreferReplica]; [ReadPreference::ReplicaOnly] fails
+    /// those reads instead, telling them apart with CLUSTER SLOTS. The
+    /// replica connection is opened by the first read, reopened like the
+    /// shared one, and has a circuit breaker of its own, so unreachable
+    /// replicas don't hold back commands to the master. With
+    /// [ReadPreference::PreferReplica], a read that fails because no replica
+    /// can be reached, or the replica is loading or cut off from its master,
+    /// is sent to the master instead.
+    ///
+    /// Single mode doesn't know of any replicas: everything goes to the one
+    /// server.
+    pub async fn exec_read<T: FromRedisValue>(&self, cmd: &mut redis::Cmd) -> RedisResult<T> {
+        let replicas = match &self.replica {
+            Some(replicas) if is_readonly(cmd) => replicas,
+            _ => return self.connection.exec(cmd).await,
+        };
+        let preference = self.options.read_preference;
+        if preference == ReadPreference::ReplicaOnly && replicas.connection.is_cluster() {
+            self.ensure_replicated(replicas, cmd).await?;
+        }
+        match replicas.connection.exec(cmd).await {
+            Err(e) => {
+                replicas.forget_topology();
+                if preference == ReadPreference::PreferReplica && replica_unavailable(&e) {
+                    self.connection.exec(cmd).await
+                } else {
+                    Err(e)
+                }
+            }
+            res => res,
+        }
+    }
+
+    /// Fail `cmd` unless its slot has a replica to read it from
+    async fn ensure_replicated(&self, replicas: &Replicas, cmd: &redis::Cmd) -> RedisResult<()> {
+        let slot =
+            match routing_key(cmd) {
+                Some(key) => slot_of(key),
+                None => return Err((
+                    ErrorKind::ClientError,
+                    "ReplicaOnly reads need a key in cluster mode, keyless ones may go to a master",
+                )
+                    .into()),
+            };
+        let cached = replicas.unreplicated.lock().unwrap().clone();
+        let unreplicated = match cached {
+            Some(unreplicated) => unreplicated,
+            None => {
+                let reply: Value = self
+                    .connection
+                    .exec(redis::cmd("CLUSTER").arg("SLOTS"))
+                    .await?;
+                let unreplicated = unreplicated(&parse_cluster_slots(&reply)?);
+                *replicas.unreplicated.lock().unwrap() = Some(unreplicated.clone());
+                unreplicated
+            }
+        };
+        if unreplicated
+            .iter()
+            .any(|&(start, end)| start <= slot && slot <= end)
+        {
+            // a refusal, not a connection failure for the breaker and retries
+            return Err((
+                ErrorKind::ClientError,
+                "no replica serves the slot of the key",
+                slot.to_string(),
+            )
+                .into());
+        }
+        Ok(())
+    }
+}
+
+/// Slot ranges served by a master alone
+fn unreplicated(ranges: &[SlotRange]) -> SlotRanges {
+    ranges
+        .iter()
+        .filter(|range| range.replicas.is_empty())
+        .map(|range| (range.start, range.end))
+        .collect()
+}
+
+/// Whether the master should serve a read the replica failed with
+/// [ReadPreference::PreferReplica]: the replica can't be reached, is still
+/// loading its data (LOADING) or lost its link to the master and refuses
+/// to serve stale data (MASTERDOWN)
+fn replica_unavailable(err: &RedisError) -> bool {
+    is_unreachable(err)
+        || err.kind() == ErrorKind::BusyLoadingError
+        || err.code() == Some("MASTERDOWN")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn with_preference(read_preference: ReadPreference) -> RedisOptions {
+        RedisOptions {
+            read_preference,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn replicas_are_only_used_when_asked_for() {
+        let cluster = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()]);
+        let r = Redis::lazy_with_options(cluster.clone(), RedisOptions::default()).unwrap();
+        assert!(r.replica.is_none());
+        let r = Redis::lazy_with_options(cluster, with_preference(ReadPreference::ReplicaOnly))
+            .unwrap();
+        let replica = &r.replica.as_ref().unwrap().connection;
+        assert!(replica.is_cluster() && replica.read_only);
+
+        let single = RedisConfig::Single("redis://127.0.0.1".into());
+        let r = Redis::lazy_with_options(single, with_preference(ReadPreference::PreferReplica))
+            .unwrap();
+        assert!(r.replica.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn replica_only_fails_without_replicas() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let sentinel = RedisConfig::Sentinel {
+            sentinels: vec![format!("redis://127.0.0.1:{}", port)],
+            master_name: "mymaster".into(),
+        };
+        let r = Redis::lazy_with_options(sentinel, with_preference(ReadPreference::ReplicaOnly))
+            .unwrap();
+        let err = r
+            .exec_read::<()>(redis::cmd("GET").arg("key"))
+            .await
+            .unwrap_err();
+        assert!(err.is_io_error());
+    }
+
+    #[test]
+    fn replica_unavailable_covers_loading_and_masterdown() {
+        let loading = RedisError::from((
+            ErrorKind::BusyLoadingError,
+            "An error was signalled by the server",
+        ));
+        assert!(replica_unavailable(&loading));
+        let masterdown = redis::parse_redis_value(
+            b"-MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.\r\n",
+        )
+        .unwrap_err();
+        assert!(replica_unavailable(&masterdown));
+        let wrong_type = RedisError::from((ErrorKind::TypeError, "wrong type"));
+        assert!(!replica_unavailable(&wrong_type));
+    }
+
+    #[test]
+    fn unreplicated_slots_are_found() {
+        let node = || ("127.0.0.1".to_string(), 7000);
+        let ranges = vec![
+            SlotRange {
+                start: 0,
+                end: 8191,
+                master: node(),
+                replicas: vec![node()],
+            },
+            SlotRange {
+                start: 8192,
+                end: 16383,
+                master: node(),
+                replicas: Vec::new(),
+            },
+        ];
+        assert_eq!(unreplicated(&ranges), vec![(8192, 16383)]);
+    }
+
+    #[actix_rt::test]
+    async fn replica_only_rejects_keyless_cluster_reads() {
+        let cluster = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()]);
+        let r = Redis::lazy_with_options(cluster, with_preference(ReadPreference::ReplicaOnly))
+            .unwrap();
+        let err = r
+            .exec_read::<u64>(&mut redis::cmd("DBSIZE"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+    }
+
+    #[actix_rt::test]
+    async fn replica_only_refuses_unreplicated_slots() {
+        let cluster = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()]);
+        let r = Redis::lazy_with_options(cluster, with_preference(ReadPreference::ReplicaOnly))
+            .unwrap();
+        let replicas = r.replica.as_ref().unwrap();
+        *replicas.unreplicated.lock().unwrap() = Some(vec![(0, 16383)]);
+        let err = r
+            .exec_read::<()>(redis::cmd("GET").arg("key"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+    }
+
+    async fn reads_go_to_replicas(r: Redis) {
+        const KEY: &str = "reads_go_to_replicas";
+        // writes go to the master
+        let _: () = r.exec_read(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        r.get_client()
+            .set_durable(KEY, 1, 1, Duration::from_secs(1))
+            .await
+            .unwrap();
+        let val: String = r.exec_read(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(val, "1");
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster with replicas, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_reads_go_to_replicas() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let options = with_preference(ReadPreference::ReplicaOnly);
+        let r = Redis::with_options(RedisConfig::ClusterSeed(seed), options)
+            .await
+            .unwrap();
+        reads_go_to_replicas(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Sentinel watching mymaster with a replica, URL in REDIS_SENTINEL"]
+    async fn sentinel_reads_go_to_replicas() {
+        let sentinel = std::env::var("REDIS_SENTINEL").unwrap();
+        let config = RedisConfig::Sentinel {
+            sentinels: vec![sentinel],
+            master_name: "mymaster".into(),
+        };
+        let r = Redis::with_options(config, with_preference(ReadPreference::ReplicaOnly))
+            .await
+            .unwrap();
+        reads_go_to_replicas(r).await;
+    }
+}
@@ -0,0 +1,429 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Retrying commands that fail with transient errors
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+use redis::{ErrorKind, FromRedisValue, RedisError, RedisResult};
+
+use crate::{command, is_readonly, GlueError, Redis, RedisClient, RedisConnection};
+
+/// Decides how long to wait before retrying a failed command
+pub trait RetryStrategy {
+    /// Delay before retry number `attempt`, which starts at 1. Returning
+    /// `None` stops retrying and surfaces the last error.
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration>;
+}
+
+impl<S: RetryStrategy + ?Sized> RetryStrategy for &mut S {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        (**self).next_delay(attempt)
+    }
+}
+
+/// Wait the same amount of time between every retry
+#[derive(Clone, Debug)]
+pub struct FixedBackoff {
+    pub delay: Duration,
+    pub max_retries: u32,
+}
+
+impl RetryStrategy for FixedBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        if attempt > self.max_retries {
+            None
+        } else {
+            Some(self.delay)
+        }
+    }
+}
+
+/// Double the delay after every retry, starting at `base` and never
+/// exceeding `max_delay`
+#[derive(Clone, Debug)]
+pub struct ExponentialBackoff {
+    pub base: Duration,
+    pub max_delay: Duration,
+    pub max_retries: u32,
+}
+
+impl Default for ExponentialBackoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(50),
+            max_delay: Duration::from_secs(2),
+            max_retries: 5,
+        }
+    }
+}
+
+impl RetryStrategy for ExponentialBackoff {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        if attempt > self.max_retries {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        Some(self.base.saturating_mul(factor).min(self.max_delay))
+    }
+}
+
+/// "Decorrelated jitter" from the AWS architecture blog: every delay is
+/// picked at random between `base` and three times the previous delay,
+/// capped at `max_delay`. Spreads out retries from many clients that failed
+/// at the same time.
+#[derive(Clone, Debug)]
+pub struct DecorrelatedJitter {
+    pub base: Duration,
+    pub max_delay: Duration,
+    pub max_retries: u32,
+    prev: Duration,
+}
+
+impl DecorrelatedJitter {
+    pub fn new(base: Duration, max_delay: Duration, max_retries: u32) -> Self {
+        Self {
+            base,
+            max_delay,
+            max_retries,
+            prev: base,
+        }
+    }
+}
+
+impl RetryStrategy for DecorrelatedJitter {
+    fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+        if attempt > self.max_retries {
+            return None;
+        }
+        let upper = self.prev.saturating_mul(3).max(self.base);
+        let delay = rand::thread_rng().gen_range(self.base..=upper);
+        self.prev = delay.min(self.max_delay);
+        Some(self.prev)
+    }
+}
+
+/// Errors that are likely to go away if the command is sent again
+pub(crate) fn is_retryable(err: &RedisError) -> bool {
+    err.is_io_error()
+        || matches!(
+            err.kind(),
+            ErrorKind::TryAgain
+                | ErrorKind::BusyLoadingError
+                | ErrorKind::MasterDown
+                | ErrorKind::ClusterDown
+        )
+}
+
+impl Redis {
+    /// execute a redis command, retrying transient failures as dictated by
+    /// `strategy`. Connection errors re-establish the connection before the
+    /// next attempt, as do writes a demoted master rejects in a
+    /// [crate::RedisConfig::Sentinel] deployment.
+    ///
+    /// Commands are resent as-is: don't use this for non-idempotent commands
+    /// like INCR unless double-application is acceptable, see
+    /// [Self::exec_read_retry] for a safe alternative.
+    pub async fn exec_retry<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        strategy: impl RetryStrategy,
+    ) -> RedisResult<T> {
+        self.retry(cmd, strategy, is_retryable).await
+    }
+
+    /// Like [Self::exec_retry], for read-only commands only (see
+    /// [crate::is_readonly]), which are safe to send any number of times.
+    ///
+    /// Retries more eagerly than [Self::exec_retry]: a script running for too
+    /// long (BUSY) or an open circuit breaker are waited out as well. Any
+    /// other command fails with [GlueError::NonIdempotent] before anything is
+    /// sent.
+    pub async fn exec_read_retry<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        strategy: impl RetryStrategy,
+    ) -> RedisResult<T> {
+        if !is_readonly(cmd) {
+            let command = command::name(cmd).unwrap_or_default();
+            return Err(GlueError::NonIdempotent { command }.into());
+        }
+        self.retry(cmd, strategy, |err| {
+            is_retryable(err)
+                || err.code() == Some("BUSY")
+                || GlueError::from_redis(err) == Some(GlueError::CircuitOpen)
+        })
+        .await
+    }
+
+    async fn retry<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        mut strategy: impl RetryStrategy,
+        retryable: impl Fn(&RedisError) -> bool,
+    ) -> RedisResult<T> {
+        let mut attempt = 0;
+        loop {
+            let err = match self.connection.exec(cmd).await {
+                Ok(val) => return Ok(val),
+                Err(err) if retryable(&err) || self.connection.lost_server(&err) => err,
+                Err(err) => return Err(err),
+            };
+            attempt += 1;
+            let delay = match strategy.next_delay(attempt) {
+                // a cluster needs a while to fail over, don't rush it
+                Some(delay) if err.kind() == ErrorKind::ClusterDown => delay.saturating_mul(2),
+                Some(delay) => delay,
+                None => return Err(err),
+            };
+            tokio::time::sleep(delay).await;
+            if self.connection.lost_server(&err) {
+                // if this fails the next attempt fails too and the strategy
+                // decides whether to keep trying
+                let _ = self.reconnect().await;
+            }
+        }
+    }
+}
+
+impl RedisConnection {
+    /// Whether `err` means the server this connection talks to is gone: a
+    /// connection error, or in a sentinel deployment a write rejected by a
+    /// master demoted to replica by a failover
+    pub(crate) fn lost_server(&self, err: &RedisError) -> bool {
+        err.is_io_error()
+            || (err.kind() == ErrorKind::ReadOnly
+                && matches!(self.client, RedisClient::Sentinel(_)))
+    }
+
+    /// Run `request`, and again on a fresh connection for as long as it fails
+    /// with a connection error, see [crate::RedisOptions::auto_reconnect]
+    pub(crate) async fn reconnecting<T, F, Fut>(&self, request: F) -> RedisResult<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = RedisResult<T>>,
+    {
+        let mut backoff = match &self.options.auto_reconnect {
+            Some(backoff) => backoff.clone(),
+            None => return request().await,
+        };
+        let mut attempt = 0;
+        loop {
+            let err = match request().await {
+                Err(err) if self.lost_server(&err) => err,
+                res => return res,
+            };
+            attempt += 1;
+            match backoff.next_delay(attempt) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(err),
+            }
+            // if this fails the next attempt fails too and the backoff
+            // decides whether to keep trying
+            let _ = self.reconnect().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+    use std::time::Instant;
+
+    #[test]
+    fn builtin_strategies_follow_schedule() {
+        let mut fixed = FixedBackoff {
+            delay: Duration::from_millis(10),
+            max_retries: 2,
+        };
+        let delays: Vec<_> = (1..=3).map(|a| fixed.next_delay(a)).collect();
+        let ten = Some(Duration::from_millis(10));
+        assert_eq!(delays, vec![ten, ten, None]);
+
+        let mut exp = ExponentialBackoff {
+            base: Duration::from_millis(10),
+            max_delay: Duration::from_millis(35),
+            max_retries: 4,
+        };
+        let delays: Vec<_> = (1..=5).map(|a| exp.next_delay(a)).collect();
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(delays, vec![ms(10), ms(20), ms(35), ms(35), None]);
+        // attempt 0 doesn't underflow, huge bases don't overflow
+        assert_eq!(exp.next_delay(0), ms(10));
+        let mut huge = ExponentialBackoff {
+            base: Duration::MAX,
+            max_delay: Duration::MAX,
+            max_retries: 40,
+        };
+        assert_eq!(huge.next_delay(40), Some(Duration::MAX));
+
+        let base = Duration::from_millis(10);
+        let max = Duration::from_millis(100);
+        let mut jitter = DecorrelatedJitter::new(base, max, 50);
+        for attempt in 1..=50 {
+            let delay = jitter.next_delay(attempt).unwrap();
+            assert!(delay >= base && delay <= max);
+        }
+        assert!(jitter.next_delay(51).is_none());
+    }
+
+    struct Recording {
+        schedule: Vec<Duration>,
+        attempts: Vec<u32>,
+    }
+
+    impl RetryStrategy for Recording {
+        fn next_delay(&mut self, attempt: u32) -> Option<Duration> {
+            self.attempts.push(attempt);
+            self.schedule.get(attempt as usize - 1).copied()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn exec_retry_follows_strategy() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let mut strategy = Recording {
+            schedule: vec![
+                Duration::from_millis(10),
+                Duration::from_millis(20),
+                Duration::from_millis(40),
+            ],
+            attempts: Vec::new(),
+        };
+
+        // always fails with a retryable error
+        let mut cmd = redis::cmd("EVAL");
+        cmd.arg("return redis.error_reply('TRYAGAIN simulated')")
+            .arg(0);
+        let start = Instant::now();
+        let err = r
+            .exec_retry::<()>(&mut cmd, &mut strategy)
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(err.kind(), redis::ErrorKind::TryAgain);
+        assert_eq!(strategy.attempts, vec![1, 2, 3, 4]);
+        assert!(start.elapsed() >= Duration::from_millis(70));
+    }
+
+    #[actix_rt::test]
+    async fn exec_retry_backs_off_longer_on_clusterdown() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let mut strategy = Recording {
+            schedule: vec![Duration::from_millis(30), Duration::from_millis(30)],
+            attempts: Vec::new(),
+        };
+
+        let mut cmd = redis::cmd("EVAL");
+        cmd.arg("return redis.error_reply('CLUSTERDOWN simulated')")
+            .arg(0);
+        let start = Instant::now();
+        let err = r
+            .exec_retry::<()>(&mut cmd, &mut strategy)
+            .await
+            .unwrap_err();
+
+        assert_eq!(GlueError::from_redis(&err), Some(GlueError::ClusterDown));
+        assert_eq!(strategy.attempts, vec![1, 2, 3]);
+        assert!(start.elapsed() >= Duration::from_millis(120));
+    }
+
+    #[actix_rt::test]
+    async fn exec_read_retry_survives_connection_loss() {
+        const KEY: &str = "exec_read_retry_survives_connection_loss";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("SET").arg(KEY).arg(7)).await.unwrap();
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let killer = r.dedicated().await.unwrap();
+        let _: () = killer
+            .exec(redis::cmd("CLIENT").arg("KILL").arg("ID").arg(id))
+            .await
+            .unwrap();
+
+        let mut strategy = Recording {
+            schedule: vec![Duration::from_millis(10); 3],
+            attempts: Vec::new(),
+        };
+        let value: u64 = r
+            .exec_read_retry(redis::cmd("GET").arg(KEY), &mut strategy)
+            .await
+            .unwrap();
+        assert_eq!(value, 7);
+        assert_eq!(strategy.attempts, vec![1]);
+    }
+
+    #[actix_rt::test]
+    async fn auto_reconnect_resends_after_connection_loss() {
+        const KEY: &str = "auto_reconnect_resends_after_connection_loss";
+
+        let options = RedisOptions {
+            auto_reconnect: Some(ExponentialBackoff {
+                base: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                max_retries: 3,
+            }),
+            ..Default::default()
+        };
+        let r = Redis::with_options(RedisConfig::Single("redis://127.0.0.1".into()), options)
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        let killer = r.dedicated().await.unwrap();
+        let _: () = killer
+            .exec(redis::cmd("CLIENT").arg("KILL").arg("ID").arg(id))
+            .await
+            .unwrap();
+
+        let _: () = con.exec(redis::cmd("SET").arg(KEY).arg(3)).await.unwrap();
+        let new_id: u64 = r
+            .get_client()
+            .exec(redis::cmd("CLIENT").arg("ID"))
+            .await
+            .unwrap();
+        assert_ne!(new_id, id);
+        let value: u64 = con.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(value, 3);
+    }
+
+    #[actix_rt::test]
+    async fn exec_read_retry_rejects_writes() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let mut strategy = Recording {
+            schedule: vec![Duration::from_millis(10)],
+            attempts: Vec::new(),
+        };
+        let err = r
+            .exec_read_retry::<()>(
+                redis::cmd("SET")
+                    .arg("exec_read_retry_rejects_writes")
+                    .arg(1),
+                &mut strategy,
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::NonIdempotent {
+                command: "SET".into()
+            })
+        );
+        assert!(strategy.attempts.is_empty());
+    }
+}
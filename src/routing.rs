@@ -0,0 +1,517 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Explicit command routing in cluster mode
+use futures::stream::{self, BoxStream, StreamExt, TryStreamExt};
+use rand::seq::SliceRandom;
+use redis::{
+    from_redis_value, Client, ConnectionAddr, ConnectionInfo, ErrorKind, FromRedisValue,
+    IntoConnectionInfo, RedisError, RedisResult, Value,
+};
+
+use crate::slot::slot_for;
+use crate::{Redis, RedisConfig, RedisConnection};
+
+/// Where [Redis::exec_routed] sends a command
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Routing<'a> {
+    /// Every primary, once
+    AllMasters,
+    /// Every primary and replica, once
+    AllNodes,
+    /// One primary picked at random
+    RandomMaster,
+    /// The primary serving a hash slot
+    Slot(u16),
+    /// The primary serving the slot of a key
+    Key(&'a str),
+}
+
+/// Which keys [Redis::cluster_scan] yields. The default yields every key.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Only keys matching this glob-style pattern (MATCH)
+    pub pattern: Option<String>,
+    /// Only keys holding this type, like `hash` (TYPE, Redis 6+)
+    pub key_type: Option<String>,
+    /// How many keys each SCAN call looks at (COUNT), a hint to the server
+    pub count: Option<usize>,
+}
+
+impl ScanOptions {
+    fn scan_cmd(&self, cursor: u64) -> redis::Cmd {
+        let mut cmd = redis::cmd("SCAN");
+        cmd.arg(cursor);
+        if let Some(pattern) = &self.pattern {
+            cmd.arg("MATCH").arg(pattern);
+        }
+        if let Some(count) = self.count {
+            cmd.arg("COUNT").arg(count);
+        }
+        if let Some(key_type) = &self.key_type {
+            cmd.arg("TYPE").arg(key_type);
+        }
+        cmd
+    }
+}
+
+/// A server walked by [Redis::cluster_scan]
+enum ScanNode {
+    /// The shared connection, in single mode
+    Shared(RedisConnection),
+    /// A cluster primary, connected to on the first SCAN
+    Primary {
+        addr: String,
+        info: ConnectionInfo,
+        con: Option<redis::aio::Connection>,
+    },
+}
+
+impl ScanNode {
+    async fn scan(&mut self, cmd: &redis::Cmd) -> RedisResult<(u64, Vec<String>)> {
+        match self {
+            Self::Shared(con) => con.exec(&mut cmd.clone()).await,
+            Self::Primary { info, con, .. } => {
+                if con.is_none() {
+                    *con = Some(Client::open(info.clone())?.get_async_connection().await?);
+                }
+                cmd.query_async(con.as_mut().unwrap()).await
+            }
+        }
+    }
+
+    /// `err` with the address of the node it happened on, when there is one
+    fn blame(&self, err: RedisError) -> RedisError {
+        match self {
+            Self::Shared(_) => err,
+            Self::Primary { addr, .. } => (
+                err.kind(),
+                "SCAN failed on a cluster node",
+                format!("{}: {}", addr, err),
+            )
+                .into(),
+        }
+    }
+
+    /// Every key the node holds that matches `options`. Ends after the first
+    /// error, which is yielded.
+    fn keys(self, options: ScanOptions) -> BoxStream<'static, RedisResult<String>> {
+        stream::try_unfold((self, Some(0)), move |(mut node, cursor)| {
+            let cmd = cursor.map(|cursor| options.scan_cmd(cursor));
+            async move {
+                let cmd = match cmd {
+                    Some(cmd) => cmd,
+                    None => return Ok(None),
+                };
+                let (next, keys) = match node.scan(&cmd).await {
+                    Ok(reply) => reply,
+                    Err(err) => return Err(node.blame(err)),
+                };
+                let cursor = if next == 0 { None } else { Some(next) };
+                Ok(Some((keys, (node, cursor))))
+            }
+        })
+        .map_ok(|keys| stream::iter(keys.into_iter().map(Ok)))
+        .try_flatten()
+        .boxed()
+    }
+}
+
+/// A range of slots from CLUSTER SLOTS, with node addresses as `(host, port)`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SlotRange {
+    pub(crate) start: u16,
+    pub(crate) end: u16,
+    pub(crate) master: (String, u16),
+    pub(crate) replicas: Vec<(String, u16)>,
+}
+
+impl RedisConfig {
+    /// Connection details of the first node, reused for direct connections
+    /// to cluster nodes so that credentials and TLS carry over
+    pub(crate) fn node_info(&self) -> RedisResult<Option<ConnectionInfo>> {
+        match self {
+            Self::Single(_) | Self::Sentinel { .. } => Ok(None),
+            Self::Cluster(nodes) => nodes
+                .first()
+                .map(|node| node.as_str().into_connection_info())
+                .transpose(),
+            Self::ClusterSeed(seed) => seed.as_str().into_connection_info().map(Some),
+        }
+    }
+}
+
+impl Redis {
+    /// Run `cmd` on the node(s) picked by `routing`, returning one reply per
+    /// node in topology order.
+    ///
+    /// Meant for administrative commands like DBSIZE or CONFIG SET that
+    /// target nodes rather than keys; aggregating the replies is up to the
+    /// caller. Each call looks up the topology (CLUSTER SLOTS) and opens
+    /// short-lived connections to the target nodes, bypassing the guards of
+    /// [crate::RedisConnection::exec]. In single mode every routing runs
+    /// `cmd` on the one server.
+    pub async fn exec_routed<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        routing: Routing<'_>,
+    ) -> RedisResult<Vec<T>> {
+        let info = match &self.node_info {
+            Some(info) => info,
+            None => return Ok(vec![self.get_client().exec(cmd).await?]),
+        };
+        let reply: Value = self
+            .get_client()
+            .exec(redis::cmd("CLUSTER").arg("SLOTS"))
+            .await?;
+        let ranges = parse_cluster_slots(&reply)?;
+        let mut replies = Vec::new();
+        for (host, port) in targets(&ranges, routing)? {
+            let node = ConnectionInfo {
+                addr: Box::new(node_addr(&info.addr, host, port)),
+                ..info.clone()
+            };
+            let mut con = Client::open(node)?.get_async_connection().await?;
+            replies.push(cmd.query_async(&mut con).await?);
+        }
+        Ok(replies)
+    }
+
+    /// Run `cmd` on the primary serving the first of `keys`, for commands
+    /// whose first argument is a key count rather than a key, like LMPOP. The
+    /// cluster connection would route those by the count, and then follow a
+    /// MOVED redirect and look up the topology again on every call.
+    ///
+    /// Keys must hash to the same slot. In single mode, and without keys,
+    /// `cmd` goes over the shared connection.
+    pub(crate) async fn exec_numkeys<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        keys: &[&str],
+    ) -> RedisResult<T> {
+        self.connection.ensure_same_slot(keys)?;
+        match (&self.node_info, keys.first()) {
+            (Some(_), Some(key)) => Ok(self
+                .exec_routed(cmd, Routing::Key(key))
+                .await?
+                .pop()
+                .expect("a served slot has a primary")),
+            _ => self.connection.exec(cmd).await,
+        }
+    }
+
+    /// Like [Self::exec_numkeys], for blocking commands: in single mode `cmd`
+    /// goes over a [dedicated](Self::dedicated) connection opened for it, so
+    /// the wait doesn't hold up the shared one.
+    pub(crate) async fn exec_numkeys_blocking<T: FromRedisValue>(
+        &self,
+        cmd: &mut redis::Cmd,
+        keys: &[&str],
+    ) -> RedisResult<T> {
+        if self.node_info.is_some() {
+            return self.exec_numkeys(cmd, keys).await;
+        }
+        self.dedicated().await?.exec(cmd).await
+    }
+}
+
+impl Redis {
+    /// Connection details of the primary serving `slot`, looked up with
+    /// CLUSTER SLOTS. In single mode, the details of the one server.
+    pub(crate) async fn slot_primary(&self, slot: u16) -> RedisResult<ConnectionInfo> {
+        let info = match (&self.node_info, &self.client) {
+            (Some(info), _) => info,
+            (None, client) => match client.single().await? {
+                Some(client) => return Ok(client.get_connection_info().clone()),
+                None => unreachable!("cluster configs have node info"),
+            },
+        };
+        let reply: Value = self
+            .get_client()
+            .exec(redis::cmd("CLUSTER").arg("SLOTS"))
+            .await?;
+        let ranges = parse_cluster_slots(&reply)?;
+        let (host, port) = targets(&ranges, Routing::Slot(slot))?
+            .pop()
+            .expect("a served slot has a primary");
+        Ok(ConnectionInfo {
+            addr: Box::new(node_addr(&info.addr, host, port)),
+            ..info.clone()
+        })
+    }
+}
+
+impl Redis {
+    /// Every key of the keyspace matching `options`, walked with SCAN.
+    ///
+    /// In cluster mode the primaries are looked up (CLUSTER SLOTS) when the
+    /// stream is first polled and SCANned one after the other, each over a
+    /// connection of its own. Every key lives on a single primary, so no key
+    /// is yielded twice across nodes, but SCAN's guarantees still apply per
+    /// node: keys added or removed during the walk may or may not show up,
+    /// and a key may repeat if the node resizes its table in between.
+    ///
+    /// A node failing mid-walk (the node going away included) yields one
+    /// error naming the node, and the walk goes on with the next one. In
+    /// single mode this is a plain SCAN over the shared connection.
+    pub fn cluster_scan(&self, options: ScanOptions) -> BoxStream<'static, RedisResult<String>> {
+        let con = self.get_client();
+        let info = match &self.node_info {
+            Some(info) => info.clone(),
+            None => return ScanNode::Shared(con).keys(options),
+        };
+        let nodes = async move {
+            let reply: Value = con.exec(redis::cmd("CLUSTER").arg("SLOTS")).await?;
+            let ranges = parse_cluster_slots(&reply)?;
+            let nodes = targets(&ranges, Routing::AllMasters)?
+                .into_iter()
+                .map(|(host, port)| ScanNode::Primary {
+                    addr: format!("{}:{}", host, port),
+                    info: ConnectionInfo {
+                        addr: Box::new(node_addr(&info.addr, host, port)),
+                        ..info.clone()
+                    },
+                    con: None,
+                })
+                .map(|node| node.keys(options.clone()))
+                .collect::<Vec<_>>();
+            Ok::<_, RedisError>(stream::iter(nodes).flatten())
+        };
+        stream::once(nodes)
+            .map(|keys| match keys {
+                Ok(keys) => keys.boxed(),
+                Err(err) => stream::once(async { Err(err) }).boxed(),
+            })
+            .flatten()
+            .boxed()
+    }
+}
+
+/// Address of a node, keeping the TLS settings of the seed address
+fn node_addr(seed: &ConnectionAddr, host: String, port: u16) -> ConnectionAddr {
+    match seed {
+        ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+            host,
+            port,
+            insecure: *insecure,
+        },
+        _ => ConnectionAddr::Tcp(host, port),
+    }
+}
+
+/// Deduplicated addresses of the nodes `routing` selects
+fn targets(ranges: &[SlotRange], routing: Routing<'_>) -> RedisResult<Vec<(String, u16)>> {
+    let mut nodes: Vec<(String, u16)> = Vec::new();
+    let mut add = |node: &(String, u16)| {
+        if !nodes.contains(node) {
+            nodes.push(node.clone());
+        }
+    };
+    let slot = match routing {
+        Routing::AllMasters | Routing::RandomMaster => {
+            ranges.iter().for_each(|r| add(&r.master));
+            None
+        }
+        Routing::AllNodes => {
+            for r in ranges {
+                add(&r.master);
+                r.replicas.iter().for_each(&mut add);
+            }
+            None
+        }
+        Routing::Slot(slot) => Some(slot),
+        Routing::Key(key) => Some(slot_for(key)),
+    };
+    if let Some(slot) = slot {
+        match ranges.iter().find(|r| r.start <= slot && slot <= r.end) {
+            Some(r) => add(&r.master),
+            None => {
+                return Err((
+                    ErrorKind::ClusterDown,
+                    "slot isn't served by any node",
+                    slot.to_string(),
+                )
+                    .into())
+            }
+        }
+    }
+    if routing == Routing::RandomMaster {
+        nodes = nodes
+            .choose(&mut rand::thread_rng())
+            .cloned()
+            .into_iter()
+            .collect();
+    }
+    Ok(nodes)
+}
+
+/// Parse a CLUSTER SLOTS reply: one `[start, end, master, replica...]` array
+/// per slot range, where every node is `[host, port, id, ...]`
+pub(crate) fn parse_cluster_slots(reply: &Value) -> RedisResult<Vec<SlotRange>> {
+    let invalid = || {
+        redis::RedisError::from((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected a CLUSTER SLOTS reply, got {:?}", reply),
+        ))
+    };
+    let node = |value: &Value| match value {
+        Value::Bulk(node) if node.len() >= 2 => {
+            Ok((from_redis_value(&node[0])?, from_redis_value(&node[1])?))
+        }
+        _ => Err(invalid()),
+    };
+    let ranges = match reply {
+        Value::Bulk(ranges) => ranges,
+        _ => return Err(invalid()),
+    };
+    ranges
+        .iter()
+        .map(|range| match range {
+            Value::Bulk(range) if range.len() >= 3 => Ok(SlotRange {
+                start: from_redis_value(&range[0])?,
+                end: from_redis_value(&range[1])?,
+                master: node(&range[2])?,
+                replicas: range[3..].iter().map(node).collect::<RedisResult<_>>()?,
+            }),
+            _ => Err(invalid()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    fn cluster_slots_reply() -> Value {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let node =
+            |port: i64, id: &str| Value::Bulk(vec![data("127.0.0.1"), Value::Int(port), data(id)]);
+        Value::Bulk(vec![
+            Value::Bulk(vec![
+                Value::Int(0),
+                Value::Int(5460),
+                node(30001, "a"),
+                node(30004, "d"),
+            ]),
+            Value::Bulk(vec![
+                Value::Int(5461),
+                Value::Int(10922),
+                node(30002, "b"),
+                node(30005, "e"),
+            ]),
+            Value::Bulk(vec![Value::Int(10923), Value::Int(16383), node(30003, "c")]),
+        ])
+    }
+
+    #[test]
+    fn targets_follow_routing() {
+        let ranges = parse_cluster_slots(&cluster_slots_reply()).unwrap();
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0].replicas, vec![("127.0.0.1".into(), 30004)]);
+        assert!(parse_cluster_slots(&Value::Int(1)).is_err());
+
+        let ports = |routing| -> Vec<u16> {
+            targets(&ranges, routing)
+                .unwrap()
+                .into_iter()
+                .map(|(_, port)| port)
+                .collect()
+        };
+        assert_eq!(ports(Routing::AllMasters), vec![30001, 30002, 30003]);
+        assert_eq!(
+            ports(Routing::AllNodes),
+            vec![30001, 30004, 30002, 30005, 30003]
+        );
+        assert_eq!(ports(Routing::Slot(6000)), vec![30002]);
+        // "foo" hashes to 12182
+        assert_eq!(ports(Routing::Key("foo")), vec![30003]);
+        assert_eq!(ports(Routing::RandomMaster).len(), 1);
+    }
+
+    #[test]
+    fn scan_options_build_scan() {
+        let options = ScanOptions {
+            pattern: Some("user:*".into()),
+            key_type: Some("hash".into()),
+            count: Some(500),
+        };
+        let mut expected = redis::cmd("SCAN");
+        expected
+            .arg(7)
+            .arg(&["MATCH", "user:*", "COUNT", "500", "TYPE", "hash"]);
+        assert_eq!(
+            options.scan_cmd(7).get_packed_command(),
+            expected.get_packed_command()
+        );
+        assert_eq!(
+            ScanOptions::default().scan_cmd(0).get_packed_command(),
+            redis::cmd("SCAN").arg(0).get_packed_command()
+        );
+    }
+
+    async fn scan_yields_every_key_once(r: Redis, prefix: &str) {
+        let con = r.get_client();
+        let keys: Vec<String> = (0..200).map(|i| format!("{}{}", prefix, i)).collect();
+        for key in &keys {
+            let _: () = con.exec(redis::cmd("SET").arg(key).arg(1)).await.unwrap();
+        }
+        let mut scanned: Vec<String> = r
+            .cluster_scan(ScanOptions {
+                pattern: Some(format!("{}*", prefix)),
+                count: Some(20),
+                ..ScanOptions::default()
+            })
+            .try_collect()
+            .await
+            .unwrap();
+        scanned.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(scanned, expected);
+    }
+
+    #[actix_rt::test]
+    async fn cluster_scan_falls_back_to_scan() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        scan_yields_every_key_once(r, "cluster_scan_falls_back_to_scan:").await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_scan_covers_every_primary() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        scan_yields_every_key_once(r, "cluster_scan_covers_every_primary:").await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn exec_routed_fans_out() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        for i in 0..10 {
+            let _: () = r
+                .get_client()
+                .exec(
+                    redis::cmd("SET")
+                        .arg(format!("exec_routed_fans_out{}", i))
+                        .arg(i),
+                )
+                .await
+                .unwrap();
+        }
+        let sizes: Vec<u64> = r
+            .exec_routed(&mut redis::cmd("DBSIZE"), Routing::AllMasters)
+            .await
+            .unwrap();
+        assert!(sizes.len() > 1);
+        assert!(sizes.iter().sum::<u64>() >= 10);
+    }
+}
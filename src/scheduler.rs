@@ -0,0 +1,109 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Delayed tasks kept in a sorted set
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use redis::RedisResult;
+
+use crate::RedisConnection;
+
+/// Pops up to ARGV[2] members of KEYS[1] scored at most ARGV[1], in one step
+/// so that the same task can't be handed to two pollers
+const POLL_DUE: &str = r#"
+local due = redis.call('ZRANGEBYSCORE', KEYS[1], '-inf', ARGV[1], 'LIMIT', 0, ARGV[2])
+if #due > 0 then
+    redis.call('ZREM', KEYS[1], unpack(due))
+end
+return due
+"#;
+
+/// Milliseconds since the Unix epoch, the score of a task due at `at`
+fn score(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl RedisConnection {
+    /// Schedule `task_id` to run at `at` in the delay queue `queue`, a sorted
+    /// set scored by due time in milliseconds (ZADD). Scheduling a task again
+    /// moves it to the new time.
+    pub async fn schedule(&self, queue: &str, task_id: &str, at: SystemTime) -> RedisResult<()> {
+        self.exec(redis::cmd("ZADD").arg(queue).arg(score(at)).arg(task_id))
+            .await
+    }
+
+    /// Take up to `max` tasks of `queue` that are due at `now`, earliest
+    /// first, removing them from the queue.
+    ///
+    /// Looking the tasks up and removing them runs as a Lua script, so
+    /// pollers racing on the same queue never get the same task. A task is
+    /// gone once returned: schedule it again if processing it fails.
+    pub async fn poll_due(
+        &self,
+        queue: &str,
+        now: SystemTime,
+        max: usize,
+    ) -> RedisResult<Vec<String>> {
+        if max == 0 {
+            return Ok(Vec::new());
+        }
+        self.exec(
+            redis::cmd("EVAL")
+                .arg(POLL_DUE)
+                .arg(1)
+                .arg(queue)
+                .arg(score(now))
+                .arg(max),
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn poll_due_takes_only_due_tasks() {
+        const QUEUE: &str = "poll_due_takes_only_due_tasks";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(QUEUE)).await.unwrap();
+        let now = SystemTime::now();
+        let minute = Duration::from_secs(60);
+        con.schedule(QUEUE, "later", now + minute).await.unwrap();
+        con.schedule(QUEUE, "second", now - minute).await.unwrap();
+        con.schedule(QUEUE, "first", now - 2 * minute)
+            .await
+            .unwrap();
+        con.schedule(QUEUE, "third", now).await.unwrap();
+
+        assert_eq!(
+            con.poll_due(QUEUE, now, 2).await.unwrap(),
+            vec!["first", "second"]
+        );
+        assert_eq!(con.poll_due(QUEUE, now, 10).await.unwrap(), vec!["third"]);
+        assert!(con.poll_due(QUEUE, now, 10).await.unwrap().is_empty());
+
+        let left: Vec<String> = con
+            .exec(redis::cmd("ZRANGE").arg(QUEUE).arg(0).arg(-1))
+            .await
+            .unwrap();
+        assert_eq!(left, vec!["later"]);
+        assert_eq!(
+            con.poll_due(QUEUE, now + 2 * minute, 10).await.unwrap(),
+            vec!["later"]
+        );
+    }
+}
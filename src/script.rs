@@ -0,0 +1,209 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Lua scripts run by their SHA1
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use redis::{ErrorKind, FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::{GlueError, Redis, Routing};
+
+#[derive(Clone)]
+struct Script {
+    source: Arc<str>,
+    sha: String,
+}
+
+/// Lua scripts registered by name and sent with EVALSHA, so their source
+/// only goes over the wire when a node doesn't know them yet, see
+/// [Redis::scripts]. Clones share the registered scripts.
+#[derive(Clone)]
+pub struct ScriptManager {
+    redis: Redis,
+    scripts: Arc<Mutex<HashMap<String, Script>>>,
+}
+
+impl Redis {
+    /// A [ScriptManager] without any scripts registered yet
+    pub fn scripts(&self) -> ScriptManager {
+        ScriptManager {
+            redis: self.clone(),
+            scripts: Arc::default(),
+        }
+    }
+}
+
+impl ScriptManager {
+    /// Register `source` as `name`, replacing any script registered under
+    /// that name, and return its SHA1. Nothing is sent to Redis: scripts are
+    /// loaded by [Self::load_all] or the first time they are invoked.
+    pub fn register(&self, name: &str, source: &str) -> String {
+        let sha = redis::Script::new(source).get_hash().to_owned();
+        let script = Script {
+            source: source.into(),
+            sha: sha.clone(),
+        };
+        self.scripts.lock().unwrap().insert(name.to_owned(), script);
+        sha
+    }
+
+    /// SHA1 of the script registered as `name`
+    pub fn sha(&self, name: &str) -> Option<String> {
+        self.scripts
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|script| script.sha.clone())
+    }
+
+    /// Load every registered script (SCRIPT LOAD) on every primary, so that
+    /// invocations don't have to fall back to EVAL later. In single mode on
+    /// the one server.
+    pub async fn load_all(&self) -> RedisResult<()> {
+        let scripts: Vec<Script> = self.scripts.lock().unwrap().values().cloned().collect();
+        for script in scripts {
+            self.load(&script).await?;
+        }
+        Ok(())
+    }
+
+    /// Run the script registered as `name` (EVALSHA) with `keys` and `args`.
+    ///
+    /// If the node serving the keys doesn't know the script (NOSCRIPT), it is
+    /// sent with EVAL instead, which also loads it there; in cluster mode it
+    /// is first loaded on every primary, so other nodes don't miss it
+    /// either. Fails with [GlueError::UnknownScript] if nothing was
+    /// registered as `name`.
+    ///
+    /// In cluster mode every key must hash to the same slot.
+    pub async fn invoke<T: FromRedisValue>(
+        &self,
+        name: &str,
+        keys: &[&str],
+        args: impl ToRedisArgs,
+    ) -> RedisResult<T> {
+        let script = self.scripts.lock().unwrap().get(name).cloned();
+        let script = match script {
+            Some(script) => script,
+            None => {
+                return Err(GlueError::UnknownScript {
+                    name: name.to_owned(),
+                }
+                .into())
+            }
+        };
+        let args = args.to_redis_args();
+        let con = self.redis.get_client();
+        let mut evalsha = redis::cmd("EVALSHA");
+        evalsha
+            .arg(&script.sha)
+            .arg(keys.len())
+            .arg(keys)
+            .arg(&args[..]);
+        match con.exec(&mut evalsha).await {
+            Err(e) if e.kind() == ErrorKind::NoScriptError => {}
+            res => return res,
+        }
+        if con.is_cluster() {
+            self.load(&script).await?;
+        }
+        let mut eval = redis::cmd("EVAL");
+        eval.arg(&*script.source)
+            .arg(keys.len())
+            .arg(keys)
+            .arg(&args[..]);
+        con.exec(&mut eval).await
+    }
+
+    async fn load(&self, script: &Script) -> RedisResult<()> {
+        self.redis
+            .exec_routed::<String>(
+                redis::cmd("SCRIPT").arg("LOAD").arg(&*script.source),
+                Routing::AllMasters,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    async fn noscript_falls_back_to_eval(r: Redis) {
+        let scripts = r.scripts();
+        let sha = scripts.register("sum", "return tonumber(ARGV[1]) + tonumber(ARGV[2])");
+        assert_eq!(scripts.sha("sum").as_deref(), Some(sha.as_str()));
+        let _: Vec<()> = r
+            .exec_routed(redis::cmd("SCRIPT").arg("FLUSH"), Routing::AllMasters)
+            .await
+            .unwrap();
+
+        let sum: u64 = scripts.invoke("sum", &[], (1, 2)).await.unwrap();
+        assert_eq!(sum, 3);
+        let loaded: Vec<Vec<bool>> = r
+            .exec_routed(
+                redis::cmd("SCRIPT").arg("EXISTS").arg(&sha),
+                Routing::AllMasters,
+            )
+            .await
+            .unwrap();
+        assert!(loaded.iter().all(|exists| exists == &[true]));
+
+        let _: () = r
+            .get_client()
+            .exec(redis::cmd("SET").arg(&["{script}key", "4"]))
+            .await
+            .unwrap();
+        scripts.register("get_plus", "return redis.call('GET', KEYS[1]) + ARGV[1]");
+        scripts.load_all().await.unwrap();
+        let got: u64 = scripts
+            .invoke("get_plus", &["{script}key"], 1)
+            .await
+            .unwrap();
+        assert_eq!(got, 5);
+
+        let err = scripts
+            .invoke::<()>("missing", &[], Vec::<u8>::new())
+            .await
+            .unwrap_err();
+        assert_eq!(
+            GlueError::from_redis(&err),
+            Some(GlueError::UnknownScript {
+                name: "missing".into()
+            })
+        );
+    }
+
+    #[test]
+    fn register_hashes_locally() {
+        let r = Redis::new_lazy(RedisConfig::Single("redis://127.0.0.1".into())).unwrap();
+        let scripts = r.scripts();
+        assert_eq!(
+            scripts.register("one", "return 1"),
+            "e0e1f9fabfc9d4800c877a703b823ac0578ff8db"
+        );
+        assert!(r.scripts().sha("one").is_none());
+        assert!(scripts.clone().sha("one").is_some());
+    }
+
+    #[actix_rt::test]
+    async fn invoke_reloads_flushed_scripts() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        noscript_falls_back_to_eval(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn invoke_loads_scripts_on_every_primary() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        noscript_falls_back_to_eval(r).await;
+    }
+}
@@ -0,0 +1,293 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Deployments whose master is discovered through Redis Sentinel
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use rand::seq::SliceRandom;
+use redis::{Client, ConnectionAddr, ConnectionInfo, ErrorKind, IntoConnectionInfo, RedisResult};
+
+use crate::RedisClient;
+
+/// The sentinels of a [crate::RedisConfig::Sentinel] deployment, asked for
+/// the address of the current master every time a connection is opened
+#[derive(Clone, Debug)]
+pub struct SentinelClient {
+    /// Addresses only: sentinels are queried without credentials
+    sentinels: Vec<ConnectionInfo>,
+    master_name: String,
+    /// Credentials and database for the master, from the first sentinel URL
+    master: ConnectionInfo,
+    /// Connect to a replica of the master instead, see [Self::for_replicas]
+    replica: bool,
+    /// `host:port` of the server the sentinels reported last, shared by
+    /// clones
+    last_server: Arc<Mutex<Option<String>>>,
+}
+
+impl SentinelClient {
+    /// Fails on invalid URLs or an empty list
+    pub(crate) fn new(sentinels: &[String], master_name: &str) -> RedisResult<Self> {
+        let sentinels = sentinels
+            .iter()
+            .map(|url| url.as_str().into_connection_info())
+            .collect::<RedisResult<Vec<ConnectionInfo>>>()?;
+        let master = match sentinels.first() {
+            Some(first) => first.clone(),
+            None => return Err((ErrorKind::InvalidClientConfig, "no sentinel URL given").into()),
+        };
+        Ok(Self {
+            sentinels: sentinels
+                .into_iter()
+                .map(|info| ConnectionInfo {
+                    addr: info.addr,
+                    db: 0,
+                    username: None,
+                    passwd: None,
+                })
+                .collect(),
+            master_name: master_name.to_owned(),
+            master,
+            replica: false,
+            last_server: Arc::default(),
+        })
+    }
+
+    /// A client whose connections go to a replica of the master, picked at
+    /// random among the healthy ones every time a connection is opened
+    pub(crate) fn for_replicas(&self) -> Self {
+        Self {
+            replica: true,
+            last_server: Arc::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether connections go to a replica, see [Self::for_replicas]
+    pub(crate) fn is_replica(&self) -> bool {
+        self.replica
+    }
+
+    /// Name the sentinels know the master by
+    pub fn master_name(&self) -> &str {
+        &self.master_name
+    }
+
+    /// `host:port` of the server the last connection was opened to
+    #[cfg(feature = "tracing")]
+    pub(crate) fn last_server(&self) -> Option<String> {
+        self.last_server.lock().unwrap().clone()
+    }
+
+    /// Credentials and database the master is connected with
+    pub(crate) fn master_info(&self) -> &ConnectionInfo {
+        &self.master
+    }
+
+    /// Client for the current master, asking the sentinels in order
+    /// (SENTINEL GET-MASTER-ADDR-BY-NAME) until one knows it. Fails with
+    /// the error of the last sentinel if none does.
+    pub(crate) async fn master(&self) -> RedisResult<Client> {
+        let mut last_err = None;
+        for sentinel in &self.sentinels {
+            match self.ask(sentinel).await {
+                Ok(Some((host, port))) => return self.client_for(sentinel, host, port),
+                Ok(None) => {
+                    last_err = Some(
+                        (
+                            ErrorKind::IoError,
+                            "sentinel doesn't know the master",
+                            self.master_name.clone(),
+                        )
+                            .into(),
+                    )
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("a sentinel config has sentinels"))
+    }
+
+    /// Client for a random healthy replica of the master, asking the
+    /// sentinels in order (SENTINEL REPLICAS) until one knows of such a
+    /// replica. Fails with the error of the last sentinel if none does.
+    pub(crate) async fn replica(&self) -> RedisResult<Client> {
+        let mut last_err = None;
+        for sentinel in &self.sentinels {
+            match self.ask_replicas(sentinel).await {
+                Ok(replicas) => {
+                    let healthy: Vec<&HashMap<String, String>> = replicas
+                        .iter()
+                        .filter(|replica| is_healthy(replica))
+                        .collect();
+                    let picked = healthy.choose(&mut rand::thread_rng()).and_then(|replica| {
+                        let port = replica.get("port")?.parse().ok()?;
+                        Some((replica.get("ip")?.clone(), port))
+                    });
+                    match picked {
+                        Some((host, port)) => return self.client_for(sentinel, host, port),
+                        None => {
+                            last_err = Some(
+                                (
+                                    ErrorKind::IoError,
+                                    "sentinel knows no healthy replica of the master",
+                                    self.master_name.clone(),
+                                )
+                                    .into(),
+                            )
+                        }
+                    }
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.expect("a sentinel config has sentinels"))
+    }
+
+    /// Client for the server at `host:port` reported by `sentinel`, with
+    /// TLS if the sentinel uses it and the master's credentials
+    fn client_for(
+        &self,
+        sentinel: &ConnectionInfo,
+        host: String,
+        port: u16,
+    ) -> RedisResult<Client> {
+        *self.last_server.lock().unwrap() = Some(format!("{}:{}", host, port));
+        let addr = match &*sentinel.addr {
+            ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+                host,
+                port,
+                insecure: *insecure,
+            },
+            _ => ConnectionAddr::Tcp(host, port),
+        };
+        Client::open(ConnectionInfo {
+            addr: Box::new(addr),
+            ..self.master.clone()
+        })
+    }
+
+    async fn ask_replicas(
+        &self,
+        sentinel: &ConnectionInfo,
+    ) -> RedisResult<Vec<HashMap<String, String>>> {
+        let mut con = Client::open(sentinel.clone())?
+            .get_async_connection()
+            .await?;
+        redis::cmd("SENTINEL")
+            .arg("REPLICAS")
+            .arg(&self.master_name)
+            .query_async(&mut con)
+            .await
+    }
+
+    async fn ask(&self, sentinel: &ConnectionInfo) -> RedisResult<Option<(String, u16)>> {
+        let mut con = Client::open(sentinel.clone())?
+            .get_async_connection()
+            .await?;
+        redis::cmd("SENTINEL")
+            .arg("GET-MASTER-ADDR-BY-NAME")
+            .arg(&self.master_name)
+            .query_async(&mut con)
+            .await
+    }
+}
+
+impl RedisClient {
+    /// The client new connections to the one server use: that of a single
+    /// config, or one for the current master of a sentinel config. `None` in
+    /// cluster mode.
+    pub(crate) async fn single(&self) -> RedisResult<Option<Client>> {
+        match self {
+            Self::Single(client) => Ok(Some(client.clone())),
+            Self::Sentinel(sentinel) => sentinel.master().await.map(Some),
+            Self::Cluster(_) => Ok(None),
+        }
+    }
+}
+
+/// Whether a replica listed by SENTINEL REPLICAS can serve reads: up as far
+/// as the sentinel can tell and replicating from its master
+fn is_healthy(replica: &HashMap<String, String>) -> bool {
+    let flags = replica.get("flags").map(String::as_str).unwrap_or_default();
+    let down = flags
+        .split(',')
+        .any(|flag| matches!(flag, "s_down" | "o_down" | "disconnected"));
+    !down && replica.get("master-link-status").map(String::as_str) == Some("ok")
+}
+
+/// Fail unless `con` talks to a master (ROLE): right after a failover, a
+/// sentinel may still hand out the old master before it is demoted
+pub(crate) async fn ensure_master(con: &mut redis::aio::Connection) -> RedisResult<()> {
+    let role: Vec<redis::Value> = redis::cmd("ROLE").query_async(con).await?;
+    match role.first() {
+        Some(redis::Value::Data(role)) if role == b"master" => Ok(()),
+        _ => Err((
+            ErrorKind::IoError,
+            "sentinel pointed at a server that isn't a master",
+        )
+            .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn sentinels_are_queried_without_credentials() {
+        let client = SentinelClient::new(
+            &[
+                "redis://:secret@10.0.0.1:26379/2".into(),
+                "redis://10.0.0.2:26379".into(),
+            ],
+            "mymaster",
+        )
+        .unwrap();
+        assert!(client
+            .sentinels
+            .iter()
+            .all(|info| info.passwd.is_none() && info.db == 0));
+        assert_eq!(client.master_info().passwd.as_deref(), Some("secret"));
+        assert_eq!(client.master_info().db, 2);
+    }
+
+    #[test]
+    fn only_healthy_replicas_serve_reads() {
+        let replica = |flags: &str, link: &str| -> HashMap<String, String> {
+            [("flags", flags), ("master-link-status", link)]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        assert!(is_healthy(&replica("slave", "ok")));
+        assert!(!is_healthy(&replica("s_down,slave", "ok")));
+        assert!(!is_healthy(&replica("slave,disconnected", "ok")));
+        assert!(!is_healthy(&replica("slave", "err")));
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Sentinel watching mymaster, URL in REDIS_SENTINEL"]
+    async fn sentinel_config_connects_to_master() {
+        let sentinel = std::env::var("REDIS_SENTINEL").unwrap();
+        let r = Redis::new(RedisConfig::Sentinel {
+            sentinels: vec![sentinel],
+            master_name: "mymaster".into(),
+        })
+        .await
+        .unwrap();
+        let con = r.get_client();
+        assert!(!con.is_cluster());
+        let _: () = con
+            .exec(redis::cmd("SET").arg(&["sentinel_config_connects_to_master", "1"]))
+            .await
+            .unwrap();
+        let role: Vec<redis::Value> = con.exec(&mut redis::cmd("ROLE")).await.unwrap();
+        assert_eq!(role[0], redis::Value::Data(b"master".to_vec()));
+    }
+}
@@ -0,0 +1,1112 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Server introspection and administration helpers
+use std::io;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use redis::{from_redis_value, ErrorKind, RedisResult, Value};
+
+use crate::{is_pong, Redis, RedisConnection, Routing};
+
+/// Round-trip latency distribution, see [RedisConnection::latency_sample]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    /// Median
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+impl LatencyStats {
+    /// Summarize `samples`, which must not be empty
+    fn from_samples(mut samples: Vec<Duration>) -> Self {
+        samples.sort();
+        let total: Duration = samples.iter().sum();
+        Self {
+            min: samples[0],
+            max: samples[samples.len() - 1],
+            mean: total / samples.len() as u32,
+            p50: percentile(&samples, 50),
+            p99: percentile(&samples, 99),
+        }
+    }
+}
+
+/// Nearest-rank percentile of the non-empty, sorted `samples`
+fn percentile(samples: &[Duration], pct: usize) -> Duration {
+    let rank = (pct * samples.len()).div_ceil(100);
+    samples[rank.max(1) - 1]
+}
+
+/// Deployment mode reported by the server
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ServerMode {
+    Standalone,
+    Cluster,
+    Sentinel,
+}
+
+/// What the server supports, see [Redis::server_capabilities]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    /// `(major, minor, patch)`
+    pub version: (u8, u8, u8),
+    pub mode: ServerMode,
+}
+
+impl Capabilities {
+    fn at_least(&self, version: (u8, u8, u8)) -> bool {
+        self.version >= version
+    }
+
+    /// RESP3 and HELLO (Redis 6+)
+    pub fn supports_resp3(&self) -> bool {
+        self.at_least((6, 0, 0))
+    }
+
+    /// CLIENT TRACKING (Redis 6+)
+    pub fn supports_client_tracking(&self) -> bool {
+        self.at_least((6, 0, 0))
+    }
+
+    /// GETDEL (Redis 6.2+)
+    pub fn supports_getdel(&self) -> bool {
+        self.at_least((6, 2, 0))
+    }
+
+    /// GETEX (Redis 6.2+)
+    pub fn supports_getex(&self) -> bool {
+        self.at_least((6, 2, 0))
+    }
+
+    /// Functions, LMPOP, ZMPOP, SINTERCARD and COMMAND LIST (Redis 7+)
+    pub fn supports_redis7_commands(&self) -> bool {
+        self.at_least((7, 0, 0))
+    }
+}
+
+/// What the server says about itself and the connection, see
+/// [RedisConnection::hello_info]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HelloInfo {
+    /// Always `redis`, or the name of a compatible server
+    pub server: String,
+    pub version: String,
+    /// Protocol the connection speaks, 2 for redis-glue
+    pub proto: u8,
+    /// Connection ID, as in CLIENT LIST and CLIENT KILL ID
+    pub id: u64,
+    pub mode: ServerMode,
+    /// `master` or `replica`
+    pub role: String,
+    pub modules: Vec<ModuleInfo>,
+}
+
+impl HelloInfo {
+    /// Whether the module called `name` is loaded, like `search` for
+    /// RediSearch or `ReJSON` for RedisJSON
+    pub fn has_module(&self, name: &str) -> bool {
+        self.modules.iter().any(|module| module.name == name)
+    }
+}
+
+/// A loaded module, see [HelloInfo::modules]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub name: String,
+    /// Encoded as `major * 10000 + minor * 100 + patch`, so 20804 is 2.8.4
+    pub version: i64,
+}
+
+/// How far a replica trails its primary, see [RedisConnection::replica_lag]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicaLag {
+    /// Address the replica announced, as `(host, port)`
+    pub addr: (String, u16),
+    /// Replication state, `online` once the initial sync is done
+    pub state: String,
+    /// Replication offset the replica acknowledged
+    pub offset: u64,
+    /// Bytes of the replication stream the replica hasn't acknowledged yet
+    pub lag_bytes: u64,
+    /// Time since the replica's last acknowledgement, in whole seconds
+    pub last_ack: Duration,
+}
+
+/// Options of a manual failover, see [RedisConnection::failover]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FailoverOpts {
+    /// Replica to promote as `(host, port)`, any suitable one otherwise
+    pub to: Option<(String, u16)>,
+    /// Promote [Self::to] even if it hasn't caught up when [Self::timeout]
+    /// elapses. Needs both of them.
+    pub force: bool,
+    /// Give up (or, with [Self::force], promote anyway) after this long
+    pub timeout: Option<Duration>,
+}
+
+impl FailoverOpts {
+    fn build(&self) -> RedisResult<redis::Cmd> {
+        if self.force && (self.to.is_none() || self.timeout.is_none()) {
+            return Err((
+                ErrorKind::ClientError,
+                "FAILOVER FORCE needs a target and a timeout",
+            )
+                .into());
+        }
+        let mut cmd = redis::cmd("FAILOVER");
+        if let Some((host, port)) = &self.to {
+            cmd.arg("TO").arg(host).arg(*port);
+            if self.force {
+                cmd.arg("FORCE");
+            }
+        }
+        if let Some(timeout) = self.timeout {
+            cmd.arg("TIMEOUT").arg(timeout.as_millis() as u64);
+        }
+        Ok(cmd)
+    }
+}
+
+/// Mode of the server behind `url`, probed over a throwaway connection, to
+/// pick the [crate::RedisConfig] variant for an endpoint that isn't known in
+/// advance.
+///
+/// Trusts `redis_mode` in INFO server first. Servers that report
+/// `standalone` are asked CLUSTER INFO too; the error a standalone server
+/// replies with is expected and confirms it.
+pub async fn detect_mode(url: &str) -> RedisResult<ServerMode> {
+    let mut con = redis::Client::open(url)?.get_async_connection().await?;
+    let info: redis::InfoDict = redis::cmd("INFO")
+        .arg("server")
+        .query_async(&mut con)
+        .await?;
+    let mode = server_mode(&info);
+    if mode != ServerMode::Standalone {
+        return Ok(mode);
+    }
+    let cluster: RedisResult<redis::InfoDict> = redis::cmd("CLUSTER")
+        .arg("INFO")
+        .query_async(&mut con)
+        .await;
+    match cluster {
+        Ok(info) if info.contains_key(&"cluster_state") => Ok(ServerMode::Cluster),
+        Ok(_) => Ok(ServerMode::Standalone),
+        Err(e) if e.kind() == ErrorKind::ResponseError => Ok(ServerMode::Standalone),
+        Err(e) => Err(e),
+    }
+}
+
+/// Mode reported as `redis_mode` by INFO server
+fn server_mode(info: &redis::InfoDict) -> ServerMode {
+    match info.get::<String>("redis_mode").as_deref() {
+        Some("cluster") => ServerMode::Cluster,
+        Some("sentinel") => ServerMode::Sentinel,
+        _ => ServerMode::Standalone,
+    }
+}
+
+fn config_cmd(subcommand: &str) -> redis::Cmd {
+    let mut cmd = redis::cmd("CONFIG");
+    cmd.arg(subcommand);
+    cmd
+}
+
+/// Parse a `major.minor.patch` version string, a missing patch counting as 0
+fn parse_version(version: &str) -> Option<(u8, u8, u8)> {
+    let mut parts = version.trim().splitn(3, '.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    let patch = match parts.next() {
+        // tolerate suffixes like `7.2.4-rc1`
+        Some(patch) => patch
+            .split(|c: char| !c.is_ascii_digit())
+            .next()?
+            .parse()
+            .ok()?,
+        None => 0,
+    };
+    Some((major, minor, patch))
+}
+
+impl Redis {
+    /// Version and mode of the server (INFO server), to gate features on.
+    /// In cluster mode this asks a single primary, see [Redis::exec_routed].
+    pub async fn server_capabilities(&self) -> RedisResult<Capabilities> {
+        let mut replies: Vec<redis::InfoDict> = self
+            .exec_routed(redis::cmd("INFO").arg("server"), Routing::RandomMaster)
+            .await?;
+        let info = replies.pop().ok_or_else(|| {
+            redis::RedisError::from((ErrorKind::ClusterDown, "no primary to ask"))
+        })?;
+        let version: String = info.get("redis_version").unwrap_or_default();
+        let version = parse_version(&version).ok_or_else(|| {
+            redis::RedisError::from((
+                ErrorKind::TypeError,
+                "Response was of incompatible type",
+                format!("unexpected redis_version {:?}", version),
+            ))
+        })?;
+        Ok(Capabilities {
+            version,
+            mode: server_mode(&info),
+        })
+    }
+}
+
+impl RedisConnection {
+    /// Server details and connection properties (HELLO, Redis 6+), modules
+    /// included, for gating features on versions and modules.
+    ///
+    /// HELLO is sent without a protocol version, so the connection stays on
+    /// RESP2. In cluster mode this asks a single node.
+    pub async fn hello_info(&self) -> RedisResult<HelloInfo> {
+        let reply = self.exec(&mut redis::cmd("HELLO")).await?;
+        parse_hello(&reply)
+    }
+
+    /// Number of commands the server supports (COMMAND COUNT)
+    pub async fn command_count(&self) -> RedisResult<u64> {
+        self.exec(redis::cmd("COMMAND").arg("COUNT")).await
+    }
+
+    /// Names of the commands the server supports, lower-cased.
+    ///
+    /// Uses COMMAND LIST (Redis 7+), falling back to extracting the names
+    /// from the full COMMAND reply on older servers. In cluster mode this asks
+    /// a single node; all nodes are expected to run the same version.
+    pub async fn command_list(&self) -> RedisResult<Vec<String>> {
+        match self.exec(redis::cmd("COMMAND").arg("LIST")).await {
+            Ok(names) => Ok(names),
+            Err(e) if e.kind() == ErrorKind::ResponseError => {
+                let reply: Value = self.exec(&mut redis::cmd("COMMAND")).await?;
+                parse_command_names(&reply)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hand the primary role over to one of this primary's replicas
+    /// (FAILOVER, Redis 6.2+). Returns once the failover has started; follow
+    /// its progress in INFO replication.
+    ///
+    /// Only for primary/replica setups: Redis Cluster uses CLUSTER FAILOVER,
+    /// sent to the replica, instead.
+    pub async fn failover(&self, opts: FailoverOpts) -> RedisResult<()> {
+        self.ensure_not_cluster("FAILOVER doesn't apply to Redis Cluster")?;
+        self.exec(&mut opts.build()?).await
+    }
+
+    /// Abort a failover started by [Self::failover] (FAILOVER ABORT)
+    pub async fn failover_abort(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("FAILOVER doesn't apply to Redis Cluster")?;
+        self.exec(redis::cmd("FAILOVER").arg("ABORT")).await
+    }
+
+    /// Replicas attached to this primary and how far behind each one is, from
+    /// the `slaveN` lines of INFO replication, to skip replicas too stale to
+    /// read from.
+    ///
+    /// The lag is the primary's `master_repl_offset` minus the offset the
+    /// replica acknowledged; replicas acknowledge about once a second, so a
+    /// few bytes of lag are normal under writes. A server without replicas,
+    /// like a replica at the end of a chain, returns an empty list. Single
+    /// mode only, as in cluster mode INFO would ask a single random node.
+    pub async fn replica_lag(&self) -> RedisResult<Vec<ReplicaLag>> {
+        self.ensure_not_cluster("replica_lag isn't supported in cluster mode")?;
+        let info: String = self.exec(redis::cmd("INFO").arg("replication")).await?;
+        parse_replica_lag(&info)
+    }
+
+    /// Write the running configuration, CONFIG SET changes included, back to
+    /// the server's config file (CONFIG REWRITE), so it survives a restart.
+    ///
+    /// Fails with an error reply if the server was started without a config
+    /// file. Single mode only: in cluster mode each node has its own file,
+    /// see [crate::Redis::exec_routed].
+    pub async fn config_rewrite(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("CONFIG REWRITE isn't supported in cluster mode")?;
+        self.exec(&mut config_cmd("REWRITE")).await
+    }
+
+    /// Reset the statistics reported by INFO, like command counts and
+    /// keyspace hits (CONFIG RESETSTAT). Same restrictions as
+    /// [Self::config_rewrite].
+    pub async fn config_resetstat(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("CONFIG RESETSTAT isn't supported in cluster mode")?;
+        self.exec(&mut config_cmd("RESETSTAT")).await
+    }
+
+    /// Start writing an RDB snapshot in the background (BGSAVE). Returns
+    /// once the save has started; see [Self::save_and_wait] to wait for it.
+    /// Single mode only: in cluster mode each node saves its own data, see
+    /// [crate::Redis::exec_routed].
+    pub async fn bgsave(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("BGSAVE isn't supported in cluster mode")?;
+        self.exec(&mut redis::cmd("BGSAVE")).await
+    }
+
+    /// Start rewriting the append-only file in the background
+    /// (BGREWRITEAOF). Same restrictions as [Self::bgsave].
+    pub async fn bgrewriteaof(&self) -> RedisResult<()> {
+        self.ensure_not_cluster("BGREWRITEAOF isn't supported in cluster mode")?;
+        self.exec(&mut redis::cmd("BGREWRITEAOF")).await
+    }
+
+    /// When the last successful RDB save finished (LASTSAVE), to the second.
+    /// Same restrictions as [Self::bgsave].
+    pub async fn last_save(&self) -> RedisResult<SystemTime> {
+        self.ensure_not_cluster("LASTSAVE isn't supported in cluster mode")?;
+        let secs: u64 = self.exec(&mut redis::cmd("LASTSAVE")).await?;
+        Ok(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    /// Trigger a background save (BGSAVE) and wait for it to finish,
+    /// polling LASTSAVE every 100ms until it moves past the time read before
+    /// triggering. Returns the new LASTSAVE time.
+    ///
+    /// Fails with a timed out IO error if that doesn't happen within
+    /// `timeout`, which is also how a failed save shows up: LASTSAVE only
+    /// moves on success, see `rdb_last_bgsave_status` in INFO persistence.
+    /// LASTSAVE has a resolution of one second, so a save finishing in the
+    /// same second as the previous one goes unnoticed until `timeout`.
+    /// Same restrictions as [Self::bgsave].
+    pub async fn save_and_wait(&self, timeout: Duration) -> RedisResult<SystemTime> {
+        let before = self.last_save().await?;
+        self.bgsave().await?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let last = self.last_save().await?;
+            if last > before {
+                return Ok(last);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "background save didn't finish in time",
+                )
+                .into());
+            }
+            tokio::time::sleep((deadline - now).min(Duration::from_millis(100))).await;
+        }
+    }
+
+    /// Move `key` from the current database to database `db` (MOVE). Returns
+    /// `false` if `key` doesn't exist or `db` already holds a key of that
+    /// name, in which case nothing is moved.
+    ///
+    /// Single mode only: Redis Cluster only has database 0.
+    pub async fn move_key(&self, key: &str, db: i64) -> RedisResult<bool> {
+        self.ensure_not_cluster("MOVE isn't available in cluster mode")?;
+        self.exec(redis::cmd("MOVE").arg(key).arg(db)).await
+    }
+
+    /// Swap the contents of databases `a` and `b` (SWAPDB), for every
+    /// connection to the server at once.
+    ///
+    /// Single mode only: Redis Cluster only has database 0.
+    pub async fn swapdb(&self, a: i64, b: i64) -> RedisResult<()> {
+        self.ensure_not_cluster("SWAPDB isn't available in cluster mode")?;
+        self.exec(redis::cmd("SWAPDB").arg(a).arg(b)).await
+    }
+
+    pub(crate) fn ensure_not_cluster(&self, msg: &'static str) -> RedisResult<()> {
+        if self.is_cluster() {
+            Err((ErrorKind::ClientError, msg).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Measure round-trip latency with `samples` PINGs, waiting `interval`
+    /// between them.
+    ///
+    /// Latencies are measured client-side, so they include the network and
+    /// the time the runtime takes to poll the reply. A failed PING aborts the
+    /// sampling with its error. In cluster mode every PING goes to all nodes,
+    /// so each sample is the round-trip of the slowest one.
+    pub async fn latency_sample(
+        &self,
+        samples: usize,
+        interval: Duration,
+    ) -> RedisResult<LatencyStats> {
+        if samples == 0 {
+            return Err((
+                ErrorKind::ClientError,
+                "latency_sample needs at least one sample",
+            )
+                .into());
+        }
+        let mut latencies = Vec::with_capacity(samples);
+        for i in 0..samples {
+            if i > 0 && !interval.is_zero() {
+                tokio::time::sleep(interval).await;
+            }
+            let start = Instant::now();
+            let reply: Value = self.exec(&mut redis::cmd("PING")).await?;
+            latencies.push(start.elapsed());
+            if !is_pong(&reply) {
+                return Err(redis::RedisError::from((
+                    ErrorKind::TypeError,
+                    "Response was of incompatible type",
+                    format!("expected PONG, got {:?}", reply),
+                )));
+            }
+        }
+        Ok(LatencyStats::from_samples(latencies))
+    }
+
+    /// Latency spikes recorded for `event` (LATENCY HISTORY), as
+    /// `(unix timestamp, latency in milliseconds)` pairs, oldest first.
+    ///
+    /// Only spikes above the server's `latency-monitor-threshold` are
+    /// recorded, and monitoring is off while that is 0. In cluster mode this
+    /// asks a single node.
+    pub async fn latency_history(&self, event: &str) -> RedisResult<Vec<(i64, i64)>> {
+        let reply: Value = self
+            .exec(redis::cmd("LATENCY").arg("HISTORY").arg(event))
+            .await?;
+        parse_latency_history(&reply)
+    }
+
+    /// Forget all recorded latency spikes (LATENCY RESET), returning the
+    /// number of event time series that were reset
+    pub async fn latency_reset(&self) -> RedisResult<u64> {
+        self.exec(redis::cmd("LATENCY").arg("RESET")).await
+    }
+
+    /// Human-readable analysis of the recorded latency spikes (LATENCY DOCTOR)
+    pub async fn latency_doctor(&self) -> RedisResult<String> {
+        self.exec(redis::cmd("LATENCY").arg("DOCTOR")).await
+    }
+}
+
+/// Pairs of a RESP2 map, which comes as a flat `[key, value, ...]` array
+fn map_entries(reply: &Value) -> Option<Vec<(String, &Value)>> {
+    match reply {
+        Value::Bulk(items) if items.len() % 2 == 0 => items
+            .chunks(2)
+            .map(|pair| Some((from_redis_value(&pair[0]).ok()?, &pair[1])))
+            .collect(),
+        _ => None,
+    }
+}
+
+/// Parse a HELLO reply, where `modules` is an array of maps holding at
+/// least `name` and `ver`
+fn parse_hello(reply: &Value) -> RedisResult<HelloInfo> {
+    let invalid = || {
+        redis::RedisError::from((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected a HELLO reply, got {:?}", reply),
+        ))
+    };
+    let fields = map_entries(reply).ok_or_else(invalid)?;
+    let field = |name: &str| {
+        fields
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| *value)
+            .ok_or_else(invalid)
+    };
+    let mode = match from_redis_value::<String>(field("mode")?)?.as_str() {
+        "cluster" => ServerMode::Cluster,
+        "sentinel" => ServerMode::Sentinel,
+        _ => ServerMode::Standalone,
+    };
+    let modules = match field("modules")? {
+        Value::Bulk(modules) => modules
+            .iter()
+            .map(|module| {
+                let module = map_entries(module).ok_or_else(invalid)?;
+                let get = |name: &str| {
+                    module
+                        .iter()
+                        .find(|(key, _)| key == name)
+                        .map(|(_, value)| *value)
+                        .ok_or_else(invalid)
+                };
+                Ok(ModuleInfo {
+                    name: from_redis_value(get("name")?)?,
+                    version: from_redis_value(get("ver")?)?,
+                })
+            })
+            .collect::<RedisResult<_>>()?,
+        _ => return Err(invalid()),
+    };
+    Ok(HelloInfo {
+        server: from_redis_value(field("server")?)?,
+        version: from_redis_value(field("version")?)?,
+        proto: from_redis_value(field("proto")?)?,
+        id: from_redis_value(field("id")?)?,
+        mode,
+        role: from_redis_value(field("role")?)?,
+        modules,
+    })
+}
+
+/// Parse INFO replication, where each replica has a line like
+/// `slave0:ip=10.0.0.2,port=6380,state=online,offset=1254,lag=0`
+fn parse_replica_lag(info: &str) -> RedisResult<Vec<ReplicaLag>> {
+    let invalid = |line: &str| {
+        redis::RedisError::from((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("unexpected INFO replication line {:?}", line),
+        ))
+    };
+    let mut master_offset = None;
+    let mut replicas = Vec::new();
+    for line in info.lines().map(str::trim) {
+        let (name, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if name == "master_repl_offset" {
+            master_offset = Some(value.parse::<u64>().map_err(|_| invalid(line))?);
+            continue;
+        }
+        let index = match name.strip_prefix("slave") {
+            Some(index) if index.parse::<usize>().is_ok() => index,
+            _ => continue,
+        };
+        let fields: Vec<(&str, &str)> =
+            value.split(',').filter_map(|f| f.split_once('=')).collect();
+        let field = |key: &str| {
+            fields
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| *value)
+                .ok_or_else(|| invalid(line))
+        };
+        let number = |key: &str| field(key)?.parse::<u64>().map_err(|_| invalid(line));
+        replicas.push((
+            index.parse::<usize>().unwrap_or_default(),
+            field("ip")?.to_owned(),
+            field("port")?.parse::<u16>().map_err(|_| invalid(line))?,
+            field("state")?.to_owned(),
+            number("offset")?,
+            number("lag")?,
+        ));
+    }
+    if replicas.is_empty() {
+        return Ok(Vec::new());
+    }
+    let master_offset = master_offset.ok_or_else(|| {
+        redis::RedisError::from((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            "INFO replication has no master_repl_offset".to_string(),
+        ))
+    })?;
+    replicas.sort_by_key(|replica| replica.0);
+    Ok(replicas
+        .into_iter()
+        .map(|(_, host, port, state, offset, lag)| ReplicaLag {
+            addr: (host, port),
+            state,
+            offset,
+            lag_bytes: master_offset.saturating_sub(offset),
+            last_ack: Duration::from_secs(lag),
+        })
+        .collect())
+}
+
+/// Parse a LATENCY HISTORY reply: an array of `[timestamp, latency]` arrays
+fn parse_latency_history(reply: &Value) -> RedisResult<Vec<(i64, i64)>> {
+    let invalid = || {
+        redis::RedisError::from((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected [[timestamp, latency], ...], got {:?}", reply),
+        ))
+    };
+    match reply {
+        Value::Bulk(samples) => samples
+            .iter()
+            .map(|sample| match sample {
+                Value::Bulk(pair) if pair.len() == 2 => {
+                    Ok((from_redis_value(&pair[0])?, from_redis_value(&pair[1])?))
+                }
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// Extract command names from a COMMAND reply, where every command is
+/// described by an array starting with its name
+fn parse_command_names(reply: &Value) -> RedisResult<Vec<String>> {
+    let commands = match reply {
+        Value::Bulk(commands) => commands,
+        _ => {
+            return Err((
+                ErrorKind::TypeError,
+                "Response was of incompatible type",
+                format!("expected an array of commands, got {:?}", reply),
+            )
+                .into())
+        }
+    };
+    commands
+        .iter()
+        .map(|command| match command {
+            Value::Bulk(details) if !details.is_empty() => from_redis_value(&details[0]),
+            _ => Err((
+                ErrorKind::TypeError,
+                "Response was of incompatible type",
+                format!("expected a command description, got {:?}", command),
+            )
+                .into()),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn parse_command_names_works() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![
+                data("get"),
+                Value::Int(2),
+                Value::Bulk(vec![Value::Status("readonly".into())]),
+                Value::Int(1),
+                Value::Int(1),
+                Value::Int(1),
+            ]),
+            Value::Bulk(vec![
+                data("set"),
+                Value::Int(-3),
+                Value::Bulk(vec![Value::Status("write".into())]),
+                Value::Int(1),
+                Value::Int(1),
+                Value::Int(1),
+            ]),
+        ]);
+        assert_eq!(
+            parse_command_names(&reply).unwrap(),
+            vec!["get".to_string(), "set".to_string()]
+        );
+        assert!(parse_command_names(&Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn parse_version_and_predicates_work() {
+        assert_eq!(parse_version("7.2.4"), Some((7, 2, 4)));
+        assert_eq!(parse_version("6.2"), Some((6, 2, 0)));
+        assert_eq!(parse_version("7.4.0-rc1\r"), Some((7, 4, 0)));
+        assert_eq!(parse_version("255.255.255"), Some((255, 255, 255)));
+        assert_eq!(parse_version("seven"), None);
+        assert_eq!(parse_version("7"), None);
+
+        let caps = |version| Capabilities {
+            version,
+            mode: ServerMode::Standalone,
+        };
+        assert!(!caps((5, 0, 14)).supports_resp3());
+        assert!(caps((6, 0, 0)).supports_client_tracking());
+        assert!(!caps((6, 0, 16)).supports_getdel());
+        assert!(caps((6, 2, 0)).supports_getdel());
+        assert!(caps((6, 2, 14)).supports_getex());
+        assert!(!caps((6, 2, 14)).supports_redis7_commands());
+        assert!(caps((7, 0, 0)).supports_redis7_commands());
+    }
+
+    #[actix_rt::test]
+    async fn server_capabilities_works() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let caps = r.server_capabilities().await.unwrap();
+        assert_eq!(caps.mode, ServerMode::Standalone);
+        assert!(caps.version >= (5, 0, 0));
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_server_capabilities_works() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let caps = r.server_capabilities().await.unwrap();
+        assert_eq!(caps.mode, ServerMode::Cluster);
+        assert!(caps.version >= (5, 0, 0));
+    }
+
+    #[test]
+    fn failover_assembles_arguments() {
+        let packed = |opts: FailoverOpts| opts.build().unwrap().get_packed_command();
+        let expected = |args: &[&str]| redis::cmd("FAILOVER").arg(args).get_packed_command();
+
+        assert_eq!(
+            packed(FailoverOpts::default()),
+            redis::cmd("FAILOVER").get_packed_command()
+        );
+        let to = Some(("10.0.0.2".to_string(), 6380));
+        assert_eq!(
+            packed(FailoverOpts {
+                to: to.clone(),
+                ..Default::default()
+            }),
+            expected(&["TO", "10.0.0.2", "6380"])
+        );
+        assert_eq!(
+            packed(FailoverOpts {
+                timeout: Some(Duration::from_secs(5)),
+                ..Default::default()
+            }),
+            expected(&["TIMEOUT", "5000"])
+        );
+        assert_eq!(
+            packed(FailoverOpts {
+                to: to.clone(),
+                force: true,
+                timeout: Some(Duration::from_millis(500)),
+            }),
+            expected(&["TO", "10.0.0.2", "6380", "FORCE", "TIMEOUT", "500"])
+        );
+        let force_without_timeout = FailoverOpts {
+            to,
+            force: true,
+            timeout: None,
+        };
+        assert!(force_without_timeout.build().is_err());
+    }
+
+    #[test]
+    fn parse_hello_works() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let module = |name: &str, ver: i64| {
+            Value::Bulk(vec![
+                data("name"),
+                data(name),
+                data("ver"),
+                Value::Int(ver),
+                data("path"),
+                data(&format!("/opt/redis-stack/lib/{}.so", name)),
+                data("args"),
+                Value::Bulk(vec![]),
+            ])
+        };
+        // as captured from redis-stack-server 7.2
+        let reply = Value::Bulk(vec![
+            data("server"),
+            data("redis"),
+            data("version"),
+            data("7.2.4"),
+            data("proto"),
+            Value::Int(2),
+            data("id"),
+            Value::Int(13),
+            data("mode"),
+            data("standalone"),
+            data("role"),
+            data("master"),
+            data("modules"),
+            Value::Bulk(vec![module("search", 20809), module("ReJSON", 20607)]),
+        ]);
+        let hello = parse_hello(&reply).unwrap();
+        assert_eq!(
+            hello,
+            HelloInfo {
+                server: "redis".into(),
+                version: "7.2.4".into(),
+                proto: 2,
+                id: 13,
+                mode: ServerMode::Standalone,
+                role: "master".into(),
+                modules: vec![
+                    ModuleInfo {
+                        name: "search".into(),
+                        version: 20809
+                    },
+                    ModuleInfo {
+                        name: "ReJSON".into(),
+                        version: 20607
+                    },
+                ],
+            }
+        );
+        assert!(hello.has_module("ReJSON"));
+        assert!(!hello.has_module("timeseries"));
+
+        assert!(parse_hello(&Value::Bulk(vec![data("server")])).is_err());
+        assert!(parse_hello(&Value::Bulk(vec![data("server"), data("redis")])).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn hello_info_matches_connection() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let hello = con.hello_info().await.unwrap();
+        assert_eq!(hello.proto, 2);
+        assert_eq!(hello.mode, ServerMode::Standalone);
+        let id: u64 = con.exec(redis::cmd("CLIENT").arg("ID")).await.unwrap();
+        assert_eq!(hello.id, id);
+    }
+
+    #[test]
+    fn config_commands_assemble_arguments() {
+        assert_eq!(
+            config_cmd("REWRITE").get_packed_command(),
+            redis::cmd("CONFIG").arg("REWRITE").get_packed_command()
+        );
+        assert_eq!(
+            config_cmd("RESETSTAT").get_packed_command(),
+            redis::cmd("CONFIG").arg("RESETSTAT").get_packed_command()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn detect_mode_finds_standalone() {
+        assert_eq!(
+            detect_mode("redis://127.0.0.1").await.unwrap(),
+            ServerMode::Standalone
+        );
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn detect_mode_finds_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        assert_eq!(detect_mode(&seed).await.unwrap(), ServerMode::Cluster);
+    }
+
+    #[actix_rt::test]
+    async fn config_resetstat_and_rewrite() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        for _ in 0..10 {
+            assert!(con.ping().await);
+        }
+        con.config_resetstat().await.unwrap();
+        let info: redis::InfoDict = con.exec(redis::cmd("INFO").arg("stats")).await.unwrap();
+        let processed: u64 = info.get("total_commands_processed").unwrap();
+        assert!(processed < 10);
+
+        let info: redis::InfoDict = con.exec(redis::cmd("INFO").arg("server")).await.unwrap();
+        let config_file: String = info.get("config_file").unwrap_or_default();
+        if config_file.is_empty() {
+            assert!(con.config_rewrite().await.is_err());
+        } else {
+            con.config_rewrite().await.unwrap();
+        }
+    }
+
+    #[test]
+    fn parse_latency_history_works() {
+        // as captured after a couple of slow EVALs
+        let reply = Value::Bulk(vec![
+            Value::Bulk(vec![Value::Int(1_700_000_000), Value::Int(105)]),
+            Value::Bulk(vec![Value::Int(1_700_000_042), Value::Int(251)]),
+        ]);
+        assert_eq!(
+            parse_latency_history(&reply).unwrap(),
+            vec![(1_700_000_000, 105), (1_700_000_042, 251)]
+        );
+        assert!(parse_latency_history(&Value::Bulk(vec![]))
+            .unwrap()
+            .is_empty());
+        assert!(parse_latency_history(&Value::Bulk(vec![Value::Int(1)])).is_err());
+    }
+
+    #[test]
+    fn latency_stats_from_samples() {
+        let samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let stats = LatencyStats::from_samples(samples);
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.max, Duration::from_millis(100));
+        assert_eq!(stats.mean, Duration::from_micros(50_500));
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+
+        let one = LatencyStats::from_samples(vec![Duration::from_millis(7)]);
+        assert_eq!(one.min, one.p50);
+        assert_eq!(one.p99, one.max);
+    }
+
+    #[actix_rt::test]
+    async fn latency_sample_is_consistent() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let stats = con
+            .latency_sample(20, Duration::from_millis(1))
+            .await
+            .unwrap();
+        assert!(stats.min <= stats.p50);
+        assert!(stats.p50 <= stats.p99);
+        assert!(stats.p99 <= stats.max);
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+        assert!(con.latency_sample(0, Duration::ZERO).await.is_err());
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn cluster_latency_sample_works() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        let stats = r
+            .get_client()
+            .latency_sample(5, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(stats.min <= stats.max);
+    }
+
+    #[actix_rt::test]
+    async fn move_key_and_swapdb_work() {
+        const KEY: &str = "move_key_and_swapdb_work";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let zero = r.db(0).await.unwrap();
+        let one = r.db(1).await.unwrap();
+        let _: () = zero.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = one.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = zero.exec(redis::cmd("SET").arg(&[KEY, "v"])).await.unwrap();
+
+        assert!(zero.move_key(KEY, 1).await.unwrap());
+        let get: Option<String> = zero.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert!(get.is_none());
+        let get: Option<String> = one.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(get.as_deref(), Some("v"));
+        assert!(!zero.move_key(KEY, 1).await.unwrap());
+        let _: () = one.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        // databases no other test uses, as the swap affects every connection
+        let twelve = r.db(12).await.unwrap();
+        let thirteen = r.db(13).await.unwrap();
+        let _: () = twelve.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        let _: () = thirteen.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        let _: () = twelve
+            .exec(redis::cmd("SET").arg(&[KEY, "12"]))
+            .await
+            .unwrap();
+        twelve.swapdb(12, 13).await.unwrap();
+        let get: Option<String> = twelve.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert!(get.is_none());
+        let get: Option<String> = thirteen.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        assert_eq!(get.as_deref(), Some("12"));
+    }
+
+    #[actix_rt::test]
+    async fn command_list_works() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let list = con.command_list().await.unwrap();
+        assert!(list.iter().any(|c| c == "get"));
+        assert!(list.iter().any(|c| c == "set"));
+        assert_eq!(con.command_count().await.unwrap(), list.len() as u64);
+    }
+
+    #[test]
+    fn parse_replica_lag_works() {
+        let info = "# Replication\r\n\
+            role:master\r\n\
+            connected_slaves:3\r\n\
+            slave0:ip=10.0.0.2,port=6380,state=online,offset=1254,lag=0\r\n\
+            slave1:ip=10.0.0.3,port=6381,state=online,offset=998,lag=2\r\n\
+            slave2:ip=::1,port=6382,state=wait_bgsave,offset=0,lag=0\r\n\
+            master_failover_state:no-failover\r\n\
+            master_replid:8c4c44d0a21e1a5f0b2e3bd1f4d6de4b1f5a0e51\r\n\
+            master_replid2:0000000000000000000000000000000000000000\r\n\
+            master_repl_offset:1268\r\n\
+            second_repl_offset:-1\r\n\
+            repl_backlog_active:1\r\n";
+        let lag = parse_replica_lag(info).unwrap();
+        assert_eq!(
+            lag,
+            vec![
+                ReplicaLag {
+                    addr: ("10.0.0.2".into(), 6380),
+                    state: "online".into(),
+                    offset: 1254,
+                    lag_bytes: 14,
+                    last_ack: Duration::from_secs(0),
+                },
+                ReplicaLag {
+                    addr: ("10.0.0.3".into(), 6381),
+                    state: "online".into(),
+                    offset: 998,
+                    lag_bytes: 270,
+                    last_ack: Duration::from_secs(2),
+                },
+                ReplicaLag {
+                    addr: ("::1".into(), 6382),
+                    state: "wait_bgsave".into(),
+                    offset: 0,
+                    lag_bytes: 1268,
+                    last_ack: Duration::from_secs(0),
+                },
+            ]
+        );
+
+        let replica = "# Replication\r\nrole:slave\r\nmaster_host:10.0.0.1\r\n\
+            master_port:6379\r\nconnected_slaves:0\r\nmaster_repl_offset:1268\r\n";
+        assert!(parse_replica_lag(replica).unwrap().is_empty());
+
+        assert!(
+            parse_replica_lag("slave0:ip=10.0.0.2,port=6380,state=online,offset=1,lag=0\r\n")
+                .is_err()
+        );
+        assert!(
+            parse_replica_lag("master_repl_offset:10\r\nslave0:ip=10.0.0.2,port=x\r\n").is_err()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn replica_lag_without_replicas() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // the test server runs on its own
+        assert!(r.get_client().replica_lag().await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn save_and_wait_advances_last_save() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let before = con.last_save().await.unwrap();
+        // LASTSAVE counts seconds, make sure the new save lands in a later one
+        actix_rt::time::sleep(Duration::from_millis(1_100)).await;
+        let saved = con.save_and_wait(Duration::from_secs(30)).await.unwrap();
+        assert!(saved > before);
+        assert_eq!(con.last_save().await.unwrap(), saved);
+    }
+
+    #[actix_rt::test]
+    async fn saves_are_refused_in_cluster_mode() {
+        let cluster = RedisConfig::Cluster(vec!["redis://127.0.0.1:7000".into()]);
+        let r = Redis::new_lazy(cluster).unwrap();
+        let con = r.get_client();
+        let err = con.last_save().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+        let err = con.save_and_wait(Duration::from_secs(1)).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ClientError);
+    }
+}
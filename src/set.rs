@@ -0,0 +1,195 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Set helpers
+use redis::{FromRedisValue, RedisResult, ToRedisArgs};
+
+use crate::{Redis, RedisConnection};
+
+impl RedisConnection {
+    /// Members present in every set in `keys` (SINTER). In cluster mode, all
+    /// keys must hash to the same slot.
+    pub async fn sinter<T: FromRedisValue>(&self, keys: &[&str]) -> RedisResult<Vec<T>> {
+        self.set_algebra("SINTER", keys).await
+    }
+
+    /// Members present in any set in `keys` (SUNION). In cluster mode, all
+    /// keys must hash to the same slot.
+    pub async fn sunion<T: FromRedisValue>(&self, keys: &[&str]) -> RedisResult<Vec<T>> {
+        self.set_algebra("SUNION", keys).await
+    }
+
+    /// Members of the first set in `keys` that aren't in any of the others
+    /// (SDIFF). In cluster mode, all keys must hash to the same slot.
+    pub async fn sdiff<T: FromRedisValue>(&self, keys: &[&str]) -> RedisResult<Vec<T>> {
+        self.set_algebra("SDIFF", keys).await
+    }
+
+    /// Whether each of `members` is in the set at `key` (SMISMEMBER, Redis
+    /// 6.2+), in a single round-trip. The result lines up with `members`:
+    /// entry `i` answers for `members[i]`.
+    pub async fn smismember<M: ToRedisArgs>(
+        &self,
+        key: &str,
+        members: &[M],
+    ) -> RedisResult<Vec<bool>> {
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.exec(redis::cmd("SMISMEMBER").arg(key).arg(members))
+            .await
+    }
+
+    /// Random members of the set at `key` (SRANDMEMBER), without removing
+    /// them.
+    ///
+    /// A positive `count` returns up to that many distinct members, a negative
+    /// one returns exactly `-count` members that may repeat. Without a count a
+    /// single member is returned, if any.
+    pub async fn srandmember<T: FromRedisValue>(
+        &self,
+        key: &str,
+        count: Option<i64>,
+    ) -> RedisResult<Vec<T>> {
+        random_sample(self, "SRANDMEMBER", key, count).await
+    }
+
+    async fn set_algebra<T: FromRedisValue>(
+        &self,
+        command: &str,
+        keys: &[&str],
+    ) -> RedisResult<Vec<T>> {
+        self.ensure_same_slot(keys)?;
+        self.exec(redis::cmd(command).arg(keys)).await
+    }
+}
+
+impl Redis {
+    /// Size of the intersection of the sets in `keys` (SINTERCARD, Redis 7+).
+    ///
+    /// With a `limit`, the server stops counting once it is reached, which
+    /// keeps "do these share at least N members" checks cheap on large sets.
+    /// In cluster mode, all keys must hash to the same slot, see
+    /// [Redis::lmpop].
+    pub async fn sintercard(&self, keys: &[&str], limit: Option<usize>) -> RedisResult<u64> {
+        let mut cmd = redis::cmd("SINTERCARD");
+        cmd.arg(keys.len()).arg(keys);
+        if let Some(limit) = limit {
+            cmd.arg("LIMIT").arg(limit);
+        }
+        self.exec_numkeys(&mut cmd, keys).await
+    }
+}
+
+/// Run SRANDMEMBER, HRANDFIELD or ZRANDMEMBER, which reply with a single
+/// element (or nil) without a count and with an array otherwise
+pub(crate) async fn random_sample<T: FromRedisValue>(
+    con: &RedisConnection,
+    command: &str,
+    key: &str,
+    count: Option<i64>,
+) -> RedisResult<Vec<T>> {
+    let mut cmd = redis::cmd(command);
+    cmd.arg(key);
+    match count {
+        Some(count) => con.exec(cmd.arg(count)).await,
+        None => {
+            let one: Option<T> = con.exec(&mut cmd).await?;
+            Ok(one.into_iter().collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn set_algebra_works() {
+        const A: &str = "{set_algebra_works}a";
+        const B: &str = "{set_algebra_works}b";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(&[A, B])).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(A).arg(&["x", "y", "z"]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(B).arg(&["y", "z", "w"]))
+            .await
+            .unwrap();
+
+        let mut inter: Vec<String> = con.sinter(&[A, B]).await.unwrap();
+        inter.sort();
+        assert_eq!(inter, vec!["y", "z"]);
+
+        let mut union: Vec<String> = con.sunion(&[A, B]).await.unwrap();
+        union.sort();
+        assert_eq!(union, vec!["w", "x", "y", "z"]);
+
+        let diff: Vec<String> = con.sdiff(&[A, B]).await.unwrap();
+        assert_eq!(diff, vec!["x"]);
+
+        assert_eq!(r.sintercard(&[A, B], None).await.unwrap(), 2);
+        assert_eq!(r.sintercard(&[A, B], Some(1)).await.unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn smismember_lines_up_with_members() {
+        const KEY: &str = "smismember_lines_up_with_members";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(KEY).arg(&["read", "write"]))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            con.smismember(KEY, &["admin", "read", "delete", "write"])
+                .await
+                .unwrap(),
+            vec![false, true, false, true]
+        );
+        let none: &[&str] = &[];
+        assert!(con.smismember(KEY, none).await.unwrap().is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn srandmember_count_sign_matters() {
+        const KEY: &str = "srandmember_count_sign_matters";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        assert!(con
+            .srandmember::<String>(KEY, None)
+            .await
+            .unwrap()
+            .is_empty());
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(KEY).arg(&["a", "b"]))
+            .await
+            .unwrap();
+
+        let one: Vec<String> = con.srandmember(KEY, None).await.unwrap();
+        assert_eq!(one.len(), 1);
+        let mut distinct: Vec<String> = con.srandmember(KEY, Some(20)).await.unwrap();
+        distinct.sort();
+        assert_eq!(distinct, vec!["a", "b"]);
+        let repeated: Vec<String> = con.srandmember(KEY, Some(-20)).await.unwrap();
+        assert_eq!(repeated.len(), 20);
+    }
+}
@@ -0,0 +1,204 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Cluster hash slot computation
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use redis::{Arg, Cmd, ErrorKind, RedisResult};
+
+use crate::{command, Redis, RedisConnection, ScanOptions};
+
+/// Number of hash slots in a Redis Cluster
+const SLOT_COUNT: u16 = 16384;
+
+/// Compute the cluster hash slot of `key` (CRC16 modulo 16384), honouring
+/// `{hash tags}`: only the part between the first `{` and the next `}` is
+/// hashed when it is non-empty, so `{user1}.a` and `{user1}.b` share a slot.
+pub fn slot_for(key: &str) -> u16 {
+    slot_of(key.as_bytes())
+}
+
+/// [slot_for] for a binary key
+pub(crate) fn slot_of(key: &[u8]) -> u16 {
+    let key = match hash_tag(key) {
+        Some(tag) => tag,
+        None => key,
+    };
+    crc16::State::<crc16::XMODEM>::calculate(key) % SLOT_COUNT
+}
+
+/// Returns the contents of the first non-empty `{...}` section of `key`
+fn hash_tag(key: &[u8]) -> Option<&[u8]> {
+    let open = key.iter().position(|b| *b == b'{')?;
+    let close = key[open + 1..].iter().position(|b| *b == b'}')?;
+    if close == 0 {
+        None
+    } else {
+        Some(&key[open + 1..open + 1 + close])
+    }
+}
+
+/// The key a cluster connection routes `cmd` by, picked like the redis
+/// crate does: the first key after the key count for EVAL and EVALSHA, the
+/// argument after the subcommand for XGROUP and XINFO, the first stream for
+/// XREAD and XREADGROUP, and the first argument otherwise. `None` for
+/// commands without one.
+pub(crate) fn routing_key(cmd: &Cmd) -> Option<&[u8]> {
+    let args: Vec<&[u8]> = cmd
+        .args_iter()
+        .map(|arg| match arg {
+            Arg::Simple(arg) => arg,
+            Arg::Cursor => b"0",
+        })
+        .collect();
+    let index = match command::name(cmd)?.as_str() {
+        "EVAL" | "EVALSHA" => {
+            let numkeys: u64 = std::str::from_utf8(args.get(2)?).ok()?.parse().ok()?;
+            if numkeys == 0 {
+                return None;
+            }
+            3
+        }
+        "XGROUP" | "XINFO" => 2,
+        "XREAD" | "XREADGROUP" => {
+            args.iter()
+                .position(|arg| arg.eq_ignore_ascii_case(b"STREAMS"))?
+                + 1
+        }
+        _ => 1,
+    };
+    args.get(index).copied()
+}
+
+impl RedisConnection {
+    /// Reject multi-key requests that span hash slots. No-op in single mode.
+    pub(crate) fn ensure_same_slot(&self, keys: &[&str]) -> RedisResult<()> {
+        if self.is_cluster() {
+            let mut slots = keys.iter().map(|k| slot_for(k));
+            if let Some(first) = slots.next() {
+                if slots.any(|s| s != first) {
+                    return Err((
+                        ErrorKind::CrossSlot,
+                        "keys in request don't hash to the same slot",
+                        keys.join(", "),
+                    )
+                        .into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Redis {
+    /// Number of keys per hash slot among up to `sample` keys, to spot slots
+    /// that hash tags made hot: an even keyspace spreads keys thinly, so a
+    /// slot far above the rest points at a tag shared by too many keys. Sort
+    /// the entries by count to rank them; slots without sampled keys are
+    /// left out.
+    ///
+    /// Keys come from [Self::cluster_scan], which walks the primaries one
+    /// after the other, so with `sample` below the number of keys the first
+    /// primaries are over-represented. The first node error ends the call.
+    /// Works in single mode too, as if the server were a cluster.
+    pub async fn slot_distribution(&self, sample: usize) -> RedisResult<HashMap<u16, u64>> {
+        let mut keys = self.cluster_scan(ScanOptions::default()).take(sample);
+        let mut counts = HashMap::new();
+        while let Some(key) = keys.next().await {
+            *counts.entry(slot_for(&key?)).or_default() += 1;
+        }
+        Ok(counts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn hash_tag_works() {
+        assert_eq!(hash_tag(b"{user1000}.following"), Some(&b"user1000"[..]));
+        assert_eq!(hash_tag(b"foo{}{bar}"), None);
+        assert_eq!(hash_tag(b"foo{{bar}}"), Some(&b"{bar"[..]));
+        assert_eq!(hash_tag(b"nobraces"), None);
+        assert_eq!(slot_for("foo"), 12182);
+    }
+
+    #[test]
+    fn same_hash_tag_same_slot() {
+        assert_eq!(slot_for("{user1}.following"), slot_for("{user1}.followers"));
+        assert_eq!(slot_for("{user1}.following"), slot_for("user1"));
+        assert_ne!(slot_for("user1.following"), slot_for("user1.followers"));
+    }
+
+    #[test]
+    fn routing_key_follows_command() {
+        let key = |cmd: &redis::Cmd| routing_key(cmd).map(|k| k.to_vec());
+        assert_eq!(key(redis::cmd("GET").arg("k")), Some(b"k".to_vec()));
+        assert_eq!(key(&redis::cmd("PING")), None);
+        assert_eq!(
+            key(redis::cmd("EVAL").arg("s").arg(1).arg("k").arg("v")),
+            Some(b"k".to_vec())
+        );
+        assert_eq!(key(redis::cmd("EVALSHA").arg("sha").arg(0).arg("v")), None);
+        assert_eq!(
+            key(redis::cmd("XINFO").arg("STREAM").arg("k")),
+            Some(b"k".to_vec())
+        );
+        assert_eq!(
+            key(redis::cmd("XREADGROUP").arg(&["GROUP", "g", "c", "streams", "k", ">"])),
+            Some(b"k".to_vec())
+        );
+    }
+
+    async fn hash_tag_dominates(r: Redis) {
+        let con = r.get_client();
+        let hot: Vec<String> = (0..100)
+            .map(|i| format!("{{slot_distribution_hot}}:{}", i))
+            .collect();
+        let spread: Vec<String> = (0..20)
+            .map(|i| format!("slot_distribution_spread:{}", i))
+            .collect();
+        let keys: Vec<&str> = hot.iter().chain(&spread).map(String::as_str).collect();
+        let _: Vec<()> = con
+            .exec_multikey(&keys, |key| {
+                let mut cmd = redis::cmd("SET");
+                cmd.arg(key).arg(1);
+                cmd
+            })
+            .await
+            .unwrap();
+
+        // large enough to cover whatever else the test server holds
+        let counts = r.slot_distribution(1_000_000).await.unwrap();
+        let hot_slot = slot_for("slot_distribution_hot");
+        assert!(counts[&hot_slot] >= 100);
+        let (top, _) = counts.iter().max_by_key(|(_, count)| **count).unwrap();
+        assert_eq!(*top, hot_slot);
+        con.del_many(&keys).await.unwrap();
+
+        let sampled = r.slot_distribution(10).await.unwrap();
+        assert!(sampled.values().sum::<u64>() <= 10);
+    }
+
+    #[actix_rt::test]
+    async fn slot_distribution_finds_hash_tag_hotspot() {
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        hash_tag_dominates(r).await;
+    }
+
+    #[actix_rt::test]
+    #[ignore = "requires a Redis Cluster, seed URL in REDIS_CLUSTER_SEED"]
+    async fn slot_distribution_finds_hash_tag_hotspot_in_cluster() {
+        let seed = std::env::var("REDIS_CLUSTER_SEED").unwrap();
+        let r = Redis::new(RedisConfig::ClusterSeed(seed)).await.unwrap();
+        hash_tag_dominates(r).await;
+    }
+}
@@ -0,0 +1,190 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! SORT and SORT_RO builder
+use redis::{ErrorKind, FromRedisValue, RedisResult};
+
+use crate::RedisConnection;
+
+/// Builder for SORT and SORT_RO, see [RedisConnection::sort]
+///
+/// In cluster mode, BY and GET patterns that refer to other keys only work if
+/// those keys hash to the same slot as the sorted key (use a `{hash tag}`),
+/// as SORT runs on a single node.
+#[derive(Clone, Debug)]
+pub struct Sort {
+    key: String,
+    read_only: bool,
+    by: Option<String>,
+    get: Vec<String>,
+    limit: Option<(usize, usize)>,
+    alpha: bool,
+    desc: bool,
+    store: Option<String>,
+}
+
+impl Sort {
+    fn new(key: &str, read_only: bool) -> Self {
+        Self {
+            key: key.to_owned(),
+            read_only,
+            by: None,
+            get: Vec::new(),
+            limit: None,
+            alpha: false,
+            desc: false,
+            store: None,
+        }
+    }
+
+    /// Sort by the values of the keys matching `pattern`, where `*` is
+    /// replaced by each element. `nosort` skips sorting altogether.
+    pub fn by(&mut self, pattern: &str) -> &mut Self {
+        self.by = Some(pattern.to_owned());
+        self
+    }
+
+    /// Return the values of the keys matching `pattern` instead of the
+    /// elements themselves. Can be repeated; `#` returns the element.
+    pub fn get(&mut self, pattern: &str) -> &mut Self {
+        self.get.push(pattern.to_owned());
+        self
+    }
+
+    /// Only return `count` elements, starting at `offset`
+    pub fn limit(&mut self, offset: usize, count: usize) -> &mut Self {
+        self.limit = Some((offset, count));
+        self
+    }
+
+    /// Sort lexicographically instead of numerically
+    pub fn alpha(&mut self) -> &mut Self {
+        self.alpha = true;
+        self
+    }
+
+    /// Sort in descending order
+    pub fn desc(&mut self) -> &mut Self {
+        self.desc = true;
+        self
+    }
+
+    /// Store the result in `dest` instead of returning it. The reply becomes
+    /// the number of stored elements. Not available with SORT_RO.
+    pub fn store(&mut self, dest: &str) -> &mut Self {
+        self.store = Some(dest.to_owned());
+        self
+    }
+
+    fn build(&self) -> RedisResult<redis::Cmd> {
+        if self.read_only && self.store.is_some() {
+            return Err((ErrorKind::ClientError, "SORT_RO can't STORE").into());
+        }
+        let mut cmd = redis::cmd(if self.read_only { "SORT_RO" } else { "SORT" });
+        cmd.arg(&self.key);
+        if let Some(by) = &self.by {
+            cmd.arg("BY").arg(by);
+        }
+        if let Some((offset, count)) = self.limit {
+            cmd.arg("LIMIT").arg(offset).arg(count);
+        }
+        for get in &self.get {
+            cmd.arg("GET").arg(get);
+        }
+        if self.desc {
+            cmd.arg("DESC");
+        }
+        if self.alpha {
+            cmd.arg("ALPHA");
+        }
+        if let Some(store) = &self.store {
+            cmd.arg("STORE").arg(store);
+        }
+        Ok(cmd)
+    }
+
+    /// Run the sort on `con`
+    pub async fn query<T: FromRedisValue>(&self, con: &RedisConnection) -> RedisResult<T> {
+        con.exec(&mut self.build()?).await
+    }
+}
+
+impl RedisConnection {
+    /// Sort the list, set or sorted set at `key` (SORT)
+    pub fn sort(&self, key: &str) -> Sort {
+        Sort::new(key, false)
+    }
+
+    /// Read-only variant of [Self::sort] (SORT_RO, Redis 7+), safe to run on
+    /// replicas
+    pub fn sort_ro(&self, key: &str) -> Sort {
+        Sort::new(key, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn sort_assembles_arguments() {
+        let cmd = Sort::new("k", false)
+            .by("w_*")
+            .get("#")
+            .get("o_*")
+            .limit(0, 5)
+            .desc()
+            .alpha()
+            .store("dest")
+            .build()
+            .unwrap();
+        let mut expected = redis::cmd("SORT");
+        expected
+            .arg("k")
+            .arg(&["BY", "w_*", "LIMIT", "0", "5", "GET", "#", "GET", "o_*"])
+            .arg(&["DESC", "ALPHA", "STORE", "dest"]);
+        assert_eq!(cmd.get_packed_command(), expected.get_packed_command());
+
+        assert!(Sort::new("k", true).store("dest").build().is_err());
+    }
+
+    #[actix_rt::test]
+    async fn sort_works() {
+        const NUMS: &str = "sort_works_nums";
+        const WORDS: &str = "sort_works_words";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[NUMS, WORDS]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(NUMS).arg(&[3, 10, 1, 2]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(
+                redis::cmd("RPUSH")
+                    .arg(WORDS)
+                    .arg(&["pear", "apple", "fig"]),
+            )
+            .await
+            .unwrap();
+
+        let sorted: Vec<usize> = con.sort(NUMS).desc().query(&con).await.unwrap();
+        assert_eq!(sorted, vec![10, 3, 2, 1]);
+
+        let sorted: Vec<String> = con.sort_ro(WORDS).alpha().query(&con).await.unwrap();
+        assert_eq!(sorted, vec!["apple", "fig", "pear"]);
+
+        let sorted: Vec<usize> = con.sort(NUMS).limit(1, 2).query(&con).await.unwrap();
+        assert_eq!(sorted, vec![2, 3]);
+    }
+}
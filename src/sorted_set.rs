@@ -0,0 +1,628 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Sorted set helpers
+use std::time::Duration;
+
+use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisResult, ToRedisArgs, Value};
+
+use crate::list::parse_mpop;
+use crate::set::random_sample;
+use crate::{Redis, RedisConnection};
+
+/// End of a sorted set to operate on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScoreEnd {
+    /// Members with the lowest scores
+    Min,
+    /// Members with the highest scores
+    Max,
+}
+
+impl ScoreEnd {
+    fn as_arg(&self) -> &'static str {
+        match self {
+            Self::Min => "MIN",
+            Self::Max => "MAX",
+        }
+    }
+}
+
+/// Builder for ZADD with its condition and reply flags, see
+/// [RedisConnection::zadd_opts]
+#[derive(Clone, Debug)]
+pub struct ZAdd {
+    key: String,
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+    members: Vec<(f64, Vec<Vec<u8>>)>,
+}
+
+impl ZAdd {
+    fn new(key: &str) -> Self {
+        Self {
+            key: key.to_owned(),
+            nx: false,
+            xx: false,
+            gt: false,
+            lt: false,
+            ch: false,
+            incr: false,
+            members: Vec::new(),
+        }
+    }
+
+    /// Only add new members, never update existing ones
+    pub fn nx(&mut self) -> &mut Self {
+        self.nx = true;
+        self
+    }
+
+    /// Only update existing members, never add new ones
+    pub fn xx(&mut self) -> &mut Self {
+        self.xx = true;
+        self
+    }
+
+    /// Only update a score when the new one is greater. New members are
+    /// still added unless [Self::xx] is set.
+    pub fn gt(&mut self) -> &mut Self {
+        self.gt = true;
+        self
+    }
+
+    /// Only update a score when the new one is lower. New members are still
+    /// added unless [Self::xx] is set.
+    pub fn lt(&mut self) -> &mut Self {
+        self.lt = true;
+        self
+    }
+
+    /// Reply with the number of members added or updated, instead of only
+    /// the added ones
+    pub fn ch(&mut self) -> &mut Self {
+        self.ch = true;
+        self
+    }
+
+    /// Add the score to the member's current one and reply with the new
+    /// score, or nil when a condition prevented the update. Takes exactly one
+    /// member.
+    pub fn incr(&mut self) -> &mut Self {
+        self.incr = true;
+        self
+    }
+
+    /// Add `member` with `score`. Can be repeated, except with [Self::incr].
+    pub fn member(&mut self, member: impl ToRedisArgs, score: f64) -> &mut Self {
+        self.members.push((score, member.to_redis_args()));
+        self
+    }
+
+    fn build(&self) -> RedisResult<redis::Cmd> {
+        let conflict = if self.nx && self.xx {
+            Some("ZADD can't combine NX and XX")
+        } else if self.nx && (self.gt || self.lt) {
+            Some("ZADD can't combine NX with GT or LT")
+        } else if self.gt && self.lt {
+            Some("ZADD can't combine GT and LT")
+        } else if self.members.is_empty() {
+            Some("ZADD needs at least one member")
+        } else if self.incr && self.members.len() > 1 {
+            Some("ZADD INCR takes a single member")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err((ErrorKind::ClientError, conflict).into());
+        }
+        let mut cmd = redis::cmd("ZADD");
+        cmd.arg(&self.key);
+        let flags = [
+            (self.nx, "NX"),
+            (self.xx, "XX"),
+            (self.gt, "GT"),
+            (self.lt, "LT"),
+            (self.ch, "CH"),
+            (self.incr, "INCR"),
+        ];
+        for (_, flag) in flags.iter().filter(|(set, _)| *set) {
+            cmd.arg(*flag);
+        }
+        for (score, member) in &self.members {
+            cmd.arg(*score).arg(member.as_slice());
+        }
+        Ok(cmd)
+    }
+
+    /// Run the ZADD on `con`. Replies with the number of added (or, with
+    /// [Self::ch], changed) members, or with the new score under
+    /// [Self::incr].
+    pub async fn query<T: FromRedisValue>(&self, con: &RedisConnection) -> RedisResult<T> {
+        con.exec(&mut self.build()?).await
+    }
+}
+
+impl RedisConnection {
+    /// Add members to the sorted set at `key` with ZADD flags (GT and LT need
+    /// Redis 6.2+)
+    pub fn zadd_opts(&self, key: &str) -> ZAdd {
+        ZAdd::new(key)
+    }
+
+    /// Random members of the sorted set at `key` (ZRANDMEMBER, Redis 6.2+).
+    ///
+    /// A positive `count` returns up to that many distinct members, a negative
+    /// one returns exactly `-count` members that may repeat. Without a count a
+    /// single member is returned, if any.
+    pub async fn zrandmember<T: FromRedisValue>(
+        &self,
+        key: &str,
+        count: Option<i64>,
+    ) -> RedisResult<Vec<T>> {
+        random_sample(self, "ZRANDMEMBER", key, count).await
+    }
+
+    /// Like [Self::zrandmember], with the scores of the members (WITHSCORES)
+    pub async fn zrandmember_with_scores<T: FromRedisValue>(
+        &self,
+        key: &str,
+        count: i64,
+    ) -> RedisResult<Vec<(T, f64)>> {
+        self.exec(
+            redis::cmd("ZRANDMEMBER")
+                .arg(key)
+                .arg(count)
+                .arg("WITHSCORES"),
+        )
+        .await
+    }
+
+    /// Members of the sorted set at `key` between `min` and `max` in
+    /// lexicographic order (ZRANGEBYLEX), for sets where all members share
+    /// the same score.
+    ///
+    /// Bounds use the ZRANGEBYLEX syntax and are passed as they are: `[a` is
+    /// inclusive, `(a` exclusive, and `-` / `+` stand for the lowest and
+    /// highest possible members. `limit` is an `(offset, count)` pair (LIMIT)
+    /// returning at most `count` members after skipping `offset` of them.
+    pub async fn zrangebylex<T: FromRedisValue>(
+        &self,
+        key: &str,
+        min: &str,
+        max: &str,
+        limit: Option<(isize, usize)>,
+    ) -> RedisResult<Vec<T>> {
+        let mut cmd = redis::cmd("ZRANGEBYLEX");
+        cmd.arg(key).arg(min).arg(max);
+        if let Some((offset, count)) = limit {
+            cmd.arg("LIMIT").arg(offset).arg(count);
+        }
+        self.exec(&mut cmd).await
+    }
+
+    /// Number of members of the sorted set at `key` between `min` and `max`
+    /// in lexicographic order (ZLEXCOUNT), bounds as in [Self::zrangebylex]
+    pub async fn zlexcount(&self, key: &str, min: &str, max: &str) -> RedisResult<u64> {
+        self.exec(redis::cmd("ZLEXCOUNT").arg(key).arg(min).arg(max))
+            .await
+    }
+
+    /// Score of `member` in the sorted set at `key` (ZSCORE), `None` if
+    /// either doesn't exist
+    pub async fn zscore(&self, key: &str, member: &str) -> RedisResult<Option<f64>> {
+        self.exec(redis::cmd("ZSCORE").arg(key).arg(member)).await
+    }
+
+    /// Scores of `members` in the sorted set at `key` (ZMSCORE, Redis 6.2+),
+    /// in the order of `members`, with `None` for the ones that aren't in
+    /// the set
+    pub async fn zmscore(&self, key: &str, members: &[&str]) -> RedisResult<Vec<Option<f64>>> {
+        if members.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.exec(redis::cmd("ZMSCORE").arg(key).arg(members)).await
+    }
+
+    /// Zero-based rank of `member` in the sorted set at `key`, lowest score
+    /// first (ZRANK) or highest score first if `rev` is set (ZREVRANK).
+    /// `None` if either doesn't exist.
+    pub async fn zrank(&self, key: &str, member: &str, rev: bool) -> RedisResult<Option<u64>> {
+        let name = if rev { "ZREVRANK" } else { "ZRANK" };
+        self.exec(redis::cmd(name).arg(key).arg(member)).await
+    }
+
+    /// Raise the score of `member` in the leaderboard at `key` to `score`,
+    /// adding it if needed, and return its effective score and zero-based
+    /// rank, highest score first.
+    ///
+    /// Scores never go down (ZADD GT): a lower `score` leaves the current one
+    /// in place, and that is the score returned. The update and the lookups
+    /// (ZSCORE, ZREVRANK) run in one MULTI/EXEC round-trip, so the rank
+    /// can't be skewed by updates from other clients in between.
+    pub async fn leaderboard_update(
+        &self,
+        key: &str,
+        member: &str,
+        score: f64,
+    ) -> RedisResult<(f64, u64)> {
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .cmd("ZADD")
+            .arg(key)
+            .arg("GT")
+            .arg(score)
+            .arg(member)
+            .ignore()
+            .cmd("ZSCORE")
+            .arg(key)
+            .arg(member)
+            .cmd("ZREVRANK")
+            .arg(key)
+            .arg(member);
+        self.exec_pipe(&pipe).await
+    }
+}
+
+impl Redis {
+    /// Pop up to `count` members from the first non-empty sorted set in `keys`
+    /// (ZMPOP, Redis 7+).
+    ///
+    /// Returns the key that was popped from along with `(member, score)`
+    /// pairs, or `None` when all sorted sets are empty. In cluster mode, all
+    /// keys must hash to the same slot, see [Redis::lmpop].
+    pub async fn zmpop<T: FromRedisValue>(
+        &self,
+        keys: &[&str],
+        from: ScoreEnd,
+        count: usize,
+    ) -> RedisResult<Option<(String, Vec<(T, f64)>)>> {
+        let mut cmd = redis::cmd("ZMPOP");
+        cmd.arg(keys.len())
+            .arg(keys)
+            .arg(from.as_arg())
+            .arg("COUNT")
+            .arg(count);
+        let reply: Value = self.exec_numkeys(&mut cmd, keys).await?;
+        parse_mpop(&reply, parse_scored)
+    }
+
+    /// Like [Redis::zmpop], but waits up to `timeout` for a member when all
+    /// sorted sets are empty (BZMPOP, Redis 7+), `None` meaning the wait
+    /// timed out. Same caveats about blocking as [Redis::blmpop].
+    pub async fn bzmpop<T: FromRedisValue>(
+        &self,
+        timeout: Duration,
+        keys: &[&str],
+        from: ScoreEnd,
+        count: usize,
+    ) -> RedisResult<Option<(String, Vec<(T, f64)>)>> {
+        self.connection.ensure_block_fits(timeout)?;
+        let mut cmd = redis::cmd("BZMPOP");
+        cmd.arg(timeout.as_secs_f64())
+            .arg(keys.len())
+            .arg(keys)
+            .arg(from.as_arg())
+            .arg("COUNT")
+            .arg(count);
+        let reply: Value = self.exec_numkeys_blocking(&mut cmd, keys).await?;
+        parse_mpop(&reply, parse_scored)
+    }
+}
+
+/// Parse a single `[member, score]` pair. Can't lean on the tuple
+/// [FromRedisValue] impl here: inside a `Vec` it flattens nested pairs.
+fn parse_scored<T: FromRedisValue>(v: &Value) -> RedisResult<(T, f64)> {
+    match v {
+        Value::Bulk(pair) if pair.len() == 2 => {
+            Ok((from_redis_value(&pair[0])?, from_redis_value(&pair[1])?))
+        }
+        _ => Err((
+            redis::ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected [member, score], got {:?}", v),
+        )
+            .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn zadd_rejects_conflicting_flags() {
+        assert!(ZAdd::new("k").nx().xx().member("a", 1.0).build().is_err());
+        assert!(ZAdd::new("k").nx().gt().member("a", 1.0).build().is_err());
+        assert!(ZAdd::new("k").gt().lt().member("a", 1.0).build().is_err());
+        assert!(ZAdd::new("k").build().is_err());
+        let two = ZAdd::new("k")
+            .incr()
+            .member("a", 1.0)
+            .member("b", 2.0)
+            .build();
+        assert!(two.is_err());
+
+        let cmd = ZAdd::new("k")
+            .xx()
+            .gt()
+            .ch()
+            .member("a", 1.5)
+            .build()
+            .unwrap();
+        let mut expected = redis::cmd("ZADD");
+        expected.arg(&["k", "XX", "GT", "CH", "1.5", "a"]);
+        assert_eq!(cmd.get_packed_command(), expected.get_packed_command());
+    }
+
+    #[actix_rt::test]
+    async fn zadd_gt_refuses_to_lower() {
+        const KEY: &str = "zadd_gt_refuses_to_lower";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        let added: u64 = con
+            .zadd_opts(KEY)
+            .member("a", 10.0)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(added, 1);
+        let changed: u64 = con
+            .zadd_opts(KEY)
+            .gt()
+            .ch()
+            .member("a", 5.0)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(changed, 0);
+        let changed: u64 = con
+            .zadd_opts(KEY)
+            .gt()
+            .ch()
+            .member("a", 20.0)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(changed, 1);
+        let score: f64 = con
+            .exec(redis::cmd("ZSCORE").arg(KEY).arg("a"))
+            .await
+            .unwrap();
+        assert_eq!(score, 20.0);
+    }
+
+    #[actix_rt::test]
+    async fn zadd_incr_returns_new_score() {
+        const KEY: &str = "zadd_incr_returns_new_score";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        let score: Option<f64> = con
+            .zadd_opts(KEY)
+            .incr()
+            .member("a", 2.5)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(score, Some(2.5));
+        let score: Option<f64> = con
+            .zadd_opts(KEY)
+            .incr()
+            .member("a", 1.0)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(score, Some(3.5));
+        let score: Option<f64> = con
+            .zadd_opts(KEY)
+            .incr()
+            .lt()
+            .member("a", 1.0)
+            .query(&con)
+            .await
+            .unwrap();
+        assert_eq!(score, None);
+    }
+
+    #[actix_rt::test]
+    async fn leaderboard_ranks_by_descending_score() {
+        const KEY: &str = "leaderboard_ranks_by_descending_score";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+
+        assert_eq!(
+            con.leaderboard_update(KEY, "alice", 10.0).await.unwrap(),
+            (10.0, 0)
+        );
+        assert_eq!(
+            con.leaderboard_update(KEY, "bob", 20.0).await.unwrap(),
+            (20.0, 0)
+        );
+        assert_eq!(
+            con.leaderboard_update(KEY, "carol", 15.0).await.unwrap(),
+            (15.0, 1)
+        );
+        assert_eq!(
+            con.leaderboard_update(KEY, "alice", 30.0).await.unwrap(),
+            (30.0, 0)
+        );
+        // lower scores are ignored
+        assert_eq!(
+            con.leaderboard_update(KEY, "bob", 5.0).await.unwrap(),
+            (20.0, 1)
+        );
+        assert_eq!(
+            con.leaderboard_update(KEY, "carol", 1.0).await.unwrap(),
+            (15.0, 2)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn zmpop_works() {
+        const EMPTY: &str = "zmpop_works_empty";
+        const FULL: &str = "zmpop_works_full";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[EMPTY, FULL]))
+            .await
+            .unwrap();
+        let _: () = con
+            .exec(redis::cmd("ZADD").arg(FULL).arg(&["1", "a", "2", "b"]))
+            .await
+            .unwrap();
+
+        let popped: Option<(String, Vec<(String, f64)>)> =
+            r.zmpop(&[EMPTY, FULL], ScoreEnd::Max, 1).await.unwrap();
+        assert_eq!(popped, Some((FULL.into(), vec![("b".into(), 2.0)])));
+    }
+
+    #[actix_rt::test]
+    async fn bzmpop_times_out_on_empty_sets() {
+        const KEY: &str = "bzmpop_times_out_on_empty_sets";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let popped: Option<(String, Vec<(String, f64)>)> = r
+            .bzmpop(Duration::from_millis(50), &[KEY], ScoreEnd::Min, 1)
+            .await
+            .unwrap();
+        assert_eq!(popped, None);
+
+        let _: () = con
+            .exec(redis::cmd("ZADD").arg(KEY).arg(&["1", "a", "2", "b"]))
+            .await
+            .unwrap();
+        let popped: Option<(String, Vec<(String, f64)>)> = r
+            .bzmpop(Duration::from_millis(50), &[KEY], ScoreEnd::Min, 1)
+            .await
+            .unwrap();
+        assert_eq!(popped, Some((KEY.into(), vec![("a".into(), 1.0)])));
+    }
+
+    #[actix_rt::test]
+    async fn score_and_rank_lookups_work() {
+        const KEY: &str = "score_and_rank_lookups_work";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(
+                redis::cmd("ZADD")
+                    .arg(KEY)
+                    .arg(&["10", "alice", "2.5", "bob", "30", "carol"]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            con.zmscore(KEY, &["carol", "nobody", "bob", "alice", "nobody"])
+                .await
+                .unwrap(),
+            vec![Some(30.0), None, Some(2.5), Some(10.0), None]
+        );
+        assert!(con.zmscore(KEY, &[]).await.unwrap().is_empty());
+        assert_eq!(
+            con.zmscore("score_and_rank_lookups_work_missing", &["alice"])
+                .await
+                .unwrap(),
+            vec![None]
+        );
+
+        assert_eq!(con.zscore(KEY, "bob").await.unwrap(), Some(2.5));
+        assert_eq!(con.zscore(KEY, "nobody").await.unwrap(), None);
+        assert_eq!(con.zrank(KEY, "bob", false).await.unwrap(), Some(0));
+        assert_eq!(con.zrank(KEY, "bob", true).await.unwrap(), Some(2));
+        assert_eq!(con.zrank(KEY, "carol", true).await.unwrap(), Some(0));
+        assert_eq!(con.zrank(KEY, "nobody", false).await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn zrandmember_works() {
+        const KEY: &str = "zrandmember_works";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("ZADD").arg(KEY).arg(&["1", "a", "2", "b"]))
+            .await
+            .unwrap();
+
+        let one: Vec<String> = con.zrandmember(KEY, None).await.unwrap();
+        assert_eq!(one.len(), 1);
+        let repeated: Vec<String> = con.zrandmember(KEY, Some(-5)).await.unwrap();
+        assert_eq!(repeated.len(), 5);
+        let mut scored: Vec<(String, f64)> = con.zrandmember_with_scores(KEY, 5).await.unwrap();
+        scored.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(scored, vec![("a".into(), 1.0), ("b".into(), 2.0)]);
+    }
+
+    #[actix_rt::test]
+    async fn lex_ranges_keep_bracket_syntax() {
+        const KEY: &str = "lex_ranges_keep_bracket_syntax";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(
+                redis::cmd("ZADD")
+                    .arg(KEY)
+                    .arg(&["0", "apple", "0", "banana", "0", "cherry", "0", "date"]),
+            )
+            .await
+            .unwrap();
+
+        let inclusive: Vec<String> = con
+            .zrangebylex(KEY, "[banana", "[date", None)
+            .await
+            .unwrap();
+        assert_eq!(inclusive, vec!["banana", "cherry", "date"]);
+        let exclusive: Vec<String> = con
+            .zrangebylex(KEY, "(banana", "(date", None)
+            .await
+            .unwrap();
+        assert_eq!(exclusive, vec!["cherry"]);
+        let limited: Vec<String> = con.zrangebylex(KEY, "-", "+", Some((1, 2))).await.unwrap();
+        assert_eq!(limited, vec!["banana", "cherry"]);
+
+        assert_eq!(con.zlexcount(KEY, "-", "+").await.unwrap(), 4);
+        assert_eq!(con.zlexcount(KEY, "(apple", "[c").await.unwrap(), 1);
+    }
+}
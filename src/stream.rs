@@ -0,0 +1,595 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Stream helpers
+use std::time::Duration;
+
+use redis::{from_redis_value, ErrorKind, RedisResult, ToRedisArgs, Value};
+
+use crate::RedisConnection;
+
+/// An entry of a stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamEntry {
+    /// ID of the entry, `<milliseconds>-<sequence>`
+    pub id: String,
+    /// Field-value pairs in the order they were added
+    pub fields: Vec<(String, Vec<u8>)>,
+}
+
+impl StreamEntry {
+    /// Value of the first `field`, `None` if the entry doesn't have it
+    pub fn get(&self, field: &str) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(f, _)| f == field)
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// An entry delivered to a consumer group but not acknowledged yet, see
+/// [RedisConnection::xpending]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingEntry {
+    pub id: String,
+    /// Consumer the entry was last delivered to
+    pub consumer: String,
+    /// Time since the entry was last delivered
+    pub idle: Duration,
+    /// How many times the entry was delivered
+    pub deliveries: u64,
+}
+
+/// Entries read back for a consumer, along with the pending ones found
+/// deleted from the stream, which are still pending until acknowledged
+#[derive(Default)]
+pub(crate) struct Batch {
+    pub(crate) entries: Vec<StreamEntry>,
+    pub(crate) deleted: Vec<String>,
+    /// ID of the last entry of the reply, deleted or not, to read on from
+    pub(crate) last_id: Option<String>,
+}
+
+/// Which entries [RedisConnection::xtrim] evicts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum XTrimStrategy {
+    /// Keep the `len` newest entries
+    MaxLen { len: u64, approximate: bool },
+    /// Evict entries with IDs lower than `id` (Redis 6.2+)
+    MinId { id: String, approximate: bool },
+}
+
+impl RedisConnection {
+    /// Append an entry with `fields` to the stream at `key` (XADD), creating
+    /// the stream if needed, and return the ID the server gave it
+    pub async fn xadd<V: ToRedisArgs>(
+        &self,
+        key: &str,
+        fields: &[(&str, V)],
+    ) -> RedisResult<String> {
+        self.exec(redis::cmd("XADD").arg(key).arg("*").arg(fields))
+            .await
+    }
+
+    /// Create consumer group `group` of the stream at `key` (XGROUP CREATE),
+    /// delivering the entries after `start`: `$` for new entries only, `0`
+    /// for every entry. With `mkstream` the stream is created if it doesn't
+    /// exist. Returns false if the group exists already.
+    pub async fn xgroup_create(
+        &self,
+        key: &str,
+        group: &str,
+        start: &str,
+        mkstream: bool,
+    ) -> RedisResult<bool> {
+        let mut cmd = redis::cmd("XGROUP");
+        cmd.arg("CREATE").arg(key).arg(group).arg(start);
+        if mkstream {
+            cmd.arg("MKSTREAM");
+        }
+        match self.exec::<()>(&mut cmd).await {
+            Ok(()) => Ok(true),
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Up to `count` entries pending in group `group` of the stream at `key`
+    /// (XPENDING), oldest first, only those of `consumer` if given
+    pub async fn xpending(
+        &self,
+        key: &str,
+        group: &str,
+        count: usize,
+        consumer: Option<&str>,
+    ) -> RedisResult<Vec<PendingEntry>> {
+        let mut cmd = redis::cmd("XPENDING");
+        cmd.arg(key).arg(group).arg("-").arg("+").arg(count);
+        if let Some(consumer) = consumer {
+            cmd.arg(consumer);
+        }
+        let pending: Vec<(String, String, u64, u64)> = self.exec(&mut cmd).await?;
+        Ok(pending
+            .into_iter()
+            .map(|(id, consumer, idle, deliveries)| PendingEntry {
+                id,
+                consumer,
+                idle: Duration::from_millis(idle),
+                deliveries,
+            })
+            .collect())
+    }
+
+    /// Number of entries in the stream at `key` (XLEN), 0 if it doesn't exist
+    pub async fn xlen(&self, key: &str) -> RedisResult<u64> {
+        self.exec(redis::cmd("XLEN").arg(key)).await
+    }
+
+    /// Entries of the stream at `key` with IDs between `start` and `end`,
+    /// both inclusive (XRANGE). `-` and `+` stand for the lowest and highest
+    /// IDs; prefix an ID with `(` to make that bound exclusive (Redis 6.2+).
+    pub async fn xrange(
+        &self,
+        key: &str,
+        start: &str,
+        end: &str,
+        count: Option<usize>,
+    ) -> RedisResult<Vec<StreamEntry>> {
+        let mut cmd = redis::cmd("XRANGE");
+        cmd.arg(key).arg(start).arg(end);
+        if let Some(count) = count {
+            cmd.arg("COUNT").arg(count);
+        }
+        let reply: Value = self.exec(&mut cmd).await?;
+        parse_entries(&reply)
+    }
+
+    /// Evict entries from the stream at `key` (XTRIM), returning how many
+    /// were removed.
+    ///
+    /// With `approximate`, the server only evicts whole internal nodes
+    /// (`~`), which is much cheaper but may leave a few more entries than
+    /// asked for.
+    pub async fn xtrim(&self, key: &str, strategy: XTrimStrategy) -> RedisResult<u64> {
+        let mut cmd = redis::cmd("XTRIM");
+        cmd.arg(key);
+        let approximate = match &strategy {
+            XTrimStrategy::MaxLen { approximate, .. } => {
+                cmd.arg("MAXLEN");
+                *approximate
+            }
+            XTrimStrategy::MinId { approximate, .. } => {
+                cmd.arg("MINID");
+                *approximate
+            }
+        };
+        cmd.arg(if approximate { "~" } else { "=" });
+        match strategy {
+            XTrimStrategy::MaxLen { len, .. } => cmd.arg(len),
+            XTrimStrategy::MinId { id, .. } => cmd.arg(id),
+        };
+        self.exec(&mut cmd).await
+    }
+
+    /// Transfer pending entries of consumer group `group` that have been idle
+    /// for at least `min_idle` to `consumer` (XAUTOCLAIM, Redis 6.2+),
+    /// scanning the group's pending entries from ID `start` (`0-0` for the
+    /// beginning). At most `count` entries are claimed, 100 by default.
+    ///
+    /// Returns the cursor to pass as `start` to continue the scan, `0-0` once
+    /// it is complete, and the claimed entries. Pending entries that were
+    /// deleted from the stream are dropped from the group by the server and
+    /// left out of the result.
+    pub async fn xautoclaim(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        min_idle: Duration,
+        start: &str,
+        count: Option<usize>,
+    ) -> RedisResult<(String, Vec<StreamEntry>)> {
+        let (cursor, batch) = self
+            .xautoclaim_batch(key, group, consumer, min_idle, start, count)
+            .await?;
+        Ok((cursor, batch.entries))
+    }
+
+    /// [Self::xautoclaim], keeping the IDs of deleted entries Redis < 7
+    /// claims as `[id, nil]` rather than dropping them from the group
+    pub(crate) async fn xautoclaim_batch(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        min_idle: Duration,
+        start: &str,
+        count: Option<usize>,
+    ) -> RedisResult<(String, Batch)> {
+        let mut cmd = redis::cmd("XAUTOCLAIM");
+        cmd.arg(key)
+            .arg(group)
+            .arg(consumer)
+            .arg(min_idle.as_millis() as u64)
+            .arg(start);
+        if let Some(count) = count {
+            cmd.arg("COUNT").arg(count);
+        }
+        let reply: Value = self.exec(&mut cmd).await?;
+        parse_autoclaim(&reply)
+    }
+
+    /// Transfer the pending entries `ids` of consumer group `group` to
+    /// `consumer` if they have been idle for at least `min_idle` (XCLAIM),
+    /// returning the entries that were claimed. Entries deleted from the
+    /// stream are left out.
+    pub async fn xclaim(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        min_idle: Duration,
+        ids: &[&str],
+    ) -> RedisResult<Vec<StreamEntry>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let reply: Value = self
+            .exec(
+                redis::cmd("XCLAIM")
+                    .arg(key)
+                    .arg(group)
+                    .arg(consumer)
+                    .arg(min_idle.as_millis() as u64)
+                    .arg(ids),
+            )
+            .await?;
+        parse_claimed(&reply)
+    }
+
+    /// Read entries of the stream at `key` as `consumer` of group `group`
+    /// (XREADGROUP), at most `count` of them.
+    ///
+    /// `id` is `>` for entries never delivered to the group, which are then
+    /// pending for `consumer` until acknowledged with [Self::xack]. Any other
+    /// ID reads back the entries pending for `consumer` after it instead,
+    /// leaving out the ones deleted from the stream meanwhile. With `block`,
+    /// waits that long for new entries (BLOCK), rounded up to a millisecond,
+    /// if there are none; an empty result means the wait timed out.
+    pub async fn xreadgroup(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        id: &str,
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> RedisResult<Vec<StreamEntry>> {
+        let batch = self
+            .xreadgroup_batch(key, group, consumer, id, count, block)
+            .await?;
+        Ok(batch.entries)
+    }
+
+    /// [Self::xreadgroup], keeping the IDs of deleted pending entries
+    pub(crate) async fn xreadgroup_batch(
+        &self,
+        key: &str,
+        group: &str,
+        consumer: &str,
+        id: &str,
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> RedisResult<Batch> {
+        let mut cmd = redis::cmd("XREADGROUP");
+        cmd.arg("GROUP").arg(group).arg(consumer);
+        if let Some(count) = count {
+            cmd.arg("COUNT").arg(count);
+        }
+        if let Some(block) = block {
+            // BLOCK 0 would wait forever
+            cmd.arg("BLOCK").arg((block.as_millis() as u64).max(1));
+        }
+        cmd.arg("STREAMS").arg(key).arg(id);
+        let reply: Value = self.exec(&mut cmd).await?;
+        parse_read_group(&reply)
+    }
+
+    /// Acknowledge the pending entries `ids` of group `group` (XACK),
+    /// returning how many were pending
+    pub async fn xack(&self, key: &str, group: &str, ids: &[&str]) -> RedisResult<u64> {
+        self.exec(redis::cmd("XACK").arg(key).arg(group).arg(ids))
+            .await
+    }
+}
+
+/// Parse an XREADGROUP reply over a single stream: `[[key, [entry, ...]]]`,
+/// or nil when BLOCK timed out. Pending entries deleted from the stream come
+/// back as `[id, nil]`.
+fn parse_read_group(reply: &Value) -> RedisResult<Batch> {
+    match reply {
+        Value::Nil => Ok(Batch::default()),
+        Value::Bulk(streams) => match streams.as_slice() {
+            [] => Ok(Batch::default()),
+            [Value::Bulk(stream)] if stream.len() == 2 => parse_batch(&stream[1]),
+            _ => Err(invalid_read_group(reply)),
+        },
+        _ => Err(invalid_read_group(reply)),
+    }
+}
+
+fn invalid_read_group(reply: &Value) -> redis::RedisError {
+    (
+        ErrorKind::TypeError,
+        "Response was of incompatible type",
+        format!("expected [[key, [entry, ...]]], got {:?}", reply),
+    )
+        .into()
+}
+
+/// Parse an XAUTOCLAIM reply: `[cursor, [entry, ...]]`, followed by the IDs of
+/// deleted entries on Redis 7+
+fn parse_autoclaim(reply: &Value) -> RedisResult<(String, Batch)> {
+    match reply {
+        Value::Bulk(parts) if parts.len() == 2 || parts.len() == 3 => {
+            Ok((from_redis_value(&parts[0])?, parse_batch(&parts[1])?))
+        }
+        _ => Err((
+            ErrorKind::TypeError,
+            "Response was of incompatible type",
+            format!("expected [cursor, [entry, ...], ...], got {:?}", reply),
+        )
+            .into()),
+    }
+}
+
+/// Parse claimed entries, skipping the ones Redis < 7 reports as deleted
+/// with a nil entry or nil fields
+fn parse_claimed(reply: &Value) -> RedisResult<Vec<StreamEntry>> {
+    Ok(parse_batch(reply)?.entries)
+}
+
+/// Parse entries read back for a consumer, setting the deleted ones apart
+fn parse_batch(reply: &Value) -> RedisResult<Batch> {
+    let items = match reply {
+        Value::Bulk(items) => items,
+        _ => return Err(invalid_entries(reply)),
+    };
+    let mut batch = Batch::default();
+    for item in items {
+        match item {
+            Value::Nil => {}
+            Value::Bulk(entry) if matches!(entry.as_slice(), [_, Value::Nil]) => {
+                let id: String = from_redis_value(&entry[0])?;
+                batch.last_id = Some(id.clone());
+                batch.deleted.push(id);
+            }
+            _ => {
+                let entry = parse_entry(item)?;
+                batch.last_id = Some(entry.id.clone());
+                batch.entries.push(entry);
+            }
+        }
+    }
+    Ok(batch)
+}
+
+/// Parse an array of `[id, [field, value, ...]]` entries, as replied by XRANGE
+/// and friends
+pub(crate) fn parse_entries(reply: &Value) -> RedisResult<Vec<StreamEntry>> {
+    match reply {
+        Value::Bulk(entries) => entries.iter().map(parse_entry).collect(),
+        _ => Err(invalid_entries(reply)),
+    }
+}
+
+fn invalid_entries(reply: &Value) -> redis::RedisError {
+    (
+        ErrorKind::TypeError,
+        "Response was of incompatible type",
+        format!("expected an array of stream entries, got {:?}", reply),
+    )
+        .into()
+}
+
+fn parse_entry(entry: &Value) -> RedisResult<StreamEntry> {
+    match entry {
+        Value::Bulk(entry) => match entry.as_slice() {
+            [id, Value::Bulk(fields)] if fields.len() % 2 == 0 => Ok(StreamEntry {
+                id: from_redis_value(id)?,
+                fields: fields
+                    .chunks(2)
+                    .map(|pair| Ok((from_redis_value(&pair[0])?, from_redis_value(&pair[1])?)))
+                    .collect::<RedisResult<_>>()?,
+            }),
+            _ => Err(invalid_entry(entry)),
+        },
+        _ => Err(invalid_entry(entry)),
+    }
+}
+
+fn invalid_entry(entry: impl std::fmt::Debug) -> redis::RedisError {
+    (
+        ErrorKind::TypeError,
+        "Response was of incompatible type",
+        format!("expected [id, [field, value, ...]], got {:?}", entry),
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[test]
+    fn parse_entries_works() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let reply = Value::Bulk(vec![Value::Bulk(vec![
+            data("1-0"),
+            Value::Bulk(vec![data("a"), data("1"), data("b"), data("2")]),
+        ])]);
+        let entries = parse_entries(&reply).unwrap();
+        assert_eq!(entries[0].id, "1-0");
+        assert_eq!(entries[0].get("b"), Some(&b"2"[..]));
+        assert_eq!(entries[0].get("c"), None);
+
+        let odd = Value::Bulk(vec![Value::Bulk(vec![
+            data("1-0"),
+            Value::Bulk(vec![data("a")]),
+        ])]);
+        assert!(parse_entries(&odd).is_err());
+    }
+
+    #[test]
+    fn parse_autoclaim_skips_deleted() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let reply = Value::Bulk(vec![
+            data("0-0"),
+            Value::Bulk(vec![
+                Value::Bulk(vec![data("1-0"), Value::Bulk(vec![data("f"), data("v")])]),
+                Value::Bulk(vec![data("2-0"), Value::Nil]),
+                Value::Nil,
+            ]),
+            Value::Bulk(vec![data("3-0")]),
+        ]);
+        let (cursor, batch) = parse_autoclaim(&reply).unwrap();
+        assert_eq!(cursor, "0-0");
+        assert_eq!(
+            batch.entries,
+            vec![StreamEntry {
+                id: "1-0".into(),
+                fields: vec![("f".into(), b"v".to_vec())]
+            }]
+        );
+        assert_eq!(batch.deleted, vec!["2-0".to_string()]);
+        assert!(parse_autoclaim(&data("0-0")).is_err());
+    }
+
+    #[test]
+    fn parse_read_group_keeps_deleted_ids() {
+        let data = |s: &str| Value::Data(s.as_bytes().to_vec());
+        let stream = |entries: Vec<Value>| {
+            Value::Bulk(vec![Value::Bulk(vec![data("key"), Value::Bulk(entries)])])
+        };
+        // a full batch of pending entries, all deleted from the stream
+        let deleted: Vec<_> = (1..=16)
+            .map(|i| Value::Bulk(vec![data(&format!("{}-0", i)), Value::Nil]))
+            .collect();
+        let batch = parse_read_group(&stream(deleted)).unwrap();
+        assert!(batch.entries.is_empty());
+        assert_eq!(batch.deleted.len(), 16);
+        assert_eq!(batch.deleted[0], "1-0");
+        assert_eq!(batch.last_id.as_deref(), Some("16-0"));
+
+        let batch = parse_read_group(&stream(vec![
+            Value::Bulk(vec![data("1-0"), Value::Bulk(vec![data("f"), data("v")])]),
+            Value::Bulk(vec![data("2-0"), Value::Nil]),
+        ]))
+        .unwrap();
+        assert_eq!(batch.entries.len(), 1);
+        assert_eq!(batch.deleted, vec!["2-0".to_string()]);
+        assert_eq!(batch.last_id.as_deref(), Some("2-0"));
+
+        let batch = parse_read_group(&Value::Nil).unwrap();
+        assert!(batch.entries.is_empty() && batch.last_id.is_none());
+    }
+
+    #[actix_rt::test]
+    async fn xautoclaim_takes_over_pending_entries() {
+        const KEY: &str = "xautoclaim_takes_over_pending_entries";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        let _: () = con
+            .exec(redis::cmd("XGROUP").arg(&["CREATE", KEY, "g", "$", "MKSTREAM"]))
+            .await
+            .unwrap();
+        let id: String = con
+            .exec(redis::cmd("XADD").arg(&[KEY, "*", "job", "1"]))
+            .await
+            .unwrap();
+        // the dead consumer reads the entry and never acknowledges it
+        let _: Value = con
+            .exec(redis::cmd("XREADGROUP").arg(&["GROUP", "g", "dead", "STREAMS", KEY, ">"]))
+            .await
+            .unwrap();
+
+        let (cursor, claimed) = con
+            .xautoclaim(KEY, "g", "healthy", Duration::ZERO, "0-0", None)
+            .await
+            .unwrap();
+        assert_eq!(cursor, "0-0");
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, id);
+        assert_eq!(claimed[0].get("job"), Some(&b"1"[..]));
+
+        let pending: Value = con
+            .exec(redis::cmd("XPENDING").arg(&[KEY, "g", "-", "+", "10", "healthy"]))
+            .await
+            .unwrap();
+        assert!(matches!(pending, Value::Bulk(ref p) if p.len() == 1));
+
+        // idle for less than a minute, so nothing to claim
+        let claimed = con
+            .xclaim(KEY, "g", "other", Duration::from_secs(60), &[&id])
+            .await
+            .unwrap();
+        assert!(claimed.is_empty());
+        let claimed = con
+            .xclaim(KEY, "g", "other", Duration::ZERO, &[&id])
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn stream_management_works() {
+        const KEY: &str = "stream_management_works";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con.exec(redis::cmd("DEL").arg(KEY)).await.unwrap();
+        for i in 1..=5 {
+            let _: String = con
+                .exec(
+                    redis::cmd("XADD")
+                        .arg(KEY)
+                        .arg(format!("{}-0", i))
+                        .arg("n")
+                        .arg(i),
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(con.xlen(KEY).await.unwrap(), 5);
+
+        let all = con.xrange(KEY, "-", "+", None).await.unwrap();
+        let ids: Vec<_> = all.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec!["1-0", "2-0", "3-0", "4-0", "5-0"]);
+        assert_eq!(all[2].get("n"), Some(&b"3"[..]));
+        let some = con.xrange(KEY, "2", "+", Some(2)).await.unwrap();
+        assert_eq!(some.len(), 2);
+        assert_eq!(some[0].id, "2-0");
+
+        let trim = XTrimStrategy::MaxLen {
+            len: 3,
+            approximate: false,
+        };
+        assert_eq!(con.xtrim(KEY, trim).await.unwrap(), 2);
+        let trim = XTrimStrategy::MinId {
+            id: "5-0".into(),
+            approximate: false,
+        };
+        assert_eq!(con.xtrim(KEY, trim).await.unwrap(), 2);
+        assert_eq!(con.xlen(KEY).await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,118 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Invalidation messages for client-side caching
+use futures::stream::{self, BoxStream, StreamExt};
+use redis::{ErrorKind, RedisResult};
+
+use crate::{Redis, RedisConnection};
+
+/// Channel the server publishes invalidations on when tracking redirects
+const INVALIDATE: &str = "__redis__:invalidate";
+
+/// An invalidation reported by the server, see [Redis::track_invalidations]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvalidatedKey {
+    /// The key was modified, expired or evicted
+    Key(String),
+    /// The database was flushed: drop everything cached locally
+    All,
+}
+
+impl Redis {
+    /// Open a connection with client-side caching tracking enabled (CLIENT
+    /// TRACKING, Redis 6+), along with the stream of invalidations for the
+    /// keys read through it.
+    ///
+    /// Invalidations arrive through a second connection subscribed to
+    /// `__redis__:invalidate`, which the tracked connection redirects to, so
+    /// this works without RESP3. The stream ends when that connection is
+    /// lost; a local cache must be dropped entirely at that point, as
+    /// invalidations may have been missed. Not available in cluster mode.
+    pub async fn track_invalidations(
+        &self,
+    ) -> RedisResult<(RedisConnection, BoxStream<'static, InvalidatedKey>)> {
+        let client = match self.client.single().await? {
+            Some(client) => client,
+            None => {
+                return Err((
+                    ErrorKind::ClientError,
+                    "client tracking is not supported in cluster mode",
+                )
+                    .into())
+            }
+        };
+        let mut listener = client.get_async_connection().await?;
+        let id: u64 = redis::cmd("CLIENT")
+            .arg("ID")
+            .query_async(&mut listener)
+            .await?;
+        let mut listener = listener.into_pubsub();
+        listener.subscribe(INVALIDATE).await?;
+
+        let tracked = self.dedicated().await?;
+        let _: () = tracked
+            .exec(
+                redis::cmd("CLIENT")
+                    .arg(&["TRACKING", "ON", "REDIRECT"])
+                    .arg(id),
+            )
+            .await?;
+
+        let invalidations = listener
+            .into_on_message()
+            .flat_map(|msg| {
+                // the payload is the array of invalidated keys, or nil when
+                // the database was flushed
+                let keys = match msg.get_payload::<Option<Vec<String>>>() {
+                    Ok(Some(keys)) => keys.into_iter().map(InvalidatedKey::Key).collect(),
+                    Ok(None) | Err(_) => vec![InvalidatedKey::All],
+                };
+                stream::iter(keys)
+            })
+            .boxed();
+        Ok((tracked, invalidations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn invalidations_arrive() {
+        const KEY: &str = "invalidations_arrive";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        // a database of its own, so that flushing it is harmless
+        let writer = r.dedicated().await.unwrap();
+        let _: () = writer.exec(redis::cmd("SELECT").arg(11)).await.unwrap();
+        let _: () = writer
+            .exec(redis::cmd("SET").arg(&[KEY, "1"]))
+            .await
+            .unwrap();
+
+        let (tracked, mut invalidations) = r.track_invalidations().await.unwrap();
+        let _: () = tracked.exec(redis::cmd("SELECT").arg(11)).await.unwrap();
+        let _: String = tracked.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        let _: () = writer
+            .exec(redis::cmd("SET").arg(&[KEY, "2"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            invalidations.next().await,
+            Some(InvalidatedKey::Key(KEY.into()))
+        );
+
+        let _: String = tracked.exec(redis::cmd("GET").arg(KEY)).await.unwrap();
+        let _: () = writer.exec(&mut redis::cmd("FLUSHDB")).await.unwrap();
+        assert_eq!(invalidations.next().await, Some(InvalidatedKey::All));
+    }
+}
@@ -0,0 +1,174 @@
+/* Redis Glue is provides abstractions over single and cluster mode Redis interactions
+ * Copyright 2021 Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License") or MIT
+ */
+
+//! Fetching a key's value whatever its type
+use redis::{ErrorKind, RedisResult};
+
+use crate::{RedisConnection, StreamEntry};
+
+/// The whole value of a key, see [RedisConnection::fetch_any]
+#[derive(Clone, Debug, PartialEq)]
+pub enum RedisValue {
+    Str(Vec<u8>),
+    /// Elements from head to tail
+    List(Vec<Vec<u8>>),
+    /// Members in no particular order
+    Set(Vec<Vec<u8>>),
+    /// Field-value pairs in no particular order
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+    /// Member-score pairs from the lowest score to the highest
+    ZSet(Vec<(Vec<u8>, f64)>),
+    /// Entries from the oldest to the newest
+    Stream(Vec<StreamEntry>),
+}
+
+impl RedisConnection {
+    /// The value of `key` whatever its type, `None` if the key doesn't exist.
+    ///
+    /// Looks the type up with TYPE first and then reads the whole value with
+    /// the matching command (GET, LRANGE, SMEMBERS, HGETALL, ZRANGE or
+    /// XRANGE), so large collections are loaded entirely. The two commands
+    /// aren't atomic: if the key is deleted in between, `None` is returned.
+    /// Types added by modules fail with an error naming the type.
+    pub async fn fetch_any(&self, key: &str) -> RedisResult<Option<RedisValue>> {
+        let key_type: String = self.exec(redis::cmd("TYPE").arg(key)).await?;
+        let value = match key_type.as_str() {
+            "none" => return Ok(None),
+            "string" => {
+                let value: Option<Vec<u8>> = self.exec(redis::cmd("GET").arg(key)).await?;
+                return Ok(value.map(RedisValue::Str));
+            }
+            "list" => RedisValue::List(
+                self.exec(redis::cmd("LRANGE").arg(key).arg(0).arg(-1))
+                    .await?,
+            ),
+            "set" => RedisValue::Set(self.exec(redis::cmd("SMEMBERS").arg(key)).await?),
+            "hash" => RedisValue::Hash(self.exec(redis::cmd("HGETALL").arg(key)).await?),
+            "zset" => RedisValue::ZSet(
+                self.exec(
+                    redis::cmd("ZRANGE")
+                        .arg(key)
+                        .arg(0)
+                        .arg(-1)
+                        .arg("WITHSCORES"),
+                )
+                .await?,
+            ),
+            "stream" => RedisValue::Stream(self.xrange(key, "-", "+", None).await?),
+            _ => {
+                return Err((
+                    ErrorKind::TypeError,
+                    "fetch_any doesn't support this type",
+                    format!("{} is a {}", key, key_type),
+                )
+                    .into())
+            }
+        };
+        // Redis removes emptied collections, so an empty one means the key
+        // went away after TYPE. Streams are the exception, they can be empty.
+        let gone = match &value {
+            RedisValue::List(v) | RedisValue::Set(v) => v.is_empty(),
+            RedisValue::Hash(v) => v.is_empty(),
+            RedisValue::ZSet(v) => v.is_empty(),
+            RedisValue::Str(_) | RedisValue::Stream(_) => false,
+        };
+        Ok(if gone { None } else { Some(value) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    #[actix_rt::test]
+    async fn fetch_any_matches_type() {
+        const STR: &str = "fetch_any_matches_type_str";
+        const LIST: &str = "fetch_any_matches_type_list";
+        const SET: &str = "fetch_any_matches_type_set";
+        const HASH: &str = "fetch_any_matches_type_hash";
+        const ZSET: &str = "fetch_any_matches_type_zset";
+        const STREAM: &str = "fetch_any_matches_type_stream";
+
+        let r = Redis::new(RedisConfig::Single("redis://127.0.0.1".into()))
+            .await
+            .unwrap();
+        let con = r.get_client();
+        let _: () = con
+            .exec(redis::cmd("DEL").arg(&[STR, LIST, SET, HASH, ZSET, STREAM]))
+            .await
+            .unwrap();
+        assert!(con.fetch_any(STR).await.unwrap().is_none());
+
+        let _: () = con.exec(redis::cmd("SET").arg(&[STR, "v"])).await.unwrap();
+        assert_eq!(
+            con.fetch_any(STR).await.unwrap(),
+            Some(RedisValue::Str(b"v".to_vec()))
+        );
+
+        let _: () = con
+            .exec(redis::cmd("RPUSH").arg(LIST).arg(&["a", "b", "a"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            con.fetch_any(LIST).await.unwrap(),
+            Some(RedisValue::List(vec![
+                b"a".to_vec(),
+                b"b".to_vec(),
+                b"a".to_vec()
+            ]))
+        );
+
+        let _: () = con
+            .exec(redis::cmd("SADD").arg(SET).arg(&["x", "y"]))
+            .await
+            .unwrap();
+        match con.fetch_any(SET).await.unwrap() {
+            Some(RedisValue::Set(mut members)) => {
+                members.sort();
+                assert_eq!(members, vec![b"x".to_vec(), b"y".to_vec()]);
+            }
+            other => panic!("expected a set, got {:?}", other),
+        }
+
+        let _: () = con
+            .exec(redis::cmd("HSET").arg(HASH).arg(&["f", "v"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            con.fetch_any(HASH).await.unwrap(),
+            Some(RedisValue::Hash(vec![(b"f".to_vec(), b"v".to_vec())]))
+        );
+
+        let _: () = con
+            .exec(
+                redis::cmd("ZADD")
+                    .arg(ZSET)
+                    .arg(&["2", "two", "1.5", "one"]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            con.fetch_any(ZSET).await.unwrap(),
+            Some(RedisValue::ZSet(vec![
+                (b"one".to_vec(), 1.5),
+                (b"two".to_vec(), 2.0)
+            ]))
+        );
+
+        let id: String = con
+            .exec(redis::cmd("XADD").arg(STREAM).arg(&["*", "f", "v"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            con.fetch_any(STREAM).await.unwrap(),
+            Some(RedisValue::Stream(vec![StreamEntry {
+                id,
+                fields: vec![("f".into(), b"v".to_vec())]
+            }]))
+        );
+    }
+}